@@ -0,0 +1,10 @@
+ALTER TABLE users ALTER COLUMN hashed_password DROP NOT NULL;
+
+CREATE TABLE oauth_flows (
+    state TEXT PRIMARY KEY,
+    provider TEXT NOT NULL,
+    pkce_verifier TEXT NOT NULL,
+    nonce TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    expires_at TIMESTAMPTZ NOT NULL
+);
@@ -0,0 +1,23 @@
+-- Addresses are about to be stored in canonical (lowercase) form, and
+-- accounts that only differ in case would clash then, so this runs first. The
+-- one that verified the address keeps it (the oldest verification, if several
+-- did); the others get a placeholder nobody can log in with, and their
+-- address is kept here for support to sort out with their owners.
+CREATE TABLE email_address_conflicts (
+    user_id UUID PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+    email_address TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+INSERT INTO email_address_conflicts (user_id, email_address)
+SELECT id, email_address FROM (
+    SELECT id, email_address, row_number() OVER (
+        PARTITION BY lower(trim(email_address))
+        ORDER BY verified_at IS NULL, verified_at, id
+    ) AS rank
+    FROM users
+) AS ranked
+WHERE rank > 1;
+
+UPDATE users SET email_address = id::text || '@conflicts.invalid'
+WHERE id IN (SELECT user_id FROM email_address_conflicts);
@@ -0,0 +1,6 @@
+-- Addresses are now stored in canonical (lowercase) form; make sure older
+-- rows follow suit, and that no two users can share an address however it
+-- was typed.
+UPDATE users SET email_address = lower(trim(email_address));
+
+CREATE UNIQUE INDEX users_email_address_lower_idx ON users (lower(email_address));
@@ -0,0 +1,9 @@
+ALTER TABLE users
+    ADD COLUMN handle TEXT,
+    ADD COLUMN avatar_url TEXT,
+    ADD COLUMN locale TEXT,
+    ADD COLUMN timezone TEXT,
+    ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now();
+
+-- Handles are unique whatever their case
+CREATE UNIQUE INDEX users_handle_idx ON users (lower(handle));
@@ -0,0 +1,10 @@
+-- Handles users have moved away from. They keep pointing at (and can only be
+-- taken back by) their last owner.
+CREATE TABLE handle_history (
+    handle TEXT NOT NULL,
+    user_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    retired_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE UNIQUE INDEX handle_history_handle_idx ON handle_history (lower(handle));
+CREATE INDEX handle_history_user_id_idx ON handle_history (user_id);
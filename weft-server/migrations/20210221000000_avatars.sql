@@ -0,0 +1,3 @@
+-- Where the uploaded avatar is kept, if `avatar_url` points at one. Set to
+-- NULL when the avatar URL is changed some other way.
+ALTER TABLE users ADD COLUMN avatar_key TEXT;
@@ -0,0 +1,36 @@
+-- Accounts their users asked to have deleted. No foreign key: the row has to
+-- outlive the user, to tell purges that are done from those still to do.
+CREATE TABLE account_deletions (
+    user_id UUID PRIMARY KEY,
+    requested_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    purge_after TIMESTAMPTZ NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    purged_at TIMESTAMPTZ
+);
+
+CREATE INDEX account_deletions_due_idx ON account_deletions (purge_after)
+WHERE purged_at IS NULL;
+
+-- Audit events stay append-only, except that purging an account may blank
+-- out the personal data in them: nothing else about an event can change.
+CREATE OR REPLACE FUNCTION audit_events_append_only() RETURNS trigger AS $$
+BEGIN
+    IF TG_OP = 'UPDATE' THEN
+        IF current_setting('weft.redacting_audit_events', true) = 'on'
+            AND NEW.id = OLD.id
+            AND NEW.occurred_at = OLD.occurred_at
+            AND NEW.kind = OLD.kind
+            AND NEW.actor_id IS NOT DISTINCT FROM OLD.actor_id
+            AND NEW.subject_id IS NOT DISTINCT FROM OLD.subject_id
+            AND NEW.request_id IS NOT DISTINCT FROM OLD.request_id
+            AND (NEW.ip_address IS NULL OR NEW.ip_address = OLD.ip_address)
+            AND (NEW.user_agent IS NULL OR NEW.user_agent = OLD.user_agent)
+            AND OLD.details @> NEW.details
+        THEN
+            RETURN NEW;
+        END IF;
+    END IF;
+    RAISE EXCEPTION 'audit_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
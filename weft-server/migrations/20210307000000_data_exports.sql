@@ -0,0 +1,20 @@
+-- Exports of everything about a user. Their files are kept in storage under
+-- exports/<user id>/<export id>, and `entries` says where each goes in the
+-- archive.
+CREATE TABLE data_exports (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    status TEXT NOT NULL DEFAULT 'pending',
+    requested_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    started_at TIMESTAMPTZ,
+    completed_at TIMESTAMPTZ,
+    expires_at TIMESTAMPTZ,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    entries JSONB NOT NULL DEFAULT '[]'
+);
+
+CREATE INDEX data_exports_user_id_idx ON data_exports (user_id, requested_at);
+-- One pending export per user at a time
+CREATE UNIQUE INDEX data_exports_pending_idx ON data_exports (user_id)
+WHERE status = 'pending';
@@ -0,0 +1,10 @@
+-- Logins waiting on their second factor, each of which can only be
+-- completed once
+CREATE TABLE pending_mfa_logins (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    expires_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX pending_mfa_logins_user_id_idx ON pending_mfa_logins (user_id);
@@ -0,0 +1,3 @@
+-- Only passkeys that verify their user (PIN, biometrics...) can log in on
+-- their own. Existing ones count as such once they've done it at least once.
+ALTER TABLE webauthn_credentials ADD COLUMN user_verified BOOLEAN NOT NULL DEFAULT false;
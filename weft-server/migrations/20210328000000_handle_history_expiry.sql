@@ -0,0 +1,3 @@
+-- Retired handles are only held on to for a while; the expired ones get
+-- cleared out whenever someone changes handles
+CREATE INDEX handle_history_retired_at_idx ON handle_history (retired_at);
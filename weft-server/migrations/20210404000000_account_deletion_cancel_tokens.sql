@@ -0,0 +1,6 @@
+-- Scheduling a deletion emails its user a link to cancel it, in case it
+-- wasn't them who asked. Only the token's hash is kept.
+ALTER TABLE account_deletions ADD COLUMN cancel_token_hash TEXT;
+
+CREATE UNIQUE INDEX account_deletions_cancel_token_hash_idx
+ON account_deletions (cancel_token_hash);
@@ -0,0 +1,6 @@
+-- Exports point at the avatar their user had when they were built instead of
+-- copying it, so a replaced avatar is kept while an export still points at it
+ALTER TABLE data_exports ADD COLUMN avatar_key TEXT;
+
+CREATE INDEX data_exports_avatar_key_idx ON data_exports (avatar_key)
+WHERE avatar_key IS NOT NULL;
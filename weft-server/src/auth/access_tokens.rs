@@ -0,0 +1,385 @@
+//! Personal access tokens, for scripts and CI jobs that can't hold on to a
+//! browser session. They're sent as `Authorization: Bearer <token>`, and
+//! only allow what their scopes say.
+
+use std::{fmt, str::FromStr};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Uuid, Row};
+
+use super::opaque::{generate_token, hash_token};
+use crate::rejections::FieldError;
+
+/// Tells personal access tokens apart from access token JWTs at a glance,
+/// and makes them easy to spot by secret scanners
+pub const TOKEN_PREFIX: &str = "weft_pat_";
+
+const DEFAULT_LIFETIME_DAYS: i64 = 30;
+const MAX_LIFETIME_DAYS: i64 = 365;
+const MAX_NAME_LENGTH: usize = 64;
+
+/// What a personal access token may do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TokenScope {
+    /// Read the owner's videos, once the video API checks for it
+    #[serde(rename = "read:videos")]
+    ReadVideos,
+    /// Upload and change the owner's videos, once the video API checks for
+    /// it
+    #[serde(rename = "write:videos")]
+    WriteVideos,
+    /// Read the profile of the token's owner, email address included
+    #[serde(rename = "read:profile")]
+    ReadProfile,
+    /// The admin API, as far as the owner's role allows
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+impl TokenScope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TokenScope::ReadVideos => "read:videos",
+            TokenScope::WriteVideos => "write:videos",
+            TokenScope::ReadProfile => "read:profile",
+            TokenScope::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for TokenScope {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read:videos" => Ok(TokenScope::ReadVideos),
+            "write:videos" => Ok(TokenScope::WriteVideos),
+            "read:profile" => Ok(TokenScope::ReadProfile),
+            "admin" => Ok(TokenScope::Admin),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum AccessTokenError {
+    RandomError,
+    DatabaseError(sqlx::Error),
+}
+
+impl fmt::Display for AccessTokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccessTokenError::RandomError => write!(f, "Could not generate access token"),
+            AccessTokenError::DatabaseError(error) => write!(f, "Database error: {:?}", error),
+        }
+    }
+}
+
+impl From<sqlx::Error> for AccessTokenError {
+    fn from(error: sqlx::Error) -> Self {
+        AccessTokenError::DatabaseError(error)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum AccessTokenValidationError {
+    EmptyName,
+    NameTooLong,
+    NoScopes,
+    InvalidLifetime,
+}
+
+impl AccessTokenValidationError {
+    pub fn field(&self) -> &'static str {
+        match self {
+            AccessTokenValidationError::EmptyName | AccessTokenValidationError::NameTooLong => {
+                "name"
+            }
+            AccessTokenValidationError::NoScopes => "scopes",
+            AccessTokenValidationError::InvalidLifetime => "expires_in_days",
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            AccessTokenValidationError::EmptyName | AccessTokenValidationError::NoScopes => {
+                "required"
+            }
+            AccessTokenValidationError::NameTooLong => "too_long",
+            AccessTokenValidationError::InvalidLifetime => "out_of_range",
+        }
+    }
+}
+
+impl fmt::Display for AccessTokenValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccessTokenValidationError::EmptyName => write!(f, "Empty name"),
+            AccessTokenValidationError::NameTooLong => write!(
+                f,
+                "Name must be at most {} characters long",
+                MAX_NAME_LENGTH
+            ),
+            AccessTokenValidationError::NoScopes => write!(f, "At least one scope is required"),
+            AccessTokenValidationError::InvalidLifetime => write!(
+                f,
+                "Tokens must expire within 1 to {} days",
+                MAX_LIFETIME_DAYS
+            ),
+        }
+    }
+}
+
+impl From<AccessTokenValidationError> for FieldError {
+    fn from(error: AccessTokenValidationError) -> Self {
+        FieldError {
+            field: error.field(),
+            code: error.code(),
+            message: error.to_string(),
+        }
+    }
+}
+
+/// What a new token should be called, allowed to do, and for how long
+#[derive(Debug, Deserialize)]
+pub struct NewAccessToken {
+    pub name: String,
+    pub scopes: Vec<TokenScope>,
+    #[serde(default)]
+    pub expires_in_days: Option<i64>,
+}
+
+impl NewAccessToken {
+    pub fn validate(&self) -> Result<(), Vec<AccessTokenValidationError>> {
+        let mut errors = vec![];
+        let name = self.name.trim();
+        if name.is_empty() {
+            errors.push(AccessTokenValidationError::EmptyName);
+        } else if name.chars().count() > MAX_NAME_LENGTH {
+            errors.push(AccessTokenValidationError::NameTooLong);
+        }
+        if self.scopes.is_empty() {
+            errors.push(AccessTokenValidationError::NoScopes);
+        }
+        if !(1..=MAX_LIFETIME_DAYS).contains(&self.lifetime_days()) {
+            errors.push(AccessTokenValidationError::InvalidLifetime);
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn lifetime_days(&self) -> i64 {
+        self.expires_in_days.unwrap_or(DEFAULT_LIFETIME_DAYS)
+    }
+}
+
+/// A personal access token, as listed to its owner. The token itself is
+/// only ever shown once, when it's created.
+#[derive(Debug, Serialize)]
+pub struct PersonalAccessToken {
+    pub id: Uuid,
+    pub name: String,
+    pub scopes: Vec<TokenScope>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+/// A freshly created token, along with the secret to use it with
+#[derive(Debug, Serialize)]
+pub struct IssuedAccessToken {
+    pub token: String,
+    #[serde(flatten)]
+    pub details: PersonalAccessToken,
+}
+
+/// Who a valid token belongs to, and what it allows
+#[derive(Debug, Clone)]
+pub struct AccessTokenGrant {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub scopes: Vec<TokenScope>,
+}
+
+/// Unknown scopes in the database (say, after one got dropped) grant nothing
+fn parse_scopes(scopes: Vec<String>) -> Vec<TokenScope> {
+    scopes
+        .iter()
+        .filter_map(|scope| scope.parse().ok())
+        .collect()
+}
+
+impl PersonalAccessToken {
+    fn from_row(row: &sqlx::postgres::PgRow) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            id: row.try_get("id")?,
+            name: row.try_get("name")?,
+            scopes: parse_scopes(row.try_get("scopes")?),
+            created_at: row.try_get("created_at")?,
+            expires_at: row.try_get("expires_at")?,
+            last_used_at: row.try_get("last_used_at")?,
+        })
+    }
+
+    /// Create a token for `user_id`. `new_token` must have been validated.
+    pub async fn create(
+        db_pool: &sqlx::PgPool,
+        user_id: &Uuid,
+        new_token: &NewAccessToken,
+    ) -> Result<IssuedAccessToken, AccessTokenError> {
+        let token = format!(
+            "{}{}",
+            TOKEN_PREFIX,
+            generate_token().map_err(|_| AccessTokenError::RandomError)?
+        );
+        let mut scopes = new_token
+            .scopes
+            .iter()
+            .map(|scope| scope.as_str().to_string())
+            .collect::<Vec<_>>();
+        scopes.sort_unstable();
+        scopes.dedup();
+
+        let row = sqlx::query(
+            r#"
+INSERT INTO personal_access_tokens (id, user_id, name, token_hash, scopes, expires_at)
+VALUES ($1, $2, $3, $4, $5, $6)
+RETURNING id, name, scopes, created_at, expires_at, last_used_at;"#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(new_token.name.trim())
+        .bind(hash_token(&token))
+        .bind(&scopes)
+        .bind(Utc::now() + Duration::days(new_token.lifetime_days()))
+        .fetch_one(db_pool)
+        .await?;
+
+        Ok(IssuedAccessToken {
+            token,
+            details: Self::from_row(&row)?,
+        })
+    }
+
+    /// Look up an unexpired token of a user in good standing, recording that
+    /// it was just used
+    pub async fn authenticate(
+        db_pool: &sqlx::PgPool,
+        token: &str,
+    ) -> Result<Option<AccessTokenGrant>, sqlx::Error> {
+        let row = sqlx::query(
+            r#"
+UPDATE personal_access_tokens SET last_used_at = now()
+WHERE token_hash = $1 AND expires_at > now()
+    AND user_id IN (SELECT id FROM users WHERE suspended_at IS NULL)
+RETURNING id, user_id, scopes;"#,
+        )
+        .bind(hash_token(token))
+        .fetch_optional(db_pool)
+        .await?;
+
+        row.map(|row| {
+            Ok(AccessTokenGrant {
+                id: row.try_get("id")?,
+                user_id: row.try_get("user_id")?,
+                scopes: parse_scopes(row.try_get("scopes")?),
+            })
+        })
+        .transpose()
+    }
+
+    pub async fn list(db_pool: &sqlx::PgPool, user_id: &Uuid) -> Result<Vec<Self>, sqlx::Error> {
+        let rows = sqlx::query(
+            r#"
+SELECT id, name, scopes, created_at, expires_at, last_used_at
+FROM personal_access_tokens
+WHERE user_id = $1
+ORDER BY created_at DESC;"#,
+        )
+        .bind(user_id)
+        .fetch_all(db_pool)
+        .await?;
+        rows.iter().map(Self::from_row).collect()
+    }
+
+    /// Returns whether `user_id` had such a token
+    pub async fn revoke(
+        db_pool: &sqlx::PgPool,
+        user_id: &Uuid,
+        id: &Uuid,
+    ) -> Result<bool, sqlx::Error> {
+        Ok(
+            sqlx::query("DELETE FROM personal_access_tokens WHERE id = $1 AND user_id = $2;")
+                .bind(id)
+                .bind(user_id)
+                .execute(db_pool)
+                .await?
+                .rows_affected()
+                > 0,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scopes_roundtrip() {
+        let scopes: Vec<TokenScope> =
+            serde_json::from_str(r#"["read:videos", "write:videos", "admin", "read:profile"]"#)
+                .unwrap();
+        assert_eq!(
+            scopes,
+            vec![
+                TokenScope::ReadVideos,
+                TokenScope::WriteVideos,
+                TokenScope::Admin,
+                TokenScope::ReadProfile,
+            ]
+        );
+        for scope in scopes {
+            assert_eq!(scope.as_str().parse(), Ok(scope));
+            assert_eq!(
+                serde_json::to_string(&scope).unwrap(),
+                format!("\"{}\"", scope.as_str())
+            );
+        }
+        assert!(serde_json::from_str::<TokenScope>(r#""delete:everything""#).is_err());
+        assert_eq!(
+            parse_scopes(vec!["admin".into(), "dropped:scope".into()]),
+            vec![TokenScope::Admin]
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let new_token = NewAccessToken {
+            name: "CI profile sync".into(),
+            scopes: vec![TokenScope::ReadProfile],
+            expires_in_days: None,
+        };
+        assert_eq!(new_token.validate(), Ok(()));
+        assert_eq!(new_token.lifetime_days(), DEFAULT_LIFETIME_DAYS);
+
+        let new_token = NewAccessToken {
+            name: " ".into(),
+            scopes: vec![],
+            expires_in_days: Some(MAX_LIFETIME_DAYS + 1),
+        };
+        assert_eq!(
+            new_token.validate(),
+            Err(vec![
+                AccessTokenValidationError::EmptyName,
+                AccessTokenValidationError::NoScopes,
+                AccessTokenValidationError::InvalidLifetime,
+            ])
+        );
+    }
+}
@@ -0,0 +1,397 @@
+//! An append-only record of security-relevant events: logins, credential
+//! changes, and whatever moderators and admins do to other users. Users can
+//! look through the events about their own account; admins through all of
+//! them.
+//!
+//! Events don't reference `users`, so they outlive the accounts they're
+//! about, and the table itself refuses updates and deletes. The one
+//! exception is purging an account, which blanks out the personal data in
+//! the events about it.
+
+use std::{fmt, str::FromStr};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Uuid, Row};
+
+use super::ClientInfo;
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEventKind {
+    Signup,
+    LoginSucceeded,
+    LoginFailed,
+    Logout,
+    SessionRevoked,
+    PasswordResetRequested,
+    PasswordChanged,
+    TotpEnabled,
+    TotpDisabled,
+    PasskeyAdded,
+    PasskeyRemoved,
+    AccessTokenCreated,
+    AccessTokenRevoked,
+    IdentityLinked,
+    IdentityUnlinked,
+    UserSuspended,
+    UserUnsuspended,
+    UserRoleChanged,
+    UserDeleted,
+    AccountDeletionRequested,
+    AccountDeletionCanceled,
+    DataExportRequested,
+}
+
+impl AuditEventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuditEventKind::Signup => "signup",
+            AuditEventKind::LoginSucceeded => "login_succeeded",
+            AuditEventKind::LoginFailed => "login_failed",
+            AuditEventKind::Logout => "logout",
+            AuditEventKind::SessionRevoked => "session_revoked",
+            AuditEventKind::PasswordResetRequested => "password_reset_requested",
+            AuditEventKind::PasswordChanged => "password_changed",
+            AuditEventKind::TotpEnabled => "totp_enabled",
+            AuditEventKind::TotpDisabled => "totp_disabled",
+            AuditEventKind::PasskeyAdded => "passkey_added",
+            AuditEventKind::PasskeyRemoved => "passkey_removed",
+            AuditEventKind::AccessTokenCreated => "access_token_created",
+            AuditEventKind::AccessTokenRevoked => "access_token_revoked",
+            AuditEventKind::IdentityLinked => "identity_linked",
+            AuditEventKind::IdentityUnlinked => "identity_unlinked",
+            AuditEventKind::UserSuspended => "user_suspended",
+            AuditEventKind::UserUnsuspended => "user_unsuspended",
+            AuditEventKind::UserRoleChanged => "user_role_changed",
+            AuditEventKind::UserDeleted => "user_deleted",
+            AuditEventKind::AccountDeletionRequested => "account_deletion_requested",
+            AuditEventKind::AccountDeletionCanceled => "account_deletion_canceled",
+            AuditEventKind::DataExportRequested => "data_export_requested",
+        }
+    }
+}
+
+impl fmt::Display for AuditEventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for AuditEventKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.into())).map_err(|_| ())
+    }
+}
+
+/// An event about to be recorded
+#[derive(Debug, Clone)]
+pub struct NewAuditEvent {
+    kind: AuditEventKind,
+    /// Who did it, if we know
+    actor_id: Option<Uuid>,
+    /// Whose account it's about, if any
+    subject_id: Option<Uuid>,
+    ip_address: Option<String>,
+    user_agent: Option<String>,
+    request_id: Option<String>,
+    details: serde_json::Value,
+}
+
+impl NewAuditEvent {
+    pub fn new(kind: AuditEventKind, client: &ClientInfo) -> Self {
+        Self {
+            kind,
+            actor_id: None,
+            subject_id: None,
+            ip_address: client.ip_address.clone(),
+            user_agent: client.user_agent.clone(),
+            request_id: client.request_id.clone(),
+            details: serde_json::Value::Object(Default::default()),
+        }
+    }
+
+    /// A user doing something to their own account, as most are
+    pub fn with_user(self, user_id: &Uuid) -> Self {
+        self.with_actor(user_id).with_subject(user_id)
+    }
+
+    pub fn with_actor(mut self, actor_id: &Uuid) -> Self {
+        self.actor_id = Some(*actor_id);
+        self
+    }
+
+    pub fn with_subject(mut self, subject_id: &Uuid) -> Self {
+        self.subject_id = Some(*subject_id);
+        self
+    }
+
+    /// Anything else worth knowing about the event. Never put secrets in
+    /// here.
+    pub fn with_details(mut self, details: serde_json::Value) -> Self {
+        self.details = details;
+        self
+    }
+
+    pub async fn record(&self, db_pool: &sqlx::PgPool) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+INSERT INTO audit_events (kind, actor_id, subject_id, ip_address, user_agent, request_id, details)
+VALUES ($1, $2, $3, $4, $5, $6, $7);"#,
+        )
+        .bind(self.kind.as_str())
+        .bind(self.actor_id)
+        .bind(self.subject_id)
+        .bind(&self.ip_address)
+        .bind(&self.user_agent)
+        .bind(&self.request_id)
+        .bind(&self.details)
+        .execute(db_pool)
+        .await
+        .map(|_| ())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditEvent {
+    pub id: i64,
+    pub occurred_at: DateTime<Utc>,
+    pub kind: AuditEventKind,
+    pub actor_id: Option<Uuid>,
+    pub subject_id: Option<Uuid>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub request_id: Option<String>,
+    pub details: serde_json::Value,
+}
+
+impl AuditEvent {
+    fn from_row(row: &sqlx::postgres::PgRow) -> Result<Self, sqlx::Error> {
+        let kind: String = row.try_get("kind")?;
+        Ok(Self {
+            id: row.try_get("id")?,
+            occurred_at: row.try_get("occurred_at")?,
+            kind: kind.parse().map_err(|_| {
+                sqlx::Error::Decode(format!("Error decoding `{}` as AuditEventKind", kind).into())
+            })?,
+            actor_id: row.try_get("actor_id")?,
+            subject_id: row.try_get("subject_id")?,
+            ip_address: row.try_get("ip_address")?,
+            user_agent: row.try_get("user_agent")?,
+            request_id: row.try_get("request_id")?,
+            details: row.try_get("details")?,
+        })
+    }
+
+    /// The event as shown to `user_id`, whose account it's about. Who else
+    /// acted on it, staff or anybody else, and from where, isn't theirs to
+    /// see.
+    pub fn redacted_for(self, user_id: &Uuid) -> Self {
+        if self.actor_id.as_ref() == Some(user_id) {
+            return self;
+        }
+        Self {
+            actor_id: None,
+            ip_address: None,
+            user_agent: None,
+            ..self
+        }
+    }
+
+    /// The events matching `query`, newest first
+    pub async fn search(
+        db_pool: &sqlx::PgPool,
+        query: &AuditQuery,
+    ) -> Result<AuditPage, sqlx::Error> {
+        let limit = query.limit();
+        let rows = sqlx::query(
+            r#"
+SELECT id, occurred_at, kind, actor_id, subject_id, ip_address, user_agent, request_id, details
+FROM audit_events
+WHERE ($1::text IS NULL OR kind = $1)
+    AND ($2::uuid IS NULL OR actor_id = $2)
+    AND ($3::uuid IS NULL OR subject_id = $3)
+    AND ($4::uuid IS NULL OR actor_id = $4 OR subject_id = $4)
+    AND ($5::text IS NULL OR ip_address = $5)
+    AND ($6::text IS NULL OR request_id = $6)
+    AND ($7::timestamptz IS NULL OR occurred_at >= $7)
+    AND ($8::timestamptz IS NULL OR occurred_at < $8)
+    AND ($9::bigint IS NULL OR id < $9)
+ORDER BY id DESC
+LIMIT $10;"#,
+        )
+        .bind(query.kind.map(AuditEventKind::as_str))
+        .bind(query.actor_id)
+        .bind(query.subject_id)
+        .bind(query.user_id)
+        .bind(&query.ip_address)
+        .bind(&query.request_id)
+        .bind(query.since)
+        .bind(query.until)
+        .bind(query.before)
+        .bind(limit)
+        .fetch_all(db_pool)
+        .await?;
+
+        let events = rows
+            .iter()
+            .map(Self::from_row)
+            .collect::<Result<Vec<_>, _>>()?;
+        let next_before = if events.len() as i64 == limit {
+            events.last().map(|event| event.id)
+        } else {
+            None
+        };
+        Ok(AuditPage {
+            events,
+            next_before,
+        })
+    }
+}
+
+/// Which events to look for. Pages go back in time: pass the previous
+/// page's `next_before` as `before` to get the next one.
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditQuery {
+    pub kind: Option<AuditEventKind>,
+    pub actor_id: Option<Uuid>,
+    pub subject_id: Option<Uuid>,
+    /// Events where this user is either the actor or the subject
+    pub user_id: Option<Uuid>,
+    pub ip_address: Option<String>,
+    pub request_id: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub before: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+impl AuditQuery {
+    /// Narrow the query down to the events involving `user_id`, whatever
+    /// else it asked for
+    pub fn for_user(self, user_id: &Uuid) -> Self {
+        Self {
+            actor_id: None,
+            subject_id: None,
+            user_id: Some(*user_id),
+            ..self
+        }
+    }
+
+    fn limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .max(1)
+            .min(MAX_PAGE_SIZE)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditPage {
+    pub events: Vec<AuditEvent>,
+    /// Where the next page starts, if there might be one
+    pub next_before: Option<i64>,
+}
+
+impl AuditPage {
+    /// The page as shown to `user_id`; see `AuditEvent::redacted_for`
+    pub fn redacted_for(self, user_id: &Uuid) -> Self {
+        Self {
+            events: self
+                .events
+                .into_iter()
+                .map(|event| event.redacted_for(user_id))
+                .collect(),
+            ..self
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kind_roundtrip() {
+        for kind in &[
+            AuditEventKind::Signup,
+            AuditEventKind::LoginFailed,
+            AuditEventKind::PasswordResetRequested,
+            AuditEventKind::UserRoleChanged,
+        ] {
+            assert_eq!(kind.as_str().parse(), Ok(*kind));
+            assert_eq!(
+                serde_json::to_value(kind).unwrap(),
+                serde_json::Value::String(kind.as_str().into())
+            );
+        }
+        assert_eq!("password_stolen".parse::<AuditEventKind>(), Err(()));
+    }
+
+    #[test]
+    fn test_query_for_user() {
+        let user_id = Uuid::new_v4();
+        let query = AuditQuery {
+            kind: Some(AuditEventKind::LoginFailed),
+            subject_id: Some(Uuid::new_v4()),
+            limit: Some(1000),
+            ..Default::default()
+        }
+        .for_user(&user_id);
+        assert_eq!(query.subject_id, None);
+        assert_eq!(query.user_id, Some(user_id));
+        assert_eq!(query.kind, Some(AuditEventKind::LoginFailed));
+        assert_eq!(query.limit(), MAX_PAGE_SIZE);
+    }
+
+    #[test]
+    fn test_redacted_for() {
+        let (user_id, admin_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let event = |actor_id| AuditEvent {
+            id: 1,
+            occurred_at: Utc::now(),
+            kind: AuditEventKind::UserSuspended,
+            actor_id,
+            subject_id: Some(user_id),
+            ip_address: Some("192.0.2.1".into()),
+            user_agent: Some("curl/7.74.0".into()),
+            request_id: Some("req-1".into()),
+            details: serde_json::json!({ "reason": "spam" }),
+        };
+
+        let own = event(Some(user_id)).redacted_for(&user_id);
+        assert_eq!(own.actor_id, Some(user_id));
+        assert_eq!(own.ip_address.as_deref(), Some("192.0.2.1"));
+        assert_eq!(own.user_agent.as_deref(), Some("curl/7.74.0"));
+
+        for actor_id in vec![Some(admin_id), None] {
+            let others = event(actor_id).redacted_for(&user_id);
+            assert_eq!(others.actor_id, None);
+            assert_eq!(others.ip_address, None);
+            assert_eq!(others.user_agent, None);
+            assert_eq!(others.subject_id, Some(user_id));
+            assert_eq!(others.details, serde_json::json!({ "reason": "spam" }));
+        }
+    }
+
+    #[test]
+    fn test_new_event() {
+        let user_id = Uuid::new_v4();
+        let client = ClientInfo {
+            ip_address: Some("192.0.2.1".into()),
+            request_id: Some("req-1".into()),
+            ..Default::default()
+        };
+        let event = NewAuditEvent::new(AuditEventKind::PasskeyAdded, &client).with_user(&user_id);
+        assert_eq!(event.actor_id, Some(user_id));
+        assert_eq!(event.subject_id, Some(user_id));
+        assert_eq!(event.ip_address.as_deref(), Some("192.0.2.1"));
+        assert_eq!(event.request_id.as_deref(), Some("req-1"));
+        assert_eq!(event.details, serde_json::json!({}));
+    }
+}
@@ -0,0 +1,177 @@
+//! Cross-site request forgery protection for cookie-authenticated requests.
+//!
+//! Browsers attach our cookies to requests whatever page they come from, so
+//! unsafe requests have to prove they come from the web app itself. Every
+//! browser we care about sends an `Origin` header with unsafe requests (and
+//! failing that, a `Referer`), which pages can't forge; we only let through
+//! those naming the server itself or one of the allowed origins.
+//!
+//! Requests authenticating with a valid `Authorization` header don't need
+//! any of this. Pages can make browsers send one cross-site, since CORS
+//! allows the header from any origin, but CORS never allows credentials, so
+//! our cookies don't go along with it: such a request only gets to do what
+//! its own token allows. That only holds where the header is what
+//! authenticates the request, not on routes going by cookies of their own.
+
+use std::{collections::HashSet, env, fmt};
+
+use url::Url;
+use warp::http::Method;
+
+#[derive(Debug, thiserror::Error)]
+pub enum CsrfError {
+    InvalidConfiguration(String),
+}
+
+impl fmt::Display for CsrfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CsrfError::InvalidConfiguration(reason) => {
+                write!(f, "Invalid CSRF configuration: {}", reason)
+            }
+        }
+    }
+}
+
+/// `url` parsed, if it has a `scheme://host[:port]` origin at all
+fn origin_of(url: &str) -> Option<Url> {
+    let url = Url::parse(url).ok()?;
+    if url.origin().is_tuple() {
+        Some(url)
+    } else {
+        None
+    }
+}
+
+fn serialize_origin(url: &Url) -> String {
+    url.origin().ascii_serialization()
+}
+
+/// Which origins unsafe requests may come from
+#[derive(Debug, Clone, Default)]
+pub struct CsrfPolicy {
+    allowed_origins: HashSet<String>,
+}
+
+impl CsrfPolicy {
+    pub fn new<I: IntoIterator<Item = S>, S: AsRef<str>>(
+        allowed_origins: I,
+    ) -> Result<Self, CsrfError> {
+        let allowed_origins = allowed_origins
+            .into_iter()
+            .map(|origin| {
+                origin_of(origin.as_ref().trim())
+                    .map(|url| serialize_origin(&url))
+                    .ok_or_else(|| CsrfError::InvalidConfiguration(origin.as_ref().into()))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { allowed_origins })
+    }
+
+    /// Allows the server's own origin, the origin of `WEFT_APP_URL` when it's
+    /// on another one, and the comma separated `WEFT_ALLOWED_ORIGINS`
+    pub fn new_from_env() -> Result<Self, CsrfError> {
+        let mut allowed_origins = vec![];
+        if let Some(app_origin) = origin_of(&super::app_url()) {
+            allowed_origins.push(serialize_origin(&app_origin));
+        }
+        if let Ok(origins) = env::var("WEFT_ALLOWED_ORIGINS") {
+            allowed_origins.extend(
+                origins
+                    .split(',')
+                    .filter(|origin| !origin.trim().is_empty())
+                    .map(str::to_string),
+            );
+        }
+        Self::new(allowed_origins)
+    }
+
+    /// Whether a request with these headers may go through. Safe methods
+    /// always may; they must not change anything anyway.
+    pub fn allows(
+        &self,
+        method: &Method,
+        origin: Option<&str>,
+        referer: Option<&str>,
+        host: Option<&str>,
+    ) -> bool {
+        if [Method::GET, Method::HEAD, Method::OPTIONS].contains(method) {
+            return true;
+        }
+
+        // `Origin: null` (sandboxed frames, privacy-sensitive redirects...)
+        // tells us nothing, and neither does a missing header
+        let source = match origin.filter(|origin| *origin != "null") {
+            Some(origin) => origin_of(origin),
+            None => referer.and_then(origin_of),
+        };
+        let source = match source {
+            Some(source) => source,
+            None => return false,
+        };
+
+        if self.allowed_origins.contains(&serialize_origin(&source)) {
+            return true;
+        }
+
+        match (source.host_str(), host) {
+            (Some(source_host), Some(host)) => {
+                let source_host = match source.port() {
+                    Some(port) => format!("{}:{}", source_host, port),
+                    None => source_host.to_string(),
+                };
+                source_host.eq_ignore_ascii_case(host.trim())
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_safe_methods_are_allowed() {
+        let policy = CsrfPolicy::default();
+        assert!(policy.allows(&Method::GET, Some("https://evil.example"), None, None));
+        assert!(policy.allows(&Method::OPTIONS, None, None, None));
+        assert!(!policy.allows(&Method::POST, None, None, Some("weft.example")));
+        assert!(!policy.allows(&Method::DELETE, Some("null"), None, Some("weft.example")));
+    }
+
+    #[test]
+    fn test_same_origin() {
+        let policy = CsrfPolicy::default();
+        let host = Some("weft.example:8443");
+        assert!(policy.allows(&Method::POST, Some("https://weft.example:8443"), None, host));
+        assert!(policy.allows(
+            &Method::PUT,
+            None,
+            Some("https://weft.example:8443/settings?tab=security"),
+            host
+        ));
+        assert!(!policy.allows(&Method::POST, Some("https://weft.example"), None, host));
+        assert!(!policy.allows(&Method::POST, Some("https://evil.example:8443"), None, host));
+        // The `Origin` header wins over the `Referer`
+        assert!(!policy.allows(
+            &Method::POST,
+            Some("https://evil.example"),
+            Some("https://weft.example:8443/"),
+            host
+        ));
+    }
+
+    #[test]
+    fn test_allowed_origins() {
+        let policy =
+            CsrfPolicy::new(&["https://app.weft.example/", "http://localhost:3000"]).unwrap();
+        let host = Some("api.weft.example");
+        assert!(policy.allows(&Method::POST, Some("https://app.weft.example"), None, host));
+        assert!(policy.allows(&Method::POST, Some("http://localhost:3000"), None, host));
+        assert!(!policy.allows(&Method::POST, Some("http://app.weft.example"), None, host));
+        assert!(!policy.allows(&Method::POST, Some("http://localhost:3001"), None, host));
+
+        assert!(CsrfPolicy::new(&["not an origin"]).is_err());
+    }
+}
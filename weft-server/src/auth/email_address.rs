@@ -0,0 +1,306 @@
+use std::{convert::TryFrom, fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+/// RFC 5321 limits a forward-path to 256 octets, angle brackets included
+const MAX_LENGTH: usize = 254;
+const MAX_LOCAL_PART_LENGTH: usize = 64;
+const MAX_LABEL_LENGTH: usize = 63;
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum EmailAddressError {
+    Empty,
+    MissingAt,
+    TooLong,
+    InvalidLocalPart,
+    InvalidDomain,
+}
+
+impl fmt::Display for EmailAddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                EmailAddressError::Empty => "Empty email address",
+                EmailAddressError::MissingAt => "Email address is missing an `@`",
+                EmailAddressError::TooLong => "Email address is too long",
+                EmailAddressError::InvalidLocalPart => "Invalid email address local part",
+                EmailAddressError::InvalidDomain => "Invalid email address domain",
+            }
+        )
+    }
+}
+
+/// A syntactically valid email address (RFC 5322, with the UTF-8 extensions
+/// of RFC 6531), kept in canonical form: the domain is converted to its
+/// ASCII (punycode) form, and the whole address is lowercased. Strictly
+/// speaking local parts are case sensitive, but no mail provider people
+/// actually use treats them that way, and we don't want `Bob@x.com` and
+/// `bob@x.com` to be two different accounts.
+///
+/// Domain literals (`user@[192.0.2.1]`) and dotless domains are rejected,
+/// since we couldn't send mail to them anyway.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash, sqlx::Type)]
+#[serde(try_from = "String")]
+pub struct EmailAddress(String);
+
+impl EmailAddress {
+    /// An address read back from the database, taken as it is. Older rows
+    /// predate the current rules, and their owners still have to be able to
+    /// log in; anything new is parsed with `from_str` before it's stored.
+    pub fn from_stored(address: String) -> Self {
+        Self(address)
+    }
+
+    pub fn to_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for EmailAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for EmailAddress {
+    type Err = EmailAddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(EmailAddressError::Empty);
+        }
+
+        // Quoted local parts may contain `@`, domains never do
+        let at = s.rfind('@').ok_or(EmailAddressError::MissingAt)?;
+        let (local_part, domain) = (&s[..at], &s[at + 1..]);
+
+        if !is_valid_local_part(local_part) {
+            return Err(EmailAddressError::InvalidLocalPart);
+        }
+        let domain = canonical_domain(domain).ok_or(EmailAddressError::InvalidDomain)?;
+
+        let address = format!("{}@{}", local_part.to_lowercase(), domain);
+        if address.len() > MAX_LENGTH {
+            return Err(EmailAddressError::TooLong);
+        }
+
+        Ok(Self(address))
+    }
+}
+
+impl TryFrom<String> for EmailAddress {
+    type Error = EmailAddressError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Self::from_str(&value)
+    }
+}
+
+/// `atext` from RFC 5322 3.2.3, extended with any non-ASCII character as
+/// allowed by RFC 6531 3.3
+fn is_atext(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c) || !c.is_ascii()
+}
+
+/// Either a `dot-atom` or a `quoted-string`
+fn is_valid_local_part(local_part: &str) -> bool {
+    if local_part.is_empty() || local_part.len() > MAX_LOCAL_PART_LENGTH {
+        return false;
+    }
+
+    if local_part.len() >= 2 && local_part.starts_with('"') && local_part.ends_with('"') {
+        return is_valid_quoted_content(&local_part[1..local_part.len() - 1]);
+    }
+
+    local_part
+        .split('.')
+        .all(|atom| !atom.is_empty() && atom.chars().all(is_atext))
+}
+
+fn is_valid_quoted_content(content: &str) -> bool {
+    let mut chars = content.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            // quoted-pair: a backslash followed by a VCHAR or a space
+            '\\' => match chars.next() {
+                Some(escaped) if escaped == ' ' || escaped.is_ascii_graphic() => {}
+                _ => return false,
+            },
+            '"' => return false,
+            // qtext, spaces included
+            c if c == ' ' || c.is_ascii_graphic() || !c.is_ascii() => {
+                if c.is_control() {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Convert `domain` to lowercase ASCII, making sure it's a hostname mail
+/// could actually be delivered to
+fn canonical_domain(domain: &str) -> Option<String> {
+    if domain.starts_with('[') {
+        return None;
+    }
+
+    let domain = idna::domain_to_ascii(domain).ok()?;
+    let labels = domain.split('.').collect::<Vec<_>>();
+    if labels.len() < 2 {
+        return None;
+    }
+
+    let valid_labels = labels.iter().all(|label| {
+        !label.is_empty()
+            && label.len() <= MAX_LABEL_LENGTH
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    });
+    let numeric_tld = labels
+        .last()
+        .map(|tld| tld.chars().all(|c| c.is_ascii_digit()))
+        .unwrap_or(true);
+
+    if valid_labels && !numeric_tld {
+        Some(domain)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<String, EmailAddressError> {
+        EmailAddress::from_str(s).map(|email_address| email_address.to_str().to_string())
+    }
+
+    #[test]
+    fn test_accepts_common_addresses() {
+        assert_eq!(parse("some@email.address"), Ok("some@email.address".into()));
+        assert_eq!(
+            parse("first.last+tag@sub.example.com"),
+            Ok("first.last+tag@sub.example.com".into())
+        );
+        assert_eq!(parse("o'brien@example.ie"), Ok("o'brien@example.ie".into()));
+    }
+
+    #[test]
+    fn test_canonicalizes() {
+        assert_eq!(
+            parse("  Some@Email.Address "),
+            Ok("some@email.address".into())
+        );
+        assert_eq!(
+            parse("user@BÜCHER.example"),
+            Ok("user@xn--bcher-kva.example".into())
+        );
+        assert_eq!(parse("JÖRG@example.de"), Ok("jörg@example.de".into()));
+    }
+
+    #[test]
+    fn test_accepts_quoted_local_parts() {
+        assert_eq!(
+            parse(r#""john doe"@example.com"#),
+            Ok(r#""john doe"@example.com"#.into())
+        );
+        assert_eq!(
+            parse(r#""a@b\"c"@example.com"#),
+            Ok(r#""a@b\"c"@example.com"#.into())
+        );
+    }
+
+    #[test]
+    fn test_rejects_invalid_addresses() {
+        assert_eq!(parse(""), Err(EmailAddressError::Empty));
+        assert_eq!(parse("   "), Err(EmailAddressError::Empty));
+        assert_eq!(parse("no-at-sign"), Err(EmailAddressError::MissingAt));
+        assert_eq!(
+            parse("@example.com"),
+            Err(EmailAddressError::InvalidLocalPart)
+        );
+        assert_eq!(
+            parse(".dot@example.com"),
+            Err(EmailAddressError::InvalidLocalPart)
+        );
+        assert_eq!(
+            parse("dot.@example.com"),
+            Err(EmailAddressError::InvalidLocalPart)
+        );
+        assert_eq!(
+            parse("two..dots@example.com"),
+            Err(EmailAddressError::InvalidLocalPart)
+        );
+        assert_eq!(
+            parse("sp ace@example.com"),
+            Err(EmailAddressError::InvalidLocalPart)
+        );
+        assert_eq!(
+            parse(r#""unterminated@example.com"#),
+            Err(EmailAddressError::InvalidLocalPart)
+        );
+        assert_eq!(parse("user@"), Err(EmailAddressError::InvalidDomain));
+        assert_eq!(
+            parse("user@localhost"),
+            Err(EmailAddressError::InvalidDomain)
+        );
+        assert_eq!(
+            parse("user@[192.0.2.1]"),
+            Err(EmailAddressError::InvalidDomain)
+        );
+        assert_eq!(
+            parse("user@192.0.2.1"),
+            Err(EmailAddressError::InvalidDomain)
+        );
+        assert_eq!(
+            parse("user@-example.com"),
+            Err(EmailAddressError::InvalidDomain)
+        );
+        assert_eq!(
+            parse("user@example..com"),
+            Err(EmailAddressError::InvalidDomain)
+        );
+        assert_eq!(
+            parse("user@exa_mple.com"),
+            Err(EmailAddressError::InvalidDomain)
+        );
+    }
+
+    #[test]
+    fn test_rejects_long_addresses() {
+        let local_part = "a".repeat(MAX_LOCAL_PART_LENGTH + 1);
+        assert_eq!(
+            parse(&format!("{}@example.com", local_part)),
+            Err(EmailAddressError::InvalidLocalPart)
+        );
+
+        let local_part = "a".repeat(MAX_LOCAL_PART_LENGTH);
+        let label = "a".repeat(MAX_LABEL_LENGTH);
+        let domain = vec![label.as_str(); 3].join(".");
+        assert_eq!(
+            parse(&format!("{}@{}", local_part, domain)),
+            Err(EmailAddressError::TooLong)
+        );
+    }
+
+    #[test]
+    fn test_deserialize_validates() {
+        assert!(serde_json::from_str::<EmailAddress>(r#""Some@Email.Address""#).is_ok());
+        assert!(serde_json::from_str::<EmailAddress>(r#""""#).is_err());
+    }
+
+    #[test]
+    fn test_stored_addresses_are_taken_as_they_are() {
+        // e.g. from before domains were converted to punycode
+        let stored = EmailAddress::from_stored("jörg@bücher.example".into());
+        assert_eq!(stored.to_str(), "jörg@bücher.example");
+        assert_ne!(parse(stored.to_str()).unwrap(), stored.to_str());
+    }
+}
@@ -0,0 +1,89 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::types::Uuid;
+
+/// An account at an external OpenID Connect provider linked to one of our
+/// users. A user can link any number of them, but each external account
+/// belongs to a single user.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct UserIdentity {
+    pub id: Uuid,
+    #[serde(skip)]
+    pub user_id: Uuid,
+    pub provider: String,
+    /// The provider's `sub` claim
+    pub subject: String,
+    pub email_address: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl UserIdentity {
+    pub async fn get_by_subject(
+        db_pool: &sqlx::PgPool,
+        provider: &str,
+        subject: &str,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as::<_, Self>(
+            r#"
+SELECT id, user_id, provider, subject, email_address, created_at
+FROM user_identities
+WHERE provider = $1 AND subject = $2;"#,
+        )
+        .bind(provider)
+        .bind(subject)
+        .fetch_optional(db_pool)
+        .await
+    }
+
+    pub async fn list_for_user(
+        db_pool: &sqlx::PgPool,
+        user_id: &Uuid,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as::<_, Self>(
+            r#"
+SELECT id, user_id, provider, subject, email_address, created_at
+FROM user_identities
+WHERE user_id = $1
+ORDER BY created_at;"#,
+        )
+        .bind(user_id)
+        .fetch_all(db_pool)
+        .await
+    }
+
+    pub async fn create(
+        db_pool: &sqlx::PgPool,
+        user_id: &Uuid,
+        provider: &str,
+        subject: &str,
+        email_address: Option<&str>,
+    ) -> Result<Self, sqlx::Error> {
+        sqlx::query_as::<_, Self>(
+            r#"
+INSERT INTO user_identities (id, user_id, provider, subject, email_address)
+VALUES ($1, $2, $3, $4, $5)
+RETURNING id, user_id, provider, subject, email_address, created_at;"#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(provider)
+        .bind(subject)
+        .bind(email_address)
+        .fetch_one(db_pool)
+        .await
+    }
+
+    /// Returns `false` if the user has no such identity
+    pub async fn delete(
+        db_pool: &sqlx::PgPool,
+        user_id: &Uuid,
+        identity_id: &Uuid,
+    ) -> Result<bool, sqlx::Error> {
+        sqlx::query("DELETE FROM user_identities WHERE id = $1 AND user_id = $2;")
+            .bind(identity_id)
+            .bind(user_id)
+            .execute(db_pool)
+            .await
+            .map(|done| done.rows_affected() > 0)
+    }
+}
@@ -1,19 +1,73 @@
+pub mod access_tokens;
+pub mod audit;
+pub mod csrf;
+pub mod email_address;
+pub mod identities;
+pub mod oauth;
+pub mod opaque;
+pub mod password_policy;
+pub mod password_reset;
 pub mod passwords;
+pub mod profile;
+pub mod refresh;
+pub mod roles;
+pub mod sessions;
+pub mod signing;
+pub mod throttle;
 pub mod tokens;
+pub mod totp;
+pub mod verification;
+pub mod webauthn;
 
-use std::{convert::TryFrom, fmt, str::FromStr};
+use std::{convert::TryFrom, env, fmt, str::FromStr};
 
 use serde::{Deserialize, Serialize};
 
-pub use passwords::{HashedPassword, PasswordHasher, PasswordHasherError};
+use crate::rejections::FieldError;
 
-#[derive(Debug)]
-pub struct AuthToken(String);
+pub use access_tokens::{NewAccessToken, PersonalAccessToken, TokenScope};
+pub use audit::{AuditEvent, AuditEventKind, AuditQuery, NewAuditEvent};
+pub use csrf::{CsrfError, CsrfPolicy};
+pub use email_address::{EmailAddress, EmailAddressError};
+pub use identities::UserIdentity;
+pub use oauth::{OAuthError, OAuthProvider, OAuthProviders};
+pub use password_policy::{PasswordPolicy, PasswordViolation};
+pub use password_reset::{ForgotPassword, PasswordReset, PasswordResetError, ResetPassword};
+pub use passwords::{HashedPassword, PasswordHasher, PasswordHasherError};
+pub use profile::{ProfileChanges, ProfileUpdate, ProfileValidationError};
+pub use refresh::{IssuedRefreshToken, RefreshTokenError, RefreshTokenManager};
+pub use roles::{Permission, Role};
+pub use sessions::{ClientInfo, PendingMfaLogin, Session, SessionInfo, TrustedProxies};
+pub use throttle::{Throttle, ThrottleKey};
+pub use tokens::UserClaims;
+pub use totp::{MfaCode, TotpCipher, TotpCredential, TotpError};
+pub use verification::{EmailVerification, VerificationError, VerifyEmail};
+pub use webauthn::{Passkey, RelyingParty, WebauthnError};
+
+use tokens::{JwtError, ValidationError};
 
 #[derive(Debug, thiserror::Error)]
 pub enum AuthError {
     MissingToken,
     InvalidToken,
+    ExpiredToken,
+    TamperedToken,
+    InvalidAudience,
+    InvalidCredentials,
+    InvalidRefreshToken,
+    ReusedRefreshToken,
+    RevokedSession,
+    OAuthFailed,
+    UnverifiedEmail,
+    InvalidVerificationToken,
+    InvalidResetToken,
+    Forbidden,
+    InvalidMfaCode,
+    InvalidPasskey,
+    CrossSiteRequest,
+    SuspendedAccount,
+    ReauthenticationRequired,
+    InvalidCancelToken,
 }
 
 impl warp::reject::Reject for AuthError {}
@@ -27,106 +81,372 @@ impl fmt::Display for AuthError {
             match self {
                 AuthError::MissingToken => "Missing token",
                 AuthError::InvalidToken => "Invalid token",
+                AuthError::ExpiredToken => "Expired token",
+                AuthError::TamperedToken => "Tampered token",
+                AuthError::InvalidAudience => "Invalid token audience",
+                AuthError::InvalidCredentials => "Invalid credentials",
+                AuthError::InvalidRefreshToken => "Invalid refresh token",
+                AuthError::ReusedRefreshToken => "Refresh token reused",
+                AuthError::RevokedSession => "Session has been revoked",
+                AuthError::OAuthFailed => "OAuth login failed",
+                AuthError::UnverifiedEmail => "Email address not verified",
+                AuthError::InvalidVerificationToken => "Invalid verification token",
+                AuthError::InvalidResetToken => "Invalid password reset token",
+                AuthError::Forbidden => "Not allowed",
+                AuthError::InvalidMfaCode => "Invalid two-factor code",
+                AuthError::InvalidPasskey => "Invalid passkey",
+                AuthError::CrossSiteRequest => "Cross-site request refused",
+                AuthError::SuspendedAccount => "Account suspended",
+                AuthError::ReauthenticationRequired => "Log in again to do this",
+                AuthError::InvalidCancelToken => "Invalid or expired cancel link",
             }
         )
     }
 }
 
-impl TryFrom<String> for AuthToken {
-    type Error = String;
+impl From<JwtError> for AuthError {
+    fn from(error: JwtError) -> Self {
+        match error {
+            JwtError::ValidationError(ValidationError::Expired(_)) => AuthError::ExpiredToken,
+            JwtError::ValidationError(ValidationError::InvalidSignature) => {
+                AuthError::TamperedToken
+            }
+            JwtError::ValidationError(ValidationError::InvalidAudience(_)) => {
+                AuthError::InvalidAudience
+            }
+            _ => AuthError::InvalidToken,
+        }
+    }
+}
+
+/// The web app's base URL, used for redirects and for links in emails
+pub fn app_url() -> String {
+    env::var("WEFT_APP_URL").unwrap_or_else(|_| "/".into())
+}
+
+/// What a request authenticated with
+#[derive(Debug, Clone)]
+pub enum Credential {
+    /// An access token of a logged in session, which can do anything its
+    /// user can
+    Session(sqlx::types::Uuid),
+    /// A personal access token, which can only do what its scopes allow
+    PersonalAccessToken {
+        id: sqlx::types::Uuid,
+        scopes: Vec<TokenScope>,
+    },
+}
+
+/// The user behind a verified access token or personal access token
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser {
+    pub user_id: sqlx::types::Uuid,
+    pub credential: Credential,
+}
 
-    fn try_from(value: String) -> Result<Self, Self::Error> {
-        Ok(Self(value))
+impl AuthenticatedUser {
+    /// The session making the request, unless it came with a personal
+    /// access token
+    pub fn session_id(&self) -> Option<&sqlx::types::Uuid> {
+        match &self.credential {
+            Credential::Session(session_id) => Some(session_id),
+            Credential::PersonalAccessToken { .. } => None,
+        }
+    }
+
+    pub fn has_scope(&self, scope: TokenScope) -> bool {
+        match &self.credential {
+            Credential::Session(_) => true,
+            Credential::PersonalAccessToken { scopes, .. } => scopes.contains(&scope),
+        }
     }
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize, Eq, PartialEq, sqlx::Type)]
-pub struct EmailAddress(String);
+/// Credentials used to log in
+#[derive(Deserialize)]
+pub struct Credentials {
+    email: EmailAddress,
+    password: String,
+}
+
+impl Credentials {
+    pub fn email_address(&self) -> &EmailAddress {
+        &self.email
+    }
 
-impl EmailAddress {
-    pub fn to_str(&self) -> &str {
-        &self.0
+    pub fn password(&self) -> &str {
+        &self.password
     }
 }
 
-impl fmt::Display for EmailAddress {
+impl fmt::Debug for Credentials {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.0)
+        f.debug_struct("Credentials")
+            .field("email", &self.email)
+            .field("password", &"[~password~redacted~]")
+            .finish()
     }
 }
 
-impl std::str::FromStr for EmailAddress {
-    type Err = &'static str;
+/// How picky the browser should be about sending a cookie along with
+/// requests started from other sites
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SameSite {
+    Strict,
+    Lax,
+}
+
+/// Cookies are `Secure` unless `WEFT_INSECURE_COOKIES` is set, which is only
+/// meant for local development over plain HTTP (browsers already treat
+/// `localhost` as secure, so it's rarely needed even then)
+fn secure_cookies() -> bool {
+    env::var("WEFT_INSECURE_COOKIES").is_err()
+}
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Self(s.into()))
+/// Build a `Set-Cookie` header value. None of our cookies are any business
+/// of the web app's scripts, so they're all `HttpOnly`.
+fn cookie(
+    name: &str,
+    value: &str,
+    path: &str,
+    max_age: Option<i64>,
+    same_site: SameSite,
+) -> String {
+    let mut cookie = format!("{}={}; Path={}", name, value, path);
+    if let Some(max_age) = max_age {
+        cookie.push_str(&format!("; Max-Age={}", max_age));
     }
+    cookie.push_str("; HttpOnly");
+    if secure_cookies() {
+        cookie.push_str("; Secure");
+    }
+    cookie.push_str(match same_site {
+        SameSite::Strict => "; SameSite=Strict",
+        SameSite::Lax => "; SameSite=Lax",
+    });
+    cookie
 }
 
-/// Credentials used to log in
-#[derive(Debug, Deserialize)]
-pub struct Credentials {
-    email: String,
-    password: String,
+/// Build the `Set-Cookie` header value dropping a cookie set by `cookie`
+fn clear_cookie(name: &str, path: &str, same_site: SameSite) -> String {
+    cookie(name, "", path, Some(0), same_site)
 }
 
-#[derive(Debug)]
+/// Name of the cookie holding the user's JWT
+pub const AUTH_TOKEN_COOKIE: &str = "Auth-Token";
+
+/// Name of the cookie holding the user's opaque refresh token
+pub const REFRESH_TOKEN_COOKIE: &str = "Refresh-Token";
+
+/// Only the token endpoints ever need to see the refresh token
+const REFRESH_TOKEN_COOKIE_PATH: &str = "/api/v1/token";
+
+/// Build the `Set-Cookie` header value that hands `token` to the browser.
+/// Only the web app itself talks to the API, so auth cookies are never sent
+/// along with requests started from other sites.
+pub fn auth_cookie(token: &str) -> String {
+    cookie(AUTH_TOKEN_COOKIE, token, "/", None, SameSite::Strict)
+}
+
+/// Build the `Set-Cookie` header value for a refresh token
+pub fn refresh_cookie(refresh_token: &IssuedRefreshToken) -> String {
+    cookie(
+        REFRESH_TOKEN_COOKIE,
+        &refresh_token.token,
+        REFRESH_TOKEN_COOKIE_PATH,
+        Some((refresh_token.expires_at - chrono::Utc::now()).num_seconds()),
+        SameSite::Strict,
+    )
+}
+
+/// Only the OAuth callback needs to see the authorization state
+const OAUTH_STATE_COOKIE_PATH: &str = "/api/v1/oauth2";
+
+/// Build the `Set-Cookie` header value remembering an OAuth `state`. It has
+/// to be `SameSite=Lax` so it survives the top-level redirect back from the
+/// provider.
+pub fn oauth_state_cookie(state: &str) -> String {
+    cookie(
+        oauth::OAUTH_STATE_COOKIE,
+        state,
+        OAUTH_STATE_COOKIE_PATH,
+        Some(600),
+        SameSite::Lax,
+    )
+}
+
+pub fn clear_oauth_state_cookie() -> String {
+    clear_cookie(
+        oauth::OAUTH_STATE_COOKIE,
+        OAUTH_STATE_COOKIE_PATH,
+        SameSite::Lax,
+    )
+}
+
+/// Name of the cookie holding an "MFA pending" token, between a correct
+/// password and a correct second factor
+pub const MFA_TOKEN_COOKIE: &str = "Mfa-Token";
+
+/// Only the second step of the login needs to see it
+const MFA_TOKEN_COOKIE_PATH: &str = "/api/v1/login/mfa";
+
+pub fn mfa_cookie(token: &str) -> String {
+    cookie(
+        MFA_TOKEN_COOKIE,
+        token,
+        MFA_TOKEN_COOKIE_PATH,
+        Some(300),
+        SameSite::Strict,
+    )
+}
+
+pub fn clear_mfa_cookie() -> String {
+    clear_cookie(MFA_TOKEN_COOKIE, MFA_TOKEN_COOKIE_PATH, SameSite::Strict)
+}
+
+/// `Set-Cookie` header values that make the browser drop both auth cookies
+pub fn clear_auth_cookies() -> Vec<String> {
+    vec![
+        clear_cookie(AUTH_TOKEN_COOKIE, "/", SameSite::Strict),
+        clear_cookie(
+            REFRESH_TOKEN_COOKIE,
+            REFRESH_TOKEN_COOKIE_PATH,
+            SameSite::Strict,
+        ),
+    ]
+}
+
+/// The token of an `Authorization: Bearer <token>` header value
+pub fn bearer_token(authorization: &str) -> Option<&str> {
+    let mut parts = authorization.trim().splitn(2, ' ');
+    match (parts.next(), parts.next().map(str::trim)) {
+        (Some(scheme), Some(token))
+            if scheme.eq_ignore_ascii_case("bearer") && !token.is_empty() =>
+        {
+            Some(token)
+        }
+        _ => None,
+    }
+}
+
+/// Attach several `Set-Cookie` headers to `reply`; `warp::reply::with_header`
+/// would keep only the last one.
+pub fn with_cookies<R: warp::Reply>(
+    reply: R,
+    cookies: Vec<String>,
+) -> Result<warp::reply::Response, warp::http::header::InvalidHeaderValue> {
+    let mut response = reply.into_response();
+    for cookie in cookies {
+        response.headers_mut().append(
+            warp::http::header::SET_COOKIE,
+            warp::http::HeaderValue::from_str(&cookie)?,
+        );
+    }
+    Ok(response)
+}
+
+#[derive(Debug, PartialEq, Eq)]
 pub enum SignupValidationError {
+    InvalidEmailAddress(EmailAddressError),
     PasswordMismatch,
     EmptyFullName,
     EmptyPassword,
+    WeakPassword(PasswordViolation),
+}
+
+impl SignupValidationError {
+    /// The signup field this error is about
+    pub fn field(&self) -> &'static str {
+        match self {
+            SignupValidationError::InvalidEmailAddress(_) => "email_address",
+            SignupValidationError::PasswordMismatch => "password_confirm",
+            SignupValidationError::EmptyFullName => "full_name",
+            SignupValidationError::EmptyPassword | SignupValidationError::WeakPassword(_) => {
+                "password"
+            }
+        }
+    }
+
+    /// A stable identifier the signup form can pick its own wording with
+    pub fn code(&self) -> &'static str {
+        match self {
+            SignupValidationError::InvalidEmailAddress(_) => "invalid",
+            SignupValidationError::PasswordMismatch => "mismatch",
+            SignupValidationError::EmptyFullName | SignupValidationError::EmptyPassword => {
+                "required"
+            }
+            SignupValidationError::WeakPassword(violation) => violation.code(),
+        }
+    }
 }
 
 impl fmt::Display for SignupValidationError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                SignupValidationError::PasswordMismatch => "Password mismatch",
-                SignupValidationError::EmptyFullName => "Empty full name",
-                SignupValidationError::EmptyPassword => "Empty password",
-            }
-        )
+        match self {
+            SignupValidationError::InvalidEmailAddress(error) => write!(f, "{}", error),
+            SignupValidationError::PasswordMismatch => write!(f, "Password mismatch"),
+            SignupValidationError::EmptyFullName => write!(f, "Empty full name"),
+            SignupValidationError::EmptyPassword => write!(f, "Empty password"),
+            SignupValidationError::WeakPassword(violation) => write!(f, "{}", violation),
+        }
+    }
+}
+
+impl From<SignupValidationError> for FieldError {
+    fn from(error: SignupValidationError) -> Self {
+        FieldError {
+            field: error.field(),
+            code: error.code(),
+            message: error.to_string(),
+        }
     }
 }
 
 /// Unvalidated signup info
-#[derive(Debug, Deserialize)]
+#[derive(Deserialize)]
 pub struct TempSignup {
-    email_address: EmailAddress,
+    email_address: String,
     full_name: String,
     password: String,
     password_confirm: String,
 }
 
-impl TryFrom<TempSignup> for Signup {
-    type Error = SignupValidationError;
+impl TempSignup {
+    /// Check every field, `password` against `policy`, reporting all the
+    /// problems found at once so the signup form can show them together.
+    pub fn validate(self, policy: &PasswordPolicy) -> Result<Signup, Vec<SignupValidationError>> {
+        let mut errors = vec![];
 
-    fn try_from(value: TempSignup) -> Result<Self, Self::Error> {
-        if value.full_name.is_empty() {
-            return Err(SignupValidationError::EmptyFullName);
-        }
+        let email_address = EmailAddress::from_str(&self.email_address)
+            .map_err(|error| errors.push(SignupValidationError::InvalidEmailAddress(error)))
+            .ok();
 
-        if value.password.is_empty() {
-            return Err(SignupValidationError::EmptyPassword);
+        if self.full_name.trim().is_empty() {
+            errors.push(SignupValidationError::EmptyFullName);
         }
 
-        if value.password != value.password_confirm {
-            return Err(SignupValidationError::PasswordMismatch);
+        if self.password.is_empty() {
+            errors.push(SignupValidationError::EmptyPassword);
+        } else if self.password != self.password_confirm {
+            errors.push(SignupValidationError::PasswordMismatch);
+        } else if let Err(violation) =
+            policy.check(&self.password, &[&self.email_address, &self.full_name])
+        {
+            errors.push(SignupValidationError::WeakPassword(violation));
         }
 
-        Ok(Signup {
-            email_address: value.email_address,
-            full_name: value.full_name,
-            password: value.password,
-        })
+        match email_address {
+            Some(email_address) if errors.is_empty() => Ok(Signup {
+                email_address,
+                full_name: self.full_name,
+                password: self.password,
+            }),
+            _ => Err(errors),
+        }
     }
 }
 
 /// Signup info
-#[derive(Deserialize)]
-#[serde(try_from = "TempSignup")]
 pub struct Signup {
     email_address: EmailAddress,
     full_name: String,
@@ -188,12 +508,30 @@ impl NewUser {
     }
 }
 
-/// Response sent when asked for a user profile. Some/most fields might be
-/// empty unless the current user asks for its own profile.
+/// A user's profile as they see it themselves. Everyone else gets to see a
+/// `PublicProfile`.
 #[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
 pub struct UserProfile {
     pub id: sqlx::types::Uuid,
     pub email_address: EmailAddress,
+    pub full_name: String,
+    pub handle: Option<String>,
+    pub avatar_url: Option<url::Url>,
+    pub locale: Option<String>,
+    pub timezone: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    /// What to send back along with changes to the profile
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// What anyone can see of a user, found by their handle
+#[derive(Debug, Serialize)]
+pub struct PublicProfile {
+    pub handle: String,
+    pub full_name: String,
+    pub avatar_url: Option<url::Url>,
+    /// When the user joined
+    pub created_at: chrono::DateTime<chrono::Utc>,
 }
 
 impl UserProfile {
@@ -202,6 +540,97 @@ impl UserProfile {
         Self {
             id: Default::default(),
             email_address: FromStr::from_str("some@email.address").unwrap(),
+            full_name: "Some Name".into(),
+            handle: None,
+            avatar_url: None,
+            locale: None,
+            timezone: None,
+            created_at: chrono::Utc::now(),
+            updated_at: chrono::Utc::now(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_signup(email_address: &str, full_name: &str, password: &str) -> TempSignup {
+        TempSignup {
+            email_address: email_address.into(),
+            full_name: full_name.into(),
+            password: password.into(),
+            password_confirm: password.into(),
         }
     }
+
+    #[test]
+    fn test_bearer_token() {
+        assert_eq!(bearer_token("Bearer weft_pat_abc"), Some("weft_pat_abc"));
+        assert_eq!(bearer_token("bearer  weft_pat_abc "), Some("weft_pat_abc"));
+        assert_eq!(bearer_token("Basic dXNlcjpwYXNz"), None);
+        assert_eq!(bearer_token("Bearer "), None);
+        assert_eq!(bearer_token("weft_pat_abc"), None);
+    }
+
+    #[test]
+    fn test_cookie_attributes() {
+        let auth = auth_cookie("some.jwt.token");
+        assert!(auth.starts_with("Auth-Token=some.jwt.token; Path=/; HttpOnly"));
+        assert!(auth.ends_with("; SameSite=Strict"));
+        assert_eq!(auth.contains("; Secure"), secure_cookies());
+
+        let mfa = clear_mfa_cookie();
+        assert!(mfa.starts_with("Mfa-Token=; Path=/api/v1/login/mfa; Max-Age=0; HttpOnly"));
+        assert!(oauth_state_cookie("state").ends_with("; SameSite=Lax"));
+    }
+
+    #[test]
+    fn test_valid_signup() {
+        let signup = temp_signup(
+            "Some@Email.Address",
+            "Some Name",
+            "correct horse battery staple",
+        )
+        .validate(&PasswordPolicy::default())
+        .unwrap();
+        assert_eq!(signup.email_address.to_str(), "some@email.address");
+    }
+
+    #[test]
+    fn test_signup_reports_every_invalid_field() {
+        let errors = temp_signup("not an address", " ", "short")
+            .validate(&PasswordPolicy::default())
+            .err()
+            .unwrap();
+        assert_eq!(
+            errors
+                .iter()
+                .map(|error| (error.field(), error.code()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("email_address", "invalid"),
+                ("full_name", "required"),
+                ("password", "too_short"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_signup_rejects_password_with_name() {
+        let errors = temp_signup(
+            "ada@email.address",
+            "Ada Lovelace",
+            "lovelace-analytical-engine",
+        )
+        .validate(&PasswordPolicy::default())
+        .err()
+        .unwrap();
+        assert_eq!(
+            errors,
+            vec![SignupValidationError::WeakPassword(
+                PasswordViolation::ContainsPersonalInfo
+            )]
+        );
+    }
 }
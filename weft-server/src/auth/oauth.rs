@@ -0,0 +1,493 @@
+use std::{collections::HashMap, env, fmt, sync::Arc};
+
+use chrono::{DateTime, Duration, Utc};
+use oauth2::{
+    basic::{BasicErrorResponse, BasicTokenType},
+    reqwest::async_http_client,
+    AuthUrl, AuthorizationCode, Client, ClientId, ClientSecret, CsrfToken, ExtraTokenFields,
+    PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope, StandardTokenResponse, TokenUrl,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Uuid, Row};
+use url::Url;
+
+const FLOW_LIFETIME_MINUTES: i64 = 10;
+const DEFAULT_SCOPES: &str = "openid email profile";
+
+/// Name of the cookie binding an authorization request to the browser that
+/// started it
+pub const OAUTH_STATE_COOKIE: &str = "OAuth-State";
+
+/// The token endpoint response of an OpenID Connect provider carries an
+/// `id_token` on top of the standard OAuth2 fields.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct IdTokenFields {
+    pub id_token: String,
+}
+
+impl ExtraTokenFields for IdTokenFields {}
+
+type OidcTokenResponse = StandardTokenResponse<IdTokenFields, BasicTokenType>;
+type OidcClient = Client<BasicErrorResponse, OidcTokenResponse, BasicTokenType>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum OAuthError {
+    InvalidConfiguration(String),
+    /// The `state` is unknown, expired or was already used
+    UnknownState,
+    ExchangeError(String),
+    InvalidIdToken(&'static str),
+    UnverifiedEmail,
+    DiscoveryError(String),
+    DatabaseError(sqlx::Error),
+}
+
+impl fmt::Display for OAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OAuthError::InvalidConfiguration(reason) => {
+                write!(f, "Invalid OAuth configuration: {}", reason)
+            }
+            OAuthError::UnknownState => write!(f, "Unknown authorization state"),
+            OAuthError::ExchangeError(reason) => write!(f, "Code exchange failed: {}", reason),
+            OAuthError::InvalidIdToken(reason) => write!(f, "Invalid id_token: {}", reason),
+            OAuthError::UnverifiedEmail => write!(f, "Email address is not verified"),
+            OAuthError::DiscoveryError(reason) => {
+                write!(f, "Provider discovery failed: {}", reason)
+            }
+            OAuthError::DatabaseError(error) => write!(f, "Database error: {:?}", error),
+        }
+    }
+}
+
+impl From<sqlx::Error> for OAuthError {
+    fn from(error: sqlx::Error) -> Self {
+        OAuthError::DatabaseError(error)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+enum Audience {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl Audience {
+    fn contains(&self, client_id: &str) -> bool {
+        match self {
+            Audience::Single(audience) => audience == client_id,
+            Audience::Multiple(audiences) => audiences.iter().any(|audience| audience == client_id),
+        }
+    }
+}
+
+/// Query string the provider redirects back to us with
+#[derive(Debug, Deserialize)]
+pub struct AuthorizationResponse {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+/// The claims we care about in an OpenID Connect `id_token`
+#[derive(Debug, Clone, Deserialize)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    aud: Audience,
+    exp: i64,
+    nonce: Option<String>,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    pub name: Option<String>,
+}
+
+impl IdTokenClaims {
+    /// Read the claims out of a compact JWT. The signature is not checked
+    /// here: the token comes straight from the provider's token endpoint over
+    /// TLS, which OpenID Connect Core (3.1.3.7) accepts as proof of origin.
+    pub fn decode(id_token: &str) -> Result<Self, OAuthError> {
+        let payload = id_token
+            .split('.')
+            .nth(1)
+            .ok_or(OAuthError::InvalidIdToken("malformed token"))?;
+        let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| OAuthError::InvalidIdToken("malformed payload"))?;
+        serde_json::from_slice(&payload).map_err(|_| OAuthError::InvalidIdToken("malformed claims"))
+    }
+
+    pub fn validate(
+        &self,
+        issuers: &[String],
+        client_id: &str,
+        nonce: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(), OAuthError> {
+        if !issuers.iter().any(|issuer| issuer == &self.iss) {
+            return Err(OAuthError::InvalidIdToken("unexpected issuer"));
+        }
+
+        if !self.aud.contains(client_id) {
+            return Err(OAuthError::InvalidIdToken("unexpected audience"));
+        }
+
+        if self.exp < now.timestamp() {
+            return Err(OAuthError::InvalidIdToken("expired"));
+        }
+
+        if self.nonce.as_deref() != Some(nonce) {
+            return Err(OAuthError::InvalidIdToken("nonce mismatch"));
+        }
+
+        Ok(())
+    }
+}
+
+/// The parts of a provider's `.well-known/openid-configuration` we use
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+/// Outcome of a successful authorization flow
+#[derive(Debug)]
+pub struct CompletedFlow {
+    pub claims: IdTokenClaims,
+    /// Set when the flow was started by a logged in user linking an identity
+    pub link_user_id: Option<Uuid>,
+}
+
+/// An OpenID Connect provider we delegate logins to
+pub struct OAuthProvider {
+    name: String,
+    client: OidcClient,
+    client_id: String,
+    issuers: Vec<String>,
+    scopes: Vec<String>,
+    /// Whether the provider's verified email addresses are good enough to
+    /// log into the existing account that has the same address
+    trust_email: bool,
+}
+
+fn invalid_configuration<E: fmt::Debug>(error: E) -> OAuthError {
+    OAuthError::InvalidConfiguration(format!("{:?}", error))
+}
+
+impl OAuthProvider {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        name: &str,
+        client_id: &str,
+        client_secret: ClientSecret,
+        auth_url: &str,
+        token_url: &str,
+        redirect_url: &str,
+        issuers: Vec<String>,
+        scopes: Vec<String>,
+    ) -> Result<Self, OAuthError> {
+        let client = OidcClient::new(
+            ClientId::new(client_id.into()),
+            Some(client_secret),
+            AuthUrl::new(auth_url.into()).map_err(invalid_configuration)?,
+            Some(TokenUrl::new(token_url.into()).map_err(invalid_configuration)?),
+        )
+        .set_redirect_url(RedirectUrl::new(redirect_url.into()).map_err(invalid_configuration)?);
+
+        Ok(Self {
+            name: name.into(),
+            client,
+            client_id: client_id.into(),
+            issuers,
+            scopes,
+            trust_email: false,
+        })
+    }
+
+    /// Only for providers that never let users pick an address they don't
+    /// own, e.g. one run by the organization the users belong to
+    pub fn with_trusted_email(mut self, trust_email: bool) -> Self {
+        self.trust_email = trust_email;
+        self
+    }
+
+    /// Build a provider out of its OpenID Connect discovery document, which
+    /// gives us its issuer and endpoints.
+    pub async fn discover(
+        name: &str,
+        discovery_url: &str,
+        client_id: &str,
+        client_secret: ClientSecret,
+        redirect_url: &str,
+        scopes: Vec<String>,
+    ) -> Result<Self, OAuthError> {
+        let metadata = reqwest::get(discovery_url)
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|error| OAuthError::DiscoveryError(format!("{}: {:?}", name, error)))?
+            .json::<ProviderMetadata>()
+            .await
+            .map_err(|error| OAuthError::DiscoveryError(format!("{}: {:?}", name, error)))?;
+
+        Self::new(
+            name,
+            client_id,
+            client_secret,
+            &metadata.authorization_endpoint,
+            &metadata.token_endpoint,
+            redirect_url,
+            vec![metadata.issuer],
+            scopes,
+        )
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn trusts_email(&self) -> bool {
+        self.trust_email
+    }
+
+    /// Build the URL to send the browser to, remembering the PKCE verifier
+    /// and nonce server-side under the returned `state`. `link_user_id` is
+    /// set when a logged in user wants to link this provider to their
+    /// account instead of logging in.
+    pub async fn start(
+        &self,
+        db_pool: &sqlx::PgPool,
+        link_user_id: Option<&Uuid>,
+    ) -> Result<(Url, String), OAuthError> {
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+        let nonce = CsrfToken::new_random();
+
+        let mut request = self
+            .client
+            .authorize_url(CsrfToken::new_random)
+            .set_pkce_challenge(pkce_challenge)
+            .add_extra_param("nonce", nonce.secret());
+        for scope in &self.scopes {
+            request = request.add_scope(Scope::new(scope.clone()));
+        }
+        let (url, state) = request.url();
+
+        sqlx::query(
+            r#"
+INSERT INTO oauth_flows (state, provider, pkce_verifier, nonce, link_user_id, expires_at)
+VALUES ($1, $2, $3, $4, $5, $6);"#,
+        )
+        .bind(state.secret())
+        .bind(&self.name)
+        .bind(pkce_verifier.secret())
+        .bind(nonce.secret())
+        .bind(link_user_id)
+        .bind(Utc::now() + Duration::minutes(FLOW_LIFETIME_MINUTES))
+        .execute(db_pool)
+        .await?;
+
+        Ok((url, state.secret().clone()))
+    }
+
+    /// Redeem the authorization `code` the provider sent back along with
+    /// `state`, returning the validated `id_token` claims.
+    pub async fn finish(
+        &self,
+        db_pool: &sqlx::PgPool,
+        code: String,
+        state: &str,
+    ) -> Result<CompletedFlow, OAuthError> {
+        let flow = sqlx::query(
+            r#"
+DELETE FROM oauth_flows
+WHERE state = $1 AND provider = $2
+RETURNING pkce_verifier, nonce, link_user_id, expires_at;"#,
+        )
+        .bind(state)
+        .bind(&self.name)
+        .fetch_optional(db_pool)
+        .await?
+        .ok_or(OAuthError::UnknownState)?;
+
+        let pkce_verifier: String = flow.try_get("pkce_verifier")?;
+        let nonce: String = flow.try_get("nonce")?;
+        let link_user_id: Option<Uuid> = flow.try_get("link_user_id")?;
+        let expires_at: DateTime<Utc> = flow.try_get("expires_at")?;
+
+        if expires_at < Utc::now() {
+            return Err(OAuthError::UnknownState);
+        }
+
+        let response = self
+            .client
+            .exchange_code(AuthorizationCode::new(code))
+            .set_pkce_verifier(PkceCodeVerifier::new(pkce_verifier))
+            .request_async(async_http_client)
+            .await
+            .map_err(|error| OAuthError::ExchangeError(format!("{:?}", error)))?;
+
+        let claims = IdTokenClaims::decode(&response.extra_fields().id_token)?;
+        claims.validate(&self.issuers, &self.client_id, &nonce, Utc::now())?;
+        Ok(CompletedFlow {
+            claims,
+            link_user_id,
+        })
+    }
+}
+
+/// Every provider users can log in with, by name
+#[derive(Default)]
+pub struct OAuthProviders(HashMap<String, Arc<OAuthProvider>>);
+
+impl OAuthProviders {
+    /// Load the providers listed in `WEFT_OAUTH_PROVIDERS` (comma separated
+    /// names). Each `<NAME>` is configured through:
+    ///
+    /// - `WEFT_OAUTH_<NAME>_DISCOVERY_URL`: its `.well-known/openid-configuration`
+    /// - `WEFT_OAUTH_<NAME>_CLIENT_ID` and `WEFT_OAUTH_<NAME>_CLIENT_SECRET`
+    /// - `WEFT_OAUTH_<NAME>_SCOPES`: space separated, defaults to `openid email profile`
+    /// - `WEFT_OAUTH_<NAME>_TRUST_EMAIL`: `true` to log users into the account
+    ///   that has the provider's verified address, which is otherwise refused
+    ///
+    /// Callbacks land on `$WEFT_PUBLIC_URL/api/v1/oauth2/<name>/end`.
+    pub async fn from_env() -> Result<Self, OAuthError> {
+        let names = match env::var("WEFT_OAUTH_PROVIDERS") {
+            Ok(names) => names,
+            Err(_) => return Ok(Self::default()),
+        };
+        let public_url = env::var("WEFT_PUBLIC_URL").map_err(invalid_configuration)?;
+
+        let mut providers = HashMap::new();
+        for name in names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            let var = |suffix: &str| {
+                let key = format!("WEFT_OAUTH_{}_{}", name.to_uppercase(), suffix);
+                env::var(&key).map_err(|_| OAuthError::InvalidConfiguration(key))
+            };
+
+            let provider = OAuthProvider::discover(
+                name,
+                &var("DISCOVERY_URL")?,
+                &var("CLIENT_ID")?,
+                ClientSecret::new(var("CLIENT_SECRET")?),
+                &format!(
+                    "{}/api/v1/oauth2/{}/end",
+                    public_url.trim_end_matches('/'),
+                    name
+                ),
+                var("SCOPES")
+                    .unwrap_or_else(|_| DEFAULT_SCOPES.into())
+                    .split_whitespace()
+                    .map(String::from)
+                    .collect(),
+            )
+            .await?
+            .with_trusted_email(
+                var("TRUST_EMAIL")
+                    .map(|trust| trust == "true")
+                    .unwrap_or(false),
+            );
+            providers.insert(name.to_string(), Arc::new(provider));
+        }
+
+        Ok(Self(providers))
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<OAuthProvider>> {
+        self.0.get(name).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_id_token(claims: &str) -> String {
+        format!(
+            "{}.{}.signature",
+            base64::encode_config(r#"{"alg":"RS256"}"#, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(claims, base64::URL_SAFE_NO_PAD),
+        )
+    }
+
+    fn issuers() -> Vec<String> {
+        vec!["https://accounts.google.com".into()]
+    }
+
+    #[test]
+    fn test_decode_and_validate() {
+        let exp = Utc::now().timestamp() + 60;
+        let id_token = encode_id_token(&format!(
+            r#"{{"iss":"https://accounts.google.com","sub":"1234","aud":"weft","exp":{},"nonce":"abc","email":"some@email.address","email_verified":true}}"#,
+            exp
+        ));
+
+        let claims = IdTokenClaims::decode(&id_token).unwrap();
+        assert_eq!(claims.sub, "1234");
+        assert!(claims.email_verified);
+        assert!(claims
+            .validate(&issuers(), "weft", "abc", Utc::now())
+            .is_ok());
+    }
+
+    #[test]
+    fn test_validate_rejects_wrong_nonce_and_audience() {
+        let exp = Utc::now().timestamp() + 60;
+        let id_token = encode_id_token(&format!(
+            r#"{{"iss":"https://accounts.google.com","sub":"1234","aud":["other","weft"],"exp":{},"nonce":"abc"}}"#,
+            exp
+        ));
+        let claims = IdTokenClaims::decode(&id_token).unwrap();
+
+        assert!(claims
+            .validate(&issuers(), "weft", "abc", Utc::now())
+            .is_ok());
+        assert!(claims
+            .validate(&issuers(), "weft", "xyz", Utc::now())
+            .is_err());
+        assert!(claims
+            .validate(&issuers(), "nope", "abc", Utc::now())
+            .is_err());
+    }
+
+    #[test]
+    fn test_validate_rejects_expired_token() {
+        let exp = Utc::now().timestamp() - 60;
+        let id_token = encode_id_token(&format!(
+            r#"{{"iss":"https://accounts.google.com","sub":"1234","aud":"weft","exp":{},"nonce":"abc"}}"#,
+            exp
+        ));
+        let claims = IdTokenClaims::decode(&id_token).unwrap();
+
+        assert!(claims
+            .validate(&issuers(), "weft", "abc", Utc::now())
+            .is_err());
+    }
+
+    #[test]
+    fn test_decode_rejects_garbage() {
+        assert!(IdTokenClaims::decode("not-a-jwt").is_err());
+    }
+
+    #[test]
+    fn test_email_not_trusted_by_default() {
+        let provider = OAuthProvider::new(
+            "example",
+            "weft",
+            ClientSecret::new("secret".into()),
+            "https://id.example/authorize",
+            "https://id.example/token",
+            "https://weft.example/api/v1/oauth2/example/end",
+            issuers(),
+            vec!["openid".into()],
+        )
+        .unwrap();
+        assert!(!provider.trusts_email());
+        assert!(provider.with_trusted_email(true).trusts_email());
+    }
+}
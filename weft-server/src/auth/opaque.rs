@@ -0,0 +1,40 @@
+//! Random bearer secrets such as refresh tokens or email verification links.
+//! Clients get the plain token; we only ever store its SHA-256 digest.
+
+use ring::{
+    digest,
+    rand::{SecureRandom, SystemRandom},
+};
+
+const TOKEN_BYTES: usize = 32;
+
+pub fn generate_token() -> Result<String, ring::error::Unspecified> {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    SystemRandom::new().fill(&mut bytes)?;
+    Ok(base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD))
+}
+
+pub fn hash_token(token: &str) -> String {
+    base64::encode_config(
+        digest::digest(&digest::SHA256, token.as_bytes()),
+        base64::URL_SAFE_NO_PAD,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generated_tokens_differ() {
+        let token = generate_token().unwrap();
+        assert_ne!(token, generate_token().unwrap());
+        assert_eq!(token.len(), 43);
+    }
+
+    #[test]
+    fn test_hash_token_is_stable() {
+        assert_eq!(hash_token("some-token"), hash_token("some-token"));
+        assert_ne!(hash_token("some-token"), hash_token("some-other-token"));
+    }
+}
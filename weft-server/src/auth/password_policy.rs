@@ -0,0 +1,325 @@
+use std::{
+    env, fmt, fs,
+    io::{BufRead, BufReader},
+    path::PathBuf,
+};
+
+use ring::digest;
+
+const DEFAULT_MIN_LENGTH: usize = 10;
+const DEFAULT_MIN_SCORE: u8 = 3;
+/// zxcvbn takes longer the longer the password, and nobody types more than
+/// this anyway
+const MAX_LENGTH: usize = 128;
+/// Anything shorter is too common to count as personal info
+const MIN_PERSONAL_INFO_LENGTH: usize = 3;
+/// Hex digits of the SHA-1 digest that name a range file
+const PREFIX_LENGTH: usize = 5;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PasswordPolicyError {
+    InvalidConfiguration(String),
+}
+
+impl fmt::Display for PasswordPolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PasswordPolicyError::InvalidConfiguration(reason) => {
+                write!(f, "Invalid password policy configuration: {}", reason)
+            }
+        }
+    }
+}
+
+/// Why a password was turned down
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PasswordViolation {
+    TooShort(usize),
+    TooLong(usize),
+    /// zxcvbn score the password got, out of 4
+    TooWeak(u8),
+    ContainsPersonalInfo,
+    Breached,
+}
+
+impl PasswordViolation {
+    pub fn code(&self) -> &'static str {
+        match self {
+            PasswordViolation::TooShort(_) => "too_short",
+            PasswordViolation::TooLong(_) => "too_long",
+            PasswordViolation::TooWeak(_) => "too_weak",
+            PasswordViolation::ContainsPersonalInfo => "contains_personal_info",
+            PasswordViolation::Breached => "breached",
+        }
+    }
+}
+
+impl fmt::Display for PasswordViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PasswordViolation::TooShort(min_length) => write!(
+                f,
+                "Password must be at least {} characters long",
+                min_length
+            ),
+            PasswordViolation::TooLong(max_length) => {
+                write!(f, "Password must be at most {} characters long", max_length)
+            }
+            PasswordViolation::TooWeak(_) => write!(f, "Password is too easy to guess"),
+            PasswordViolation::ContainsPersonalInfo => {
+                write!(f, "Password must not contain your name or email address")
+            }
+            PasswordViolation::Breached => {
+                write!(f, "Password has appeared in a data breach")
+            }
+        }
+    }
+}
+
+/// Passwords known from public breaches, stored the way the "Have I Been
+/// Pwned" range API serves them: one file per 5 hex digit prefix of the
+/// uppercase SHA-1 digest, each holding `SUFFIX:COUNT` lines. Only the one
+/// range file a password falls into is ever read.
+#[derive(Debug, Clone)]
+pub struct BreachedPasswords {
+    directory: PathBuf,
+}
+
+impl BreachedPasswords {
+    pub fn new<P: Into<PathBuf>>(directory: P) -> Self {
+        Self {
+            directory: directory.into(),
+        }
+    }
+
+    pub fn contains(&self, password: &str) -> bool {
+        let digest = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, password.as_bytes());
+        let hex = digest
+            .as_ref()
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<String>();
+        let (prefix, suffix) = hex.split_at(PREFIX_LENGTH);
+
+        let file = match fs::File::open(self.directory.join(prefix)) {
+            Ok(file) => file,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return false,
+            Err(error) => {
+                // A missing list shouldn't keep people from signing up
+                log::warn!(
+                    "Could not read breached password range {}: {}",
+                    prefix,
+                    error
+                );
+                return false;
+            }
+        };
+
+        BufReader::new(file)
+            .lines()
+            .filter_map(Result::ok)
+            .any(|line| {
+                line.split(':')
+                    .next()
+                    .map(|line_suffix| line_suffix.trim().eq_ignore_ascii_case(suffix))
+                    .unwrap_or(false)
+            })
+    }
+}
+
+/// What it takes for a password to be accepted
+#[derive(Debug, Clone)]
+pub struct PasswordPolicy {
+    min_length: usize,
+    min_score: u8,
+    breached: Option<BreachedPasswords>,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: DEFAULT_MIN_LENGTH,
+            min_score: DEFAULT_MIN_SCORE,
+            breached: None,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    /// Reads `WEFT_PASSWORD_MIN_LENGTH`, `WEFT_PASSWORD_MIN_SCORE` (0 to 4)
+    /// and `WEFT_BREACHED_PASSWORDS_DIR`, all optional
+    pub fn new_from_env() -> Result<Self, PasswordPolicyError> {
+        let mut policy = Self::default();
+
+        if let Ok(min_length) = env::var("WEFT_PASSWORD_MIN_LENGTH") {
+            policy = policy.with_min_length(min_length.parse().map_err(|_| {
+                PasswordPolicyError::InvalidConfiguration("WEFT_PASSWORD_MIN_LENGTH".into())
+            })?);
+        }
+
+        if let Ok(min_score) = env::var("WEFT_PASSWORD_MIN_SCORE") {
+            match min_score.parse() {
+                Ok(min_score) if min_score <= 4 => policy = policy.with_min_score(min_score),
+                _ => {
+                    return Err(PasswordPolicyError::InvalidConfiguration(
+                        "WEFT_PASSWORD_MIN_SCORE".into(),
+                    ))
+                }
+            }
+        }
+
+        if let Ok(directory) = env::var("WEFT_BREACHED_PASSWORDS_DIR") {
+            policy = policy.with_breached_passwords(BreachedPasswords::new(directory));
+        }
+
+        Ok(policy)
+    }
+
+    pub fn with_min_length(mut self, min_length: usize) -> Self {
+        self.min_length = min_length;
+        self
+    }
+
+    pub fn with_min_score(mut self, min_score: u8) -> Self {
+        self.min_score = min_score;
+        self
+    }
+
+    pub fn with_breached_passwords(mut self, breached: BreachedPasswords) -> Self {
+        self.breached = Some(breached);
+        self
+    }
+
+    /// Check `password` against the policy. `personal_info` holds whatever
+    /// we know about its owner (email address, name...) that shouldn't show
+    /// up in it.
+    pub fn check(&self, password: &str, personal_info: &[&str]) -> Result<(), PasswordViolation> {
+        let length = password.chars().count();
+        if length < self.min_length {
+            return Err(PasswordViolation::TooShort(self.min_length));
+        }
+        if length > MAX_LENGTH {
+            return Err(PasswordViolation::TooLong(MAX_LENGTH));
+        }
+
+        let lowercase_password = password.to_lowercase();
+        let personal_words = personal_info
+            .iter()
+            .flat_map(|info| info.split(|c: char| c == '@' || c.is_whitespace()))
+            .map(str::to_lowercase)
+            .filter(|word| word.chars().count() >= MIN_PERSONAL_INFO_LENGTH)
+            .collect::<Vec<_>>();
+        if personal_words
+            .iter()
+            .any(|word| lowercase_password.contains(word.as_str()))
+        {
+            return Err(PasswordViolation::ContainsPersonalInfo);
+        }
+
+        let user_inputs = personal_words
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        let score = zxcvbn::zxcvbn(password, &user_inputs)
+            .map(|entropy| entropy.score())
+            .unwrap_or(0);
+        if score < self.min_score {
+            return Err(PasswordViolation::TooWeak(score));
+        }
+
+        if let Some(breached) = &self.breached {
+            if breached.contains(password) {
+                return Err(PasswordViolation::Breached);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STRONG_PASSWORD: &str = "correct horse battery staple";
+
+    #[test]
+    fn test_accepts_strong_passwords() {
+        let policy = PasswordPolicy::default();
+        assert_eq!(
+            policy.check(STRONG_PASSWORD, &["some@email.address"]),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_rejects_short_passwords() {
+        let policy = PasswordPolicy::default().with_min_length(12);
+        assert_eq!(
+            policy.check("Tr0ub4dor&3", &[]),
+            Err(PasswordViolation::TooShort(12))
+        );
+    }
+
+    #[test]
+    fn test_rejects_long_passwords() {
+        let policy = PasswordPolicy::default();
+        let password = "correct horse battery staple ".repeat(5);
+        assert_eq!(
+            policy.check(&password, &[]),
+            Err(PasswordViolation::TooLong(MAX_LENGTH))
+        );
+        assert!(!matches!(
+            policy.check(&password[..MAX_LENGTH], &[]),
+            Err(PasswordViolation::TooLong(_))
+        ));
+    }
+
+    #[test]
+    fn test_rejects_weak_passwords() {
+        let policy = PasswordPolicy::default();
+        assert!(matches!(
+            policy.check("password123", &[]),
+            Err(PasswordViolation::TooWeak(_))
+        ));
+    }
+
+    #[test]
+    fn test_rejects_personal_info() {
+        let policy = PasswordPolicy::default();
+        assert_eq!(
+            policy.check("Margaret-Hamilton-1936", &["Margaret Hamilton"]),
+            Err(PasswordViolation::ContainsPersonalInfo)
+        );
+        assert_eq!(
+            policy.check("my email is someone!", &["someone@email.address"]),
+            Err(PasswordViolation::ContainsPersonalInfo)
+        );
+    }
+
+    #[test]
+    fn test_rejects_breached_passwords() {
+        let directory =
+            env::temp_dir().join(format!("weft-breached-{}", sqlx::types::Uuid::new_v4()));
+        fs::create_dir_all(&directory).unwrap();
+        // SHA-1 of "correct horse battery staple" is ABF7AAD6438836DBE526AA231ABDE2D0EEF74D42
+        fs::write(
+            directory.join("ABF7A"),
+            "0000000000000000000000000000000000A:1\r\nAD6438836DBE526AA231ABDE2D0EEF74D42:42\r\n",
+        )
+        .unwrap();
+
+        let policy =
+            PasswordPolicy::default().with_breached_passwords(BreachedPasswords::new(&directory));
+        assert_eq!(
+            policy.check(STRONG_PASSWORD, &[]),
+            Err(PasswordViolation::Breached)
+        );
+        assert_eq!(
+            policy.check("an entirely different passphrase", &[]),
+            Ok(())
+        );
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
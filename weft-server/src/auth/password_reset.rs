@@ -0,0 +1,165 @@
+use std::fmt;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use sqlx::{types::Uuid, Row};
+
+use super::{
+    opaque::{generate_token, hash_token},
+    EmailAddress, HashedPassword, Session,
+};
+use crate::mailer::Message;
+
+const LIFETIME_MINUTES: i64 = 60;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PasswordResetError {
+    /// Unknown, expired or already used token
+    InvalidToken,
+    RandomError,
+    DatabaseError(sqlx::Error),
+}
+
+impl fmt::Display for PasswordResetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                PasswordResetError::InvalidToken => "Invalid password reset token".into(),
+                PasswordResetError::RandomError => "Could not generate password reset token".into(),
+                PasswordResetError::DatabaseError(error) => format!("Database error: {:?}", error),
+            }
+        )
+    }
+}
+
+impl From<sqlx::Error> for PasswordResetError {
+    fn from(error: sqlx::Error) -> Self {
+        PasswordResetError::DatabaseError(error)
+    }
+}
+
+/// Body of a "forgot my password" request
+#[derive(Debug, Deserialize)]
+pub struct ForgotPassword {
+    pub email_address: EmailAddress,
+}
+
+/// Body of a password reset request
+#[derive(Deserialize)]
+pub struct ResetPassword {
+    pub token: String,
+    pub password: String,
+    pub password_confirm: String,
+}
+
+impl fmt::Debug for ResetPassword {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResetPassword")
+            .field("token", &"[~token~redacted~]")
+            .field("password", &"[~password~redacted~]")
+            .field("password_confirm", &"[~password~redacted~]")
+            .finish()
+    }
+}
+
+/// Short lived, single use tokens letting a user pick a new password
+pub struct PasswordReset;
+
+impl PasswordReset {
+    /// Create a reset token for `user_id`, returning the email to send out
+    /// with it.
+    pub async fn create(
+        db_pool: &sqlx::PgPool,
+        user_id: &Uuid,
+        email_address: &EmailAddress,
+    ) -> Result<Message, PasswordResetError> {
+        let token = generate_token().map_err(|_| PasswordResetError::RandomError)?;
+
+        sqlx::query(
+            r#"
+INSERT INTO password_resets (token_hash, user_id, expires_at)
+VALUES ($1, $2, $3);"#,
+        )
+        .bind(hash_token(&token))
+        .bind(user_id)
+        .bind(Utc::now() + Duration::minutes(LIFETIME_MINUTES))
+        .execute(db_pool)
+        .await?;
+
+        Ok(Self::message(email_address, &token))
+    }
+
+    fn message(email_address: &EmailAddress, token: &str) -> Message {
+        Message {
+            to: email_address.clone(),
+            subject: "Reset your password".into(),
+            body: format!(
+                "Follow this link to pick a new password:\n\n{}/reset-password?token={}\n\nThe link expires in {} minutes. If you didn't ask for this, you can ignore this email.",
+                super::app_url().trim_end_matches('/'),
+                token,
+                LIFETIME_MINUTES,
+            ),
+        }
+    }
+
+    /// Use up `token`, replacing its user's password with `hashed_password`.
+    /// Every other reset token, session and refresh token of the user is
+    /// revoked along the way. Returns the user's id.
+    pub async fn redeem(
+        db_pool: &sqlx::PgPool,
+        token: &str,
+        hashed_password: &HashedPassword,
+    ) -> Result<Uuid, PasswordResetError> {
+        let mut transaction = db_pool.begin().await?;
+
+        let row = sqlx::query(
+            r#"
+DELETE FROM password_resets
+WHERE token_hash = $1
+RETURNING user_id, expires_at;"#,
+        )
+        .bind(hash_token(token))
+        .fetch_optional(&mut transaction)
+        .await?
+        .ok_or(PasswordResetError::InvalidToken)?;
+
+        let user_id: Uuid = row.try_get("user_id")?;
+        let expires_at: DateTime<Utc> = row.try_get("expires_at")?;
+
+        if expires_at < Utc::now() {
+            return Err(PasswordResetError::InvalidToken);
+        }
+
+        sqlx::query("UPDATE users SET hashed_password = $2 WHERE id = $1;")
+            .bind(&user_id)
+            .bind(hashed_password.as_str())
+            .execute(&mut transaction)
+            .await?;
+
+        sqlx::query("DELETE FROM password_resets WHERE user_id = $1;")
+            .bind(&user_id)
+            .execute(&mut transaction)
+            .await?;
+
+        Session::revoke_all_within(&mut transaction, &user_id, None).await?;
+
+        transaction.commit().await?;
+        Ok(user_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_message_contains_link() {
+        let email_address = EmailAddress::from_str("some@email.address").unwrap();
+        let message = PasswordReset::message(&email_address, "some-token");
+        assert_eq!(message.to, email_address);
+        assert!(message.body.contains("/reset-password?token=some-token"));
+    }
+}
@@ -1,19 +1,126 @@
-use std::{env, fmt};
+use std::{collections::HashMap, env, fmt};
 
-#[derive(Debug, Clone)]
+use serde::Serialize;
+
+/// Id of the key hashes without one were made with: `WEFT_SECRET_KEY`
+pub const LEGACY_KEY_ID: &str = "legacy";
+
+const DEFAULT_MEMORY_SIZE: u32 = 32768;
+const DEFAULT_ITERATIONS: u32 = 3;
+const DEFAULT_LANES: u32 = 1;
+
+/// A password hash as we store it: the id of the key it was peppered with,
+/// followed by the argon2 PHC string, which carries its own parameters.
+/// (e.g. `2021a:$argon2id$v=19$m=32768,t=3,p=1$<salt>$<hash>`). Hashes from
+/// before key rotation existed have no key id.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct HashedPassword(String);
 
 impl HashedPassword {
+    fn new(key_id: &str, phc: String) -> Self {
+        Self(format!("{}:{}", key_id, phc))
+    }
+
     pub fn as_str(&self) -> &str {
         self.0.as_str()
     }
+
+    pub fn key_id(&self) -> &str {
+        match self.0.find(":$") {
+            Some(colon) => &self.0[..colon],
+            None => LEGACY_KEY_ID,
+        }
+    }
+
+    fn phc(&self) -> &str {
+        match self.0.find(":$") {
+            Some(colon) => &self.0[colon + 1..],
+            None => &self.0,
+        }
+    }
+
+    /// The argon2 parameters this hash was made with
+    pub fn params(&self) -> Option<Argon2Params> {
+        Argon2Params::from_phc(self.phc())
+    }
 }
 
 impl std::str::FromStr for HashedPassword {
     type Err = &'static str;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Self(s.into()))
+        let hashed_password = Self(s.into());
+        if hashed_password.phc().starts_with("$argon2") && hashed_password.params().is_some() {
+            Ok(hashed_password)
+        } else {
+            Err("Not an argon2 password hash")
+        }
+    }
+}
+
+/// argon2 cost parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Argon2Params {
+    /// In KiB
+    pub memory_size: u32,
+    pub iterations: u32,
+    pub lanes: u32,
+}
+
+impl Default for Argon2Params {
+    fn default() -> Self {
+        Self {
+            memory_size: DEFAULT_MEMORY_SIZE,
+            iterations: DEFAULT_ITERATIONS,
+            lanes: DEFAULT_LANES,
+        }
+    }
+}
+
+impl Argon2Params {
+    /// Reads `WEFT_ARGON2_MEMORY_SIZE` (KiB), `WEFT_ARGON2_ITERATIONS` and
+    /// `WEFT_ARGON2_LANES`, falling back to the defaults for missing ones
+    pub fn new_from_env() -> Result<Self, PasswordHasherError> {
+        let var = |name: &str, default: u32| match env::var(name) {
+            Ok(value) => value
+                .parse()
+                .map_err(|_| PasswordHasherError::InvalidParameters),
+            Err(_) => Ok(default),
+        };
+
+        Ok(Self {
+            memory_size: var("WEFT_ARGON2_MEMORY_SIZE", DEFAULT_MEMORY_SIZE)?,
+            iterations: var("WEFT_ARGON2_ITERATIONS", DEFAULT_ITERATIONS)?,
+            lanes: var("WEFT_ARGON2_LANES", DEFAULT_LANES)?,
+        })
+    }
+
+    /// Parse the `m=...,t=...,p=...` section of a PHC string
+    fn from_phc(phc: &str) -> Option<Self> {
+        let section = phc.split('$').find(|section| section.starts_with("m="))?;
+        let (mut memory_size, mut iterations, mut lanes) = (None, None, None);
+        for param in section.split(',') {
+            let mut parts = param.splitn(2, '=');
+            let (name, value) = (parts.next()?, parts.next()?);
+            match name {
+                "m" => memory_size = value.parse().ok(),
+                "t" => iterations = value.parse().ok(),
+                "p" => lanes = value.parse().ok(),
+                // e.g. `keyid` or `data`, which we don't use
+                _ => {}
+            }
+        }
+
+        Some(Self {
+            memory_size: memory_size?,
+            iterations: iterations?,
+            lanes: lanes?,
+        })
+    }
+
+    /// Whether hashes made with `self` are cheaper to crack than with `other`
+    fn is_weaker_than(&self, other: &Self) -> bool {
+        self.memory_size < other.memory_size || self.iterations < other.iterations
     }
 }
 
@@ -21,6 +128,9 @@ impl std::str::FromStr for HashedPassword {
 pub enum PasswordHasherError {
     NoSecretKey,
     InvalidSecretKey,
+    InvalidParameters,
+    /// The hash was made with a key that is no longer in the keyring
+    UnknownKey(String),
     HashingError(argonautica::Error),
 }
 
@@ -32,15 +142,34 @@ impl fmt::Display for PasswordHasherError {
             match self {
                 PasswordHasherError::NoSecretKey => "No secret key".into(),
                 PasswordHasherError::InvalidSecretKey => "Invalid secret key".into(),
+                PasswordHasherError::InvalidParameters => "Invalid argon2 parameters".into(),
+                PasswordHasherError::UnknownKey(key_id) => format!("Unknown key `{}`", key_id),
                 PasswordHasherError::HashingError(error) => format!("Hashing error: {:?}", error),
             }
         )
     }
 }
 
+/// How many stored hashes are still on outdated settings
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct HashReport {
+    pub total: u64,
+    pub up_to_date: u64,
+    /// Made with a key other than the current one
+    pub outdated_key: u64,
+    /// Made with cheaper parameters than the current ones
+    pub weak_params: u64,
+    pub by_key: HashMap<String, u64>,
+}
+
+/// Hashes passwords with argon2, peppered with a secret key. Several keys
+/// can be configured so they can be rotated: new hashes always use the
+/// current one, older ones are still verified with whichever key made them.
 #[derive(Debug)]
 pub struct PasswordHasher {
-    secret: String,
+    current_key_id: String,
+    keys: HashMap<String, String>,
+    params: Argon2Params,
 }
 
 impl PasswordHasher {
@@ -52,28 +181,92 @@ impl PasswordHasher {
         }
     }
 
+    /// A hasher with a single, legacy key
     pub fn new<S: AsRef<str>>(secret: S) -> Result<Self, PasswordHasherError> {
         Self::validate_secret(secret).map(|secret| Self {
-            secret: secret.as_ref().into(),
+            current_key_id: LEGACY_KEY_ID.into(),
+            keys: vec![(LEGACY_KEY_ID.to_string(), secret.as_ref().to_string())]
+                .into_iter()
+                .collect(),
+            params: Argon2Params::default(),
         })
     }
 
+    /// Reads the keyring from `WEFT_PASSWORD_KEYS`, a comma separated list of
+    /// `id=secret` pairs, the first of which is the current key. The old
+    /// `WEFT_SECRET_KEY` is still used for legacy hashes, and as the current
+    /// key if there's no keyring. Parameters come from `Argon2Params::new_from_env`.
     pub fn new_from_env_key() -> Result<Self, PasswordHasherError> {
-        env::var("WEFT_SECRET_KEY")
-            .map_err(|error| match error {
-                env::VarError::NotPresent => PasswordHasherError::NoSecretKey,
-                env::VarError::NotUnicode(_) => PasswordHasherError::InvalidSecretKey,
-            })
-            .and_then(Self::validate_secret)
-            .map(|secret| Self { secret })
+        let mut keys = HashMap::new();
+        let mut current_key_id = None;
+
+        match env::var("WEFT_SECRET_KEY") {
+            Ok(secret) => {
+                keys.insert(LEGACY_KEY_ID.to_string(), Self::validate_secret(secret)?);
+            }
+            Err(env::VarError::NotUnicode(_)) => return Err(PasswordHasherError::InvalidSecretKey),
+            Err(env::VarError::NotPresent) => {}
+        }
+
+        if let Ok(keyring) = env::var("WEFT_PASSWORD_KEYS") {
+            for entry in keyring.split(',').filter(|entry| !entry.trim().is_empty()) {
+                let mut parts = entry.trim().splitn(2, '=');
+                let (key_id, secret) = match (parts.next(), parts.next()) {
+                    (Some(key_id), Some(secret)) if is_valid_key_id(key_id) => (key_id, secret),
+                    _ => return Err(PasswordHasherError::InvalidSecretKey),
+                };
+                keys.insert(
+                    key_id.to_string(),
+                    Self::validate_secret(secret)?.to_string(),
+                );
+                current_key_id.get_or_insert_with(|| key_id.to_string());
+            }
+        }
+
+        let current_key_id = match current_key_id {
+            Some(key_id) => key_id,
+            None if keys.contains_key(LEGACY_KEY_ID) => LEGACY_KEY_ID.to_string(),
+            None => return Err(PasswordHasherError::NoSecretKey),
+        };
+
+        Ok(Self {
+            current_key_id,
+            keys,
+            params: Argon2Params::new_from_env()?,
+        })
+    }
+
+    /// Add `secret` to the keyring, and make it the current key
+    pub fn with_current_key<S: AsRef<str>>(
+        mut self,
+        key_id: &str,
+        secret: S,
+    ) -> Result<Self, PasswordHasherError> {
+        if !is_valid_key_id(key_id) {
+            return Err(PasswordHasherError::InvalidSecretKey);
+        }
+        let secret = Self::validate_secret(secret)?;
+        self.keys
+            .insert(key_id.to_string(), secret.as_ref().to_string());
+        self.current_key_id = key_id.to_string();
+        Ok(self)
+    }
+
+    pub fn with_params(mut self, params: Argon2Params) -> Self {
+        self.params = params;
+        self
     }
 
     pub fn hash_password(&self, password: &str) -> Result<HashedPassword, PasswordHasherError> {
         argonautica::Hasher::default()
+            .configure_memory_size(self.params.memory_size)
+            .configure_iterations(self.params.iterations)
+            .configure_lanes(self.params.lanes)
+            .configure_threads(self.params.lanes)
             .with_password(password)
-            .with_secret_key(self.secret.to_owned())
+            .with_secret_key(self.keys[&self.current_key_id].to_owned())
             .hash()
-            .map(HashedPassword)
+            .map(|phc| HashedPassword::new(&self.current_key_id, phc))
             .map_err(PasswordHasherError::HashingError)
     }
 
@@ -82,21 +275,92 @@ impl PasswordHasher {
         password: &str,
         hashed_password: &HashedPassword,
     ) -> Result<bool, PasswordHasherError> {
+        let secret = self
+            .keys
+            .get(hashed_password.key_id())
+            .ok_or_else(|| PasswordHasherError::UnknownKey(hashed_password.key_id().into()))?;
+
         argonautica::Verifier::default()
             .with_password(password.to_owned())
-            .with_hash(&hashed_password.0)
-            .with_secret_key(self.secret.to_owned())
+            .with_hash(hashed_password.phc())
+            .with_secret_key(secret.to_owned())
             .verify()
             .map_err(PasswordHasherError::HashingError)
     }
+
+    fn has_outdated_key(&self, hashed_password: &HashedPassword) -> bool {
+        hashed_password.key_id() != self.current_key_id
+    }
+
+    fn has_weak_params(&self, hashed_password: &HashedPassword) -> bool {
+        hashed_password
+            .params()
+            .map(|params| params.is_weaker_than(&self.params))
+            .unwrap_or(true)
+    }
+
+    /// Whether `hashed_password` should be replaced by a fresh hash, next
+    /// time we get to see the plain password
+    pub fn needs_rehash(&self, hashed_password: &HashedPassword) -> bool {
+        self.has_outdated_key(hashed_password) || self.has_weak_params(hashed_password)
+    }
+
+    /// Tally `hashed_passwords` by key and settings
+    pub fn report<'a, I>(&self, hashed_passwords: I) -> HashReport
+    where
+        I: IntoIterator<Item = &'a HashedPassword>,
+    {
+        hashed_passwords
+            .into_iter()
+            .fold(HashReport::default(), |mut report, hashed_password| {
+                report.total += 1;
+                *report
+                    .by_key
+                    .entry(hashed_password.key_id().to_string())
+                    .or_default() += 1;
+
+                let outdated_key = self.has_outdated_key(hashed_password);
+                let weak_params = self.has_weak_params(hashed_password);
+                if outdated_key {
+                    report.outdated_key += 1;
+                }
+                if weak_params {
+                    report.weak_params += 1;
+                }
+                if !outdated_key && !weak_params {
+                    report.up_to_date += 1;
+                }
+                report
+            })
+    }
+}
+
+fn is_valid_key_id(key_id: &str) -> bool {
+    !key_id.is_empty()
+        && key_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::str::FromStr;
 
     const TEST_SECRET: &'static str = "fogwrtspgvjzaylwogmwnvuximgrqrmdwmtymgbpgfkqkrdgzxkdcvsfqpkzolvklhhtuqaoareiwkrfybdtrdevyrhdksbvwhpltsqbeyplxgumzbchtgryoqukaafvxmnlftanopntxppdxyyttnnhjcxaowly";
+    const OTHER_TEST_SECRET: &'static str = "kqzvmbnyhwexrjdtlgfaopcsuikqzvmbnyhwexrjdtlg";
     const SHORT_TEST_SECRET: &'static str = "cfkwxxjduqoitbrmbhffgckvcgpuz";
+    /// Made by argonautica's defaults, before keys and parameters were stored
+    const LEGACY_HASH: &'static str =
+        "$argon2id$v=19$m=4096,t=192,p=4$c2FsdHNhbHRzYWx0c2FsdA$aGFzaGhhc2hoYXNoaGFzaA";
+
+    fn cheap_params() -> Argon2Params {
+        Argon2Params {
+            memory_size: 1024,
+            iterations: 1,
+            lanes: 1,
+        }
+    }
 
     #[test]
     fn test_build_hasher() {
@@ -126,4 +390,102 @@ mod tests {
         let password_hash = hasher.hash_password(password).unwrap();
         assert!(hasher.verify_password(password, &password_hash).unwrap());
     }
+
+    #[test]
+    fn test_parse_legacy_hash() {
+        let hashed_password = HashedPassword::from_str(LEGACY_HASH).unwrap();
+        assert_eq!(hashed_password.key_id(), LEGACY_KEY_ID);
+        assert_eq!(
+            hashed_password.params(),
+            Some(Argon2Params {
+                memory_size: 4096,
+                iterations: 192,
+                lanes: 4,
+            })
+        );
+        assert!(HashedPassword::from_str("not-a-hash").is_err());
+    }
+
+    #[test]
+    fn test_hash_stores_key_and_params() {
+        let hasher = PasswordHasher::new(TEST_SECRET)
+            .unwrap()
+            .with_current_key("2021a", OTHER_TEST_SECRET)
+            .unwrap()
+            .with_params(cheap_params());
+        let hashed_password = hasher.hash_password("some-password").unwrap();
+
+        assert!(hashed_password.as_str().starts_with("2021a:$argon2id$"));
+        let parsed = HashedPassword::from_str(hashed_password.as_str()).unwrap();
+        assert_eq!(parsed.key_id(), "2021a");
+        assert_eq!(parsed.params(), Some(cheap_params()));
+    }
+
+    #[test]
+    fn test_verify_with_rotated_key() {
+        let old_hasher = PasswordHasher::new(TEST_SECRET)
+            .unwrap()
+            .with_params(cheap_params());
+        let hashed_password = old_hasher.hash_password("some-password").unwrap();
+
+        let new_hasher = old_hasher
+            .with_current_key("2021a", OTHER_TEST_SECRET)
+            .unwrap();
+        assert!(new_hasher
+            .verify_password("some-password", &hashed_password)
+            .unwrap());
+        assert!(new_hasher.needs_rehash(&hashed_password));
+        assert!(!new_hasher.needs_rehash(&new_hasher.hash_password("some-password").unwrap()));
+    }
+
+    #[test]
+    fn test_verify_with_unknown_key() {
+        let hashed_password = PasswordHasher::new(TEST_SECRET)
+            .unwrap()
+            .with_current_key("2021a", OTHER_TEST_SECRET)
+            .unwrap()
+            .with_params(cheap_params())
+            .hash_password("some-password")
+            .unwrap();
+        assert_eq!(
+            PasswordHasher::new(TEST_SECRET)
+                .unwrap()
+                .verify_password("some-password", &hashed_password),
+            Err(PasswordHasherError::UnknownKey("2021a".into()))
+        );
+    }
+
+    #[test]
+    fn test_weak_params_need_rehash() {
+        let weak = PasswordHasher::new(TEST_SECRET)
+            .unwrap()
+            .with_params(cheap_params())
+            .hash_password("some-password")
+            .unwrap();
+        assert!(PasswordHasher::new(TEST_SECRET)
+            .unwrap()
+            .needs_rehash(&weak));
+    }
+
+    #[test]
+    fn test_report() {
+        let hasher = PasswordHasher::new(TEST_SECRET)
+            .unwrap()
+            .with_current_key("2021a", OTHER_TEST_SECRET)
+            .unwrap()
+            .with_params(cheap_params());
+        let hashes = vec![
+            HashedPassword::from_str(LEGACY_HASH).unwrap(),
+            hasher.hash_password("some-password").unwrap(),
+            hasher.hash_password("some-other-password").unwrap(),
+        ];
+
+        let report = hasher.report(&hashes);
+        assert_eq!(report.total, 3);
+        assert_eq!(report.up_to_date, 2);
+        assert_eq!(report.outdated_key, 1);
+        assert_eq!(report.weak_params, 0);
+        assert_eq!(report.by_key[LEGACY_KEY_ID], 1);
+        assert_eq!(report.by_key["2021a"], 2);
+    }
 }
@@ -0,0 +1,469 @@
+//! What users can change about themselves, besides their credentials.
+//!
+//! Changes are partial: fields missing from an update are left alone, and
+//! `null` (or a blank string) clears the optional ones. Every update names
+//! the `updated_at` of the profile it was made to, so one made to a stale
+//! copy is turned down instead of silently undoing someone else's changes.
+//!
+//! Handles name public profiles in URLs, so they're limited to URL-safe
+//! characters and can't take over words the app uses itself. Handles users
+//! move away from stay theirs for a while, redirecting to whatever they go
+//! by now, but only the last few: cycling through handles doesn't hoard
+//! them.
+
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer};
+use sqlx::Row;
+use url::Url;
+
+use crate::rejections::FieldError;
+
+pub const MAX_FULL_NAME_LENGTH: usize = 100;
+pub const MIN_HANDLE_LENGTH: usize = 3;
+pub const MAX_HANDLE_LENGTH: usize = 30;
+/// How long a retired handle stays with its last owner
+pub const HANDLE_RESERVATION_DAYS: i32 = 90;
+/// Retired handles one user holds on to at most; older ones are let go
+pub const MAX_RETIRED_HANDLES: i64 = 3;
+const MAX_AVATAR_URL_LENGTH: usize = 2048;
+const MAX_LOCALE_LENGTH: usize = 35;
+const MAX_TIMEZONE_LENGTH: usize = 64;
+
+/// Handles that could be mistaken for the app's own pages or staff, or clash
+/// with `/user/...` routes
+const RESERVED_HANDLES: &[&str] = &[
+    "about",
+    "account",
+    "admin",
+    "administrator",
+    "api",
+    "app",
+    "audit",
+    "auth",
+    "avatar",
+    "blog",
+    "delete",
+    "deletion",
+    "email",
+    "export",
+    "help",
+    "identities",
+    "login",
+    "logout",
+    "me",
+    "mfa",
+    "moderator",
+    "null",
+    "oauth",
+    "passkeys",
+    "password",
+    "privacy",
+    "root",
+    "security",
+    "sessions",
+    "settings",
+    "signup",
+    "staff",
+    "static",
+    "support",
+    "system",
+    "terms",
+    "tokens",
+    "undefined",
+    "user",
+    "users",
+    "weft",
+    "www",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProfileValidationError {
+    EmptyFullName,
+    FullNameTooLong,
+    InvalidHandle,
+    ReservedHandle,
+    InvalidAvatarUrl,
+    InvalidLocale,
+    InvalidTimezone,
+}
+
+impl ProfileValidationError {
+    /// The profile field this error is about
+    pub fn field(&self) -> &'static str {
+        match self {
+            ProfileValidationError::EmptyFullName | ProfileValidationError::FullNameTooLong => {
+                "full_name"
+            }
+            ProfileValidationError::InvalidHandle | ProfileValidationError::ReservedHandle => {
+                "handle"
+            }
+            ProfileValidationError::InvalidAvatarUrl => "avatar_url",
+            ProfileValidationError::InvalidLocale => "locale",
+            ProfileValidationError::InvalidTimezone => "timezone",
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ProfileValidationError::EmptyFullName => "required",
+            ProfileValidationError::FullNameTooLong => "too_long",
+            ProfileValidationError::ReservedHandle => "reserved",
+            _ => "invalid",
+        }
+    }
+}
+
+impl fmt::Display for ProfileValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProfileValidationError::EmptyFullName => write!(f, "Empty full name"),
+            ProfileValidationError::FullNameTooLong => write!(
+                f,
+                "Full name must be at most {} characters long",
+                MAX_FULL_NAME_LENGTH
+            ),
+            ProfileValidationError::InvalidHandle => write!(
+                f,
+                "Handle must be {} to {} letters, digits, dashes or underscores",
+                MIN_HANDLE_LENGTH, MAX_HANDLE_LENGTH
+            ),
+            ProfileValidationError::ReservedHandle => write!(f, "Handle is reserved"),
+            ProfileValidationError::InvalidAvatarUrl => {
+                write!(f, "Avatar must be an uploaded image")
+            }
+            ProfileValidationError::InvalidLocale => {
+                write!(f, "Locale must be a language tag, like `en` or `pt-BR`")
+            }
+            ProfileValidationError::InvalidTimezone => {
+                write!(f, "Timezone must be a name like `Europe/Madrid`")
+            }
+        }
+    }
+}
+
+impl From<ProfileValidationError> for FieldError {
+    fn from(error: ProfileValidationError) -> Self {
+        FieldError {
+            field: error.field(),
+            code: error.code(),
+            message: error.to_string(),
+        }
+    }
+}
+
+/// Tell a `null` field (`Some(None)`) apart from a missing one (`None`)
+fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
+
+/// Unvalidated profile changes
+#[derive(Debug, Deserialize)]
+pub struct ProfileUpdate {
+    #[serde(default)]
+    pub full_name: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub handle: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub avatar_url: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub locale: Option<Option<String>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub timezone: Option<Option<String>>,
+    /// `updated_at` of the profile these changes were made to
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Validated profile changes, with the same missing/`None` distinction
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProfileChanges {
+    pub full_name: Option<String>,
+    pub handle: Option<Option<String>>,
+    pub avatar_url: Option<Option<Url>>,
+    pub locale: Option<Option<String>>,
+    pub timezone: Option<Option<String>>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A blank optional value clears the field, same as `null`
+fn non_blank(value: Option<String>) -> Option<String> {
+    value
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// Whether `handle` is made of URL-safe characters only, and long enough
+/// not to be squatting. Says nothing about it being taken.
+pub fn is_valid_handle(handle: &str) -> bool {
+    (MIN_HANDLE_LENGTH..=MAX_HANDLE_LENGTH).contains(&handle.len())
+        && handle
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        && handle.starts_with(|c: char| c.is_ascii_alphanumeric())
+}
+
+pub fn is_reserved_handle(handle: &str) -> bool {
+    RESERVED_HANDLES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(handle))
+}
+
+/// `locale` as a BCP 47 language tag in its usual case (`pt-BR`, `zh-Hant`),
+/// if it looks like one. Whether we have translations for it is another
+/// matter.
+fn canonical_locale(locale: &str) -> Option<String> {
+    if locale.len() > MAX_LOCALE_LENGTH {
+        return None;
+    }
+    let mut subtags = locale.split(&['-', '_'][..]);
+    let language = subtags.next()?;
+    if !(2..=3).contains(&language.len()) || !language.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+
+    let mut canonical = language.to_ascii_lowercase();
+    for subtag in subtags {
+        if subtag.is_empty()
+            || subtag.len() > 8
+            || !subtag.chars().all(|c| c.is_ascii_alphanumeric())
+        {
+            return None;
+        }
+        canonical.push('-');
+        match subtag.len() {
+            // Regions
+            2 => canonical.push_str(&subtag.to_ascii_uppercase()),
+            // Scripts
+            4 if subtag.chars().all(|c| c.is_ascii_alphabetic()) => {
+                canonical.push_str(&subtag[..1].to_ascii_uppercase());
+                canonical.push_str(&subtag[1..].to_ascii_lowercase());
+            }
+            _ => canonical.push_str(&subtag.to_ascii_lowercase()),
+        }
+    }
+    Some(canonical)
+}
+
+/// Whether `timezone` looks like an IANA time zone name. Only the database
+/// knows which ones actually exist; see `is_known_timezone`.
+fn is_timezone_name(timezone: &str) -> bool {
+    timezone.len() <= MAX_TIMEZONE_LENGTH
+        && timezone.split('/').all(|part| {
+            part.starts_with(|c: char| c.is_ascii_alphabetic())
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "_+-".contains(c))
+        })
+}
+
+/// Whether Postgres knows `timezone` by that name
+pub async fn is_known_timezone(
+    db_pool: &sqlx::PgPool,
+    timezone: &str,
+) -> Result<bool, sqlx::Error> {
+    sqlx::query("SELECT EXISTS (SELECT 1 FROM pg_timezone_names WHERE name = $1);")
+        .bind(timezone)
+        .fetch_one(db_pool)
+        .await
+        .map(|row| row.get(0))
+}
+
+impl ProfileUpdate {
+    /// Check every field present, reporting all the problems found at once.
+    /// Avatars have to be under `media_url`, where we serve uploaded ones
+    /// from: anywhere else could be told about everybody viewing the
+    /// profile, or start serving something else.
+    pub fn validate(self, media_url: &Url) -> Result<ProfileChanges, Vec<ProfileValidationError>> {
+        let mut errors = vec![];
+
+        let full_name = self.full_name.map(|full_name| full_name.trim().to_string());
+        match &full_name {
+            Some(full_name) if full_name.is_empty() => {
+                errors.push(ProfileValidationError::EmptyFullName)
+            }
+            Some(full_name) if full_name.chars().count() > MAX_FULL_NAME_LENGTH => {
+                errors.push(ProfileValidationError::FullNameTooLong)
+            }
+            _ => {}
+        }
+
+        let handle = self.handle.map(non_blank);
+        if let Some(Some(handle)) = &handle {
+            if !is_valid_handle(handle) {
+                errors.push(ProfileValidationError::InvalidHandle);
+            } else if is_reserved_handle(handle) {
+                errors.push(ProfileValidationError::ReservedHandle);
+            }
+        }
+
+        let avatar_url = self.avatar_url.map(|avatar_url| {
+            non_blank(avatar_url).and_then(|avatar_url| {
+                Url::parse(&avatar_url)
+                    .ok()
+                    .filter(|url| {
+                        avatar_url.len() <= MAX_AVATAR_URL_LENGTH
+                            && url.origin() == media_url.origin()
+                            && url.path().starts_with(media_url.path())
+                    })
+                    .or_else(|| {
+                        errors.push(ProfileValidationError::InvalidAvatarUrl);
+                        None
+                    })
+            })
+        });
+
+        let locale = self.locale.map(|locale| {
+            non_blank(locale).and_then(|locale| {
+                canonical_locale(&locale).or_else(|| {
+                    errors.push(ProfileValidationError::InvalidLocale);
+                    None
+                })
+            })
+        });
+
+        let timezone = self.timezone.map(non_blank);
+        if let Some(Some(timezone)) = &timezone {
+            if !is_timezone_name(timezone) {
+                errors.push(ProfileValidationError::InvalidTimezone);
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(ProfileChanges {
+                full_name,
+                handle,
+                avatar_url,
+                locale,
+                timezone,
+                updated_at: self.updated_at,
+            })
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update(mut json: serde_json::Value) -> ProfileUpdate {
+        json["updated_at"] = "2021-01-31T10:00:00.123456Z".into();
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn media_url() -> Url {
+        Url::parse("https://cdn.weft.example/media/").unwrap()
+    }
+
+    #[test]
+    fn test_missing_and_null_fields() {
+        let changes = update(serde_json::json!({
+            "full_name": " Ada Lovelace ",
+            "handle": null,
+            "locale": "",
+        }))
+        .validate(&media_url())
+        .unwrap();
+        assert_eq!(changes.full_name.as_deref(), Some("Ada Lovelace"));
+        assert_eq!(changes.handle, Some(None));
+        assert_eq!(changes.locale, Some(None));
+        assert_eq!(changes.avatar_url, None);
+        assert_eq!(changes.timezone, None);
+        assert_eq!(changes.updated_at.timestamp_subsec_micros(), 123456);
+    }
+
+    #[test]
+    fn test_valid_fields() {
+        let changes = update(serde_json::json!({
+            "handle": "ada_1815",
+            "avatar_url": "https://cdn.weft.example/media/avatars/ada.png",
+            "locale": "pt_br",
+            "timezone": "America/Argentina/Buenos_Aires",
+        }))
+        .validate(&media_url())
+        .unwrap();
+        assert_eq!(changes.handle, Some(Some("ada_1815".into())));
+        assert_eq!(
+            changes.avatar_url.unwrap().unwrap().as_str(),
+            "https://cdn.weft.example/media/avatars/ada.png"
+        );
+        assert_eq!(changes.locale, Some(Some("pt-BR".into())));
+        assert_eq!(
+            canonical_locale("ZH-hant-tw").as_deref(),
+            Some("zh-Hant-TW")
+        );
+    }
+
+    #[test]
+    fn test_reports_every_invalid_field() {
+        let errors = update(serde_json::json!({
+            "full_name": "  ",
+            "handle": "no spaces please",
+            "avatar_url": "javascript:alert(1)",
+            "locale": "english",
+            "timezone": "../etc/passwd",
+        }))
+        .validate(&media_url())
+        .err()
+        .unwrap();
+        assert_eq!(
+            errors
+                .iter()
+                .map(|error| (error.field(), error.code()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("full_name", "required"),
+                ("handle", "invalid"),
+                ("avatar_url", "invalid"),
+                ("locale", "invalid"),
+                ("timezone", "invalid"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_avatars_are_ours() {
+        for avatar_url in &[
+            "https://tracker.example/ada.png",
+            "http://cdn.weft.example/media/ada.png",
+            "https://cdn.weft.example:8443/media/ada.png",
+            "https://cdn.weft.example/elsewhere/ada.png",
+            "https://cdn.weft.example.tracker.example/media/ada.png",
+        ] {
+            let errors = update(serde_json::json!({ "avatar_url": avatar_url }))
+                .validate(&media_url())
+                .err()
+                .unwrap();
+            assert_eq!(errors, vec![ProfileValidationError::InvalidAvatarUrl]);
+        }
+    }
+
+    #[test]
+    fn test_handles() {
+        assert!(is_valid_handle("ada"));
+        assert!(is_valid_handle("Ada-Lovelace_1815"));
+        assert!(!is_valid_handle("ad"));
+        assert!(!is_valid_handle("-ada"));
+        assert!(!is_valid_handle("ada/lovelace"));
+        assert!(!is_valid_handle("adá"));
+        assert!(!is_valid_handle(&"a".repeat(MAX_HANDLE_LENGTH + 1)));
+
+        let errors = update(serde_json::json!({"handle": "Admin"}))
+            .validate(&media_url())
+            .err()
+            .unwrap();
+        assert_eq!(errors, vec![ProfileValidationError::ReservedHandle]);
+        assert_eq!(errors[0].code(), "reserved");
+    }
+
+    #[test]
+    fn test_update_needs_updated_at() {
+        assert!(serde_json::from_str::<ProfileUpdate>(r#"{"full_name": "Ada"}"#).is_err());
+    }
+}
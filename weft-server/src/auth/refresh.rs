@@ -0,0 +1,296 @@
+use std::{env, fmt};
+
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{types::Uuid, Row};
+
+use super::opaque::{generate_token, hash_token};
+
+const DEFAULT_LIFETIME_DAYS: i64 = 30;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RefreshTokenError {
+    /// Unknown, expired or revoked token
+    InvalidToken,
+    /// An already rotated token was presented again; its family has been revoked
+    ReusedToken,
+    RandomError,
+    DatabaseError(sqlx::Error),
+}
+
+impl fmt::Display for RefreshTokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                RefreshTokenError::InvalidToken => "Invalid refresh token".into(),
+                RefreshTokenError::ReusedToken => "Refresh token reused".into(),
+                RefreshTokenError::RandomError => "Could not generate refresh token".into(),
+                RefreshTokenError::DatabaseError(error) => format!("Database error: {:?}", error),
+            }
+        )
+    }
+}
+
+impl From<sqlx::Error> for RefreshTokenError {
+    fn from(error: sqlx::Error) -> Self {
+        RefreshTokenError::DatabaseError(error)
+    }
+}
+
+/// A freshly minted refresh token. The plain token is only ever handed to
+/// the client; we keep its SHA-256 digest.
+#[derive(Debug)]
+pub struct IssuedRefreshToken {
+    pub user_id: Uuid,
+    pub family_id: Uuid,
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Issues and rotates opaque refresh tokens. Every login starts a new token
+/// "family"; each refresh retires the presented token and issues the next
+/// one in the same family.
+pub struct RefreshTokenManager {
+    lifetime: Duration,
+}
+
+impl RefreshTokenManager {
+    pub fn new(lifetime: Duration) -> Self {
+        Self { lifetime }
+    }
+
+    /// Reads the token lifetime (in days) from `WEFT_REFRESH_TOKEN_LIFETIME`
+    pub fn new_from_env() -> Result<Self, std::num::ParseIntError> {
+        let days = match env::var("WEFT_REFRESH_TOKEN_LIFETIME") {
+            Ok(days) => days.parse()?,
+            Err(_) => DEFAULT_LIFETIME_DAYS,
+        };
+        Ok(Self::new(Duration::days(days)))
+    }
+
+    async fn insert<'c, E>(
+        &self,
+        executor: E,
+        user_id: &Uuid,
+        family_id: &Uuid,
+    ) -> Result<IssuedRefreshToken, RefreshTokenError>
+    where
+        E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+    {
+        let token = generate_token().map_err(|_| RefreshTokenError::RandomError)?;
+        let expires_at = Utc::now() + self.lifetime;
+
+        sqlx::query(
+            r#"
+INSERT INTO refresh_tokens (id, family_id, user_id, token_hash, expires_at)
+VALUES ($1, $2, $3, $4, $5);"#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(family_id)
+        .bind(user_id)
+        .bind(hash_token(&token))
+        .bind(expires_at)
+        .execute(executor)
+        .await?;
+
+        Ok(IssuedRefreshToken {
+            user_id: *user_id,
+            family_id: *family_id,
+            token,
+            expires_at,
+        })
+    }
+
+    /// Start a new token family for a freshly created session. The family
+    /// id is the session id, so revoking one revokes the other.
+    pub async fn issue(
+        &self,
+        db_pool: &sqlx::PgPool,
+        user_id: &Uuid,
+        session_id: &Uuid,
+    ) -> Result<IssuedRefreshToken, RefreshTokenError> {
+        self.insert(db_pool, user_id, session_id).await
+    }
+
+    /// Exchange `token` for the next one in its family. Presenting a token
+    /// that was already rotated revokes the whole family and its session,
+    /// since either the legitimate client or an attacker is holding a stolen
+    /// copy.
+    pub async fn rotate(
+        &self,
+        db_pool: &sqlx::PgPool,
+        token: &str,
+    ) -> Result<IssuedRefreshToken, RefreshTokenError> {
+        let mut transaction = db_pool.begin().await?;
+
+        let row = sqlx::query(
+            r#"
+SELECT user_id, family_id, expires_at, rotated_at, revoked_at
+FROM refresh_tokens
+WHERE token_hash = $1
+FOR UPDATE;"#,
+        )
+        .bind(hash_token(token))
+        .fetch_optional(&mut transaction)
+        .await?
+        .ok_or(RefreshTokenError::InvalidToken)?;
+
+        let user_id: Uuid = row.try_get("user_id")?;
+        let family_id: Uuid = row.try_get("family_id")?;
+        let expires_at: DateTime<Utc> = row.try_get("expires_at")?;
+        let rotated_at: Option<DateTime<Utc>> = row.try_get("rotated_at")?;
+        let revoked_at: Option<DateTime<Utc>> = row.try_get("revoked_at")?;
+
+        if revoked_at.is_some() {
+            return Err(RefreshTokenError::InvalidToken);
+        }
+
+        if rotated_at.is_some() {
+            Self::revoke_family_with(&mut transaction, &family_id).await?;
+            transaction.commit().await?;
+            return Err(RefreshTokenError::ReusedToken);
+        }
+
+        if expires_at < Utc::now() {
+            return Err(RefreshTokenError::InvalidToken);
+        }
+
+        sqlx::query("UPDATE refresh_tokens SET rotated_at = now() WHERE token_hash = $1;")
+            .bind(hash_token(token))
+            .execute(&mut transaction)
+            .await?;
+
+        let issued = self.insert(&mut transaction, &user_id, &family_id).await?;
+        transaction.commit().await?;
+
+        Ok(issued)
+    }
+
+    async fn revoke_family_with<'c, E>(executor: E, family_id: &Uuid) -> Result<(), sqlx::Error>
+    where
+        E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+    {
+        sqlx::query(
+            r#"
+WITH revoked_tokens AS (
+    UPDATE refresh_tokens SET revoked_at = now()
+    WHERE family_id = $1 AND revoked_at IS NULL
+)
+UPDATE sessions SET revoked_at = now()
+WHERE id = $1 AND revoked_at IS NULL;"#,
+        )
+        .bind(family_id)
+        .execute(executor)
+        .await
+        .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        auth::{ClientInfo, Session},
+        test_db,
+    };
+
+    /// A new session of a new user, and the first refresh token of its
+    /// family
+    async fn login(
+        db_pool: &sqlx::PgPool,
+        manager: &RefreshTokenManager,
+    ) -> (Session, IssuedRefreshToken) {
+        let user = test_db::create_user(db_pool).await;
+        let session = Session::create(db_pool, user.id(), &ClientInfo::default())
+            .await
+            .unwrap();
+        let issued = manager
+            .issue(db_pool, user.id(), &session.id)
+            .await
+            .unwrap();
+        (session, issued)
+    }
+
+    async fn is_session_revoked(db_pool: &sqlx::PgPool, session: &Session) -> bool {
+        !Session::touch(
+            db_pool,
+            &session.user_id,
+            &session.id,
+            &ClientInfo::default(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database"]
+    async fn test_rotated_tokens_are_reused_tokens() {
+        let db_pool = test_db::pool().await;
+        let manager = RefreshTokenManager::new(Duration::days(1));
+        let (session, first) = login(&db_pool, &manager).await;
+
+        let second = manager.rotate(&db_pool, &first.token).await.unwrap();
+        assert_eq!(second.family_id, session.id);
+        assert_ne!(second.token, first.token);
+        assert!(matches!(
+            manager.rotate(&db_pool, &first.token).await,
+            Err(RefreshTokenError::ReusedToken)
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database"]
+    async fn test_reuse_revokes_the_family() {
+        let db_pool = test_db::pool().await;
+        let manager = RefreshTokenManager::new(Duration::days(1));
+        let (session, first) = login(&db_pool, &manager).await;
+        let second = manager.rotate(&db_pool, &first.token).await.unwrap();
+        let third = manager.rotate(&db_pool, &second.token).await.unwrap();
+
+        // Whoever holds a copy of the first token gives the theft away
+        assert!(matches!(
+            manager.rotate(&db_pool, &first.token).await,
+            Err(RefreshTokenError::ReusedToken)
+        ));
+        let unrevoked: i64 = sqlx::query(
+            "SELECT count(*) FROM refresh_tokens WHERE family_id = $1 AND revoked_at IS NULL;",
+        )
+        .bind(session.id)
+        .fetch_one(&db_pool)
+        .await
+        .unwrap()
+        .get(0);
+        assert_eq!(unrevoked, 0);
+        assert!(is_session_revoked(&db_pool, &session).await);
+        // The latest token went along with the rest
+        assert!(matches!(
+            manager.rotate(&db_pool, &third.token).await,
+            Err(RefreshTokenError::InvalidToken)
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database"]
+    async fn test_expired_tokens_are_not_rotated() {
+        let db_pool = test_db::pool().await;
+        let manager = RefreshTokenManager::new(Duration::seconds(-1));
+        let (session, expired) = login(&db_pool, &manager).await;
+
+        assert!(matches!(
+            manager.rotate(&db_pool, &expired.token).await,
+            Err(RefreshTokenError::InvalidToken)
+        ));
+        let row = sqlx::query(
+            "SELECT count(*), count(rotated_at) FROM refresh_tokens WHERE family_id = $1;",
+        )
+        .bind(session.id)
+        .fetch_one(&db_pool)
+        .await
+        .unwrap();
+        let (tokens, rotated): (i64, i64) = (row.get(0), row.get(1));
+        assert_eq!((tokens, rotated), (1, 0));
+        assert!(!is_session_revoked(&db_pool, &session).await);
+    }
+}
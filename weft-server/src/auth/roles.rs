@@ -0,0 +1,177 @@
+//! What users are allowed to do beyond managing their own account. Every
+//! user has exactly one role, and every role a fixed set of permissions;
+//! endpoints check for permissions, never for roles.
+
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Moderator,
+    Admin,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// List users, suspend and unsuspend them
+    ManageUsers,
+    DeleteUsers,
+    /// Give users another role
+    ManageRoles,
+    /// Look at reports on the state of stored credentials
+    ViewSecurityReports,
+    /// Search the audit log of every user
+    ViewAuditLog,
+}
+
+impl Default for Role {
+    fn default() -> Self {
+        Role::User
+    }
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn permissions(self) -> &'static [Permission] {
+        match self {
+            Role::User => &[],
+            Role::Moderator => &[Permission::ManageUsers],
+            Role::Admin => &[
+                Permission::ManageUsers,
+                Permission::DeleteUsers,
+                Permission::ManageRoles,
+                Permission::ViewSecurityReports,
+                Permission::ViewAuditLog,
+            ],
+        }
+    }
+
+    pub fn has_permission(self, permission: Permission) -> bool {
+        self.permissions().contains(&permission)
+    }
+
+    /// Whether a user with this role may act on one with `other`: nobody
+    /// gets to suspend, delete or demote their peers or their betters
+    pub fn outranks(self, other: Role) -> bool {
+        self > other
+    }
+}
+
+/// Which users to list, a page at a time
+#[derive(Debug, Deserialize)]
+pub struct UserSearch {
+    pub search: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+impl UserSearch {
+    const DEFAULT_LIMIT: i64 = 50;
+    const MAX_LIMIT: i64 = 200;
+
+    pub fn limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(Self::DEFAULT_LIMIT)
+            .max(1)
+            .min(Self::MAX_LIMIT)
+    }
+
+    pub fn offset(&self) -> i64 {
+        self.offset.unwrap_or(0).max(0)
+    }
+
+    /// The search term, unless it's blank
+    pub fn search(&self) -> Option<&str> {
+        self.search
+            .as_deref()
+            .map(str::trim)
+            .filter(|search| !search.is_empty())
+    }
+}
+
+/// Why a user is being suspended, shown to other moderators
+#[derive(Debug, Deserialize)]
+pub struct SuspendUser {
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangeRole {
+    pub role: Role,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for Role {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "user" => Ok(Role::User),
+            "moderator" => Ok(Role::Moderator),
+            "admin" => Ok(Role::Admin),
+            _ => Err(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_permissions() {
+        assert!(!Role::User.has_permission(Permission::ManageUsers));
+        assert!(Role::Moderator.has_permission(Permission::ManageUsers));
+        assert!(!Role::Moderator.has_permission(Permission::DeleteUsers));
+        assert!(Role::Admin.has_permission(Permission::ManageRoles));
+    }
+
+    #[test]
+    fn test_outranks() {
+        assert!(Role::Admin.outranks(Role::Moderator));
+        assert!(Role::Moderator.outranks(Role::User));
+        assert!(!Role::Moderator.outranks(Role::Moderator));
+        assert!(!Role::User.outranks(Role::Admin));
+    }
+
+    #[test]
+    fn test_user_search_bounds() {
+        let search: UserSearch =
+            serde_json::from_str(r#"{"search": "  ", "limit": 10000, "offset": -5}"#).unwrap();
+        assert_eq!(search.search(), None);
+        assert_eq!(search.limit(), UserSearch::MAX_LIMIT);
+        assert_eq!(search.offset(), 0);
+
+        let search: UserSearch = serde_json::from_str(r#"{"search": " weft "}"#).unwrap();
+        assert_eq!(search.search(), Some("weft"));
+        assert_eq!(search.limit(), UserSearch::DEFAULT_LIMIT);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for role in &[Role::User, Role::Moderator, Role::Admin] {
+            assert_eq!(role.as_str().parse(), Ok(*role));
+            assert_eq!(
+                serde_json::to_string(role).unwrap(),
+                format!("\"{}\"", role)
+            );
+        }
+        assert_eq!("root".parse::<Role>(), Err(()));
+    }
+}
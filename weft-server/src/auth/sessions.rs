@@ -0,0 +1,472 @@
+use std::{
+    env,
+    net::{AddrParseError, IpAddr},
+};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Serialize;
+use sqlx::types::Uuid;
+
+use super::tokens::MFA_PENDING_LIFETIME_MINUTES;
+
+/// Who is on the other end of a request, as far as we can tell
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    /// Ties together everything logged about a request
+    pub request_id: Option<String>,
+}
+
+/// The proxies in front of us, whose `X-Forwarded-For` we believe. Any
+/// other client could put whatever it likes in there.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(Vec<IpAddr>);
+
+impl TrustedProxies {
+    pub fn new(proxies: Vec<IpAddr>) -> Self {
+        Self(proxies)
+    }
+
+    /// Reads the proxies' addresses from `WEFT_TRUSTED_PROXIES`, comma
+    /// separated. Without any, whoever connects to us is the client.
+    pub fn new_from_env() -> Result<Self, AddrParseError> {
+        env::var("WEFT_TRUSTED_PROXIES")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|proxy| !proxy.is_empty())
+            .map(str::parse)
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    /// The client's address: the one connecting to us, unless that's a
+    /// trusted proxy, in which case the one it says it forwarded for.
+    /// `X-Forwarded-For` is read from the right, since that's where each
+    /// proxy appends to it, and only for as long as it's our proxies doing
+    /// the appending.
+    pub fn client_ip(&self, remote: Option<IpAddr>, forwarded_for: Option<&str>) -> Option<IpAddr> {
+        let mut client = remote?;
+        for hop in forwarded_for.unwrap_or_default().rsplit(',') {
+            if !self.0.contains(&client) {
+                break;
+            }
+            match hop.trim().parse() {
+                Ok(address) => client = address,
+                Err(_) => break,
+            }
+        }
+        Some(client)
+    }
+}
+
+/// Longest `X-Request-Id` we'll take from a proxy in front of us
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// The request id a proxy in front of us assigned, if it looks sane, or a
+/// new one
+pub fn request_id_or_new(header: Option<String>) -> String {
+    header
+        .filter(|request_id| {
+            !request_id.is_empty()
+                && request_id.len() <= MAX_REQUEST_ID_LENGTH
+                && request_id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c))
+        })
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+/// A logged in device/browser. The session id doubles as the `jti` of every
+/// access token minted for it, and as the family id of its refresh tokens.
+#[derive(Debug, Serialize, sqlx::FromRow)]
+pub struct Session {
+    pub id: Uuid,
+    #[serde(skip)]
+    pub user_id: Uuid,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+}
+
+/// A session as listed to its owner
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    #[serde(flatten)]
+    pub session: Session,
+    /// Whether this is the session making the request
+    pub current: bool,
+}
+
+impl Session {
+    pub async fn create(
+        db_pool: &sqlx::PgPool,
+        user_id: &Uuid,
+        client: &ClientInfo,
+    ) -> Result<Self, sqlx::Error> {
+        sqlx::query_as::<_, Self>(
+            r#"
+INSERT INTO sessions (id, user_id, user_agent, ip_address)
+VALUES ($1, $2, $3, $4)
+RETURNING id, user_id, user_agent, ip_address, created_at, last_seen_at;"#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(&client.user_agent)
+        .bind(&client.ip_address)
+        .fetch_one(db_pool)
+        .await
+    }
+
+    /// Bump the session's last-seen time, telling whether it is still active
+    pub async fn touch(
+        db_pool: &sqlx::PgPool,
+        user_id: &Uuid,
+        session_id: &Uuid,
+        client: &ClientInfo,
+    ) -> Result<bool, sqlx::Error> {
+        sqlx::query(
+            r#"
+UPDATE sessions
+SET last_seen_at = now(),
+    ip_address = COALESCE($3, ip_address)
+WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL;"#,
+        )
+        .bind(session_id)
+        .bind(user_id)
+        .bind(&client.ip_address)
+        .execute(db_pool)
+        .await
+        .map(|done| done.rows_affected() > 0)
+    }
+
+    pub async fn list_active(
+        db_pool: &sqlx::PgPool,
+        user_id: &Uuid,
+    ) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as::<_, Self>(
+            r#"
+SELECT id, user_id, user_agent, ip_address, created_at, last_seen_at
+FROM sessions
+WHERE user_id = $1 AND revoked_at IS NULL
+ORDER BY last_seen_at DESC;"#,
+        )
+        .bind(user_id)
+        .fetch_all(db_pool)
+        .await
+    }
+
+    /// One of the user's active sessions
+    pub async fn get(
+        db_pool: &sqlx::PgPool,
+        user_id: &Uuid,
+        session_id: &Uuid,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as::<_, Self>(
+            r#"
+SELECT id, user_id, user_agent, ip_address, created_at, last_seen_at
+FROM sessions
+WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL;"#,
+        )
+        .bind(session_id)
+        .bind(user_id)
+        .fetch_optional(db_pool)
+        .await
+    }
+
+    /// Revoke one of the user's sessions along with its refresh tokens.
+    /// Returns `false` if there was no such active session.
+    pub async fn revoke(
+        db_pool: &sqlx::PgPool,
+        user_id: &Uuid,
+        session_id: &Uuid,
+    ) -> Result<bool, sqlx::Error> {
+        let mut transaction = db_pool.begin().await?;
+
+        let revoked = sqlx::query(
+            "UPDATE sessions SET revoked_at = now() WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL;",
+        )
+        .bind(session_id)
+        .bind(user_id)
+        .execute(&mut transaction)
+        .await?
+        .rows_affected()
+            > 0;
+
+        sqlx::query(
+            "UPDATE refresh_tokens SET revoked_at = now() WHERE family_id = $1 AND user_id = $2 AND revoked_at IS NULL;",
+        )
+        .bind(session_id)
+        .bind(user_id)
+        .execute(&mut transaction)
+        .await?;
+
+        transaction.commit().await?;
+        Ok(revoked)
+    }
+
+    /// Revoke every active session of the user except `keep_session_id`, if
+    /// given. Returns how many sessions were revoked.
+    pub async fn revoke_all(
+        db_pool: &sqlx::PgPool,
+        user_id: &Uuid,
+        keep_session_id: Option<&Uuid>,
+    ) -> Result<u64, sqlx::Error> {
+        let mut transaction = db_pool.begin().await?;
+        let revoked = Self::revoke_all_within(&mut transaction, user_id, keep_session_id).await?;
+        transaction.commit().await?;
+        Ok(revoked)
+    }
+
+    /// Same as `revoke_all`, as part of a larger transaction
+    pub async fn revoke_all_within(
+        transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        user_id: &Uuid,
+        keep_session_id: Option<&Uuid>,
+    ) -> Result<u64, sqlx::Error> {
+        let revoked = sqlx::query(
+            r#"
+UPDATE sessions SET revoked_at = now()
+WHERE user_id = $1 AND revoked_at IS NULL AND ($2::uuid IS NULL OR id <> $2);"#,
+        )
+        .bind(user_id)
+        .bind(keep_session_id)
+        .execute(&mut *transaction)
+        .await?
+        .rows_affected();
+
+        sqlx::query(
+            r#"
+UPDATE refresh_tokens SET revoked_at = now()
+WHERE user_id = $1 AND revoked_at IS NULL AND ($2::uuid IS NULL OR family_id <> $2);"#,
+        )
+        .bind(user_id)
+        .bind(keep_session_id)
+        .execute(&mut *transaction)
+        .await?;
+
+        Ok(revoked)
+    }
+}
+
+/// A login that got the password right and still has to pass a second
+/// factor. Its id is the `jti` of the "MFA pending" token, so that token
+/// can complete a login once, and only once.
+pub struct PendingMfaLogin;
+
+impl PendingMfaLogin {
+    /// Good for as long as the token is
+    pub async fn create(db_pool: &sqlx::PgPool, user_id: &Uuid) -> Result<Uuid, sqlx::Error> {
+        // Whatever was left behind by logins nobody finished
+        sqlx::query("DELETE FROM pending_mfa_logins WHERE expires_at <= now();")
+            .execute(db_pool)
+            .await?;
+
+        let id = Uuid::new_v4();
+        sqlx::query(
+            "INSERT INTO pending_mfa_logins (id, user_id, expires_at) VALUES ($1, $2, $3);",
+        )
+        .bind(id)
+        .bind(user_id)
+        .bind(Utc::now() + Duration::minutes(MFA_PENDING_LIFETIME_MINUTES))
+        .execute(db_pool)
+        .await?;
+        Ok(id)
+    }
+
+    /// Mark the pending login as completed. Returns `false` if it already
+    /// was, or has expired.
+    pub async fn complete(
+        db_pool: &sqlx::PgPool,
+        id: &Uuid,
+        user_id: &Uuid,
+    ) -> Result<bool, sqlx::Error> {
+        sqlx::query(
+            "DELETE FROM pending_mfa_logins WHERE id = $1 AND user_id = $2 AND expires_at > now();",
+        )
+        .bind(id)
+        .bind(user_id)
+        .execute(db_pool)
+        .await
+        .map(|done| done.rows_affected() > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::Duration;
+
+    use crate::{
+        auth::{IssuedRefreshToken, RefreshTokenError, RefreshTokenManager},
+        test_db,
+    };
+
+    /// A new session of `user_id`, along with its first refresh token
+    async fn login(
+        db_pool: &sqlx::PgPool,
+        refresh_manager: &RefreshTokenManager,
+        user_id: &Uuid,
+    ) -> (Session, IssuedRefreshToken) {
+        let session = Session::create(db_pool, user_id, &ClientInfo::default())
+            .await
+            .unwrap();
+        let issued = refresh_manager
+            .issue(db_pool, user_id, &session.id)
+            .await
+            .unwrap();
+        (session, issued)
+    }
+
+    async fn is_active(db_pool: &sqlx::PgPool, session: &Session) -> bool {
+        Session::touch(
+            db_pool,
+            &session.user_id,
+            &session.id,
+            &ClientInfo::default(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database"]
+    async fn test_revoke() {
+        let db_pool = test_db::pool().await;
+        let refresh_manager = RefreshTokenManager::new(Duration::days(1));
+        let user = test_db::create_user(&db_pool).await;
+        let (session, issued) = login(&db_pool, &refresh_manager, user.id()).await;
+        let (other, other_issued) = login(&db_pool, &refresh_manager, user.id()).await;
+        assert!(is_active(&db_pool, &session).await);
+
+        // Nobody else's to revoke
+        let stranger = test_db::create_user(&db_pool).await;
+        assert!(!Session::revoke(&db_pool, stranger.id(), &session.id)
+            .await
+            .unwrap());
+        assert!(is_active(&db_pool, &session).await);
+
+        // Access tokens of the session stop working, and so does its
+        // refresh token
+        assert!(Session::revoke(&db_pool, user.id(), &session.id)
+            .await
+            .unwrap());
+        assert!(!is_active(&db_pool, &session).await);
+        assert!(matches!(
+            refresh_manager.rotate(&db_pool, &issued.token).await,
+            Err(RefreshTokenError::InvalidToken)
+        ));
+        assert!(!Session::revoke(&db_pool, user.id(), &session.id)
+            .await
+            .unwrap());
+
+        assert!(is_active(&db_pool, &other).await);
+        refresh_manager
+            .rotate(&db_pool, &other_issued.token)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database"]
+    async fn test_revoke_all_but_current() {
+        let db_pool = test_db::pool().await;
+        let refresh_manager = RefreshTokenManager::new(Duration::days(1));
+        let user = test_db::create_user(&db_pool).await;
+        let (current, current_issued) = login(&db_pool, &refresh_manager, user.id()).await;
+        let (first, first_issued) = login(&db_pool, &refresh_manager, user.id()).await;
+        let (second, _) = login(&db_pool, &refresh_manager, user.id()).await;
+
+        assert_eq!(
+            Session::revoke_all(&db_pool, user.id(), Some(&current.id))
+                .await
+                .unwrap(),
+            2
+        );
+        assert!(!is_active(&db_pool, &first).await);
+        assert!(!is_active(&db_pool, &second).await);
+        assert!(matches!(
+            refresh_manager.rotate(&db_pool, &first_issued.token).await,
+            Err(RefreshTokenError::InvalidToken)
+        ));
+
+        // The current session and its refresh token family carry on
+        assert!(is_active(&db_pool, &current).await);
+        refresh_manager
+            .rotate(&db_pool, &current_issued.token)
+            .await
+            .unwrap();
+        assert_eq!(
+            Session::list_active(&db_pool, user.id())
+                .await
+                .unwrap()
+                .iter()
+                .map(|session| session.id)
+                .collect::<Vec<_>>(),
+            vec![current.id]
+        );
+    }
+
+    #[test]
+    fn test_request_id() {
+        assert_eq!(
+            request_id_or_new(Some("req-42.a:b_c".into())),
+            "req-42.a:b_c"
+        );
+        let too_long = "x".repeat(MAX_REQUEST_ID_LENGTH + 1);
+        for bogus in &["", "spaces are odd", "<script>", too_long.as_str()] {
+            let request_id = request_id_or_new(Some(bogus.to_string()));
+            assert!(request_id.parse::<Uuid>().is_ok(), "{:?}", bogus);
+        }
+        assert!(request_id_or_new(None).parse::<Uuid>().is_ok());
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let ip = |address: &str| address.parse::<IpAddr>().unwrap();
+        let proxies = TrustedProxies::new(vec![ip("10.0.0.1"), ip("10.0.0.2")]);
+
+        // Straight from the client, which can't vouch for anybody
+        assert_eq!(
+            proxies.client_ip(Some(ip("192.0.2.7")), Some("198.51.100.1")),
+            Some(ip("192.0.2.7"))
+        );
+        assert_eq!(proxies.client_ip(None, Some("198.51.100.1")), None);
+
+        // Through our proxies, behind whatever the client made up
+        assert_eq!(
+            proxies.client_ip(Some(ip("10.0.0.1")), Some("198.51.100.1, 192.0.2.7")),
+            Some(ip("192.0.2.7"))
+        );
+        assert_eq!(
+            proxies.client_ip(
+                Some(ip("10.0.0.1")),
+                Some("198.51.100.1, 192.0.2.7, 10.0.0.2")
+            ),
+            Some(ip("192.0.2.7"))
+        );
+        assert_eq!(
+            proxies.client_ip(Some(ip("10.0.0.1")), Some("2001:db8::1")),
+            Some(ip("2001:db8::1"))
+        );
+
+        // Our proxy forwarding for nobody in particular
+        assert_eq!(
+            proxies.client_ip(Some(ip("10.0.0.1")), None),
+            Some(ip("10.0.0.1"))
+        );
+        assert_eq!(
+            proxies.client_ip(Some(ip("10.0.0.1")), Some("unknown")),
+            Some(ip("10.0.0.1"))
+        );
+
+        // Without trusted proxies, nobody is believed
+        assert_eq!(
+            TrustedProxies::default().client_ip(Some(ip("10.0.0.1")), Some("192.0.2.7")),
+            Some(ip("10.0.0.1"))
+        );
+    }
+}
@@ -0,0 +1,342 @@
+//! Slows down password guessing and signup spam. Every attempt is counted
+//! per account and per client IP before it's made, and taken back if it
+//! succeeds; past a few free ones, each new failure blocks further attempts
+//! for twice as long as the previous one, and past `lockout_after` the
+//! account (or IP) is locked out for a while.
+//!
+//! Counters live in Postgres so every server instance sees the same ones.
+//! Locking an account out is also something an attacker can do to its owner
+//! on purpose, which is why lockouts are temporary.
+
+use std::{env, fmt};
+
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{types::Uuid, Row};
+
+use super::EmailAddress;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ThrottleError {
+    InvalidConfiguration(String),
+    DatabaseError(sqlx::Error),
+}
+
+impl fmt::Display for ThrottleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ThrottleError::InvalidConfiguration(reason) => {
+                write!(f, "Invalid throttling configuration: {}", reason)
+            }
+            ThrottleError::DatabaseError(error) => write!(f, "Database error: {:?}", error),
+        }
+    }
+}
+
+impl From<sqlx::Error> for ThrottleError {
+    fn from(error: sqlx::Error) -> Self {
+        ThrottleError::DatabaseError(error)
+    }
+}
+
+/// How many attempts are let through, and how long to wait after that
+#[derive(Debug, Clone)]
+pub struct ThrottlePolicy {
+    /// Failures allowed before any delay kicks in
+    free_attempts: u32,
+    /// Delay after the first failure past the free ones, doubled each time
+    base_delay: Duration,
+    max_delay: Duration,
+    /// Failures after which we stop backing off and lock out
+    lockout_after: u32,
+    lockout: Duration,
+    /// Failures older than this are forgotten
+    window: Duration,
+}
+
+impl ThrottlePolicy {
+    /// Failed logins of a single account
+    pub fn account() -> Self {
+        Self {
+            free_attempts: 3,
+            base_delay: Duration::seconds(1),
+            max_delay: Duration::minutes(5),
+            lockout_after: 10,
+            lockout: Duration::minutes(15),
+            window: Duration::hours(1),
+        }
+    }
+
+    /// Failed logins from a single IP, across accounts. More lenient than
+    /// per account, since many people can share an address.
+    pub fn client() -> Self {
+        Self {
+            free_attempts: 20,
+            base_delay: Duration::seconds(1),
+            max_delay: Duration::minutes(5),
+            lockout_after: 100,
+            lockout: Duration::hours(1),
+            window: Duration::hours(1),
+        }
+    }
+
+    /// Signups from a single IP, all of them counted
+    pub fn signup() -> Self {
+        Self {
+            free_attempts: 5,
+            base_delay: Duration::minutes(1),
+            max_delay: Duration::hours(1),
+            lockout_after: 20,
+            lockout: Duration::hours(24),
+            window: Duration::hours(24),
+        }
+    }
+
+    pub fn with_lockout(mut self, lockout_after: u32, lockout: Duration) -> Self {
+        self.lockout_after = lockout_after;
+        self.lockout = lockout;
+        self
+    }
+
+    /// How long to block attempts after the `failures`th one, if at all
+    fn delay_after(&self, failures: u32) -> Option<Duration> {
+        if failures >= self.lockout_after {
+            return Some(self.lockout);
+        }
+        let backoffs = failures.checked_sub(self.free_attempts + 1)?;
+        // Past 2^20 any sane max_delay is reached anyway
+        let delay = self.base_delay * 2i32.pow(backoffs.min(20));
+        Some(delay.min(self.max_delay))
+    }
+}
+
+/// What attempts are counted against
+#[derive(Debug, Clone)]
+pub enum ThrottleKey {
+    LoginAccount(EmailAddress),
+    /// Second factor of a login, which is just as guessable
+    MfaAccount(Uuid),
+    LoginClient(String),
+    SignupClient(String),
+}
+
+impl ThrottleKey {
+    fn as_key(&self) -> String {
+        match self {
+            ThrottleKey::LoginAccount(email_address) => format!("login:account:{}", email_address),
+            ThrottleKey::MfaAccount(user_id) => format!("mfa:account:{}", user_id),
+            ThrottleKey::LoginClient(ip_address) => format!("login:client:{}", ip_address),
+            ThrottleKey::SignupClient(ip_address) => format!("signup:client:{}", ip_address),
+        }
+    }
+}
+
+/// Attempts were blocked; the client should come back after `retry_after`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Throttled {
+    pub retry_after: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    account: ThrottlePolicy,
+    client: ThrottlePolicy,
+    signup: ThrottlePolicy,
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self {
+            account: ThrottlePolicy::account(),
+            client: ThrottlePolicy::client(),
+            signup: ThrottlePolicy::signup(),
+        }
+    }
+}
+
+impl Throttle {
+    /// Reads `WEFT_LOGIN_MAX_FAILURES` and `WEFT_LOGIN_LOCKOUT_MINUTES`, which
+    /// set when and for how long accounts get locked out; both optional
+    pub fn new_from_env() -> Result<Self, ThrottleError> {
+        let mut throttle = Self::default();
+        let parse = |key: &str| -> Result<Option<u32>, ThrottleError> {
+            match env::var(key) {
+                Ok(value) => value
+                    .parse()
+                    .map(Some)
+                    .map_err(|_| ThrottleError::InvalidConfiguration(key.into())),
+                Err(_) => Ok(None),
+            }
+        };
+
+        let lockout_after = parse("WEFT_LOGIN_MAX_FAILURES")?;
+        let lockout = parse("WEFT_LOGIN_LOCKOUT_MINUTES")?;
+        if lockout_after.is_some() || lockout.is_some() {
+            let default = ThrottlePolicy::account();
+            throttle.account = default.clone().with_lockout(
+                lockout_after.unwrap_or(default.lockout_after),
+                lockout
+                    .map(|minutes| Duration::minutes(minutes.into()))
+                    .unwrap_or(default.lockout),
+            );
+        }
+        Ok(throttle)
+    }
+
+    fn policy(&self, key: &ThrottleKey) -> &ThrottlePolicy {
+        match key {
+            ThrottleKey::LoginAccount(_) | ThrottleKey::MfaAccount(_) => &self.account,
+            ThrottleKey::LoginClient(_) => &self.client,
+            ThrottleKey::SignupClient(_) => &self.signup,
+        }
+    }
+
+    /// Count an attempt against each of `keys`, unless attempts are blocked
+    /// for any of them, in which case nothing is counted and how long to
+    /// wait is returned instead. Attempts are counted before they're made,
+    /// as if they were going to fail, so concurrent guesses can't all get
+    /// in before the first failure is recorded: `reset` or `release` them
+    /// once they turn out fine.
+    pub async fn reserve(
+        &self,
+        db_pool: &sqlx::PgPool,
+        keys: &[ThrottleKey],
+    ) -> Result<Option<Throttled>, ThrottleError> {
+        // Always lock the rows in the same order, so concurrent
+        // reservations of overlapping keys can't deadlock
+        let mut keys = keys.iter().collect::<Vec<_>>();
+        keys.sort_by_key(|key| key.as_key());
+
+        let mut transaction = db_pool.begin().await?;
+        let mut blocks = vec![];
+        for key in keys {
+            let policy = self.policy(key);
+            let row = sqlx::query(
+                r#"
+INSERT INTO auth_throttles (key, failures, last_failure_at)
+VALUES ($1, 1, now())
+ON CONFLICT (key) DO UPDATE SET
+    failures = CASE
+        WHEN auth_throttles.blocked_until > now() THEN auth_throttles.failures
+        WHEN auth_throttles.last_failure_at < now() - $2 * interval '1 second' THEN 1
+        ELSE auth_throttles.failures + 1
+    END,
+    last_failure_at = CASE
+        WHEN auth_throttles.blocked_until > now() THEN auth_throttles.last_failure_at
+        ELSE now()
+    END
+RETURNING failures, CASE WHEN blocked_until > now() THEN blocked_until END;"#,
+            )
+            .bind(key.as_key())
+            .bind(policy.window.num_seconds() as f64)
+            .fetch_one(&mut transaction)
+            .await?;
+            let failures: i32 = row.try_get(0)?;
+            let blocked_until: Option<DateTime<Utc>> = row.try_get(1)?;
+
+            if let Some(blocked_until) = blocked_until {
+                // Rolling back takes back what was counted against the
+                // other keys too
+                transaction.rollback().await?;
+                return Ok(Some(Throttled {
+                    retry_after: blocked_until - Utc::now(),
+                }));
+            }
+            if let Some(delay) = policy.delay_after(failures as u32) {
+                blocks.push((key.as_key(), Utc::now() + delay));
+            }
+        }
+
+        for (key, blocked_until) in blocks {
+            sqlx::query("UPDATE auth_throttles SET blocked_until = $2 WHERE key = $1;")
+                .bind(key)
+                .bind(blocked_until)
+                .execute(&mut transaction)
+                .await?;
+        }
+        transaction.commit().await?;
+        Ok(None)
+    }
+
+    /// Take back an attempt reserved against `key` that didn't fail, without
+    /// forgetting the failures before it. Whatever it blocked stays
+    /// blocked, since the failures before it would have.
+    pub async fn release(
+        &self,
+        db_pool: &sqlx::PgPool,
+        key: &ThrottleKey,
+    ) -> Result<(), ThrottleError> {
+        sqlx::query(
+            "UPDATE auth_throttles SET failures = greatest(failures - 1, 0) WHERE key = $1;",
+        )
+        .bind(key.as_key())
+        .execute(db_pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Forget the failures counted against `key`, after a successful login
+    pub async fn reset(
+        &self,
+        db_pool: &sqlx::PgPool,
+        key: &ThrottleKey,
+    ) -> Result<(), ThrottleError> {
+        sqlx::query("DELETE FROM auth_throttles WHERE key = $1;")
+            .bind(key.as_key())
+            .execute(db_pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Forget every attempt counted against `keys`, e.g. because the account
+    /// they're about is gone
+    pub async fn forget_within(
+        transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        keys: &[ThrottleKey],
+    ) -> Result<(), sqlx::Error> {
+        let keys = keys.iter().map(ThrottleKey::as_key).collect::<Vec<_>>();
+        sqlx::query("DELETE FROM auth_throttles WHERE key = ANY($1);")
+            .bind(&keys)
+            .execute(&mut *transaction)
+            .await
+            .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_then_locks_out() {
+        let policy = ThrottlePolicy::account();
+        assert_eq!(policy.delay_after(1), None);
+        assert_eq!(policy.delay_after(3), None);
+        assert_eq!(policy.delay_after(4), Some(Duration::seconds(1)));
+        assert_eq!(policy.delay_after(5), Some(Duration::seconds(2)));
+        assert_eq!(policy.delay_after(7), Some(Duration::seconds(8)));
+        assert_eq!(policy.delay_after(9), Some(Duration::seconds(32)));
+        assert_eq!(policy.delay_after(10), Some(Duration::minutes(15)));
+        assert_eq!(policy.delay_after(1000), Some(Duration::minutes(15)));
+    }
+
+    #[test]
+    fn test_backoff_is_capped() {
+        let policy = ThrottlePolicy::client();
+        assert_eq!(policy.delay_after(30), Some(Duration::minutes(5)));
+        assert_eq!(policy.delay_after(99), Some(Duration::minutes(5)));
+        assert_eq!(policy.delay_after(100), Some(Duration::hours(1)));
+    }
+
+    #[test]
+    fn test_keys_dont_collide() {
+        let email_address: EmailAddress = "some@email.address".parse().unwrap();
+        assert_eq!(
+            ThrottleKey::LoginAccount(email_address).as_key(),
+            "login:account:some@email.address"
+        );
+        assert_ne!(
+            ThrottleKey::LoginClient("192.0.2.1".into()).as_key(),
+            ThrottleKey::SignupClient("192.0.2.1".into()).as_key()
+        );
+    }
+}
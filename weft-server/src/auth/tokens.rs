@@ -1,59 +1,356 @@
-use std::env;
+use std::{env, str::FromStr};
 
-pub use biscuit::errors::Error as JwtError;
+pub use biscuit::errors::{Error as JwtError, ValidationError};
 use biscuit::{
-    jwa::SignatureAlgorithm,
-    jws::{RegisteredHeader, Secret},
-    ClaimsSet, Empty, JWT,
+    ClaimPresenceOptions, ClaimsSet, Presence, RegisteredClaims, SingleOrMultiple, StringOrUri,
+    Validation, ValidationOptions,
 };
-use serde::{de::DeserializeOwned, Serialize};
+use chrono::{DateTime, Duration, Utc};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::types::Uuid;
 
-const SIGNATURE_ALGORITHM: SignatureAlgorithm = SignatureAlgorithm::HS256;
+use super::signing::SigningKey;
+
+const DEFAULT_ISSUER: &str = "weft";
+const DEFAULT_AUDIENCE: &str = "weft";
+const DEFAULT_LIFETIME_MINUTES: i64 = 15;
+pub const MFA_PENDING_LIFETIME_MINUTES: i64 = 5;
+
+/// Private claims carried by the `Auth-Token` JWT
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct UserClaims {
+    pub user_id: Uuid,
+}
+
+/// JOSE header of the tokens we mint
+#[derive(Debug, Deserialize, Serialize)]
+struct Header {
+    alg: String,
+    typ: String,
+    kid: String,
+}
 
 pub struct TokenManager {
-    secret: Secret,
+    key: SigningKey,
+    /// Keys in use before `key`, for tokens signed before the rotation
+    previous_keys: Vec<SigningKey>,
+    /// When `key` took over. Once every token signed before then has
+    /// expired, previous keys are neither accepted nor published any more.
+    rotated_at: Option<DateTime<Utc>>,
+    issuer: String,
+    audience: String,
+    lifetime: Duration,
+}
+
+fn string_or_uri(value: &str) -> Result<StringOrUri, JwtError> {
+    StringOrUri::from_str(value).map_err(|error| JwtError::GenericError(format!("{:?}", error)))
+}
+
+fn generic_error<E: std::fmt::Debug>(error: E) -> JwtError {
+    JwtError::GenericError(format!("{:?}", error))
+}
+
+fn base64url_json<T: Serialize>(value: &T) -> Result<String, JwtError> {
+    Ok(base64::encode_config(
+        serde_json::to_vec(value).map_err(generic_error)?,
+        base64::URL_SAFE_NO_PAD,
+    ))
+}
+
+fn decode_base64url(value: &str) -> Result<Vec<u8>, JwtError> {
+    base64::decode_config(value, base64::URL_SAFE_NO_PAD).map_err(generic_error)
 }
 
 impl TokenManager {
+    /// A manager signing with HS256 and `secret`
     pub fn new<S: AsRef<str>>(secret: S) -> Self {
+        Self::new_with_key(SigningKey::hmac("hs256", secret.as_ref()))
+    }
+
+    pub fn new_with_key(key: SigningKey) -> Self {
         Self {
-            secret: Secret::Bytes(secret.as_ref().to_string().into_bytes()),
+            key,
+            previous_keys: vec![],
+            rotated_at: None,
+            issuer: DEFAULT_ISSUER.into(),
+            audience: DEFAULT_AUDIENCE.into(),
+            lifetime: Duration::minutes(DEFAULT_LIFETIME_MINUTES),
         }
     }
 
+    /// Reads the signing key through `SigningKey::new_from_env`, previous
+    /// keys through `SigningKey::previous_from_env` along with the time of
+    /// the rotation (RFC 3339) from `WEFT_JWT_ROTATED_AT`, and optionally the
+    /// issuer, audience and token lifetime (in minutes) from
+    /// `WEFT_TOKEN_ISSUER`, `WEFT_TOKEN_AUDIENCE` and `WEFT_TOKEN_LIFETIME`.
     pub fn new_from_env_key() -> Result<Self, JwtError> {
-        env::var("WEFT_SECRET_KEY")
-            .map_err(|error| JwtError::GenericError(format!("{:?}", error)))
-            .map(Self::new)
+        let mut manager = SigningKey::new_from_env()
+            .map_err(generic_error)
+            .map(Self::new_with_key)?;
+
+        for key in SigningKey::previous_from_env().map_err(generic_error)? {
+            manager = manager.with_previous_key(key);
+        }
+
+        if let Ok(rotated_at) = env::var("WEFT_JWT_ROTATED_AT") {
+            let rotated_at = DateTime::parse_from_rfc3339(&rotated_at).map_err(generic_error)?;
+            manager = manager.with_rotated_at(rotated_at.with_timezone(&Utc));
+        }
+
+        if let Ok(issuer) = env::var("WEFT_TOKEN_ISSUER") {
+            manager = manager.with_issuer(issuer);
+        }
+
+        if let Ok(audience) = env::var("WEFT_TOKEN_AUDIENCE") {
+            manager = manager.with_audience(audience);
+        }
+
+        if let Ok(lifetime) = env::var("WEFT_TOKEN_LIFETIME") {
+            let minutes = lifetime
+                .parse()
+                .map_err(|error| JwtError::GenericError(format!("{:?}", error)))?;
+            manager = manager.with_lifetime(Duration::minutes(minutes));
+        }
+
+        Ok(manager)
+    }
+
+    pub fn with_issuer<S: Into<String>>(mut self, issuer: S) -> Self {
+        self.issuer = issuer.into();
+        self
+    }
+
+    pub fn with_audience<S: Into<String>>(mut self, audience: S) -> Self {
+        self.audience = audience.into();
+        self
+    }
+
+    pub fn with_lifetime(mut self, lifetime: Duration) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+
+    /// Keep verifying tokens signed with `key`, which was in use before the
+    /// current one
+    pub fn with_previous_key(mut self, key: SigningKey) -> Self {
+        self.previous_keys.push(key);
+        self
+    }
+
+    pub fn with_rotated_at(mut self, rotated_at: DateTime<Utc>) -> Self {
+        self.rotated_at = Some(rotated_at);
+        self
+    }
+
+    /// The current key, followed by the previous ones as long as tokens
+    /// they signed may still be valid. Without a rotation time, previous
+    /// keys are kept until they're taken out of the configuration.
+    fn verification_keys(&self) -> impl Iterator<Item = &SigningKey> {
+        let longest_lifetime = std::cmp::max(
+            self.lifetime,
+            Duration::minutes(MFA_PENDING_LIFETIME_MINUTES),
+        );
+        let previous_keys_valid = self
+            .rotated_at
+            .map(|rotated_at| Utc::now() < rotated_at + longest_lifetime)
+            .unwrap_or(true);
+        std::iter::once(&self.key).chain(
+            self.previous_keys
+                .iter()
+                .filter(move |_| previous_keys_valid),
+        )
+    }
+
+    /// The JWK Set published at `/.well-known/jwks.json`: the public keys
+    /// tokens that are still valid may have been signed with
+    pub fn jwks(&self) -> Value {
+        json!({
+            "keys": self
+                .verification_keys()
+                .filter_map(SigningKey::jwk)
+                .collect::<Vec<_>>()
+        })
+    }
+
+    /// "MFA pending" tokens get their own audience, so they can never pass
+    /// for an access token
+    fn mfa_pending_audience(&self) -> String {
+        format!("{}:mfa-pending", self.audience)
+    }
+
+    fn registered_claims(
+        &self,
+        audience: &str,
+        lifetime: Duration,
+        token_id: Option<String>,
+    ) -> Result<RegisteredClaims, JwtError> {
+        let now = Utc::now();
+        Ok(RegisteredClaims {
+            issuer: Some(string_or_uri(&self.issuer)?),
+            audience: Some(SingleOrMultiple::Single(string_or_uri(audience)?)),
+            expiry: Some(From::from(now + lifetime)),
+            not_before: Some(From::from(now)),
+            issued_at: Some(From::from(now)),
+            id: token_id,
+            ..Default::default()
+        })
+    }
+
+    fn validation_options(&self, audience: &str) -> Result<ValidationOptions, JwtError> {
+        Ok(ValidationOptions {
+            claim_presence_options: ClaimPresenceOptions {
+                issued_at: Presence::Required,
+                not_before: Presence::Required,
+                expiry: Presence::Required,
+                issuer: Presence::Required,
+                audience: Presence::Required,
+                ..Default::default()
+            },
+            issued_at: Validation::Ignored,
+            not_before: Validation::Validate(()),
+            expiry: Validation::Validate(()),
+            issuer: Validation::Validate(string_or_uri(&self.issuer)?),
+            audience: Validation::Validate(string_or_uri(audience)?),
+            ..Default::default()
+        })
+    }
+
+    fn encode_token<T>(
+        &self,
+        claims: T,
+        audience: &str,
+        lifetime: Duration,
+        token_id: Option<String>,
+    ) -> Result<String, JwtError>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        let header = Header {
+            alg: self.key.algorithm().into(),
+            typ: "JWT".into(),
+            kid: self.key.key_id().into(),
+        };
+        let claims_set = ClaimsSet {
+            private: claims,
+            registered: self.registered_claims(audience, lifetime, token_id)?,
+        };
+
+        let signing_input = format!(
+            "{}.{}",
+            base64url_json(&header)?,
+            base64url_json(&claims_set)?
+        );
+        let signature = self
+            .key
+            .sign(signing_input.as_bytes())
+            .map_err(generic_error)?;
+        Ok(format!(
+            "{}.{}",
+            signing_input,
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        ))
     }
 
     pub fn create_token<T>(&self, claims: T) -> Result<String, JwtError>
     where
         T: Serialize + DeserializeOwned,
     {
-        JWT::new_decoded(
-            From::from(RegisteredHeader {
-                algorithm: SIGNATURE_ALGORITHM,
-                ..Default::default()
-            }),
-            ClaimsSet {
-                private: claims,
-                registered: Default::default(),
-            },
+        self.encode_token(claims, &self.audience, self.lifetime, None)
+    }
+
+    /// Same as `create_token`, also setting the `jti` registered claim
+    pub fn create_token_with_id<T>(&self, claims: T, token_id: &str) -> Result<String, JwtError>
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        self.encode_token(claims, &self.audience, self.lifetime, Some(token_id.into()))
+    }
+
+    /// A short lived token proving `claims.user_id` got their password right,
+    /// but still has to pass their second factor. `token_id` names the
+    /// pending login, so it can only be completed once.
+    pub fn create_mfa_pending_token(
+        &self,
+        claims: UserClaims,
+        token_id: &str,
+    ) -> Result<String, JwtError> {
+        self.encode_token(
+            claims,
+            &self.mfa_pending_audience(),
+            Duration::minutes(MFA_PENDING_LIFETIME_MINUTES),
+            Some(token_id.into()),
         )
-        .into_encoded(&self.secret)?
-        .encoded()
-        .map(ToString::to_string)
     }
 
+    /// The claims of an "MFA pending" token, along with its `jti`
+    pub fn verify_mfa_pending_token<S: AsRef<str>>(
+        &self,
+        token: S,
+    ) -> Result<(UserClaims, String), JwtError> {
+        let claims_set = self.decode_token(token, &self.mfa_pending_audience())?;
+        match claims_set.registered.id {
+            Some(token_id) => Ok((claims_set.private, token_id)),
+            None => Err(generic_error("Missing token id")),
+        }
+    }
+
+    /// Checks the token's signature and its registered claims (expiry,
+    /// issue/not-before times, issuer and audience). The key is picked by
+    /// the token's key id among our own, and has to be of the algorithm the
+    /// token claims, so a token can't pick how it gets verified.
+    fn decode_token<S, T>(&self, token: S, audience: &str) -> Result<ClaimsSet<T>, JwtError>
+    where
+        S: AsRef<str>,
+        T: Serialize + DeserializeOwned,
+    {
+        let token = token.as_ref();
+        let (signing_input, signature) = match token.rfind('.') {
+            Some(dot) => (&token[..dot], &token[dot + 1..]),
+            None => return Err(generic_error("Malformed token")),
+        };
+        let (header, payload) = match signing_input.find('.') {
+            Some(dot) => (&signing_input[..dot], &signing_input[dot + 1..]),
+            None => return Err(generic_error("Malformed token")),
+        };
+
+        let header: Header =
+            serde_json::from_slice(&decode_base64url(header)?).map_err(generic_error)?;
+        let key = self
+            .verification_keys()
+            .find(|key| key.key_id() == header.kid && key.algorithm() == header.alg);
+        let signature = decode_base64url(signature)?;
+        if !key
+            .map(|key| key.verify(signing_input.as_bytes(), &signature))
+            .unwrap_or(false)
+        {
+            return Err(JwtError::ValidationError(ValidationError::InvalidSignature));
+        }
+
+        let claims_set: ClaimsSet<T> =
+            serde_json::from_slice(&decode_base64url(payload)?).map_err(generic_error)?;
+        claims_set.validate(self.validation_options(audience)?)?;
+        Ok(claims_set)
+    }
+
+    /// Verify `token` and hand back its private claims
     pub fn verify_token<S, T>(&self, token: S) -> Result<T, JwtError>
     where
         S: AsRef<str>,
         T: Serialize + DeserializeOwned,
     {
-        JWT::<T, Empty>::new_encoded(token.as_ref())
-            .into_decoded(&self.secret, SIGNATURE_ALGORITHM)
-            .map(|decoded| decoded.unwrap_decoded().1.private)
+        self.decode_token(token, &self.audience)
+            .map(|claims_set| claims_set.private)
+    }
+
+    /// Verify `token` and hand back its private claims along with its `jti`
+    pub fn verify_token_with_id<S, T>(&self, token: S) -> Result<(T, Option<String>), JwtError>
+    where
+        S: AsRef<str>,
+        T: Serialize + DeserializeOwned,
+    {
+        self.decode_token(token, &self.audience)
+            .map(|claims_set| (claims_set.private, claims_set.registered.id))
     }
 }
 
@@ -78,4 +375,167 @@ mod tests {
         let decoded_profile = manager.verify_token(encoded_token).unwrap();
         assert_eq!(profile, decoded_profile);
     }
+
+    #[test]
+    fn test_token_id_roundtrip() {
+        let manager = TokenManager::new(TEST_SECRET);
+        let claims = UserClaims {
+            user_id: Default::default(),
+        };
+        let encoded_token = manager
+            .create_token_with_id(claims.clone(), "some-token-id")
+            .unwrap();
+
+        let (decoded_claims, token_id) = manager.verify_token_with_id(encoded_token).unwrap();
+        assert_eq!(claims, decoded_claims);
+        assert_eq!(token_id.as_deref(), Some("some-token-id"));
+    }
+
+    #[test]
+    fn test_expired_token() {
+        let manager = TokenManager::new(TEST_SECRET).with_lifetime(Duration::seconds(-10));
+        let encoded_token = manager
+            .create_token(UserClaims {
+                user_id: Default::default(),
+            })
+            .unwrap();
+
+        match manager.verify_token::<_, UserClaims>(encoded_token) {
+            Err(JwtError::ValidationError(ValidationError::Expired(_))) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_tampered_token() {
+        let manager = TokenManager::new(TEST_SECRET);
+        let other_manager = TokenManager::new(TEST_SECRET.chars().rev().collect::<String>());
+        let encoded_token = other_manager
+            .create_token(UserClaims {
+                user_id: Default::default(),
+            })
+            .unwrap();
+
+        match manager.verify_token::<_, UserClaims>(encoded_token) {
+            Err(JwtError::ValidationError(ValidationError::InvalidSignature)) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_asymmetric_roundtrip() {
+        use super::super::signing::tests::{test_ed25519_key, test_rsa_key};
+
+        for key in vec![test_rsa_key(), test_ed25519_key()] {
+            let manager = TokenManager::new_with_key(key);
+            let claims = UserClaims {
+                user_id: Default::default(),
+            };
+            let encoded_token = manager.create_token(claims.clone()).unwrap();
+            assert_eq!(
+                manager
+                    .verify_token::<_, UserClaims>(encoded_token)
+                    .unwrap(),
+                claims
+            );
+        }
+    }
+
+    #[test]
+    fn test_other_key_id() {
+        let manager = TokenManager::new_with_key(SigningKey::hmac("2021-01", TEST_SECRET));
+        let other_manager = TokenManager::new_with_key(SigningKey::hmac("2020-12", TEST_SECRET));
+        let encoded_token = other_manager
+            .create_token(UserClaims {
+                user_id: Default::default(),
+            })
+            .unwrap();
+
+        match manager.verify_token::<_, UserClaims>(encoded_token) {
+            Err(JwtError::ValidationError(ValidationError::InvalidSignature)) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_previous_key_id() {
+        let previous_manager = TokenManager::new_with_key(SigningKey::hmac("2020-12", TEST_SECRET));
+        let encoded_token = previous_manager
+            .create_token(UserClaims {
+                user_id: Default::default(),
+            })
+            .unwrap();
+
+        let manager = TokenManager::new_with_key(SigningKey::hmac("2021-01", "some-new-secret"))
+            .with_previous_key(SigningKey::hmac("2020-12", TEST_SECRET));
+        assert!(manager
+            .verify_token::<_, UserClaims>(&encoded_token)
+            .is_ok());
+
+        // Past the lifetime of any token the previous key could have signed
+        let manager = manager.with_rotated_at(Utc::now() - Duration::hours(1));
+        match manager.verify_token::<_, UserClaims>(&encoded_token) {
+            Err(JwtError::ValidationError(ValidationError::InvalidSignature)) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_jwks_publishes_previous_keys() {
+        use super::super::signing::tests::{test_ed25519_key, test_rsa_key};
+
+        let manager = TokenManager::new_with_key(test_ed25519_key())
+            .with_previous_key(test_rsa_key())
+            .with_previous_key(SigningKey::hmac("hs256", TEST_SECRET))
+            .with_rotated_at(Utc::now());
+        let algorithms = manager.jwks()["keys"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|jwk| jwk["alg"].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(algorithms, vec!["EdDSA", "RS256"]);
+
+        let manager = manager.with_rotated_at(Utc::now() - Duration::hours(1));
+        assert_eq!(manager.jwks()["keys"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_mfa_pending_tokens_are_not_access_tokens() {
+        let manager = TokenManager::new(TEST_SECRET);
+        let claims = UserClaims {
+            user_id: Default::default(),
+        };
+
+        let mfa_token = manager
+            .create_mfa_pending_token(claims.clone(), "some-login-id")
+            .unwrap();
+        assert_eq!(
+            manager.verify_mfa_pending_token(&mfa_token).unwrap(),
+            (claims.clone(), "some-login-id".to_string())
+        );
+        match manager.verify_token::<_, UserClaims>(&mfa_token) {
+            Err(JwtError::ValidationError(ValidationError::InvalidAudience(_))) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        let access_token = manager.create_token(claims).unwrap();
+        assert!(manager.verify_mfa_pending_token(access_token).is_err());
+    }
+
+    #[test]
+    fn test_wrong_audience() {
+        let manager = TokenManager::new(TEST_SECRET).with_audience("weft-api");
+        let other_manager = TokenManager::new(TEST_SECRET).with_audience("somebody-else");
+        let encoded_token = other_manager
+            .create_token(UserClaims {
+                user_id: Default::default(),
+            })
+            .unwrap();
+
+        match manager.verify_token::<_, UserClaims>(encoded_token) {
+            Err(JwtError::ValidationError(ValidationError::InvalidAudience(_))) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
 }
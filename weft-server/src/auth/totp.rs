@@ -0,0 +1,590 @@
+use std::{env, fmt};
+
+use chrono::Utc;
+use ring::{
+    aead, constant_time, hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Uuid, Row};
+use url::Url;
+
+use super::{opaque::hash_token, EmailAddress};
+
+const SECRET_BYTES: usize = 20;
+const DIGITS: u32 = 6;
+const PERIOD_SECONDS: i64 = 30;
+/// Accept codes one period early or late, for clock drift
+const ALLOWED_DRIFT_STEPS: i64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_BYTES: usize = 5;
+const DEFAULT_ISSUER: &str = "Weft";
+/// Used to derive the key TOTP secrets are encrypted with from the shared
+/// `WEFT_SECRET_KEY`
+const SECRET_KEY_CONTEXT: &[u8] = b"weft-totp-secret-key";
+/// Marks stored secrets that are encrypted; older ones are plain base32
+const ENCRYPTED_PREFIX: &str = "v1:";
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+#[derive(Debug, thiserror::Error)]
+pub enum TotpError {
+    InvalidCode,
+    NotEnrolled,
+    AlreadyEnrolled,
+    InvalidConfiguration(String),
+    RandomError,
+    UnreadableSecret,
+    DatabaseError(sqlx::Error),
+}
+
+impl fmt::Display for TotpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                TotpError::InvalidCode => "Invalid code".into(),
+                TotpError::NotEnrolled => "Two-factor authentication is not set up".into(),
+                TotpError::AlreadyEnrolled => "Two-factor authentication is already set up".into(),
+                TotpError::InvalidConfiguration(reason) => format!(
+                    "Invalid two-factor authentication configuration: {}",
+                    reason
+                ),
+                TotpError::RandomError => "Could not generate secret".into(),
+                TotpError::UnreadableSecret => "Could not decrypt secret".into(),
+                TotpError::DatabaseError(error) => format!("Database error: {:?}", error),
+            }
+        )
+    }
+}
+
+impl From<sqlx::Error> for TotpError {
+    fn from(error: sqlx::Error) -> Self {
+        TotpError::DatabaseError(error)
+    }
+}
+
+/// Body of any request carrying a TOTP or recovery code
+#[derive(Debug, Deserialize)]
+pub struct MfaCode {
+    pub code: String,
+}
+
+/// What an authenticator app needs to start generating codes. The URI is
+/// also what goes into the QR code.
+#[derive(Debug, Serialize)]
+pub struct TotpEnrollment {
+    pub secret: String,
+    pub provisioning_uri: String,
+}
+
+/// Unpadded RFC 4648 base32, which is what authenticator apps expect
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut output = String::new();
+    let (mut buffer, mut bits) = (0u32, 0u32);
+    for byte in bytes {
+        buffer = (buffer << 8) | u32::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            output.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        output.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    output
+}
+
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut output = vec![];
+    let (mut buffer, mut bits) = (0u32, 0u32);
+    for c in encoded.bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|&letter| letter == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((buffer >> bits) as u8);
+        }
+    }
+    Some(output)
+}
+
+fn random_bytes(length: usize) -> Result<Vec<u8>, TotpError> {
+    let mut bytes = vec![0u8; length];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| TotpError::RandomError)?;
+    Ok(bytes)
+}
+
+/// Codes are typed by people, so ignore case, spaces and dashes
+fn normalize_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .collect::<String>()
+        .to_lowercase()
+}
+
+/// An RFC 6238 generator: HMAC-SHA1, 6 digits, 30 second periods, as
+/// supported by every authenticator app
+pub struct Totp {
+    secret: Vec<u8>,
+}
+
+impl Totp {
+    pub fn generate() -> Result<Self, TotpError> {
+        Ok(Self {
+            secret: random_bytes(SECRET_BYTES)?,
+        })
+    }
+
+    pub fn from_base32(secret: &str) -> Option<Self> {
+        base32_decode(secret).map(|secret| Self { secret })
+    }
+
+    pub fn secret_base32(&self) -> String {
+        base32_encode(&self.secret)
+    }
+
+    fn current_step() -> i64 {
+        Utc::now().timestamp() / PERIOD_SECONDS
+    }
+
+    fn code_at(&self, step: i64) -> String {
+        let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, &self.secret);
+        let digest = hmac::sign(&key, &step.to_be_bytes());
+        let digest = digest.as_ref();
+
+        // RFC 4226 5.3 dynamic truncation
+        let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+        let binary = (u32::from(digest[offset] & 0x7f) << 24)
+            | (u32::from(digest[offset + 1]) << 16)
+            | (u32::from(digest[offset + 2]) << 8)
+            | u32::from(digest[offset + 3]);
+        format!(
+            "{:0width$}",
+            binary % 10u32.pow(DIGITS),
+            width = DIGITS as usize
+        )
+    }
+
+    /// The time step `code` is valid for, if any, around `step`
+    fn verify_at(&self, code: &str, step: i64) -> Option<i64> {
+        let code = normalize_code(code);
+        (step - ALLOWED_DRIFT_STEPS..=step + ALLOWED_DRIFT_STEPS).find(|&candidate| {
+            constant_time::verify_slices_are_equal(
+                self.code_at(candidate).as_bytes(),
+                code.as_bytes(),
+            )
+            .is_ok()
+        })
+    }
+
+    /// `otpauth://` URI for authenticator apps, labelled with `account`
+    pub fn provisioning_uri(&self, issuer: &str, account: &str) -> String {
+        let mut uri = Url::parse("otpauth://totp/").expect("Static URL must parse");
+        uri.set_path(&format!("{}:{}", issuer, account));
+        uri.query_pairs_mut()
+            .append_pair("secret", &self.secret_base32())
+            .append_pair("issuer", issuer)
+            .append_pair("algorithm", "SHA1")
+            .append_pair("digits", &DIGITS.to_string())
+            .append_pair("period", &PERIOD_SECONDS.to_string());
+        uri.to_string()
+    }
+}
+
+/// Encrypts TOTP secrets before they're stored, with AES-256-GCM and a key
+/// derived from the server secret. Each one is bound to its user, so it
+/// can't be moved over to somebody else's account either.
+pub struct TotpCipher {
+    key: aead::LessSafeKey,
+}
+
+impl TotpCipher {
+    pub fn new<S: AsRef<[u8]>>(server_secret: S) -> Self {
+        let derived = hmac::sign(
+            &hmac::Key::new(hmac::HMAC_SHA256, server_secret.as_ref()),
+            SECRET_KEY_CONTEXT,
+        );
+        let key = aead::UnboundKey::new(&aead::AES_256_GCM, derived.as_ref())
+            .expect("HMAC-SHA256 output must make an AES-256 key");
+        Self {
+            key: aead::LessSafeKey::new(key),
+        }
+    }
+
+    /// Derives the key from `WEFT_SECRET_KEY`
+    pub fn new_from_env() -> Result<Self, TotpError> {
+        env::var("WEFT_SECRET_KEY")
+            .map(Self::new)
+            .map_err(|_| TotpError::InvalidConfiguration("WEFT_SECRET_KEY is not set".into()))
+    }
+
+    fn seal(&self, user_id: &Uuid, totp: &Totp) -> Result<String, TotpError> {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| TotpError::RandomError)?;
+
+        let mut sealed = totp.secret.clone();
+        self.key
+            .seal_in_place_append_tag(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::from(user_id.as_bytes()),
+                &mut sealed,
+            )
+            .map_err(|_| TotpError::RandomError)?;
+
+        let mut stored = nonce.to_vec();
+        stored.extend(sealed);
+        Ok(format!("{}{}", ENCRYPTED_PREFIX, base64::encode(stored)))
+    }
+
+    fn open(&self, user_id: &Uuid, stored: &str) -> Result<Totp, TotpError> {
+        if !stored.starts_with(ENCRYPTED_PREFIX) {
+            // Stored before secrets were encrypted, and not re-encrypted yet
+            return Totp::from_base32(stored).ok_or(TotpError::UnreadableSecret);
+        }
+
+        let sealed = base64::decode(&stored[ENCRYPTED_PREFIX.len()..])
+            .map_err(|_| TotpError::UnreadableSecret)?;
+        if sealed.len() < aead::NONCE_LEN {
+            return Err(TotpError::UnreadableSecret);
+        }
+        let (nonce, ciphertext) = sealed.split_at(aead::NONCE_LEN);
+        let nonce = aead::Nonce::try_assume_unique_for_key(nonce)
+            .map_err(|_| TotpError::UnreadableSecret)?;
+        let mut ciphertext = ciphertext.to_vec();
+        let secret = self
+            .key
+            .open_in_place(nonce, aead::Aad::from(user_id.as_bytes()), &mut ciphertext)
+            .map_err(|_| TotpError::UnreadableSecret)?;
+        Ok(Totp {
+            secret: secret.to_vec(),
+        })
+    }
+}
+
+fn generate_recovery_code() -> Result<String, TotpError> {
+    let code = base32_encode(&random_bytes(RECOVERY_CODE_BYTES)?).to_lowercase();
+    Ok(format!("{}-{}", &code[..4], &code[4..]))
+}
+
+/// A user's TOTP second factor, and their recovery codes
+pub struct TotpCredential;
+
+impl TotpCredential {
+    /// Start over with a new, unconfirmed secret. Fails if the user already
+    /// has a confirmed one.
+    pub async fn begin_enrollment(
+        db_pool: &sqlx::PgPool,
+        cipher: &TotpCipher,
+        user_id: &Uuid,
+        email_address: &EmailAddress,
+    ) -> Result<TotpEnrollment, TotpError> {
+        let totp = Totp::generate()?;
+
+        let replaced = sqlx::query(
+            r#"
+INSERT INTO totp_credentials (user_id, secret)
+VALUES ($1, $2)
+ON CONFLICT (user_id) DO UPDATE SET secret = $2, created_at = now()
+WHERE totp_credentials.confirmed_at IS NULL;"#,
+        )
+        .bind(user_id)
+        .bind(cipher.seal(user_id, &totp)?)
+        .execute(db_pool)
+        .await?
+        .rows_affected();
+
+        if replaced == 0 {
+            return Err(TotpError::AlreadyEnrolled);
+        }
+
+        let issuer = env::var("WEFT_TOTP_ISSUER").unwrap_or_else(|_| DEFAULT_ISSUER.into());
+        Ok(TotpEnrollment {
+            provisioning_uri: totp.provisioning_uri(&issuer, email_address.to_str()),
+            secret: totp.secret_base32(),
+        })
+    }
+
+    /// Turn two-factor authentication on, once the user proves their app
+    /// works. Returns the recovery codes, the only time they're ever shown.
+    pub async fn confirm(
+        db_pool: &sqlx::PgPool,
+        cipher: &TotpCipher,
+        user_id: &Uuid,
+        code: &str,
+    ) -> Result<Vec<String>, TotpError> {
+        let mut transaction = db_pool.begin().await?;
+
+        let row = sqlx::query(
+            "SELECT secret, confirmed_at IS NOT NULL FROM totp_credentials WHERE user_id = $1 FOR UPDATE;",
+        )
+        .bind(user_id)
+        .fetch_optional(&mut transaction)
+        .await?
+        .ok_or(TotpError::NotEnrolled)?;
+
+        if row.get::<bool, _>(1) {
+            return Err(TotpError::AlreadyEnrolled);
+        }
+        let step = cipher
+            .open(user_id, row.get(0))?
+            .verify_at(code, Totp::current_step())
+            .ok_or(TotpError::InvalidCode)?;
+
+        sqlx::query(
+            "UPDATE totp_credentials SET confirmed_at = now(), last_used_step = $2 WHERE user_id = $1;",
+        )
+        .bind(user_id)
+        .bind(step)
+        .execute(&mut transaction)
+        .await?;
+
+        let recovery_codes = Self::replace_recovery_codes(&mut transaction, user_id).await?;
+        transaction.commit().await?;
+        Ok(recovery_codes)
+    }
+
+    async fn replace_recovery_codes(
+        transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        user_id: &Uuid,
+    ) -> Result<Vec<String>, TotpError> {
+        sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1;")
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        let mut recovery_codes = vec![];
+        for _ in 0..RECOVERY_CODE_COUNT {
+            let code = generate_recovery_code()?;
+            sqlx::query("INSERT INTO recovery_codes (id, user_id, code_hash) VALUES ($1, $2, $3);")
+                .bind(Uuid::new_v4())
+                .bind(user_id)
+                .bind(hash_token(&normalize_code(&code)))
+                .execute(&mut *transaction)
+                .await?;
+            recovery_codes.push(code);
+        }
+        Ok(recovery_codes)
+    }
+
+    /// Whether logging in as `user_id` takes a second factor
+    pub async fn is_enabled(db_pool: &sqlx::PgPool, user_id: &Uuid) -> Result<bool, TotpError> {
+        Ok(sqlx::query(
+            "SELECT 1 FROM totp_credentials WHERE user_id = $1 AND confirmed_at IS NOT NULL;",
+        )
+        .bind(user_id)
+        .fetch_optional(db_pool)
+        .await?
+        .is_some())
+    }
+
+    /// Check `code`, either a current TOTP code or an unused recovery code.
+    /// Either is only accepted once.
+    pub async fn verify(
+        db_pool: &sqlx::PgPool,
+        cipher: &TotpCipher,
+        user_id: &Uuid,
+        code: &str,
+    ) -> Result<(), TotpError> {
+        let secret = sqlx::query(
+            "SELECT secret FROM totp_credentials WHERE user_id = $1 AND confirmed_at IS NOT NULL;",
+        )
+        .bind(user_id)
+        .fetch_optional(db_pool)
+        .await?
+        .ok_or(TotpError::NotEnrolled)?
+        .get::<String, _>(0);
+
+        if let Some(step) = cipher
+            .open(user_id, &secret)?
+            .verify_at(code, Totp::current_step())
+        {
+            // Whoever saw the code go by mustn't be able to use it again
+            let accepted = sqlx::query(
+                r#"
+UPDATE totp_credentials SET last_used_step = $2
+WHERE user_id = $1 AND (last_used_step IS NULL OR last_used_step < $2);"#,
+            )
+            .bind(user_id)
+            .bind(step)
+            .execute(db_pool)
+            .await?
+            .rows_affected()
+                > 0;
+            return if accepted {
+                Ok(())
+            } else {
+                Err(TotpError::InvalidCode)
+            };
+        }
+
+        let used_recovery_code = sqlx::query(
+            r#"
+UPDATE recovery_codes SET used_at = now()
+WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL;"#,
+        )
+        .bind(user_id)
+        .bind(hash_token(&normalize_code(code)))
+        .execute(db_pool)
+        .await?
+        .rows_affected()
+            > 0;
+
+        if used_recovery_code {
+            Ok(())
+        } else {
+            Err(TotpError::InvalidCode)
+        }
+    }
+
+    /// Encrypt the secrets stored before they were encrypted. Returns how
+    /// many there were.
+    pub async fn encrypt_stored_secrets(
+        db_pool: &sqlx::PgPool,
+        cipher: &TotpCipher,
+    ) -> Result<u64, TotpError> {
+        let rows = sqlx::query(
+            "SELECT user_id, secret FROM totp_credentials WHERE secret NOT LIKE $1 || '%';",
+        )
+        .bind(ENCRYPTED_PREFIX)
+        .fetch_all(db_pool)
+        .await?;
+
+        let mut encrypted = 0;
+        for row in rows {
+            let user_id: Uuid = row.try_get(0)?;
+            let secret: String = row.try_get(1)?;
+            let totp = cipher.open(&user_id, &secret)?;
+            // Unless it was replaced in the meantime
+            encrypted += sqlx::query(
+                "UPDATE totp_credentials SET secret = $3 WHERE user_id = $1 AND secret = $2;",
+            )
+            .bind(user_id)
+            .bind(&secret)
+            .bind(cipher.seal(&user_id, &totp)?)
+            .execute(db_pool)
+            .await?
+            .rows_affected();
+        }
+        Ok(encrypted)
+    }
+
+    /// Turn two-factor authentication off, dropping the recovery codes too
+    pub async fn disable(db_pool: &sqlx::PgPool, user_id: &Uuid) -> Result<(), TotpError> {
+        let mut transaction = db_pool.begin().await?;
+        sqlx::query("DELETE FROM totp_credentials WHERE user_id = $1;")
+            .bind(user_id)
+            .execute(&mut transaction)
+            .await?;
+        sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1;")
+            .bind(user_id)
+            .execute(&mut transaction)
+            .await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The RFC 6238 appendix B test secret, "12345678901234567890"
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn test_base32_roundtrip() {
+        assert_eq!(base32_encode(b"12345678901234567890"), RFC_SECRET);
+        assert_eq!(base32_encode(b"f"), "MY");
+        assert_eq!(base32_decode("MY").unwrap(), b"f");
+        assert_eq!(
+            base32_decode(&RFC_SECRET.to_lowercase()).unwrap(),
+            b"12345678901234567890"
+        );
+        assert!(base32_decode("not base32!").is_none());
+    }
+
+    #[test]
+    fn test_rfc_6238_vectors() {
+        let totp = Totp::from_base32(RFC_SECRET).unwrap();
+        // The RFC lists 8 digit codes; ours are their last 6 digits
+        for (time, code) in vec![
+            (59, "287082"),
+            (1_111_111_109, "081804"),
+            (1_234_567_890, "005924"),
+            (2_000_000_000, "279037"),
+        ] {
+            assert_eq!(totp.code_at(time / PERIOD_SECONDS), code);
+        }
+    }
+
+    #[test]
+    fn test_verify_allows_drift() {
+        let totp = Totp::generate().unwrap();
+        let code = totp.code_at(1000);
+        assert_eq!(totp.verify_at(&code, 1000), Some(1000));
+        assert_eq!(totp.verify_at(&code, 1001), Some(1000));
+        assert_eq!(totp.verify_at(&code, 999), Some(1000));
+        assert_eq!(totp.verify_at(&code, 1002), None);
+        assert_eq!(totp.verify_at("not a code", 1000), None);
+    }
+
+    #[test]
+    fn test_provisioning_uri() {
+        let totp = Totp::from_base32(RFC_SECRET).unwrap();
+        assert_eq!(
+            totp.provisioning_uri("Weft", "some@email.address"),
+            format!(
+                "otpauth://totp/Weft:some@email.address?secret={}&issuer=Weft&algorithm=SHA1&digits=6&period=30",
+                RFC_SECRET
+            )
+        );
+    }
+
+    #[test]
+    fn test_encrypted_secrets() {
+        let cipher = TotpCipher::new("some-server-secret");
+        let (user_id, other_user_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let totp = Totp::generate().unwrap();
+
+        let stored = cipher.seal(&user_id, &totp).unwrap();
+        assert!(stored.starts_with(ENCRYPTED_PREFIX));
+        assert!(!stored.contains(&totp.secret_base32()));
+        assert_ne!(stored, cipher.seal(&user_id, &totp).unwrap());
+        assert_eq!(cipher.open(&user_id, &stored).unwrap().secret, totp.secret);
+
+        // Not anybody else's, and not with another server secret
+        assert!(matches!(
+            cipher.open(&other_user_id, &stored),
+            Err(TotpError::UnreadableSecret)
+        ));
+        assert!(matches!(
+            TotpCipher::new("another-server-secret").open(&user_id, &stored),
+            Err(TotpError::UnreadableSecret)
+        ));
+    }
+
+    #[test]
+    fn test_plain_secrets_still_read() {
+        let cipher = TotpCipher::new("some-server-secret");
+        let totp = cipher.open(&Uuid::new_v4(), RFC_SECRET).unwrap();
+        assert_eq!(totp.secret, b"12345678901234567890");
+    }
+
+    #[test]
+    fn test_recovery_codes() {
+        let code = generate_recovery_code().unwrap();
+        assert_eq!(code.len(), 9);
+        assert_ne!(code, generate_recovery_code().unwrap());
+        assert_eq!(normalize_code(&code.to_uppercase()), code.replace('-', ""));
+    }
+}
@@ -0,0 +1,145 @@
+use std::fmt;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+use sqlx::{types::Uuid, Row};
+
+use super::{
+    opaque::{generate_token, hash_token},
+    EmailAddress,
+};
+use crate::mailer::Message;
+
+const LIFETIME_HOURS: i64 = 48;
+
+#[derive(Debug, thiserror::Error)]
+pub enum VerificationError {
+    /// Unknown, expired or already used token, or the address changed since
+    InvalidToken,
+    RandomError,
+    DatabaseError(sqlx::Error),
+}
+
+impl fmt::Display for VerificationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                VerificationError::InvalidToken => "Invalid verification token".into(),
+                VerificationError::RandomError => "Could not generate verification token".into(),
+                VerificationError::DatabaseError(error) => format!("Database error: {:?}", error),
+            }
+        )
+    }
+}
+
+impl From<sqlx::Error> for VerificationError {
+    fn from(error: sqlx::Error) -> Self {
+        VerificationError::DatabaseError(error)
+    }
+}
+
+/// Body of an email verification request
+#[derive(Debug, Deserialize)]
+pub struct VerifyEmail {
+    pub token: String,
+}
+
+/// Single use tokens proving a user can read the mailbox they signed up with
+pub struct EmailVerification;
+
+impl EmailVerification {
+    /// Create a verification token for `email_address`, returning the email
+    /// to send out with it.
+    pub async fn create(
+        db_pool: &sqlx::PgPool,
+        user_id: &Uuid,
+        email_address: &EmailAddress,
+    ) -> Result<Message, VerificationError> {
+        let token = generate_token().map_err(|_| VerificationError::RandomError)?;
+
+        sqlx::query(
+            r#"
+INSERT INTO email_verifications (token_hash, user_id, email_address, expires_at)
+VALUES ($1, $2, $3, $4);"#,
+        )
+        .bind(hash_token(&token))
+        .bind(user_id)
+        .bind(email_address.to_str())
+        .bind(Utc::now() + Duration::hours(LIFETIME_HOURS))
+        .execute(db_pool)
+        .await?;
+
+        Ok(Self::message(email_address, &token))
+    }
+
+    fn message(email_address: &EmailAddress, token: &str) -> Message {
+        Message {
+            to: email_address.clone(),
+            subject: "Please verify your email address".into(),
+            body: format!(
+                "Follow this link to verify your email address:\n\n{}/verify-email?token={}\n\nThe link expires in {} hours.",
+                super::app_url().trim_end_matches('/'),
+                token,
+                LIFETIME_HOURS,
+            ),
+        }
+    }
+
+    /// Use up `token`, marking its user as verified. Returns the user's id.
+    pub async fn redeem(db_pool: &sqlx::PgPool, token: &str) -> Result<Uuid, VerificationError> {
+        let mut transaction = db_pool.begin().await?;
+
+        let row = sqlx::query(
+            r#"
+DELETE FROM email_verifications
+WHERE token_hash = $1
+RETURNING user_id, email_address, expires_at;"#,
+        )
+        .bind(hash_token(token))
+        .fetch_optional(&mut transaction)
+        .await?
+        .ok_or(VerificationError::InvalidToken)?;
+
+        let user_id: Uuid = row.try_get("user_id")?;
+        let email_address: String = row.try_get("email_address")?;
+        let expires_at: DateTime<Utc> = row.try_get("expires_at")?;
+
+        if expires_at < Utc::now() {
+            return Err(VerificationError::InvalidToken);
+        }
+
+        // The address might have changed since the email was sent
+        let verified = sqlx::query(
+            "UPDATE users SET verified_at = now() WHERE id = $1 AND email_address = $2;",
+        )
+        .bind(&user_id)
+        .bind(&email_address)
+        .execute(&mut transaction)
+        .await?
+        .rows_affected()
+            > 0;
+
+        if !verified {
+            return Err(VerificationError::InvalidToken);
+        }
+
+        transaction.commit().await?;
+        Ok(user_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_message_contains_link() {
+        let email_address = EmailAddress::from_str("some@email.address").unwrap();
+        let message = EmailVerification::message(&email_address, "some-token");
+        assert_eq!(message.to, email_address);
+        assert!(message.body.contains("/verify-email?token=some-token"));
+    }
+}
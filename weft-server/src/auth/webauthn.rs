@@ -0,0 +1,1363 @@
+//! WebAuthn passkeys (https://www.w3.org/TR/webauthn-2/), usable both as a
+//! second factor after a password and as a login of their own.
+//!
+//! We ask authenticators for no attestation and don't check any that comes
+//! anyway: any authenticator the browser is happy with is fine by us.
+
+use std::{collections::BTreeMap, env, fmt};
+
+use chrono::{DateTime, Duration, Utc};
+use ring::{digest, signature};
+use serde::{Deserialize, Serialize};
+use serde_cbor::Value;
+use sqlx::{types::Uuid, Row};
+use url::Url;
+
+use super::opaque::generate_token;
+
+const CEREMONY_LIFETIME_MINUTES: i64 = 5;
+const DEFAULT_ORIGIN: &str = "http://localhost:3030";
+const DEFAULT_RP_NAME: &str = "Weft";
+const DEFAULT_PASSKEY_NAME: &str = "Passkey";
+const MAX_PASSKEY_NAME_LENGTH: usize = 64;
+
+/// COSE algorithm identifiers we accept, in order of preference
+const COSE_ES256: i64 = -7;
+const COSE_EDDSA: i64 = -8;
+const COSE_RS256: i64 = -257;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+/// rpIdHash, flags and signCount
+const AUTHENTICATOR_DATA_MIN_LENGTH: usize = 37;
+const AAGUID_LENGTH: usize = 16;
+
+#[derive(Debug, thiserror::Error)]
+pub enum WebauthnError {
+    InvalidConfiguration(String),
+    /// The challenge is unknown, expired, was already used, or belongs to
+    /// another ceremony
+    UnknownChallenge,
+    InvalidClientData(&'static str),
+    InvalidAuthenticatorData(&'static str),
+    UnsupportedAlgorithm,
+    InvalidSignature,
+    UserNotVerified,
+    UnknownCredential,
+    DuplicateCredential,
+    /// The signature counter went backwards, so there might be two copies
+    /// of the authenticator around
+    ClonedAuthenticator,
+    RandomError,
+    DatabaseError(sqlx::Error),
+}
+
+impl fmt::Display for WebauthnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WebauthnError::InvalidConfiguration(reason) => {
+                write!(f, "Invalid WebAuthn configuration: {}", reason)
+            }
+            WebauthnError::UnknownChallenge => write!(f, "Unknown challenge"),
+            WebauthnError::InvalidClientData(reason) => {
+                write!(f, "Invalid client data: {}", reason)
+            }
+            WebauthnError::InvalidAuthenticatorData(reason) => {
+                write!(f, "Invalid authenticator data: {}", reason)
+            }
+            WebauthnError::UnsupportedAlgorithm => write!(f, "Unsupported public key algorithm"),
+            WebauthnError::InvalidSignature => write!(f, "Invalid signature"),
+            WebauthnError::UserNotVerified => write!(f, "User verification is required"),
+            WebauthnError::UnknownCredential => write!(f, "Unknown credential"),
+            WebauthnError::DuplicateCredential => write!(f, "Credential already registered"),
+            WebauthnError::ClonedAuthenticator => write!(f, "Signature counter went backwards"),
+            WebauthnError::RandomError => write!(f, "Could not generate challenge"),
+            WebauthnError::DatabaseError(error) => write!(f, "Database error: {:?}", error),
+        }
+    }
+}
+
+impl From<sqlx::Error> for WebauthnError {
+    fn from(error: sqlx::Error) -> Self {
+        WebauthnError::DatabaseError(error)
+    }
+}
+
+fn encode_base64url<T: AsRef<[u8]>>(bytes: T) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn decode_base64url(encoded: &str) -> Option<Vec<u8>> {
+    base64::decode_config(encoded.trim_end_matches('='), base64::URL_SAFE_NO_PAD).ok()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Ceremony {
+    Registration,
+    Authentication,
+}
+
+impl Ceremony {
+    fn as_str(self) -> &'static str {
+        match self {
+            Ceremony::Registration => "registration",
+            Ceremony::Authentication => "authentication",
+        }
+    }
+
+    /// `type` the browser puts in the client data
+    fn client_data_type(self) -> &'static str {
+        match self {
+            Ceremony::Registration => "webauthn.create",
+            Ceremony::Authentication => "webauthn.get",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RelyingPartyEntity {
+    id: String,
+    name: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserEntity {
+    id: String,
+    name: String,
+    display_name: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CredentialParameters {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    alg: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CredentialDescriptor {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    id: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    transports: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticatorSelection {
+    resident_key: &'static str,
+    require_resident_key: bool,
+    user_verification: &'static str,
+}
+
+/// What `navigator.credentials.create()` takes as `publicKey`, with binary
+/// fields base64url encoded
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreationOptions {
+    rp: RelyingPartyEntity,
+    user: UserEntity,
+    challenge: String,
+    pub_key_cred_params: Vec<CredentialParameters>,
+    timeout: i64,
+    attestation: &'static str,
+    exclude_credentials: Vec<CredentialDescriptor>,
+    authenticator_selection: AuthenticatorSelection,
+}
+
+/// What `navigator.credentials.get()` takes as `publicKey`, with binary
+/// fields base64url encoded
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestOptions {
+    challenge: String,
+    timeout: i64,
+    rp_id: String,
+    allow_credentials: Vec<CredentialDescriptor>,
+    user_verification: &'static str,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub attestation_object: String,
+    #[serde(default)]
+    pub transports: Vec<String>,
+}
+
+/// A new credential, as returned by `navigator.credentials.create()`, with
+/// an optional name to tell it apart from the user's other passkeys
+#[derive(Debug, Deserialize)]
+pub struct RegistrationCredential {
+    pub id: String,
+    pub response: AttestationResponse,
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+    #[serde(default)]
+    pub user_handle: Option<String>,
+}
+
+/// A signed challenge, as returned by `navigator.credentials.get()`
+#[derive(Debug, Deserialize)]
+pub struct AssertionCredential {
+    pub id: String,
+    pub response: AssertionResponse,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+impl ClientData {
+    fn parse(client_data_json: &[u8]) -> Result<Self, WebauthnError> {
+        serde_json::from_slice(client_data_json)
+            .map_err(|_| WebauthnError::InvalidClientData("not JSON"))
+    }
+}
+
+/// A credential that passed the registration ceremony, ready to be stored
+#[derive(Debug)]
+pub struct VerifiedCredential {
+    pub credential_id: Vec<u8>,
+    /// COSE_Key encoded, as the authenticator sent it
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+    pub transports: Vec<String>,
+    /// Whether the authenticator verified the user (PIN, biometrics...)
+    /// rather than just checking someone was there
+    pub user_verified: bool,
+}
+
+/// What an assertion that checked out tells about the credential
+#[derive(Debug)]
+pub struct VerifiedAssertion {
+    pub sign_count: u32,
+    pub user_verified: bool,
+}
+
+struct AuthenticatorData {
+    rp_id_hash: Vec<u8>,
+    flags: u8,
+    sign_count: u32,
+    /// Credential id and COSE_Key, only there on registration
+    attested_credential: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl AuthenticatorData {
+    fn parse(data: &[u8]) -> Result<Self, WebauthnError> {
+        if data.len() < AUTHENTICATOR_DATA_MIN_LENGTH {
+            return Err(WebauthnError::InvalidAuthenticatorData("too short"));
+        }
+        let flags = data[32];
+        let sign_count = u32::from_be_bytes([data[33], data[34], data[35], data[36]]);
+
+        let attested_credential = if flags & FLAG_ATTESTED_CREDENTIAL_DATA != 0 {
+            let rest = &data[AUTHENTICATOR_DATA_MIN_LENGTH..];
+            let id_offset = AAGUID_LENGTH + 2;
+            if rest.len() < id_offset {
+                return Err(WebauthnError::InvalidAuthenticatorData("truncated"));
+            }
+            let id_length =
+                u16::from_be_bytes([rest[AAGUID_LENGTH], rest[AAGUID_LENGTH + 1]]) as usize;
+            if rest.len() < id_offset + id_length {
+                return Err(WebauthnError::InvalidAuthenticatorData("truncated"));
+            }
+            let credential_id = rest[id_offset..id_offset + id_length].to_vec();
+
+            // The key is followed by extensions, if any, so we need to know
+            // where its CBOR encoding ends
+            let key_bytes = &rest[id_offset + id_length..];
+            let mut deserializer = serde_cbor::Deserializer::from_slice(key_bytes);
+            serde::de::IgnoredAny::deserialize(&mut deserializer)
+                .map_err(|_| WebauthnError::InvalidAuthenticatorData("bad public key"))?;
+            let public_key = key_bytes[..deserializer.byte_offset()].to_vec();
+
+            Some((credential_id, public_key))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            rp_id_hash: data[..32].to_vec(),
+            flags,
+            sign_count,
+            attested_credential,
+        })
+    }
+}
+
+/// A credential public key, decoded from its COSE_Key form (RFC 8152)
+enum PublicKey {
+    /// Uncompressed P-256 point
+    Es256(Vec<u8>),
+    Ed25519(Vec<u8>),
+    Rs256 {
+        n: Vec<u8>,
+        e: Vec<u8>,
+    },
+}
+
+impl PublicKey {
+    fn from_cose(cose_key: &[u8]) -> Result<Self, WebauthnError> {
+        let map: BTreeMap<Value, Value> = serde_cbor::from_slice(cose_key)
+            .map_err(|_| WebauthnError::InvalidAuthenticatorData("bad public key"))?;
+        let integer = |label: i128| match map.get(&Value::Integer(label)) {
+            Some(Value::Integer(value)) => Some(*value),
+            _ => None,
+        };
+        let bytes = |label: i128| match map.get(&Value::Integer(label)) {
+            Some(Value::Bytes(value)) => Some(value.clone()),
+            _ => None,
+        };
+
+        // kty is label 1, alg label 3; the rest depend on the key type
+        match (integer(1), integer(3).map(|alg| alg as i64)) {
+            (Some(2), Some(COSE_ES256)) if integer(-1) == Some(1) => match (bytes(-2), bytes(-3)) {
+                (Some(x), Some(y)) if x.len() == 32 && y.len() == 32 => {
+                    let mut point = vec![0x04];
+                    point.extend(x);
+                    point.extend(y);
+                    Ok(PublicKey::Es256(point))
+                }
+                _ => Err(WebauthnError::InvalidAuthenticatorData("bad public key")),
+            },
+            (Some(1), Some(COSE_EDDSA)) if integer(-1) == Some(6) => match bytes(-2) {
+                Some(x) if x.len() == 32 => Ok(PublicKey::Ed25519(x)),
+                _ => Err(WebauthnError::InvalidAuthenticatorData("bad public key")),
+            },
+            (Some(3), Some(COSE_RS256)) => match (bytes(-1), bytes(-2)) {
+                (Some(n), Some(e)) => Ok(PublicKey::Rs256 { n, e }),
+                _ => Err(WebauthnError::InvalidAuthenticatorData("bad public key")),
+            },
+            _ => Err(WebauthnError::UnsupportedAlgorithm),
+        }
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> Result<(), WebauthnError> {
+        match self {
+            PublicKey::Es256(point) => {
+                signature::UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_ASN1, point)
+                    .verify(message, signature)
+            }
+            PublicKey::Ed25519(key) => signature::UnparsedPublicKey::new(&signature::ED25519, key)
+                .verify(message, signature),
+            PublicKey::Rs256 { n, e } => signature::RsaPublicKeyComponents { n, e }.verify(
+                &signature::RSA_PKCS1_2048_8192_SHA256,
+                message,
+                signature,
+            ),
+        }
+        .map_err(|_| WebauthnError::InvalidSignature)
+    }
+}
+
+/// Who passkeys are created for: our web app's origin, and the domain
+/// (`rp.id`) they're scoped to.
+#[derive(Debug, Clone)]
+pub struct RelyingParty {
+    id: String,
+    name: String,
+    origin: String,
+}
+
+impl RelyingParty {
+    pub fn new(origin: &str, name: &str) -> Result<Self, WebauthnError> {
+        let url = Url::parse(origin)
+            .map_err(|_| WebauthnError::InvalidConfiguration("invalid origin".into()))?;
+        let id = url
+            .host_str()
+            .ok_or_else(|| WebauthnError::InvalidConfiguration("origin has no host".into()))?
+            .to_string();
+        Ok(Self {
+            id,
+            name: name.into(),
+            origin: url.origin().ascii_serialization(),
+        })
+    }
+
+    /// Reads `WEFT_WEBAUTHN_ORIGIN` (the web app's origin), and optionally
+    /// `WEFT_WEBAUTHN_RP_ID`, to scope passkeys to a parent domain of the
+    /// origin's, and `WEFT_WEBAUTHN_RP_NAME`
+    pub fn new_from_env() -> Result<Self, WebauthnError> {
+        let origin = env::var("WEFT_WEBAUTHN_ORIGIN").unwrap_or_else(|_| DEFAULT_ORIGIN.into());
+        let name = env::var("WEFT_WEBAUTHN_RP_NAME").unwrap_or_else(|_| DEFAULT_RP_NAME.into());
+        let relying_party = Self::new(&origin, &name)?;
+
+        match env::var("WEFT_WEBAUTHN_RP_ID") {
+            Ok(id) => relying_party.with_id(id),
+            Err(_) => Ok(relying_party),
+        }
+    }
+
+    /// The RP id has to be the origin's host, or one of its parent domains
+    pub fn with_id(mut self, id: String) -> Result<Self, WebauthnError> {
+        if self.id != id && !self.id.ends_with(&format!(".{}", id)) {
+            return Err(WebauthnError::InvalidConfiguration(format!(
+                "{} is not a registrable suffix of {}",
+                id, self.id
+            )));
+        }
+        self.id = id;
+        Ok(self)
+    }
+
+    fn check_client_data(
+        &self,
+        client_data_json: &[u8],
+        ceremony: Ceremony,
+        challenge: &str,
+    ) -> Result<(), WebauthnError> {
+        let client_data = ClientData::parse(client_data_json)?;
+        if client_data.kind != ceremony.client_data_type() {
+            return Err(WebauthnError::InvalidClientData("wrong type"));
+        }
+        if client_data.challenge != challenge {
+            return Err(WebauthnError::InvalidClientData("wrong challenge"));
+        }
+        if client_data.origin != self.origin {
+            return Err(WebauthnError::InvalidClientData("wrong origin"));
+        }
+        Ok(())
+    }
+
+    fn check_authenticator_data(
+        &self,
+        authenticator_data: &AuthenticatorData,
+        require_user_verification: bool,
+    ) -> Result<(), WebauthnError> {
+        let rp_id_hash = digest::digest(&digest::SHA256, self.id.as_bytes());
+        if authenticator_data.rp_id_hash != rp_id_hash.as_ref() {
+            return Err(WebauthnError::InvalidAuthenticatorData(
+                "wrong relying party",
+            ));
+        }
+        if authenticator_data.flags & FLAG_USER_PRESENT == 0 {
+            return Err(WebauthnError::InvalidAuthenticatorData("user not present"));
+        }
+        if require_user_verification && authenticator_data.flags & FLAG_USER_VERIFIED == 0 {
+            return Err(WebauthnError::UserNotVerified);
+        }
+        Ok(())
+    }
+
+    /// Check a registration response against the `challenge` it was created
+    /// for (steps 7 to 21 of https://www.w3.org/TR/webauthn-2/#sctn-registering-a-new-credential,
+    /// attestation aside)
+    pub fn verify_registration(
+        &self,
+        challenge: &str,
+        credential: &RegistrationCredential,
+        require_user_verification: bool,
+    ) -> Result<VerifiedCredential, WebauthnError> {
+        let client_data_json = decode_base64url(&credential.response.client_data_json)
+            .ok_or(WebauthnError::InvalidClientData("not base64url"))?;
+        self.check_client_data(&client_data_json, Ceremony::Registration, challenge)?;
+
+        let attestation_object = decode_base64url(&credential.response.attestation_object)
+            .ok_or(WebauthnError::InvalidAuthenticatorData("not base64url"))?;
+        let attestation: BTreeMap<String, Value> = serde_cbor::from_slice(&attestation_object)
+            .map_err(|_| WebauthnError::InvalidAuthenticatorData("bad attestation object"))?;
+        let authenticator_data = match attestation.get("authData") {
+            Some(Value::Bytes(data)) => AuthenticatorData::parse(data)?,
+            _ => return Err(WebauthnError::InvalidAuthenticatorData("missing authData")),
+        };
+        self.check_authenticator_data(&authenticator_data, require_user_verification)?;
+
+        let (credential_id, public_key) = authenticator_data.attested_credential.ok_or(
+            WebauthnError::InvalidAuthenticatorData("missing credential"),
+        )?;
+        if decode_base64url(&credential.id).as_ref() != Some(&credential_id) {
+            return Err(WebauthnError::InvalidAuthenticatorData(
+                "credential id mismatch",
+            ));
+        }
+        PublicKey::from_cose(&public_key)?;
+
+        Ok(VerifiedCredential {
+            credential_id,
+            public_key,
+            sign_count: authenticator_data.sign_count,
+            transports: credential.response.transports.clone(),
+            user_verified: authenticator_data.flags & FLAG_USER_VERIFIED != 0,
+        })
+    }
+
+    /// Check an assertion against the `challenge` it was created for, and
+    /// the stored credential's public key and signature counter (steps 7 to
+    /// 21 of https://www.w3.org/TR/webauthn-2/#sctn-verifying-assertion).
+    pub fn verify_assertion(
+        &self,
+        challenge: &str,
+        credential: &AssertionCredential,
+        public_key: &[u8],
+        sign_count: u32,
+        require_user_verification: bool,
+    ) -> Result<VerifiedAssertion, WebauthnError> {
+        let client_data_json = decode_base64url(&credential.response.client_data_json)
+            .ok_or(WebauthnError::InvalidClientData("not base64url"))?;
+        self.check_client_data(&client_data_json, Ceremony::Authentication, challenge)?;
+
+        let raw_authenticator_data = decode_base64url(&credential.response.authenticator_data)
+            .ok_or(WebauthnError::InvalidAuthenticatorData("not base64url"))?;
+        let authenticator_data = AuthenticatorData::parse(&raw_authenticator_data)?;
+        self.check_authenticator_data(&authenticator_data, require_user_verification)?;
+
+        let signature = decode_base64url(&credential.response.signature)
+            .ok_or(WebauthnError::InvalidSignature)?;
+        let mut signed = raw_authenticator_data;
+        signed.extend_from_slice(digest::digest(&digest::SHA256, &client_data_json).as_ref());
+        PublicKey::from_cose(public_key)?.verify(&signed, &signature)?;
+
+        // Authenticators that don't keep a counter always send 0
+        if (authenticator_data.sign_count != 0 || sign_count != 0)
+            && authenticator_data.sign_count <= sign_count
+        {
+            return Err(WebauthnError::ClonedAuthenticator);
+        }
+        Ok(VerifiedAssertion {
+            sign_count: authenticator_data.sign_count,
+            user_verified: authenticator_data.flags & FLAG_USER_VERIFIED != 0,
+        })
+    }
+}
+
+/// The challenge a ceremony response was signed over, so it can be looked up
+fn client_data_challenge(client_data_json: &str) -> Result<String, WebauthnError> {
+    let client_data_json = decode_base64url(client_data_json)
+        .ok_or(WebauthnError::InvalidClientData("not base64url"))?;
+    Ok(ClientData::parse(&client_data_json)?.challenge)
+}
+
+/// Remember a fresh challenge for `ceremony`, bound to `user_id` unless the
+/// user isn't known yet (passwordless login)
+async fn create_challenge(
+    db_pool: &sqlx::PgPool,
+    ceremony: Ceremony,
+    user_id: Option<&Uuid>,
+) -> Result<String, WebauthnError> {
+    let challenge = generate_token().map_err(|_| WebauthnError::RandomError)?;
+    sqlx::query(
+        r#"
+INSERT INTO webauthn_challenges (challenge, ceremony, user_id, expires_at)
+VALUES ($1, $2, $3, $4);"#,
+    )
+    .bind(&challenge)
+    .bind(ceremony.as_str())
+    .bind(user_id)
+    .bind(Utc::now() + Duration::minutes(CEREMONY_LIFETIME_MINUTES))
+    .execute(db_pool)
+    .await?;
+    Ok(challenge)
+}
+
+/// Use up `challenge`, returning the user it was bound to, if any
+async fn redeem_challenge(
+    db_pool: &sqlx::PgPool,
+    ceremony: Ceremony,
+    challenge: &str,
+) -> Result<Option<Uuid>, WebauthnError> {
+    let row = sqlx::query(
+        r#"
+DELETE FROM webauthn_challenges
+WHERE challenge = $1 AND ceremony = $2
+RETURNING user_id, expires_at;"#,
+    )
+    .bind(challenge)
+    .bind(ceremony.as_str())
+    .fetch_optional(db_pool)
+    .await?
+    .ok_or(WebauthnError::UnknownChallenge)?;
+
+    let expires_at: DateTime<Utc> = row.try_get("expires_at")?;
+    if expires_at < Utc::now() {
+        return Err(WebauthnError::UnknownChallenge);
+    }
+    Ok(row.try_get("user_id")?)
+}
+
+/// A registered passkey, as shown to its owner
+#[derive(Debug, Serialize)]
+pub struct Passkey {
+    pub id: Uuid,
+    pub name: String,
+    pub transports: Vec<String>,
+    /// Whether it ever verified its user, which is what it takes to log in
+    /// with it alone
+    pub user_verified: bool,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RenamePasskey {
+    pub name: String,
+}
+
+fn passkey_name(name: Option<&str>) -> String {
+    match name.map(str::trim) {
+        Some(name) if !name.is_empty() => name.chars().take(MAX_PASSKEY_NAME_LENGTH).collect(),
+        _ => DEFAULT_PASSKEY_NAME.into(),
+    }
+}
+
+impl Passkey {
+    fn from_row(row: &sqlx::postgres::PgRow) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            id: row.try_get("id")?,
+            name: row.try_get("name")?,
+            transports: row.try_get("transports")?,
+            user_verified: row.try_get("user_verified")?,
+            created_at: row.try_get("created_at")?,
+            last_used_at: row.try_get("last_used_at")?,
+        })
+    }
+
+    async fn descriptors(
+        db_pool: &sqlx::PgPool,
+        user_id: &Uuid,
+    ) -> Result<Vec<CredentialDescriptor>, WebauthnError> {
+        let rows = sqlx::query(
+            "SELECT credential_id, transports FROM webauthn_credentials WHERE user_id = $1;",
+        )
+        .bind(user_id)
+        .fetch_all(db_pool)
+        .await?;
+
+        rows.iter()
+            .map(|row| -> Result<_, WebauthnError> {
+                Ok(CredentialDescriptor {
+                    kind: "public-key",
+                    id: encode_base64url(row.try_get::<Vec<u8>, _>("credential_id")?),
+                    transports: row.try_get("transports")?,
+                })
+            })
+            .collect()
+    }
+
+    /// Options for adding a passkey to `user_id`'s account. Their existing
+    /// passkeys are excluded, so an authenticator isn't registered twice.
+    pub async fn start_registration(
+        db_pool: &sqlx::PgPool,
+        relying_party: &RelyingParty,
+        user_id: &Uuid,
+        user_name: &str,
+    ) -> Result<CreationOptions, WebauthnError> {
+        let challenge = create_challenge(db_pool, Ceremony::Registration, Some(user_id)).await?;
+        Ok(CreationOptions {
+            rp: RelyingPartyEntity {
+                id: relying_party.id.clone(),
+                name: relying_party.name.clone(),
+            },
+            user: UserEntity {
+                id: encode_base64url(user_id.as_bytes()),
+                name: user_name.into(),
+                display_name: user_name.into(),
+            },
+            challenge,
+            pub_key_cred_params: [COSE_ES256, COSE_EDDSA, COSE_RS256]
+                .iter()
+                .map(|&alg| CredentialParameters {
+                    kind: "public-key",
+                    alg,
+                })
+                .collect(),
+            timeout: Duration::minutes(CEREMONY_LIFETIME_MINUTES).num_milliseconds(),
+            attestation: "none",
+            exclude_credentials: Self::descriptors(db_pool, user_id).await?,
+            authenticator_selection: AuthenticatorSelection {
+                resident_key: "preferred",
+                require_resident_key: false,
+                user_verification: "preferred",
+            },
+        })
+    }
+
+    pub async fn finish_registration(
+        db_pool: &sqlx::PgPool,
+        relying_party: &RelyingParty,
+        user_id: &Uuid,
+        credential: &RegistrationCredential,
+    ) -> Result<Self, WebauthnError> {
+        let challenge = client_data_challenge(&credential.response.client_data_json)?;
+        if redeem_challenge(db_pool, Ceremony::Registration, &challenge).await? != Some(*user_id) {
+            return Err(WebauthnError::UnknownChallenge);
+        }
+        let verified = relying_party.verify_registration(&challenge, credential, false)?;
+
+        let row = sqlx::query(
+            r#"
+INSERT INTO webauthn_credentials
+    (id, user_id, credential_id, public_key, sign_count, transports, name, user_verified)
+VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+RETURNING id, name, transports, user_verified, created_at, last_used_at;"#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .bind(&verified.credential_id)
+        .bind(&verified.public_key)
+        .bind(i64::from(verified.sign_count))
+        .bind(&verified.transports)
+        .bind(passkey_name(credential.name.as_deref()))
+        .bind(verified.user_verified)
+        .fetch_one(db_pool)
+        .await
+        .map_err(|error| {
+            if crate::models::is_unique_violation(&error) {
+                WebauthnError::DuplicateCredential
+            } else {
+                error.into()
+            }
+        })?;
+        Ok(Self::from_row(&row)?)
+    }
+
+    /// Options for signing in with a passkey. With a `user_id` (passkey as a
+    /// second factor) only their passkeys are allowed; without one
+    /// (passwordless login) the authenticator offers whatever it has for us,
+    /// and has to verify the user itself.
+    pub async fn start_authentication(
+        db_pool: &sqlx::PgPool,
+        relying_party: &RelyingParty,
+        user_id: Option<&Uuid>,
+    ) -> Result<RequestOptions, WebauthnError> {
+        let challenge = create_challenge(db_pool, Ceremony::Authentication, user_id).await?;
+        let (allow_credentials, user_verification) = match user_id {
+            Some(user_id) => (Self::descriptors(db_pool, user_id).await?, "preferred"),
+            None => (vec![], "required"),
+        };
+        Ok(RequestOptions {
+            challenge,
+            timeout: Duration::minutes(CEREMONY_LIFETIME_MINUTES).num_milliseconds(),
+            rp_id: relying_party.id.clone(),
+            allow_credentials,
+            user_verification,
+        })
+    }
+
+    /// Check an assertion, returning the id of the user it signs in. A
+    /// challenge bound to a user only accepts that user's passkeys.
+    pub async fn finish_authentication(
+        db_pool: &sqlx::PgPool,
+        relying_party: &RelyingParty,
+        credential: &AssertionCredential,
+        require_user_verification: bool,
+    ) -> Result<Uuid, WebauthnError> {
+        let challenge = client_data_challenge(&credential.response.client_data_json)?;
+        let challenge_user_id =
+            redeem_challenge(db_pool, Ceremony::Authentication, &challenge).await?;
+
+        let credential_id =
+            decode_base64url(&credential.id).ok_or(WebauthnError::UnknownCredential)?;
+        let row = sqlx::query(
+            r#"
+SELECT id, user_id, public_key, sign_count
+FROM webauthn_credentials
+WHERE credential_id = $1;"#,
+        )
+        .bind(&credential_id)
+        .fetch_optional(db_pool)
+        .await?
+        .ok_or(WebauthnError::UnknownCredential)?;
+
+        let id: Uuid = row.try_get("id")?;
+        let user_id: Uuid = row.try_get("user_id")?;
+        let public_key: Vec<u8> = row.try_get("public_key")?;
+        let sign_count: i64 = row.try_get("sign_count")?;
+
+        if challenge_user_id.map_or(false, |challenge_user_id| challenge_user_id != user_id) {
+            return Err(WebauthnError::UnknownCredential);
+        }
+        if let Some(user_handle) = &credential.response.user_handle {
+            if decode_base64url(user_handle).as_deref() != Some(&user_id.as_bytes()[..]) {
+                return Err(WebauthnError::UnknownCredential);
+            }
+        }
+
+        let verified = relying_party.verify_assertion(
+            &challenge,
+            credential,
+            &public_key,
+            sign_count as u32,
+            require_user_verification,
+        )?;
+
+        // Two copies of the authenticator can't both get in with the same
+        // counter value by being quick about it
+        let updated = sqlx::query(
+            r#"
+UPDATE webauthn_credentials
+SET sign_count = $2, user_verified = user_verified OR $3, last_used_at = now()
+WHERE id = $1 AND (sign_count < $2 OR (sign_count = 0 AND $2 = 0));"#,
+        )
+        .bind(id)
+        .bind(i64::from(verified.sign_count))
+        .bind(verified.user_verified)
+        .execute(db_pool)
+        .await?
+        .rows_affected();
+        if updated == 0 {
+            return Err(WebauthnError::ClonedAuthenticator);
+        }
+        Ok(user_id)
+    }
+
+    pub async fn list(db_pool: &sqlx::PgPool, user_id: &Uuid) -> Result<Vec<Self>, WebauthnError> {
+        let rows = sqlx::query(
+            r#"
+SELECT id, name, transports, user_verified, created_at, last_used_at
+FROM webauthn_credentials
+WHERE user_id = $1
+ORDER BY created_at;"#,
+        )
+        .bind(user_id)
+        .fetch_all(db_pool)
+        .await?;
+        Ok(rows.iter().map(Self::from_row).collect::<Result<_, _>>()?)
+    }
+
+    /// Whether `user_id` can use a passkey as their second factor
+    pub async fn exists_for(db_pool: &sqlx::PgPool, user_id: &Uuid) -> Result<bool, WebauthnError> {
+        Ok(
+            sqlx::query("SELECT 1 FROM webauthn_credentials WHERE user_id = $1 LIMIT 1;")
+                .bind(user_id)
+                .fetch_optional(db_pool)
+                .await?
+                .is_some(),
+        )
+    }
+
+    /// Returns whether `user_id` had such a passkey
+    pub async fn rename(
+        db_pool: &sqlx::PgPool,
+        user_id: &Uuid,
+        id: &Uuid,
+        name: &str,
+    ) -> Result<bool, WebauthnError> {
+        Ok(
+            sqlx::query(
+                "UPDATE webauthn_credentials SET name = $3 WHERE id = $1 AND user_id = $2;",
+            )
+            .bind(id)
+            .bind(user_id)
+            .bind(passkey_name(Some(name)))
+            .execute(db_pool)
+            .await?
+            .rows_affected()
+                > 0,
+        )
+    }
+
+    /// Returns whether `user_id` had such a passkey
+    pub async fn delete(
+        db_pool: &sqlx::PgPool,
+        user_id: &Uuid,
+        id: &Uuid,
+    ) -> Result<bool, WebauthnError> {
+        Ok(
+            sqlx::query("DELETE FROM webauthn_credentials WHERE id = $1 AND user_id = $2;")
+                .bind(id)
+                .bind(user_id)
+                .execute(db_pool)
+                .await?
+                .rows_affected()
+                > 0,
+        )
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use ring::{
+        rand::{SecureRandom, SystemRandom},
+        signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING},
+    };
+
+    use crate::test_db;
+
+    pub const ORIGIN: &str = "https://weft.example";
+    pub const RP_ID: &str = "weft.example";
+
+    /// A software authenticator with a single P-256 credential, standing in
+    /// for a security key or a platform authenticator
+    pub struct SoftwareAuthenticator {
+        key_pair: EcdsaKeyPair,
+        credential_id: Vec<u8>,
+        pub sign_count: u32,
+        pub user_verification: bool,
+    }
+
+    impl SoftwareAuthenticator {
+        pub fn new() -> Self {
+            let rng = SystemRandom::new();
+            let pkcs8 =
+                EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+            let mut credential_id = vec![0u8; 16];
+            rng.fill(&mut credential_id).unwrap();
+            Self {
+                key_pair: EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref())
+                    .unwrap(),
+                credential_id,
+                sign_count: 0,
+                user_verification: true,
+            }
+        }
+
+        fn cose_key(&self) -> Vec<u8> {
+            let point = self.key_pair.public_key().as_ref();
+            let mut key = BTreeMap::new();
+            key.insert(Value::Integer(1), Value::Integer(2));
+            key.insert(Value::Integer(3), Value::Integer(COSE_ES256.into()));
+            key.insert(Value::Integer(-1), Value::Integer(1));
+            key.insert(Value::Integer(-2), Value::Bytes(point[1..33].to_vec()));
+            key.insert(Value::Integer(-3), Value::Bytes(point[33..].to_vec()));
+            serde_cbor::to_vec(&key).unwrap()
+        }
+
+        fn authenticator_data(&mut self, rp_id: &str, attested: bool) -> Vec<u8> {
+            self.sign_count += 1;
+            let mut flags = FLAG_USER_PRESENT;
+            if self.user_verification {
+                flags |= FLAG_USER_VERIFIED;
+            }
+            if attested {
+                flags |= FLAG_ATTESTED_CREDENTIAL_DATA;
+            }
+
+            let mut data = digest::digest(&digest::SHA256, rp_id.as_bytes())
+                .as_ref()
+                .to_vec();
+            data.push(flags);
+            data.extend_from_slice(&self.sign_count.to_be_bytes());
+            if attested {
+                data.extend_from_slice(&[0u8; AAGUID_LENGTH]);
+                data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+                data.extend_from_slice(&self.credential_id);
+                data.extend(self.cose_key());
+            }
+            data
+        }
+
+        pub fn register(
+            &mut self,
+            rp_id: &str,
+            origin: &str,
+            challenge: &str,
+        ) -> RegistrationCredential {
+            let mut attestation = BTreeMap::new();
+            attestation.insert("fmt".to_string(), Value::Text("none".into()));
+            attestation.insert("attStmt".to_string(), Value::Map(BTreeMap::new()));
+            attestation.insert(
+                "authData".to_string(),
+                Value::Bytes(self.authenticator_data(rp_id, true)),
+            );
+
+            RegistrationCredential {
+                id: encode_base64url(&self.credential_id),
+                response: AttestationResponse {
+                    client_data_json: client_data("webauthn.create", challenge, origin),
+                    attestation_object: encode_base64url(serde_cbor::to_vec(&attestation).unwrap()),
+                    transports: vec!["internal".into()],
+                },
+                name: Some("Test key".into()),
+            }
+        }
+
+        pub fn assert(
+            &mut self,
+            rp_id: &str,
+            origin: &str,
+            challenge: &str,
+        ) -> AssertionCredential {
+            let authenticator_data = self.authenticator_data(rp_id, false);
+            let client_data_json = client_data("webauthn.get", challenge, origin);
+
+            let mut signed = authenticator_data.clone();
+            signed.extend_from_slice(
+                digest::digest(
+                    &digest::SHA256,
+                    &decode_base64url(&client_data_json).unwrap(),
+                )
+                .as_ref(),
+            );
+            let signature = self.key_pair.sign(&SystemRandom::new(), &signed).unwrap();
+
+            AssertionCredential {
+                id: encode_base64url(&self.credential_id),
+                response: AssertionResponse {
+                    client_data_json,
+                    authenticator_data: encode_base64url(authenticator_data),
+                    signature: encode_base64url(signature),
+                    user_handle: None,
+                },
+            }
+        }
+    }
+
+    fn client_data(kind: &str, challenge: &str, origin: &str) -> String {
+        encode_base64url(
+            serde_json::json!({ "type": kind, "challenge": challenge, "origin": origin })
+                .to_string(),
+        )
+    }
+
+    pub fn relying_party() -> RelyingParty {
+        RelyingParty::new(ORIGIN, "Weft").unwrap()
+    }
+
+    /// Register `authenticator` as one of `user_id`'s passkeys
+    pub async fn register_passkey(
+        db_pool: &sqlx::PgPool,
+        authenticator: &mut SoftwareAuthenticator,
+        user_id: &Uuid,
+    ) -> Passkey {
+        let relying_party = relying_party();
+        let options = Passkey::start_registration(db_pool, &relying_party, user_id, "test")
+            .await
+            .unwrap();
+        let registration = authenticator.register(RP_ID, ORIGIN, &options.challenge);
+        Passkey::finish_registration(db_pool, &relying_party, user_id, &registration)
+            .await
+            .unwrap()
+    }
+
+    /// An assertion over a fresh challenge, for `user_id`'s login or for
+    /// anyone's
+    async fn assertion(
+        db_pool: &sqlx::PgPool,
+        authenticator: &mut SoftwareAuthenticator,
+        user_id: Option<&Uuid>,
+    ) -> AssertionCredential {
+        let options = Passkey::start_authentication(db_pool, &relying_party(), user_id)
+            .await
+            .unwrap();
+        authenticator.assert(RP_ID, ORIGIN, &options.challenge)
+    }
+
+    #[test]
+    fn test_relying_party_id() {
+        let relying_party =
+            RelyingParty::new("https://app.weft.example:8443/login", "Weft").unwrap();
+        assert_eq!(relying_party.id, "app.weft.example");
+        assert_eq!(relying_party.origin, "https://app.weft.example:8443");
+        assert!(relying_party.clone().with_id("weft.example".into()).is_ok());
+        assert!(relying_party.with_id("other.example".into()).is_err());
+    }
+
+    #[test]
+    fn test_register_and_authenticate() {
+        let relying_party = relying_party();
+        let mut authenticator = SoftwareAuthenticator::new();
+
+        let registration = authenticator.register("weft.example", ORIGIN, "register-challenge");
+        let verified = relying_party
+            .verify_registration("register-challenge", &registration, true)
+            .unwrap();
+        assert_eq!(verified.credential_id, authenticator.credential_id);
+        assert_eq!(verified.sign_count, 1);
+        assert_eq!(verified.transports, vec!["internal".to_string()]);
+        assert!(verified.user_verified);
+
+        let assertion = authenticator.assert("weft.example", ORIGIN, "login-challenge");
+        let assertion = relying_party
+            .verify_assertion(
+                "login-challenge",
+                &assertion,
+                &verified.public_key,
+                verified.sign_count,
+                true,
+            )
+            .unwrap();
+        assert_eq!(assertion.sign_count, 2);
+        assert!(assertion.user_verified);
+    }
+
+    #[test]
+    fn test_rejects_wrong_challenge_origin_and_relying_party() {
+        let relying_party = relying_party();
+        let mut authenticator = SoftwareAuthenticator::new();
+
+        let registration = authenticator.register("weft.example", ORIGIN, "challenge");
+        assert!(matches!(
+            relying_party.verify_registration("other-challenge", &registration, false),
+            Err(WebauthnError::InvalidClientData(_))
+        ));
+
+        let registration =
+            authenticator.register("weft.example", "https://evil.example", "challenge");
+        assert!(matches!(
+            relying_party.verify_registration("challenge", &registration, false),
+            Err(WebauthnError::InvalidClientData(_))
+        ));
+
+        let registration = authenticator.register("evil.example", ORIGIN, "challenge");
+        assert!(matches!(
+            relying_party.verify_registration("challenge", &registration, false),
+            Err(WebauthnError::InvalidAuthenticatorData(_))
+        ));
+
+        // A registration response can't be passed off as an assertion
+        let verified = relying_party
+            .verify_registration(
+                "challenge",
+                &authenticator.register("weft.example", ORIGIN, "challenge"),
+                false,
+            )
+            .unwrap();
+        let mut assertion = authenticator.assert("weft.example", ORIGIN, "challenge");
+        assertion.response.client_data_json = client_data("webauthn.create", "challenge", ORIGIN);
+        assert!(matches!(
+            relying_party.verify_assertion("challenge", &assertion, &verified.public_key, 0, false),
+            Err(WebauthnError::InvalidClientData(_))
+        ));
+    }
+
+    #[test]
+    fn test_rejects_bad_signatures() {
+        let relying_party = relying_party();
+        let mut authenticator = SoftwareAuthenticator::new();
+        let verified = relying_party
+            .verify_registration(
+                "challenge",
+                &authenticator.register("weft.example", ORIGIN, "challenge"),
+                false,
+            )
+            .unwrap();
+
+        // Signed by some other key
+        let impostor_key = SoftwareAuthenticator::new().cose_key();
+        let assertion = authenticator.assert("weft.example", ORIGIN, "challenge");
+        assert!(matches!(
+            relying_party.verify_assertion("challenge", &assertion, &impostor_key, 0, false),
+            Err(WebauthnError::InvalidSignature)
+        ));
+
+        // Signature over different authenticator data
+        let mut assertion = authenticator.assert("weft.example", ORIGIN, "challenge");
+        assertion.response.authenticator_data =
+            encode_base64url(authenticator.authenticator_data("weft.example", false));
+        assert!(matches!(
+            relying_party.verify_assertion("challenge", &assertion, &verified.public_key, 0, false),
+            Err(WebauthnError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn test_rejects_cloned_authenticators() {
+        let relying_party = relying_party();
+        let mut authenticator = SoftwareAuthenticator::new();
+        let verified = relying_party
+            .verify_registration(
+                "challenge",
+                &authenticator.register("weft.example", ORIGIN, "challenge"),
+                false,
+            )
+            .unwrap();
+
+        let assertion = authenticator.assert("weft.example", ORIGIN, "challenge");
+        assert!(matches!(
+            relying_party.verify_assertion(
+                "challenge",
+                &assertion,
+                &verified.public_key,
+                10,
+                false
+            ),
+            Err(WebauthnError::ClonedAuthenticator)
+        ));
+    }
+
+    #[test]
+    fn test_user_verification() {
+        let relying_party = relying_party();
+        let mut authenticator = SoftwareAuthenticator::new();
+        let verified = relying_party
+            .verify_registration(
+                "challenge",
+                &authenticator.register("weft.example", ORIGIN, "challenge"),
+                false,
+            )
+            .unwrap();
+
+        authenticator.user_verification = false;
+        let assertion = authenticator.assert("weft.example", ORIGIN, "challenge");
+        assert!(matches!(
+            relying_party.verify_assertion("challenge", &assertion, &verified.public_key, 0, true),
+            Err(WebauthnError::UserNotVerified)
+        ));
+        let assertion = authenticator.assert("weft.example", ORIGIN, "challenge");
+        assert!(
+            !relying_party
+                .verify_assertion("challenge", &assertion, &verified.public_key, 0, false)
+                .unwrap()
+                .user_verified
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database"]
+    async fn test_finish_authentication() {
+        let db_pool = test_db::pool().await;
+        let user = test_db::create_user(&db_pool).await;
+        let mut authenticator = SoftwareAuthenticator::new();
+        let passkey = register_passkey(&db_pool, &mut authenticator, user.id()).await;
+        assert!(passkey.user_verified);
+
+        let credential = assertion(&db_pool, &mut authenticator, Some(user.id())).await;
+        assert_eq!(
+            Passkey::finish_authentication(&db_pool, &relying_party(), &credential, true)
+                .await
+                .unwrap(),
+            *user.id()
+        );
+        let passkeys = Passkey::list(&db_pool, user.id()).await.unwrap();
+        assert!(passkeys[0].last_used_at.is_some());
+
+        // Challenges are good for one assertion only
+        assert!(matches!(
+            Passkey::finish_authentication(&db_pool, &relying_party(), &credential, true).await,
+            Err(WebauthnError::UnknownChallenge)
+        ));
+
+        // Passwordless, the user isn't known until the passkey says who
+        let credential = assertion(&db_pool, &mut authenticator, None).await;
+        assert_eq!(
+            Passkey::finish_authentication(&db_pool, &relying_party(), &credential, true)
+                .await
+                .unwrap(),
+            *user.id()
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database"]
+    async fn test_challenges_are_bound() {
+        let db_pool = test_db::pool().await;
+        let user = test_db::create_user(&db_pool).await;
+        let other_user = test_db::create_user(&db_pool).await;
+        let mut authenticator = SoftwareAuthenticator::new();
+        register_passkey(&db_pool, &mut authenticator, user.id()).await;
+
+        // Assertions only count as answers to a challenge we made...
+        let credential = authenticator.assert(RP_ID, ORIGIN, "made-up-challenge");
+        assert!(matches!(
+            Passkey::finish_authentication(&db_pool, &relying_party(), &credential, false).await,
+            Err(WebauthnError::UnknownChallenge)
+        ));
+
+        // ...for logging in...
+        let options = Passkey::start_registration(&db_pool, &relying_party(), user.id(), "test")
+            .await
+            .unwrap();
+        let credential = authenticator.assert(RP_ID, ORIGIN, &options.challenge);
+        assert!(matches!(
+            Passkey::finish_authentication(&db_pool, &relying_party(), &credential, false).await,
+            Err(WebauthnError::UnknownChallenge)
+        ));
+
+        // ...as the user whose login it is
+        let credential = assertion(&db_pool, &mut authenticator, Some(other_user.id())).await;
+        assert!(matches!(
+            Passkey::finish_authentication(&db_pool, &relying_party(), &credential, false).await,
+            Err(WebauthnError::UnknownCredential)
+        ));
+
+        // And the user handle has to be the passkey's user
+        let mut credential = assertion(&db_pool, &mut authenticator, None).await;
+        credential.response.user_handle = Some(encode_base64url(other_user.id().as_bytes()));
+        assert!(matches!(
+            Passkey::finish_authentication(&db_pool, &relying_party(), &credential, false).await,
+            Err(WebauthnError::UnknownCredential)
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database"]
+    async fn test_passkeys_without_user_verification() {
+        let db_pool = test_db::pool().await;
+        let user = test_db::create_user(&db_pool).await;
+        let mut authenticator = SoftwareAuthenticator::new();
+        authenticator.user_verification = false;
+        let passkey = register_passkey(&db_pool, &mut authenticator, user.id()).await;
+        assert!(!passkey.user_verified);
+
+        // Good enough for a second factor, not for a login of its own
+        let credential = assertion(&db_pool, &mut authenticator, Some(user.id())).await;
+        assert!(
+            Passkey::finish_authentication(&db_pool, &relying_party(), &credential, false)
+                .await
+                .is_ok()
+        );
+        let credential = assertion(&db_pool, &mut authenticator, None).await;
+        assert!(matches!(
+            Passkey::finish_authentication(&db_pool, &relying_party(), &credential, true).await,
+            Err(WebauthnError::UserNotVerified)
+        ));
+
+        // Until it does verify its user
+        authenticator.user_verification = true;
+        let credential = assertion(&db_pool, &mut authenticator, None).await;
+        assert!(
+            Passkey::finish_authentication(&db_pool, &relying_party(), &credential, true)
+                .await
+                .is_ok()
+        );
+        assert!(Passkey::list(&db_pool, user.id()).await.unwrap()[0].user_verified);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database"]
+    async fn test_cloned_authenticators_race() {
+        let db_pool = test_db::pool().await;
+        let user = test_db::create_user(&db_pool).await;
+        let mut authenticator = SoftwareAuthenticator::new();
+        register_passkey(&db_pool, &mut authenticator, user.id()).await;
+
+        // Two copies of the same authenticator, with the same counter
+        let credential = assertion(&db_pool, &mut authenticator, Some(user.id())).await;
+        authenticator.sign_count -= 1;
+        let clone_credential = assertion(&db_pool, &mut authenticator, Some(user.id())).await;
+
+        let relying_party = relying_party();
+        let (first, second) = futures::join!(
+            Passkey::finish_authentication(&db_pool, &relying_party, &credential, false),
+            Passkey::finish_authentication(&db_pool, &relying_party, &clone_credential, false),
+        );
+        assert!(first.is_ok() != second.is_ok());
+        assert!(matches!(
+            first.and(second),
+            Err(WebauthnError::ClonedAuthenticator)
+        ));
+    }
+
+    #[test]
+    fn test_passkey_name() {
+        assert_eq!(passkey_name(None), DEFAULT_PASSKEY_NAME);
+        assert_eq!(passkey_name(Some("  ")), DEFAULT_PASSKEY_NAME);
+        assert_eq!(passkey_name(Some(" Laptop ")), "Laptop");
+        assert_eq!(
+            passkey_name(Some(&"x".repeat(100))).len(),
+            MAX_PASSKEY_NAME_LENGTH
+        );
+    }
+}
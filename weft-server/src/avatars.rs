@@ -0,0 +1,335 @@
+//! Turning uploaded pictures into avatars: a square crop of the middle of
+//! the picture, in each of `AVATAR_SIZES`.
+//!
+//! Pictures are decoded and encoded again from their pixels alone, so none
+//! of the metadata of the upload (EXIF, with its camera details and GPS
+//! coordinates) makes it into the avatar. The EXIF orientation is applied
+//! first, or pictures taken with phones would come out sideways.
+
+use std::{fmt, io::Cursor};
+
+use image::{imageops::FilterType, DynamicImage, GenericImageView, ImageFormat};
+use ring::digest;
+
+use crate::rejections::FieldError;
+
+/// Side, in pixels, of each version of an avatar
+pub const AVATAR_SIZES: [u32; 3] = [64, 128, 512];
+/// Size of the version profiles link to
+pub const PROFILE_AVATAR_SIZE: u32 = 128;
+pub const MAX_UPLOAD_BYTES: u64 = 10 * 1024 * 1024;
+/// Decoding is refused beyond this many pixels, so a tiny upload can't
+/// claim gigabytes of memory
+const MAX_PIXELS: u64 = 50_000_000;
+const JPEG_QUALITY: u8 = 85;
+
+#[derive(Debug, thiserror::Error)]
+pub enum AvatarError {
+    UnsupportedFormat,
+    TooLarge,
+    InvalidImage(String),
+}
+
+impl fmt::Display for AvatarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AvatarError::UnsupportedFormat => write!(f, "Avatar must be a PNG, JPEG or WebP image"),
+            AvatarError::TooLarge => write!(f, "Avatar image is too large"),
+            AvatarError::InvalidImage(reason) => write!(f, "Invalid avatar image: {}", reason),
+        }
+    }
+}
+
+impl AvatarError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AvatarError::UnsupportedFormat => "unsupported_format",
+            AvatarError::TooLarge => "too_large",
+            AvatarError::InvalidImage(_) => "invalid",
+        }
+    }
+}
+
+impl From<AvatarError> for FieldError {
+    fn from(error: AvatarError) -> Self {
+        FieldError {
+            field: "avatar",
+            code: error.code(),
+            message: error.to_string(),
+        }
+    }
+}
+
+impl From<image::ImageError> for AvatarError {
+    fn from(error: image::ImageError) -> Self {
+        AvatarError::InvalidImage(error.to_string())
+    }
+}
+
+/// Every version of an avatar, ready to be stored
+#[derive(Debug)]
+pub struct Avatar {
+    /// Hex digest of the largest version, to name the avatar after its
+    /// content
+    pub hash: String,
+    /// `png` when the picture has transparency, `jpg` otherwise
+    pub extension: &'static str,
+    /// Side and encoded image of each version, smallest first
+    pub versions: Vec<(u32, Vec<u8>)>,
+}
+
+impl Avatar {
+    /// Crop and resize an uploaded PNG, JPEG or WebP picture. This is slow
+    /// work, better done on the blocking thread pool.
+    pub fn from_upload(upload: &[u8]) -> Result<Self, AvatarError> {
+        let format = match image::guess_format(upload) {
+            Ok(format @ ImageFormat::Png)
+            | Ok(format @ ImageFormat::Jpeg)
+            | Ok(format @ ImageFormat::WebP) => format,
+            _ => return Err(AvatarError::UnsupportedFormat),
+        };
+
+        let (width, height) =
+            image::io::Reader::with_format(Cursor::new(upload), format).into_dimensions()?;
+        if u64::from(width) * u64::from(height) > MAX_PIXELS {
+            return Err(AvatarError::TooLarge);
+        }
+
+        let mut picture = image::load_from_memory_with_format(upload, format)?;
+        if format == ImageFormat::Jpeg {
+            if let Some(orientation) = exif_orientation(upload) {
+                picture = orient(picture, orientation);
+            }
+        }
+
+        let (width, height) = picture.dimensions();
+        let side = width.min(height);
+        if side == 0 {
+            return Err(AvatarError::InvalidImage("Empty image".into()));
+        }
+        let square = picture.crop_imm((width - side) / 2, (height - side) / 2, side, side);
+
+        let has_alpha = square.color().has_alpha();
+        let versions = AVATAR_SIZES
+            .iter()
+            .map(|&size| {
+                let mut encoded = vec![];
+                let resized = square.resize_exact(size, size, FilterType::Lanczos3);
+                if has_alpha {
+                    resized.write_to(&mut encoded, image::ImageOutputFormat::Png)?;
+                } else {
+                    resized.write_to(&mut encoded, image::ImageOutputFormat::Jpeg(JPEG_QUALITY))?;
+                }
+                Ok((size, encoded))
+            })
+            .collect::<Result<Vec<_>, AvatarError>>()?;
+
+        let largest = &versions[versions.len() - 1].1;
+        let hash = digest::digest(&digest::SHA256, largest)
+            .as_ref()
+            .iter()
+            .take(16)
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+
+        Ok(Self {
+            hash,
+            extension: if has_alpha { "png" } else { "jpg" },
+            versions,
+        })
+    }
+}
+
+/// Turn `picture` upright according to an EXIF orientation tag value
+fn orient(picture: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => picture.fliph(),
+        3 => picture.rotate180(),
+        4 => picture.flipv(),
+        5 => picture.rotate90().fliph(),
+        6 => picture.rotate90(),
+        7 => picture.rotate270().fliph(),
+        8 => picture.rotate270(),
+        _ => picture,
+    }
+}
+
+/// The orientation tag of a JPEG's EXIF data, if it has any
+fn exif_orientation(jpeg: &[u8]) -> Option<u16> {
+    const ORIENTATION_TAG: u16 = 0x0112;
+
+    // Walk the segments before the image data, looking for APP1
+    let mut position = 2;
+    let exif = loop {
+        if jpeg.get(position)? != &0xFF {
+            return None;
+        }
+        let marker = *jpeg.get(position + 1)?;
+        let length = usize::from(u16::from_be_bytes([
+            *jpeg.get(position + 2)?,
+            *jpeg.get(position + 3)?,
+        ]));
+        let segment = jpeg.get(position + 4..position + 2 + length)?;
+        match marker {
+            0xE1 if segment.starts_with(b"Exif\0\0") => break &segment[6..],
+            // Start of scan: no more metadata from here on
+            0xDA => return None,
+            _ => position += 2 + length,
+        }
+    };
+
+    let big_endian = match exif.get(0..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let u16_at = |offset: usize| {
+        let bytes = [*exif.get(offset)?, *exif.get(offset + 1)?];
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let u32_at = |offset: usize| {
+        let bytes = [
+            *exif.get(offset)?,
+            *exif.get(offset + 1)?,
+            *exif.get(offset + 2)?,
+            *exif.get(offset + 3)?,
+        ];
+        Some(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    };
+
+    let first_directory = u32_at(4)? as usize;
+    let entries = u16_at(first_directory)?;
+    (0..usize::from(entries))
+        .map(|entry| first_directory + 2 + entry * 12)
+        .find(|&entry| u16_at(entry) == Some(ORIENTATION_TAG))
+        .and_then(|entry| u16_at(entry + 8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use image::{ImageOutputFormat, Rgb, RgbImage, Rgba, RgbaImage};
+
+    fn encode(picture: DynamicImage, format: ImageOutputFormat) -> Vec<u8> {
+        let mut encoded = vec![];
+        picture.write_to(&mut encoded, format).unwrap();
+        encoded
+    }
+
+    /// A JPEG with nothing but an EXIF orientation tag before `jpeg`'s own
+    /// segments
+    fn with_orientation(jpeg: &[u8], orientation: u16) -> Vec<u8> {
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01".to_vec();
+        exif.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1]);
+        exif.extend_from_slice(&orientation.to_be_bytes());
+        exif.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+
+        let mut tagged = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        tagged.extend_from_slice(&(exif.len() as u16 + 2).to_be_bytes());
+        tagged.extend_from_slice(&exif);
+        tagged.extend_from_slice(&jpeg[2..]);
+        tagged
+    }
+
+    #[test]
+    fn test_crops_and_resizes() {
+        let picture = RgbImage::from_fn(300, 200, |x, _| {
+            if !(50..250).contains(&x) {
+                Rgb([255, 0, 0])
+            } else {
+                Rgb([0, 0, 255])
+            }
+        });
+        let upload = encode(DynamicImage::ImageRgb8(picture), ImageOutputFormat::Png);
+
+        let avatar = Avatar::from_upload(&upload).unwrap();
+        assert_eq!(avatar.extension, "jpg");
+        assert_eq!(avatar.hash.len(), 32);
+        assert_eq!(
+            avatar
+                .versions
+                .iter()
+                .map(|(size, _)| *size)
+                .collect::<Vec<_>>(),
+            AVATAR_SIZES.to_vec()
+        );
+        for (size, encoded) in &avatar.versions {
+            let version = image::load_from_memory(encoded).unwrap();
+            assert_eq!(version.dimensions(), (*size, *size));
+            // Only the blue middle is left
+            let corner = version.to_rgb8().get_pixel(0, 0).0;
+            assert!(corner[2] > 200 && corner[0] < 50, "{:?}", corner);
+        }
+
+        // Same picture, same name
+        assert_eq!(Avatar::from_upload(&upload).unwrap().hash, avatar.hash);
+    }
+
+    #[test]
+    fn test_keeps_transparency() {
+        let picture = RgbaImage::from_pixel(10, 10, Rgba([0, 0, 0, 0]));
+        let upload = encode(DynamicImage::ImageRgba8(picture), ImageOutputFormat::Png);
+        let avatar = Avatar::from_upload(&upload).unwrap();
+        assert_eq!(avatar.extension, "png");
+        assert!(image::load_from_memory(&avatar.versions[0].1)
+            .unwrap()
+            .color()
+            .has_alpha());
+    }
+
+    #[test]
+    fn test_applies_and_strips_orientation() {
+        let picture = RgbImage::from_fn(40, 20, |x, _| {
+            if x < 20 {
+                Rgb([255, 255, 255])
+            } else {
+                Rgb([0, 0, 0])
+            }
+        });
+        let jpeg = encode(
+            DynamicImage::ImageRgb8(picture),
+            ImageOutputFormat::Jpeg(95),
+        );
+        assert_eq!(exif_orientation(&jpeg), None);
+
+        let upload = with_orientation(&jpeg, 6);
+        assert_eq!(exif_orientation(&upload), Some(6));
+
+        let avatar = Avatar::from_upload(&upload).unwrap();
+        // Turned upright, the white half is at the top
+        let smallest = image::load_from_memory(&avatar.versions[0].1)
+            .unwrap()
+            .to_rgb8();
+        assert!(smallest.get_pixel(32, 2).0[0] > 200);
+        assert!(smallest.get_pixel(32, 61).0[0] < 50);
+        for (_, encoded) in &avatar.versions {
+            assert_eq!(exif_orientation(encoded), None);
+            assert!(!encoded.windows(4).any(|window| window == b"Exif"));
+        }
+    }
+
+    #[test]
+    fn test_rejects_other_formats() {
+        assert!(matches!(
+            Avatar::from_upload(b"GIF89a\x01\x00\x01\x00"),
+            Err(AvatarError::UnsupportedFormat)
+        ));
+        assert!(matches!(
+            Avatar::from_upload(b"not an image at all"),
+            Err(AvatarError::UnsupportedFormat)
+        ));
+        assert!(matches!(
+            Avatar::from_upload(b"\x89PNG\r\n\x1a\n truncated"),
+            Err(AvatarError::InvalidImage(_))
+        ));
+    }
+}
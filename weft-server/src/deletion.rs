@@ -0,0 +1,555 @@
+//! Self-service account deletion. Users ask for their account to go away,
+//! and it does once the grace period is over, unless they change their mind
+//! before that. Asking takes their password or second factor again, and
+//! they're emailed a link to cancel, in case it wasn't them.
+//!
+//! Purging an account deletes its stored media and data exports first, and
+//! everything in the database after, so a purge that fails halfway leaves
+//! the user around for the next attempt to find. Every step is fine to run
+//! again.
+
+use std::{env, fmt, sync::Arc};
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Uuid, Row};
+
+use crate::{
+    auth::{
+        opaque::{generate_token, hash_token},
+        AuditEventKind, ClientInfo, EmailAddress, NewAuditEvent, Throttle, ThrottleKey,
+    },
+    mailer::Message,
+    storage::{self, Storage, StorageError},
+};
+
+const DEFAULT_GRACE_DAYS: i64 = 14;
+/// Accounts purged per run, so a backlog doesn't hold a connection forever
+const PURGE_BATCH_SIZE: i64 = 100;
+const PURGE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+/// Audit event details that may say something about a person rather than
+/// about what happened
+const PERSONAL_DETAILS: &[&str] = &["email_address", "name", "reason"];
+
+#[derive(Debug, thiserror::Error)]
+pub enum DeletionError {
+    InvalidConfiguration(String),
+    RandomError,
+    DatabaseError(sqlx::Error),
+    StorageError(StorageError),
+}
+
+impl fmt::Display for DeletionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeletionError::InvalidConfiguration(reason) => {
+                write!(f, "Invalid account deletion configuration: {}", reason)
+            }
+            DeletionError::RandomError => write!(f, "Could not generate cancel token"),
+            DeletionError::DatabaseError(error) => write!(f, "Database error: {:?}", error),
+            DeletionError::StorageError(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl From<sqlx::Error> for DeletionError {
+    fn from(error: sqlx::Error) -> Self {
+        DeletionError::DatabaseError(error)
+    }
+}
+
+impl From<StorageError> for DeletionError {
+    fn from(error: StorageError) -> Self {
+        DeletionError::StorageError(error)
+    }
+}
+
+/// How long accounts stick around after their users ask for them to be
+/// deleted
+#[derive(Debug, Clone)]
+pub struct DeletionPolicy {
+    grace_period: Duration,
+}
+
+impl Default for DeletionPolicy {
+    fn default() -> Self {
+        Self {
+            grace_period: Duration::days(DEFAULT_GRACE_DAYS),
+        }
+    }
+}
+
+impl DeletionPolicy {
+    pub fn new(grace_period: Duration) -> Self {
+        Self { grace_period }
+    }
+
+    /// Reads the grace period, in days, from `WEFT_DELETION_GRACE_DAYS`
+    pub fn new_from_env() -> Result<Self, DeletionError> {
+        match env::var("WEFT_DELETION_GRACE_DAYS") {
+            Ok(days) => days
+                .parse::<u32>()
+                .map(|days| Self::new(Duration::days(days.into())))
+                .map_err(|_| DeletionError::InvalidConfiguration(days)),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    pub fn purge_after(&self, requested_at: DateTime<Utc>) -> DateTime<Utc> {
+        requested_at + self.grace_period
+    }
+}
+
+/// Body of an account deletion request: whichever of their password and
+/// TOTP code the user has
+#[derive(Default, Deserialize)]
+pub struct ConfirmDeletion {
+    pub password: Option<String>,
+    pub code: Option<String>,
+}
+
+impl fmt::Debug for ConfirmDeletion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConfirmDeletion")
+            .field("password", &"[~password~redacted~]")
+            .field("code", &"[~code~redacted~]")
+            .finish()
+    }
+}
+
+/// Body of a request canceling a deletion through the emailed link
+#[derive(Deserialize)]
+pub struct CancelDeletion {
+    pub token: String,
+}
+
+impl fmt::Debug for CancelDeletion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CancelDeletion")
+            .field("token", &"[~token~redacted~]")
+            .finish()
+    }
+}
+
+/// A pending deletion, as shown to the user it's about
+#[derive(Debug, Serialize)]
+pub struct ScheduledDeletion {
+    pub requested_at: DateTime<Utc>,
+    /// Until when it can be canceled
+    pub purge_after: DateTime<Utc>,
+}
+
+impl ScheduledDeletion {
+    fn from_row(row: &sqlx::postgres::PgRow) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            requested_at: row.try_get("requested_at")?,
+            purge_after: row.try_get("purge_after")?,
+        })
+    }
+
+    /// Schedule the deletion of a user's account, returning the email to
+    /// send out with a link to cancel it. Asking again doesn't push back an
+    /// already scheduled deletion, but replaces the link.
+    pub async fn schedule(
+        db_pool: &sqlx::PgPool,
+        policy: &DeletionPolicy,
+        user_id: &Uuid,
+        email_address: &EmailAddress,
+    ) -> Result<(Self, Message), DeletionError> {
+        let token = generate_token().map_err(|_| DeletionError::RandomError)?;
+        let requested_at = Utc::now();
+        let deletion = sqlx::query(
+            r#"
+INSERT INTO account_deletions (user_id, requested_at, purge_after, cancel_token_hash)
+VALUES ($1, $2, $3, $4)
+ON CONFLICT (user_id) DO UPDATE SET cancel_token_hash = EXCLUDED.cancel_token_hash
+RETURNING requested_at, purge_after;"#,
+        )
+        .bind(user_id)
+        .bind(requested_at)
+        .bind(policy.purge_after(requested_at))
+        .bind(hash_token(&token))
+        .fetch_one(db_pool)
+        .await
+        .and_then(|row| Self::from_row(&row))?;
+
+        let message = deletion.message(email_address, &token);
+        Ok((deletion, message))
+    }
+
+    fn message(&self, email_address: &EmailAddress, token: &str) -> Message {
+        Message {
+            to: email_address.clone(),
+            subject: "Your account is going to be deleted".into(),
+            body: format!(
+                "Your account and everything in it will be deleted on {} UTC. If you didn't ask for this, or changed your mind, follow this link to keep your account:\n\n{}/cancel-deletion?token={}",
+                self.purge_after.format("%Y-%m-%d %H:%M"),
+                crate::auth::app_url().trim_end_matches('/'),
+                token,
+            ),
+        }
+    }
+
+    /// The user's pending deletion, if any
+    pub async fn get(db_pool: &sqlx::PgPool, user_id: &Uuid) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query(
+            r#"
+SELECT requested_at, purge_after FROM account_deletions
+WHERE user_id = $1 AND purged_at IS NULL;"#,
+        )
+        .bind(user_id)
+        .fetch_optional(db_pool)
+        .await?
+        .as_ref()
+        .map(Self::from_row)
+        .transpose()
+    }
+
+    /// Cancel the user's pending deletion. Once the grace period is over
+    /// it's too late, and `false` is returned, same as when there's nothing
+    /// to cancel.
+    pub async fn cancel(db_pool: &sqlx::PgPool, user_id: &Uuid) -> Result<bool, sqlx::Error> {
+        sqlx::query(
+            r#"
+DELETE FROM account_deletions
+WHERE user_id = $1 AND purged_at IS NULL AND purge_after > now();"#,
+        )
+        .bind(user_id)
+        .execute(db_pool)
+        .await
+        .map(|done| done.rows_affected() > 0)
+    }
+
+    /// Cancel the deletion `token` was emailed about, on the same terms as
+    /// `cancel`. Returns whose deletion it was.
+    pub async fn cancel_with_token(
+        db_pool: &sqlx::PgPool,
+        token: &str,
+    ) -> Result<Option<Uuid>, sqlx::Error> {
+        match sqlx::query(
+            r#"
+DELETE FROM account_deletions
+WHERE cancel_token_hash = $1 AND purged_at IS NULL AND purge_after > now()
+RETURNING user_id;"#,
+        )
+        .bind(hash_token(token))
+        .fetch_optional(db_pool)
+        .await?
+        {
+            Some(row) => Ok(Some(row.try_get(0)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.purge_after <= Utc::now()
+    }
+}
+
+/// Delete everything about user `user_id`: their stored media, the user
+/// along with everything referencing them, and the personal data in the
+/// audit events about them. The events themselves stay, and recording the
+/// deletion is up to the caller. Returns `false` if there was no such user
+/// (anymore).
+pub async fn purge_account(
+    db_pool: &sqlx::PgPool,
+    storage: Arc<dyn Storage>,
+    user_id: &Uuid,
+) -> Result<bool, DeletionError> {
+    purge_media(storage, user_id).await?;
+
+    let mut transaction = db_pool.begin().await?;
+    let email_address: Option<String> =
+        match sqlx::query("SELECT email_address FROM users WHERE id = $1 FOR UPDATE;")
+            .bind(user_id)
+            .fetch_optional(&mut transaction)
+            .await?
+        {
+            Some(row) => Some(row.try_get(0)?),
+            None => None,
+        };
+
+    // Only purging accounts gets past the append-only trigger, and only to
+    // blank out personal data
+    sqlx::query("SELECT set_config('weft.redacting_audit_events', 'on', true);")
+        .execute(&mut transaction)
+        .await?;
+    // What they did, and what whoever it was tried on their account
+    sqlx::query(
+        r#"
+UPDATE audit_events
+SET ip_address = NULL, user_agent = NULL, details = details - $2::text[]
+WHERE actor_id = $1
+    OR (actor_id IS NULL AND subject_id = $1)
+    OR (kind = 'login_failed' AND subject_id IS NULL
+        AND lower(details->>'email_address') = lower($3));"#,
+    )
+    .bind(user_id)
+    .bind(PERSONAL_DETAILS)
+    .bind(&email_address)
+    .execute(&mut transaction)
+    .await?;
+    // What others did to them. Where those others were is none of this
+    // user's business.
+    sqlx::query(
+        r#"
+UPDATE audit_events SET details = details - $2::text[]
+WHERE subject_id = $1 AND actor_id <> $1;"#,
+    )
+    .bind(user_id)
+    .bind(PERSONAL_DETAILS)
+    .execute(&mut transaction)
+    .await?;
+
+    let mut throttle_keys = vec![ThrottleKey::MfaAccount(*user_id)];
+    if let Some(email_address) = email_address
+        .as_deref()
+        .map(|email_address| EmailAddress::from_stored(email_address.to_string()))
+    {
+        throttle_keys.push(ThrottleKey::LoginAccount(email_address));
+    }
+    Throttle::forget_within(&mut transaction, &throttle_keys).await?;
+
+    // Sessions, tokens, credentials, identities and settings go along
+    sqlx::query("DELETE FROM users WHERE id = $1;")
+        .bind(user_id)
+        .execute(&mut transaction)
+        .await?;
+    sqlx::query(
+        "UPDATE account_deletions SET purged_at = now(), last_error = NULL WHERE user_id = $1;",
+    )
+    .bind(user_id)
+    .execute(&mut transaction)
+    .await?;
+    transaction.commit().await?;
+    Ok(email_address.is_some())
+}
+
+/// Delete the files stored for user `user_id`: their avatars and exports.
+///
+/// There is no videos table yet, so no videos to delete either. Once videos
+/// are stored, their rows, original media and thumbnails have to go here
+/// too, before the user row they hang off is deleted.
+async fn purge_media(storage: Arc<dyn Storage>, user_id: &Uuid) -> Result<(), DeletionError> {
+    let prefixes = vec![
+        format!("avatars/{}", user_id),
+        format!("exports/{}", user_id),
+    ];
+    storage::in_background(storage, move |storage| {
+        prefixes
+            .iter()
+            .try_for_each(|prefix| storage.delete(prefix))
+    })
+    .await?;
+    Ok(())
+}
+
+/// Purge the accounts whose grace period is over. Those that fail are left
+/// for the next run. Returns how many were purged.
+pub async fn purge_due(
+    db_pool: &sqlx::PgPool,
+    storage: Arc<dyn Storage>,
+) -> Result<usize, DeletionError> {
+    let due = sqlx::query(
+        r#"
+SELECT user_id FROM account_deletions
+WHERE purged_at IS NULL AND purge_after <= now()
+ORDER BY purge_after
+LIMIT $1;"#,
+    )
+    .bind(PURGE_BATCH_SIZE)
+    .fetch_all(db_pool)
+    .await?;
+
+    let mut purged = 0;
+    for row in due {
+        let user_id: Uuid = row.try_get(0)?;
+        match purge_account(db_pool, storage.clone(), &user_id).await {
+            Ok(existed) => {
+                purged += 1;
+                if existed {
+                    record_purge(db_pool, &user_id).await;
+                }
+            }
+            Err(error) => {
+                log::warn!("Could not purge user {}: {}", user_id, error);
+                sqlx::query(
+                    r#"
+UPDATE account_deletions SET attempts = attempts + 1, last_error = $2
+WHERE user_id = $1;"#,
+                )
+                .bind(user_id)
+                .bind(error.to_string())
+                .execute(db_pool)
+                .await?;
+            }
+        }
+    }
+    Ok(purged)
+}
+
+async fn record_purge(db_pool: &sqlx::PgPool, user_id: &Uuid) {
+    if let Err(error) = NewAuditEvent::new(AuditEventKind::UserDeleted, &ClientInfo::default())
+        .with_subject(user_id)
+        .with_details(serde_json::json!({ "purged": true }))
+        .record(db_pool)
+        .await
+    {
+        log::error!("Could not record purge of user {}: {}", user_id, error);
+    }
+}
+
+/// Keep purging due accounts, every `PURGE_INTERVAL`
+pub async fn purge_periodically(db_pool: sqlx::PgPool, storage: Arc<dyn Storage>) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match purge_due(&db_pool, storage.clone()).await {
+            Ok(0) => {}
+            Ok(purged) => log::info!("Purged {} deleted account(s)", purged),
+            Err(error) => log::error!("Could not purge deleted accounts: {}", error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{models::User, storage::MemoryStorage, test_db};
+
+    fn client() -> ClientInfo {
+        ClientInfo {
+            user_agent: Some("Test Browser".into()),
+            ip_address: Some("192.0.2.1".into()),
+            request_id: None,
+        }
+    }
+
+    #[test]
+    fn test_grace_period() {
+        let requested_at = Utc::now();
+        assert_eq!(
+            DeletionPolicy::default().purge_after(requested_at),
+            requested_at + Duration::days(DEFAULT_GRACE_DAYS)
+        );
+        assert_eq!(
+            DeletionPolicy::new(Duration::zero()).purge_after(requested_at),
+            requested_at
+        );
+    }
+
+    #[test]
+    fn test_is_due() {
+        let requested_at = Utc::now() - Duration::days(3);
+        let deletion = |grace_days| ScheduledDeletion {
+            requested_at,
+            purge_after: DeletionPolicy::new(Duration::days(grace_days)).purge_after(requested_at),
+        };
+        assert!(deletion(1).is_due());
+        assert!(!deletion(7).is_due());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database"]
+    async fn test_purge_is_resumable() {
+        let db_pool = test_db::pool().await;
+        let user = test_db::create_user(&db_pool).await;
+        let user_id = *user.id();
+        let storage = Arc::new(MemoryStorage::default());
+        storage
+            .put(&format!("avatars/{}/64.jpg", user_id), b"avatar")
+            .unwrap();
+        ScheduledDeletion::schedule(
+            &db_pool,
+            &DeletionPolicy::new(Duration::zero()),
+            &user_id,
+            user.email_address(),
+        )
+        .await
+        .unwrap();
+        NewAuditEvent::new(AuditEventKind::LoginSucceeded, &client())
+            .with_user(&user_id)
+            .record(&db_pool)
+            .await
+            .unwrap();
+
+        // As if a previous run died right after deleting the media
+        storage.delete(&format!("avatars/{}", user_id)).unwrap();
+        assert!(purge_account(&db_pool, storage.clone(), &user_id)
+            .await
+            .unwrap());
+        assert!(storage.keys().is_empty());
+        assert!(User::get_by_id(&db_pool, &user_id).await.unwrap().is_none());
+        let (ip_address, user_agent): (Option<String>, Option<String>) =
+            sqlx::query_as("SELECT ip_address, user_agent FROM audit_events WHERE actor_id = $1;")
+                .bind(user_id)
+                .fetch_one(&db_pool)
+                .await
+                .unwrap();
+        assert_eq!((ip_address, user_agent), (None, None));
+
+        // Running it again finds nothing left to do
+        assert!(!purge_account(&db_pool, storage.clone(), &user_id)
+            .await
+            .unwrap());
+        assert!(ScheduledDeletion::get(&db_pool, &user_id)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database"]
+    async fn test_audit_events_are_append_only() {
+        let db_pool = test_db::pool().await;
+        let user = test_db::create_user(&db_pool).await;
+        NewAuditEvent::new(AuditEventKind::LoginSucceeded, &client())
+            .with_user(user.id())
+            .with_details(serde_json::json!({ "method": "password", "name": "Test User" }))
+            .record(&db_pool)
+            .await
+            .unwrap();
+
+        let error = sqlx::query("UPDATE audit_events SET ip_address = NULL WHERE actor_id = $1;")
+            .bind(user.id())
+            .execute(&db_pool)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("append-only"));
+
+        // Redacting lets personal data go, and nothing else
+        let mut transaction = db_pool.begin().await.unwrap();
+        sqlx::query("SELECT set_config('weft.redacting_audit_events', 'on', true);")
+            .execute(&mut transaction)
+            .await
+            .unwrap();
+        sqlx::query(
+            r#"
+UPDATE audit_events SET ip_address = NULL, details = details - 'name'
+WHERE actor_id = $1;"#,
+        )
+        .bind(user.id())
+        .execute(&mut transaction)
+        .await
+        .unwrap();
+        let error = sqlx::query("UPDATE audit_events SET kind = 'logout' WHERE actor_id = $1;")
+            .bind(user.id())
+            .execute(&mut transaction)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("append-only"));
+    }
+
+    #[test]
+    fn test_message_contains_cancel_link() {
+        let deletion = ScheduledDeletion {
+            requested_at: Utc::now(),
+            purge_after: Utc::now(),
+        };
+        let email_address = EmailAddress::from_stored("some@email.address".into());
+        let message = deletion.message(&email_address, "some-token");
+        assert_eq!(message.to, email_address);
+        assert!(message.body.contains("/cancel-deletion?token=some-token"));
+    }
+}
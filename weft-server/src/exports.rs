@@ -0,0 +1,934 @@
+//! Exports of everything we hold about a user, for them to take with them.
+//!
+//! Asking for an export starts building it in the background: the user's
+//! data is written to storage as a set of JSON files, next to which their
+//! media is pointed at where it's kept already, and they get an email with
+//! a signed link once it's done. Nothing is zipped until the link is
+//! followed; the archive is then streamed out a chunk at a time, so neither
+//! it nor any file in it ever has to fit anywhere as a whole.
+//!
+//! Videos aren't stored anywhere yet, so exports can't hold them either:
+//! video metadata, original media, thumbnails and comments are all missing.
+//! They belong in `collect` once there is a videos table to read them from.
+
+use std::{env, fmt, str::FromStr, sync::Arc};
+
+use chrono::{DateTime, Duration, Utc};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Uuid, Row};
+use url::Url;
+use warp::hyper::{body::Bytes, Body};
+
+use crate::{
+    auth::{AuditEvent, AuditQuery, PersonalAccessToken, UserIdentity},
+    avatars::AVATAR_SIZES,
+    mailer::{send_in_background, Mailer, Message},
+    models,
+    storage::{self, Storage, StorageError},
+    zip::ZipStream,
+};
+
+const DEFAULT_LINK_HOURS: i64 = 48;
+/// Used to derive the key links are signed with from `WEFT_SECRET_KEY`,
+/// when they don't have one of their own
+const LINK_KEY_CONTEXT: &[u8] = b"weft-export-link-key";
+const MAX_ATTEMPTS: i32 = 3;
+const AUDIT_PAGE_SIZE: i64 = 200;
+/// How much of a file is read from storage at a time when streaming out an
+/// archive
+const CHUNK_SIZE: usize = 256 * 1024;
+/// Exports listed for a user, newest first
+const MAX_LISTED: i64 = 20;
+/// How often stalled exports are retried and expired ones removed
+const WORKER_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+#[derive(Debug, thiserror::Error)]
+pub enum ExportError {
+    InvalidConfiguration(String),
+    DatabaseError(sqlx::Error),
+    StorageError(StorageError),
+    SerializationError(serde_json::Error),
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExportError::InvalidConfiguration(reason) => {
+                write!(f, "Invalid data export configuration: {}", reason)
+            }
+            ExportError::DatabaseError(error) => write!(f, "Database error: {:?}", error),
+            ExportError::StorageError(error) => write!(f, "{}", error),
+            ExportError::SerializationError(error) => {
+                write!(f, "Could not serialize exported data: {}", error)
+            }
+        }
+    }
+}
+
+impl From<sqlx::Error> for ExportError {
+    fn from(error: sqlx::Error) -> Self {
+        ExportError::DatabaseError(error)
+    }
+}
+
+impl From<StorageError> for ExportError {
+    fn from(error: StorageError) -> Self {
+        ExportError::StorageError(error)
+    }
+}
+
+impl From<serde_json::Error> for ExportError {
+    fn from(error: serde_json::Error) -> Self {
+        ExportError::SerializationError(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportStatus {
+    Pending,
+    Ready,
+    /// Gave up after `MAX_ATTEMPTS`
+    Failed,
+}
+
+impl FromStr for ExportStatus {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(ExportStatus::Pending),
+            "ready" => Ok(ExportStatus::Ready),
+            "failed" => Ok(ExportStatus::Failed),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Signs and checks the links archives are downloaded through. Links work
+/// without logging in, for as long as `lifetime`.
+pub struct ExportLinks {
+    key: hmac::Key,
+    base_url: Url,
+    lifetime: Duration,
+}
+
+/// The signed part of a download link
+#[derive(Debug, Deserialize)]
+pub struct SignedLink {
+    pub expires: i64,
+    pub signature: String,
+}
+
+impl ExportLinks {
+    /// `base_url` is where the API is, with a trailing slash
+    pub fn new(secret: &[u8], base_url: Url, lifetime: Duration) -> Self {
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret),
+            base_url,
+            lifetime,
+        }
+    }
+
+    /// Signs with `WEFT_EXPORT_LINK_SECRET`, or else a key derived from
+    /// `WEFT_SECRET_KEY`, for links to `WEFT_PUBLIC_URL` that last
+    /// `WEFT_EXPORT_LINK_HOURS` (48)
+    pub fn new_from_env() -> Result<Self, ExportError> {
+        let secret = match env::var("WEFT_EXPORT_LINK_SECRET") {
+            Ok(secret) => secret.into_bytes(),
+            Err(_) => {
+                let shared = env::var("WEFT_SECRET_KEY").map_err(|_| {
+                    ExportError::InvalidConfiguration(
+                        "WEFT_EXPORT_LINK_SECRET or WEFT_SECRET_KEY".into(),
+                    )
+                })?;
+                hmac::sign(
+                    &hmac::Key::new(hmac::HMAC_SHA256, shared.as_bytes()),
+                    LINK_KEY_CONTEXT,
+                )
+                .as_ref()
+                .to_vec()
+            }
+        };
+
+        let base_url = format!(
+            "{}/",
+            env::var("WEFT_PUBLIC_URL")
+                .as_deref()
+                .unwrap_or("http://127.0.0.1:3030")
+                .trim_end_matches('/')
+        );
+        let base_url =
+            Url::parse(&base_url).map_err(|_| ExportError::InvalidConfiguration(base_url))?;
+
+        let lifetime = match env::var("WEFT_EXPORT_LINK_HOURS") {
+            Ok(hours) => hours
+                .parse::<u32>()
+                .ok()
+                .filter(|hours| *hours > 0)
+                .map(|hours| Duration::hours(hours.into()))
+                .ok_or(ExportError::InvalidConfiguration(hours))?,
+            Err(_) => Duration::hours(DEFAULT_LINK_HOURS),
+        };
+        Ok(Self::new(&secret, base_url, lifetime))
+    }
+
+    fn message(export_id: &Uuid, expires: i64) -> String {
+        format!("{}:{}", export_id, expires)
+    }
+
+    /// A link to the archive of export `export_id`, good until `expires_at`
+    pub fn url(&self, export_id: &Uuid, expires_at: &DateTime<Utc>) -> Url {
+        let expires = expires_at.timestamp();
+        let signature = hmac::sign(&self.key, Self::message(export_id, expires).as_bytes());
+        let mut url = self
+            .base_url
+            .join(&format!("api/v1/user/export/{}/archive.zip", export_id))
+            .expect("Export links are valid URLs");
+        url.query_pairs_mut()
+            .append_pair("expires", &expires.to_string())
+            .append_pair(
+                "signature",
+                &base64::encode_config(signature.as_ref(), base64::URL_SAFE_NO_PAD),
+            );
+        url
+    }
+
+    /// Whether `link` was signed by us for export `export_id`, and is still
+    /// good
+    pub fn verify(&self, export_id: &Uuid, link: &SignedLink) -> bool {
+        if link.expires <= Utc::now().timestamp() {
+            return false;
+        }
+        base64::decode_config(&link.signature, base64::URL_SAFE_NO_PAD)
+            .map(|signature| {
+                hmac::verify(
+                    &self.key,
+                    Self::message(export_id, link.expires).as_bytes(),
+                    &signature,
+                )
+                .is_ok()
+            })
+            .unwrap_or(false)
+    }
+}
+
+/// A file of an export: where it goes in the archive, and where it's kept
+/// in storage
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportEntry {
+    pub path: String,
+    pub key: String,
+}
+
+/// An export, as shown to the user it's about
+#[derive(Debug, Serialize)]
+pub struct DataExport {
+    pub id: Uuid,
+    pub status: ExportStatus,
+    pub requested_at: DateTime<Utc>,
+    pub completed_at: Option<DateTime<Utc>>,
+    /// Until when the archive can be downloaded
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Only there while the archive can be downloaded
+    pub download_url: Option<Url>,
+}
+
+impl DataExport {
+    fn from_row(row: &sqlx::postgres::PgRow) -> Result<Self, sqlx::Error> {
+        let status: String = row.try_get("status")?;
+        Ok(Self {
+            id: row.try_get("id")?,
+            status: status.parse().map_err(|_| {
+                sqlx::Error::Decode(format!("Error decoding `{}` as ExportStatus", status).into())
+            })?,
+            requested_at: row.try_get("requested_at")?,
+            completed_at: row.try_get("completed_at")?,
+            expires_at: row.try_get("expires_at")?,
+            download_url: None,
+        })
+    }
+
+    /// Fill in the download link, if the archive can still be downloaded
+    pub fn with_download_url(mut self, links: &ExportLinks) -> Self {
+        self.download_url = match (self.status, &self.expires_at) {
+            (ExportStatus::Ready, Some(expires_at)) if *expires_at > Utc::now() => {
+                Some(links.url(&self.id, expires_at))
+            }
+            _ => None,
+        };
+        self
+    }
+
+    /// Ask for an export of the user's data. While one is pending, asking
+    /// again gets that one.
+    pub async fn request(db_pool: &sqlx::PgPool, user_id: &Uuid) -> Result<Self, sqlx::Error> {
+        sqlx::query(
+            r#"
+INSERT INTO data_exports (id, user_id) VALUES ($1, $2)
+ON CONFLICT (user_id) WHERE status = 'pending' DO UPDATE SET user_id = EXCLUDED.user_id
+RETURNING id, status, requested_at, completed_at, expires_at;"#,
+        )
+        .bind(Uuid::new_v4())
+        .bind(user_id)
+        .fetch_one(db_pool)
+        .await
+        .and_then(|row| Self::from_row(&row))
+    }
+
+    /// The user's latest exports, newest first
+    pub async fn list(db_pool: &sqlx::PgPool, user_id: &Uuid) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query(
+            r#"
+SELECT id, status, requested_at, completed_at, expires_at FROM data_exports
+WHERE user_id = $1
+ORDER BY requested_at DESC
+LIMIT $2;"#,
+        )
+        .bind(user_id)
+        .bind(MAX_LISTED)
+        .fetch_all(db_pool)
+        .await?
+        .iter()
+        .map(Self::from_row)
+        .collect()
+    }
+
+    /// Whether avatar `avatar_key` is still some user's, or in an export
+    /// that may yet be downloaded. Those that aren't can go.
+    pub async fn avatar_in_use(
+        db_pool: &sqlx::PgPool,
+        avatar_key: &str,
+    ) -> Result<bool, sqlx::Error> {
+        sqlx::query(
+            r#"
+SELECT EXISTS (SELECT 1 FROM users WHERE avatar_key = $1)
+    OR EXISTS (
+        SELECT 1 FROM data_exports
+        WHERE avatar_key = $1
+            AND (status = 'pending' OR (status = 'ready' AND expires_at > now()))
+    );"#,
+        )
+        .bind(avatar_key)
+        .fetch_one(db_pool)
+        .await?
+        .try_get(0)
+    }
+
+    /// When export `export_id` was built and what goes in its archive, as
+    /// long as it can be downloaded
+    pub async fn downloadable(
+        db_pool: &sqlx::PgPool,
+        export_id: &Uuid,
+    ) -> Result<Option<(DateTime<Utc>, Vec<ExportEntry>)>, ExportError> {
+        let row = sqlx::query(
+            r#"
+SELECT completed_at, entries FROM data_exports
+WHERE id = $1 AND status = 'ready' AND expires_at > now();"#,
+        )
+        .bind(export_id)
+        .fetch_optional(db_pool)
+        .await?;
+        match row {
+            Some(row) => Ok(Some((
+                row.try_get("completed_at")?,
+                serde_json::from_value(row.try_get("entries")?)?,
+            ))),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Build export `export_id` and email its user a link to it, unless it's
+/// done or someone else is building it already. Failed builds are left for
+/// `run_periodically` to retry.
+pub async fn build_export(
+    db_pool: &sqlx::PgPool,
+    storage: Arc<dyn Storage>,
+    mailer: Arc<dyn Mailer>,
+    links: &ExportLinks,
+    export_id: &Uuid,
+) -> Result<(), ExportError> {
+    // Builds that have been going for an hour died along with their server
+    let user_id: Uuid = match sqlx::query(
+        r#"
+UPDATE data_exports SET started_at = now(), attempts = attempts + 1
+WHERE id = $1 AND status = 'pending'
+    AND (started_at IS NULL OR started_at < now() - interval '1 hour')
+RETURNING user_id;"#,
+    )
+    .bind(export_id)
+    .fetch_optional(db_pool)
+    .await?
+    {
+        Some(row) => row.try_get(0)?,
+        None => return Ok(()),
+    };
+
+    let (user, expires_at) = match build(db_pool, storage, links, export_id, &user_id).await {
+        Ok(Some(built)) => built,
+        Ok(None) => return Ok(()),
+        Err(error) => {
+            record_failure(db_pool, export_id, &error).await?;
+            return Err(error);
+        }
+    };
+
+    let message = Message {
+        to: user.email_address().clone(),
+        subject: "Your data export is ready".into(),
+        body: format!(
+            "Everything we hold about your account is ready to download:\n\n{}\n\nThe link expires on {} UTC.",
+            links.url(export_id, &expires_at),
+            expires_at.format("%Y-%m-%d %H:%M"),
+        ),
+    };
+    if let Err(error) = send_in_background(mailer, message).await {
+        log::warn!("Could not send data export email: {:?}", error);
+    }
+    Ok(())
+}
+
+/// Collect everything about user `user_id` for export `export_id`, and mark
+/// it ready. Returns the user and until when the archive can be downloaded,
+/// unless the user is gone.
+async fn build(
+    db_pool: &sqlx::PgPool,
+    storage: Arc<dyn Storage>,
+    links: &ExportLinks,
+    export_id: &Uuid,
+    user_id: &Uuid,
+) -> Result<Option<(models::User, DateTime<Utc>)>, ExportError> {
+    let user = match models::User::get_by_id(db_pool, user_id).await? {
+        Some(user) => user,
+        None => return Ok(None),
+    };
+    let (entries, avatar_key) = collect(db_pool, storage, export_id, &user).await?;
+
+    let expires_at = Utc::now() + links.lifetime;
+    sqlx::query(
+        r#"
+UPDATE data_exports
+SET status = 'ready', completed_at = now(), expires_at = $2, entries = $3, avatar_key = $4,
+    last_error = NULL
+WHERE id = $1;"#,
+    )
+    .bind(export_id)
+    .bind(expires_at)
+    .bind(serde_json::to_value(&entries)?)
+    .bind(avatar_key)
+    .execute(db_pool)
+    .await?;
+    Ok(Some((user, expires_at)))
+}
+
+/// Leave a failed build for the next attempt, or give up on it after
+/// `MAX_ATTEMPTS`
+async fn record_failure(
+    db_pool: &sqlx::PgPool,
+    export_id: &Uuid,
+    error: &ExportError,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+UPDATE data_exports
+SET started_at = NULL, last_error = $2,
+    status = CASE WHEN attempts >= $3 THEN 'failed' ELSE status END
+WHERE id = $1;"#,
+    )
+    .bind(export_id)
+    .bind(error.to_string())
+    .bind(MAX_ATTEMPTS)
+    .execute(db_pool)
+    .await
+    .map(|_| ())
+}
+
+fn to_json<T: Serialize>(path: &str, value: &T) -> Result<(String, Vec<u8>), ExportError> {
+    Ok((path.into(), serde_json::to_vec_pretty(value)?))
+}
+
+/// Write everything about `user` to storage, returning what goes where in
+/// the archive, and the avatar it points at. Videos are missing, see the
+/// module documentation.
+async fn collect(
+    db_pool: &sqlx::PgPool,
+    storage: Arc<dyn Storage>,
+    export_id: &Uuid,
+    user: &models::User,
+) -> Result<(Vec<ExportEntry>, Option<String>), ExportError> {
+    let mut documents = vec![to_json("profile.json", &user.get_profile())?];
+
+    let settings = sqlx::query(
+        "SELECT email_notifications, updated_at FROM user_settings WHERE user_id = $1;",
+    )
+    .bind(user.id())
+    .fetch_optional(db_pool)
+    .await?;
+    if let Some(settings) = settings {
+        let email_notifications: bool = settings.try_get("email_notifications")?;
+        let updated_at: DateTime<Utc> = settings.try_get("updated_at")?;
+        documents.push(to_json(
+            "settings.json",
+            &serde_json::json!({
+                "email_notifications": email_notifications,
+                "updated_at": updated_at,
+            }),
+        )?);
+    }
+
+    let handles = sqlx::query(
+        "SELECT handle, retired_at FROM handle_history WHERE user_id = $1 ORDER BY retired_at;",
+    )
+    .bind(user.id())
+    .fetch_all(db_pool)
+    .await?
+    .iter()
+    .map(|row| {
+        let handle: String = row.try_get("handle")?;
+        let retired_at: DateTime<Utc> = row.try_get("retired_at")?;
+        Ok(serde_json::json!({ "handle": handle, "retired_at": retired_at }))
+    })
+    .collect::<Result<Vec<_>, sqlx::Error>>()?;
+    documents.push(to_json("handle_history.json", &handles)?);
+
+    // Provider email addresses included
+    let identities = UserIdentity::list_for_user(db_pool, user.id()).await?;
+    documents.push(to_json("identities.json", &identities)?);
+
+    // Revoked ones too: where they were used from is still kept
+    let sessions = sqlx::query(
+        r#"
+SELECT id, user_agent, ip_address, created_at, last_seen_at, revoked_at
+FROM sessions
+WHERE user_id = $1
+ORDER BY created_at;"#,
+    )
+    .bind(user.id())
+    .fetch_all(db_pool)
+    .await?
+    .iter()
+    .map(|row| {
+        let id: Uuid = row.try_get("id")?;
+        let user_agent: Option<String> = row.try_get("user_agent")?;
+        let ip_address: Option<String> = row.try_get("ip_address")?;
+        let created_at: DateTime<Utc> = row.try_get("created_at")?;
+        let last_seen_at: DateTime<Utc> = row.try_get("last_seen_at")?;
+        let revoked_at: Option<DateTime<Utc>> = row.try_get("revoked_at")?;
+        Ok(serde_json::json!({
+            "id": id,
+            "user_agent": user_agent,
+            "ip_address": ip_address,
+            "created_at": created_at,
+            "last_seen_at": last_seen_at,
+            "revoked_at": revoked_at,
+        }))
+    })
+    .collect::<Result<Vec<_>, sqlx::Error>>()?;
+    documents.push(to_json("sessions.json", &sessions)?);
+
+    let passkeys = sqlx::query(
+        r#"
+SELECT id, name, transports, created_at, last_used_at
+FROM webauthn_credentials
+WHERE user_id = $1
+ORDER BY created_at;"#,
+    )
+    .bind(user.id())
+    .fetch_all(db_pool)
+    .await?
+    .iter()
+    .map(|row| {
+        let id: Uuid = row.try_get("id")?;
+        let name: String = row.try_get("name")?;
+        let transports: Vec<String> = row.try_get("transports")?;
+        let created_at: DateTime<Utc> = row.try_get("created_at")?;
+        let last_used_at: Option<DateTime<Utc>> = row.try_get("last_used_at")?;
+        Ok(serde_json::json!({
+            "id": id,
+            "name": name,
+            "transports": transports,
+            "created_at": created_at,
+            "last_used_at": last_used_at,
+        }))
+    })
+    .collect::<Result<Vec<_>, sqlx::Error>>()?;
+    documents.push(to_json("passkeys.json", &passkeys)?);
+
+    let access_tokens = PersonalAccessToken::list(db_pool, user.id()).await?;
+    documents.push(to_json("access_tokens.json", &access_tokens)?);
+
+    // Whether it's set up, never the secret itself
+    let totp = sqlx::query(
+        r#"
+SELECT created_at, confirmed_at,
+    (SELECT count(*) FROM recovery_codes
+     WHERE user_id = $1 AND used_at IS NULL) AS recovery_codes_left
+FROM totp_credentials
+WHERE user_id = $1;"#,
+    )
+    .bind(user.id())
+    .fetch_optional(db_pool)
+    .await?;
+    if let Some(totp) = totp {
+        let created_at: DateTime<Utc> = totp.try_get("created_at")?;
+        let confirmed_at: Option<DateTime<Utc>> = totp.try_get("confirmed_at")?;
+        let recovery_codes_left: i64 = totp.try_get("recovery_codes_left")?;
+        documents.push(to_json(
+            "totp.json",
+            &serde_json::json!({
+                "created_at": created_at,
+                "confirmed_at": confirmed_at,
+                "recovery_codes_left": recovery_codes_left,
+            }),
+        )?);
+    }
+
+    let mut events = vec![];
+    let mut before = None;
+    loop {
+        let page = AuditEvent::search(
+            db_pool,
+            &AuditQuery {
+                before,
+                limit: Some(AUDIT_PAGE_SIZE),
+                ..AuditQuery::default()
+            }
+            .for_user(user.id()),
+        )
+        .await?
+        .redacted_for(user.id());
+        events.extend(page.events);
+        before = page.next_before;
+        if before.is_none() {
+            break;
+        }
+    }
+    documents.push(to_json("audit_events.json", &events)?);
+
+    let avatar_key: Option<String> = sqlx::query("SELECT avatar_key FROM users WHERE id = $1;")
+        .bind(user.id())
+        .fetch_one(db_pool)
+        .await?
+        .try_get(0)?;
+
+    let prefix = format!("exports/{}/{}", user.id(), export_id);
+    let stored_avatar_key = avatar_key.clone();
+    let entries = storage::in_background(storage, move |storage| {
+        let mut entries = vec![];
+        for (path, content) in documents {
+            let key = format!("{}/{}", prefix, path);
+            storage.put(&key, &content)?;
+            entries.push(ExportEntry { path, key });
+        }
+        // Avatars are named after their content, and kept while an export
+        // points at them, so there's no need for a copy
+        if let Some(avatar_key) = stored_avatar_key {
+            for size in AVATAR_SIZES.iter() {
+                for extension in &["jpg", "png"] {
+                    let name = format!("{}.{}", size, extension);
+                    let key = format!("{}/{}", avatar_key, name);
+                    if storage.get_range(&key, 0, 0)?.is_some() {
+                        entries.push(ExportEntry {
+                            path: format!("avatar/{}", name),
+                            key,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(entries)
+    })
+    .await?;
+    Ok((entries, avatar_key))
+}
+
+/// Stream out the archive of `entries`, reading them from storage a chunk
+/// at a time. Should a file be missing, the download is cut short rather
+/// than quietly leaving it out.
+pub fn stream_archive(
+    storage: Arc<dyn Storage>,
+    entries: Vec<ExportEntry>,
+    modified: DateTime<Utc>,
+) -> Body {
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut zip = ZipStream::new();
+        for entry in entries {
+            // Failing to send means the client went away
+            let header = zip.file_header(&entry.path, modified);
+            if sender.send_data(Bytes::from(header)).await.is_err() {
+                return;
+            }
+
+            let mut crc = crc32fast::Hasher::new();
+            let mut size = 0;
+            loop {
+                let key = entry.key.clone();
+                let chunk = match storage::in_background(storage.clone(), move |storage| {
+                    storage.get_range(&key, size, CHUNK_SIZE)
+                })
+                .await
+                {
+                    Ok(Some(chunk)) => chunk,
+                    Ok(None) => {
+                        log::error!("Exported file {} is missing", entry.key);
+                        sender.abort();
+                        return;
+                    }
+                    Err(error) => {
+                        log::error!("Could not read exported file {}: {}", entry.key, error);
+                        sender.abort();
+                        return;
+                    }
+                };
+                let last = chunk.len() < CHUNK_SIZE;
+                crc.update(&chunk);
+                size += chunk.len() as u64;
+                if sender.send_data(Bytes::from(chunk)).await.is_err() {
+                    return;
+                }
+                if last {
+                    break;
+                }
+            }
+
+            let descriptor = zip.data_descriptor(crc.finalize(), size);
+            if sender.send_data(Bytes::from(descriptor)).await.is_err() {
+                return;
+            }
+        }
+        let _ = sender.send_data(Bytes::from(zip.finish())).await;
+    });
+    body
+}
+
+/// Delete the files of exports that can't be downloaded anymore, and the
+/// exports themselves, along with the replaced avatars only they held on to
+async fn remove_expired(
+    db_pool: &sqlx::PgPool,
+    storage: Arc<dyn Storage>,
+) -> Result<usize, ExportError> {
+    let expired = sqlx::query(
+        r#"
+SELECT id, user_id FROM data_exports
+WHERE (status = 'ready' AND expires_at <= now())
+    OR (status = 'failed' AND requested_at < now() - interval '7 days');"#,
+    )
+    .fetch_all(db_pool)
+    .await?;
+
+    for row in &expired {
+        let export_id: Uuid = row.try_get("id")?;
+        let user_id: Uuid = row.try_get("user_id")?;
+        let prefix = format!("exports/{}/{}", user_id, export_id);
+        storage::in_background(storage.clone(), move |storage| storage.delete(&prefix)).await?;
+        let avatar_key: Option<String> =
+            sqlx::query("DELETE FROM data_exports WHERE id = $1 RETURNING avatar_key;")
+                .bind(export_id)
+                .fetch_one(db_pool)
+                .await?
+                .try_get(0)?;
+        if let Some(avatar_key) = avatar_key {
+            if !DataExport::avatar_in_use(db_pool, &avatar_key).await? {
+                storage::in_background(storage.clone(), move |storage| storage.delete(&avatar_key))
+                    .await?;
+            }
+        }
+    }
+    Ok(expired.len())
+}
+
+/// Every `WORKER_INTERVAL`, retry the exports whose builds failed or died,
+/// and clean up the expired ones
+pub async fn run_periodically(
+    db_pool: sqlx::PgPool,
+    storage: Arc<dyn Storage>,
+    mailer: Arc<dyn Mailer>,
+    links: Arc<ExportLinks>,
+) {
+    let mut interval = tokio::time::interval(WORKER_INTERVAL);
+    loop {
+        interval.tick().await;
+        let stalled = sqlx::query(
+            r#"
+SELECT id FROM data_exports
+WHERE status = 'pending'
+    AND (started_at IS NULL OR started_at < now() - interval '1 hour')
+ORDER BY requested_at
+LIMIT 20;"#,
+        )
+        .fetch_all(&db_pool)
+        .await;
+        match stalled {
+            Ok(rows) => {
+                for row in rows {
+                    let export_id: Uuid = row.get(0);
+                    if let Err(error) = build_export(
+                        &db_pool,
+                        storage.clone(),
+                        mailer.clone(),
+                        &links,
+                        &export_id,
+                    )
+                    .await
+                    {
+                        log::warn!("Could not build data export {}: {}", export_id, error);
+                    }
+                }
+            }
+            Err(error) => log::error!("Could not look for stalled data exports: {:?}", error),
+        }
+
+        match remove_expired(&db_pool, storage.clone()).await {
+            Ok(0) => {}
+            Ok(removed) => log::info!("Removed {} expired data export(s)", removed),
+            Err(error) => log::error!("Could not remove expired data exports: {}", error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::MemoryStorage;
+
+    fn links() -> ExportLinks {
+        ExportLinks::new(
+            b"secret",
+            Url::parse("https://weft.example/").unwrap(),
+            Duration::hours(DEFAULT_LINK_HOURS),
+        )
+    }
+
+    fn signed(url: &Url) -> SignedLink {
+        let query = url
+            .query_pairs()
+            .collect::<std::collections::HashMap<_, _>>();
+        SignedLink {
+            expires: query["expires"].parse().unwrap(),
+            signature: query["signature"].to_string(),
+        }
+    }
+
+    #[test]
+    fn test_signed_links() {
+        let links = links();
+        let export_id = Uuid::new_v4();
+        let url = links.url(&export_id, &(Utc::now() + Duration::hours(1)));
+        assert_eq!(
+            url.path(),
+            format!("/api/v1/user/export/{}/archive.zip", export_id)
+        );
+        let link = signed(&url);
+        assert!(links.verify(&export_id, &link));
+
+        // Not for another export, nor for longer, nor with another key
+        assert!(!links.verify(&Uuid::new_v4(), &link));
+        assert!(!links.verify(
+            &export_id,
+            &SignedLink {
+                expires: link.expires + 3600,
+                ..signed(&url)
+            }
+        ));
+        let other = ExportLinks::new(
+            b"other secret",
+            Url::parse("https://weft.example/").unwrap(),
+            Duration::hours(1),
+        );
+        assert!(!other.verify(&export_id, &link));
+        assert!(!links.verify(
+            &export_id,
+            &SignedLink {
+                signature: "not base64!".into(),
+                ..signed(&url)
+            }
+        ));
+    }
+
+    #[test]
+    fn test_expired_links() {
+        let links = links();
+        let export_id = Uuid::new_v4();
+        let url = links.url(&export_id, &(Utc::now() - Duration::seconds(1)));
+        assert!(!links.verify(&export_id, &signed(&url)));
+    }
+
+    #[tokio::test]
+    async fn test_stream_archive() {
+        let storage = Arc::new(MemoryStorage::default());
+        let large = (0..CHUNK_SIZE * 2 + 10)
+            .map(|n| n as u8)
+            .collect::<Vec<_>>();
+        storage.put("exports/u/e/profile.json", b"{}").unwrap();
+        storage.put("avatars/u/h/512.jpg", &large).unwrap();
+        let entries = vec![
+            ExportEntry {
+                path: "profile.json".into(),
+                key: "exports/u/e/profile.json".into(),
+            },
+            ExportEntry {
+                path: "avatar/512.jpg".into(),
+                key: "avatars/u/h/512.jpg".into(),
+            },
+        ];
+
+        let archive = warp::hyper::body::to_bytes(stream_archive(
+            storage.clone(),
+            entries.clone(),
+            Utc::now(),
+        ))
+        .await
+        .unwrap();
+        // Each file between its header and data descriptor, in one piece
+        let profile = 30 + "profile.json".len();
+        assert_eq!(&archive[profile..profile + 2], b"{}");
+        let avatar = profile + 2 + 16 + 30 + "avatar/512.jpg".len();
+        assert_eq!(&archive[avatar..avatar + large.len()], &large[..]);
+        let descriptor = avatar + large.len();
+        assert_eq!(
+            &archive[descriptor + 4..descriptor + 8],
+            &crc32fast::hash(&large).to_le_bytes()
+        );
+        // Two files in the central directory
+        assert_eq!(&archive[archive.len() - 12..archive.len() - 10], &[2, 0]);
+
+        // Missing files cut the download short
+        storage.delete("avatars/u/h").unwrap();
+        assert!(
+            warp::hyper::body::to_bytes(stream_archive(storage, entries, Utc::now()))
+                .await
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_download_url_only_when_ready() {
+        let links = links();
+        let export = |status, expires_at| DataExport {
+            id: Uuid::new_v4(),
+            status,
+            requested_at: Utc::now(),
+            completed_at: None,
+            expires_at,
+            download_url: None,
+        };
+        let soon = Some(Utc::now() + Duration::hours(1));
+        let past = Some(Utc::now() - Duration::hours(1));
+        assert!(export(ExportStatus::Ready, soon)
+            .with_download_url(&links)
+            .download_url
+            .is_some());
+        assert!(export(ExportStatus::Ready, past)
+            .with_download_url(&links)
+            .download_url
+            .is_none());
+        assert!(export(ExportStatus::Pending, None)
+            .with_download_url(&links)
+            .download_url
+            .is_none());
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    env, fmt, fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use chrono::Utc;
+use lettre::{smtp::authentication::Credentials, SmtpClient, Transport};
+use lettre_email::EmailBuilder;
+
+use crate::auth::EmailAddress;
+
+/// A plain text email
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub to: EmailAddress,
+    pub subject: String,
+    pub body: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MailerError {
+    InvalidConfiguration(String),
+    BuildError(String),
+    DeliveryError(String),
+    IoError(std::io::Error),
+}
+
+impl fmt::Display for MailerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MailerError::InvalidConfiguration(reason) => {
+                write!(f, "Invalid mailer configuration: {}", reason)
+            }
+            MailerError::BuildError(reason) => write!(f, "Could not build email: {}", reason),
+            MailerError::DeliveryError(reason) => write!(f, "Could not deliver email: {}", reason),
+            MailerError::IoError(error) => write!(f, "Could not write email: {:?}", error),
+        }
+    }
+}
+
+impl From<std::io::Error> for MailerError {
+    fn from(error: std::io::Error) -> Self {
+        MailerError::IoError(error)
+    }
+}
+
+/// Something that can get emails to our users. Sending may block, so call
+/// it through `send_in_background` from async code.
+pub trait Mailer: Send + Sync {
+    fn send(&self, message: &Message) -> Result<(), MailerError>;
+}
+
+/// Delivers through an SMTP relay
+pub struct SmtpMailer {
+    host: String,
+    credentials: Option<Credentials>,
+    from: String,
+}
+
+impl SmtpMailer {
+    pub fn new(host: String, credentials: Option<Credentials>, from: String) -> Self {
+        Self {
+            host,
+            credentials,
+            from,
+        }
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, message: &Message) -> Result<(), MailerError> {
+        let email = EmailBuilder::new()
+            .to(message.to.to_str())
+            .from(self.from.as_str())
+            .subject(message.subject.as_str())
+            .text(message.body.as_str())
+            .build()
+            .map_err(|error| MailerError::BuildError(format!("{:?}", error)))?;
+
+        let mut client = SmtpClient::new_simple(&self.host)
+            .map_err(|error| MailerError::DeliveryError(format!("{:?}", error)))?;
+        if let Some(credentials) = &self.credentials {
+            client = client.credentials(credentials.clone());
+        }
+
+        client
+            .transport()
+            .send(email.into())
+            .map(|_| ())
+            .map_err(|error| MailerError::DeliveryError(format!("{:?}", error)))
+    }
+}
+
+/// Writes every email as a file into a directory, for local development
+pub struct OutboxMailer {
+    directory: PathBuf,
+}
+
+impl OutboxMailer {
+    pub fn new<P: Into<PathBuf>>(directory: P) -> Self {
+        Self {
+            directory: directory.into(),
+        }
+    }
+}
+
+impl Mailer for OutboxMailer {
+    fn send(&self, message: &Message) -> Result<(), MailerError> {
+        fs::create_dir_all(&self.directory)?;
+        let path = self.directory.join(format!(
+            "{}-{}.eml",
+            Utc::now().format("%Y%m%dT%H%M%S%.f"),
+            sqlx::types::Uuid::new_v4()
+        ));
+        fs::write(
+            path,
+            format!(
+                "To: {}\r\nSubject: {}\r\n\r\n{}\r\n",
+                message.to, message.subject, message.body
+            ),
+        )?;
+        Ok(())
+    }
+}
+
+/// Keeps every email in memory, so tests can look at what was sent
+#[derive(Default)]
+pub struct MemoryMailer {
+    messages: Mutex<Vec<Message>>,
+}
+
+impl MemoryMailer {
+    #[cfg(test)]
+    pub fn messages(&self) -> Vec<Message> {
+        self.messages.lock().unwrap().clone()
+    }
+}
+
+impl Mailer for MemoryMailer {
+    fn send(&self, message: &Message) -> Result<(), MailerError> {
+        self.messages.lock().unwrap().push(message.clone());
+        Ok(())
+    }
+}
+
+/// Pick a mailer through `WEFT_MAILER`:
+///
+/// - `smtp`: relays through `WEFT_SMTP_HOST`, optionally authenticating with
+///   `WEFT_SMTP_USERNAME` and `WEFT_SMTP_PASSWORD`, sending from `WEFT_MAIL_FROM`
+/// - `outbox` (default): writes files into `WEFT_MAILER_OUTBOX` (`./outbox`)
+/// - `memory`: keeps emails in memory
+pub fn mailer_from_env() -> Result<Arc<dyn Mailer>, MailerError> {
+    let var = |name: &str| {
+        env::var(name).map_err(|_| MailerError::InvalidConfiguration(name.to_string()))
+    };
+
+    match env::var("WEFT_MAILER").as_deref().unwrap_or("outbox") {
+        "smtp" => {
+            let credentials = match (var("WEFT_SMTP_USERNAME"), var("WEFT_SMTP_PASSWORD")) {
+                (Ok(username), Ok(password)) => Some(Credentials::new(username, password)),
+                _ => None,
+            };
+            Ok(Arc::new(SmtpMailer::new(
+                var("WEFT_SMTP_HOST")?,
+                credentials,
+                var("WEFT_MAIL_FROM")?,
+            )))
+        }
+        "outbox" => Ok(Arc::new(OutboxMailer::new(
+            var("WEFT_MAILER_OUTBOX").unwrap_or_else(|_| "outbox".into()),
+        ))),
+        "memory" => Ok(Arc::new(MemoryMailer::default())),
+        other => Err(MailerError::InvalidConfiguration(format!(
+            "Unknown mailer `{}`",
+            other
+        ))),
+    }
+}
+
+/// Send `message` on the blocking thread pool
+pub async fn send_in_background(
+    mailer: Arc<dyn Mailer>,
+    message: Message,
+) -> Result<(), MailerError> {
+    tokio::task::spawn_blocking(move || mailer.send(&message))
+        .await
+        .map_err(|error| MailerError::DeliveryError(format!("{:?}", error)))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn message() -> Message {
+        Message {
+            to: EmailAddress::from_str("some@email.address").unwrap(),
+            subject: "Hello".into(),
+            body: "Hello there".into(),
+        }
+    }
+
+    #[test]
+    fn test_memory_mailer_keeps_messages() {
+        let mailer = MemoryMailer::default();
+        mailer.send(&message()).unwrap();
+        assert_eq!(mailer.messages(), vec![message()]);
+    }
+
+    #[test]
+    fn test_outbox_mailer_writes_files() {
+        let directory =
+            env::temp_dir().join(format!("weft-outbox-{}", sqlx::types::Uuid::new_v4()));
+        OutboxMailer::new(&directory).send(&message()).unwrap();
+
+        let entries = fs::read_dir(&directory).unwrap().collect::<Vec<_>>();
+        assert_eq!(entries.len(), 1);
+        let contents = fs::read_to_string(entries[0].as_ref().unwrap().path()).unwrap();
+        assert!(contents.contains("Subject: Hello"));
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -1,77 +1,2367 @@
 use std::{
-    collections::HashMap,
-    convert::{Infallible, TryFrom, TryInto}};
+    collections::{BTreeMap, HashMap},
+    convert::{Infallible, TryInto},
+    net::SocketAddr,
+    str::FromStr,
+    sync::Arc,
+};
 
 use anyhow::{Context, Error};
-use futures::future::TryFutureExt;
 use hyper::server::Server;
 use listenfd::ListenFd;
-use warp::{Filter, Rejection, Reply};
+use warp::{
+    http::{header, HeaderValue},
+    Filter, Rejection, Reply,
+};
 
 mod auth;
+mod avatars;
+mod deletion;
+mod exports;
+mod mailer;
 mod models;
 mod rejections;
+mod storage;
+#[cfg(test)]
+mod test_db;
+mod zip;
 
-use crate::auth::{AuthError, AuthToken, UserProfile};
+use crate::{
+    auth::{
+        access_tokens,
+        oauth::{self, AuthorizationResponse, CompletedFlow},
+        profile,
+        roles::{ChangeRole, SuspendUser, UserSearch},
+        sessions,
+        tokens::TokenManager,
+        webauthn::{AssertionCredential, RegistrationCredential, RenamePasskey},
+        AuditEvent, AuditEventKind, AuditQuery, AuthError, AuthenticatedUser, ClientInfo,
+        Credential, CsrfPolicy, EmailAddress, EmailVerification, ForgotPassword, MfaCode,
+        NewAccessToken, NewAuditEvent, OAuthError, OAuthProvider, OAuthProviders, Passkey,
+        PasswordHasher, PasswordHasherError, PasswordPolicy, PasswordReset, PasswordResetError,
+        PendingMfaLogin, Permission, PersonalAccessToken, ProfileUpdate, ProfileValidationError,
+        RefreshTokenError, RefreshTokenManager, RelyingParty, ResetPassword, Role, Session,
+        SessionInfo, Throttle, ThrottleKey, TokenScope, TotpCipher, TotpCredential, TotpError,
+        TrustedProxies, UserClaims, UserIdentity, VerificationError, VerifyEmail, WebauthnError,
+    },
+    avatars::{Avatar, PROFILE_AVATAR_SIZE},
+    deletion::{CancelDeletion, ConfirmDeletion, DeletionPolicy, ScheduledDeletion},
+    exports::{DataExport, ExportLinks, SignedLink},
+    mailer::{mailer_from_env, send_in_background, Mailer},
+    rejections::{
+        server_error, Conflict, FieldError, IntoRejection, TooManyRequests, ValidationFailed,
+    },
+    storage::{storage_from_env, Storage},
+};
 
-/// Extract user's JWT
-pub fn auth_user() -> impl Filter<Extract = (AuthToken,), Error = Rejection> + Copy {
-    warp::cookie::cookie("Auth-Token").and_then(|auth_token: String| async move {
-        Ok::<_, Rejection>(
-            AuthToken::try_from(auth_token)
-                .map_err(|_| warp::reject::custom(AuthError::InvalidToken))?,
+/// Extract the requesting client's user agent and address, and the id of
+/// the request. Behind `trusted_proxies`, the address is the one they
+/// forwarded the request for.
+pub fn client_info(
+    trusted_proxies: Arc<TrustedProxies>,
+) -> impl Filter<Extract = (ClientInfo,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("user-agent")
+        .and(warp::addr::remote())
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and(warp::header::optional::<String>("x-request-id"))
+        .map(
+            move |user_agent,
+                  remote: Option<SocketAddr>,
+                  forwarded_for: Option<String>,
+                  request_id| ClientInfo {
+                user_agent,
+                ip_address: trusted_proxies
+                    .client_ip(remote.map(|address| address.ip()), forwarded_for.as_deref())
+                    .map(|address| address.to_string()),
+                request_id: Some(sessions::request_id_or_new(request_id)),
+            },
         )
+}
+
+/// Routes authenticating with cookies of their own, which an `Authorization`
+/// header doesn't stand in for
+const COOKIE_AUTHENTICATED_PATHS: &[&str] = &["/api/v1/token/refresh", "/api/v1/login/mfa"];
+
+/// Whether `authorization` holds a valid access token or personal access
+/// token. The session behind an access token may have been revoked since;
+/// `auth_user` still turns the request away then.
+async fn authenticated_by_bearer(
+    token_manager: &TokenManager,
+    db_pool: &sqlx::PgPool,
+    authorization: Option<&str>,
+) -> Result<bool, Rejection> {
+    match authorization.and_then(auth::bearer_token) {
+        Some(token) if token.starts_with(access_tokens::TOKEN_PREFIX) => {
+            Ok(PersonalAccessToken::authenticate(db_pool, token)
+                .await
+                .map_err(server_error)?
+                .is_some())
+        }
+        Some(token) => Ok(token_manager
+            .verify_token_with_id::<_, UserClaims>(token)
+            .is_ok()),
+        None => Ok(false),
+    }
+}
+
+/// Refuse unsafe requests coming from other sites, unless they authenticate
+/// with a valid `Authorization: Bearer` header instead of our cookies. Not
+/// on the routes authenticating with cookies of their own, though, which
+/// never look at that header.
+pub fn csrf_protection(
+    policy: Arc<CsrfPolicy>,
+    token_manager: Arc<TokenManager>,
+    db_pool: sqlx::PgPool,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::header::optional::<String>("origin"))
+        .and(warp::header::optional::<String>("referer"))
+        .and(warp::header::optional::<String>("host"))
+        .and_then(
+            move |method: warp::http::Method,
+                  path: warp::path::FullPath,
+                  authorization: Option<String>,
+                  origin: Option<String>,
+                  referer: Option<String>,
+                  host: Option<String>| {
+                let policy = policy.clone();
+                let token_manager = token_manager.clone();
+                let db_pool = db_pool.clone();
+                async move {
+                    if policy.allows(
+                        &method,
+                        origin.as_deref(),
+                        referer.as_deref(),
+                        host.as_deref(),
+                    ) {
+                        return Ok(());
+                    }
+                    let cookie_authenticated = COOKIE_AUTHENTICATED_PATHS
+                        .iter()
+                        .any(|prefix| path.as_str().starts_with(prefix));
+                    if !cookie_authenticated
+                        && authenticated_by_bearer(
+                            &token_manager,
+                            &db_pool,
+                            authorization.as_deref(),
+                        )
+                        .await?
+                    {
+                        Ok(())
+                    } else {
+                        Err(AuthError::CrossSiteRequest.into_rejection())
+                    }
+                }
+            },
+        )
+        .untuple_one()
+}
+
+/// Verify a session's JWT, making sure the session is still active
+async fn authenticate_session(
+    token_manager: &TokenManager,
+    db_pool: &sqlx::PgPool,
+    auth_token: &str,
+    client: &ClientInfo,
+) -> Result<AuthenticatedUser, Rejection> {
+    let (claims, token_id) = token_manager
+        .verify_token_with_id::<_, UserClaims>(auth_token)
+        .map_err(|error| AuthError::from(error).into_rejection())?;
+    let session_id = token_id
+        .and_then(|token_id| token_id.parse().ok())
+        .ok_or_else(|| AuthError::InvalidToken.into_rejection())?;
+
+    if !Session::touch(db_pool, &claims.user_id, &session_id, client)
+        .await
+        .map_err(server_error)?
+    {
+        return Err(AuthError::RevokedSession.into_rejection());
+    }
+
+    Ok(AuthenticatedUser {
+        user_id: claims.user_id,
+        credential: Credential::Session(session_id),
+    })
+}
+
+async fn authenticate_personal_access_token(
+    db_pool: &sqlx::PgPool,
+    token: &str,
+) -> Result<AuthenticatedUser, Rejection> {
+    let grant = PersonalAccessToken::authenticate(db_pool, token)
+        .await
+        .map_err(server_error)?
+        .ok_or_else(|| AuthError::InvalidToken.into_rejection())?;
+    Ok(AuthenticatedUser {
+        user_id: grant.user_id,
+        credential: Credential::PersonalAccessToken {
+            id: grant.id,
+            scopes: grant.scopes,
+        },
+    })
+}
+
+/// Extract and verify the user's credential: either a session's JWT, from
+/// the `Auth-Token` cookie or an `Authorization: Bearer` header, or a
+/// personal access token from the header. Endpoints that need more than a
+/// personal access token's scopes should use `session_user` instead.
+pub fn auth_user(
+    token_manager: Arc<TokenManager>,
+    db_pool: sqlx::PgPool,
+    trusted_proxies: Arc<TrustedProxies>,
+) -> impl Filter<Extract = (AuthenticatedUser,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::cookie::optional(auth::AUTH_TOKEN_COOKIE))
+        .and(client_info(trusted_proxies))
+        .and_then(
+            move |authorization: Option<String>, auth_token: Option<String>, client: ClientInfo| {
+                let token_manager = token_manager.clone();
+                let db_pool = db_pool.clone();
+                async move {
+                    let bearer_token = match authorization {
+                        Some(authorization) => Some(
+                            auth::bearer_token(&authorization)
+                                .ok_or_else(|| AuthError::InvalidToken.into_rejection())?
+                                .to_string(),
+                        ),
+                        None => None,
+                    };
+
+                    match (bearer_token, auth_token) {
+                        (Some(token), _) if token.starts_with(access_tokens::TOKEN_PREFIX) => {
+                            authenticate_personal_access_token(&db_pool, &token).await
+                        }
+                        (Some(token), _) | (None, Some(token)) => {
+                            authenticate_session(&token_manager, &db_pool, &token, &client).await
+                        }
+                        (None, None) => Err(AuthError::MissingToken.into_rejection()),
+                    }
+                }
+            },
+        )
+}
+
+/// Like `auth_user`, but only lets logged in sessions through: managing the
+/// account itself takes more than a personal access token
+pub fn session_user(
+    token_manager: Arc<TokenManager>,
+    db_pool: sqlx::PgPool,
+    trusted_proxies: Arc<TrustedProxies>,
+) -> impl Filter<Extract = (AuthenticatedUser,), Error = Rejection> + Clone {
+    auth_user(token_manager, db_pool, trusted_proxies).and_then(
+        |user: AuthenticatedUser| async move {
+            match user.credential {
+                Credential::Session(_) => Ok(user),
+                Credential::PersonalAccessToken { .. } => {
+                    Err(AuthError::Forbidden.into_rejection())
+                }
+            }
+        },
+    )
+}
+
+/// Let `user` through only if their credential allows `scope`
+pub async fn require_scope(
+    scope: TokenScope,
+    user: AuthenticatedUser,
+) -> Result<AuthenticatedUser, Rejection> {
+    if user.has_scope(scope) {
+        Ok(user)
+    } else {
+        Err(AuthError::Forbidden.into_rejection())
+    }
+}
+
+/// Like `session_user`, but also requires the user to have verified their
+/// email address
+pub fn verified_user(
+    token_manager: Arc<TokenManager>,
+    db_pool: sqlx::PgPool,
+    trusted_proxies: Arc<TrustedProxies>,
+) -> impl Filter<Extract = (AuthenticatedUser,), Error = Rejection> + Clone {
+    session_user(token_manager, db_pool.clone(), trusted_proxies).and_then(
+        move |user: AuthenticatedUser| {
+            let db_pool = db_pool.clone();
+            async move {
+                if models::User::is_verified(&db_pool, &user.user_id)
+                    .await
+                    .map_err(server_error)?
+                {
+                    Ok(user)
+                } else {
+                    Err(AuthError::UnverifiedEmail.into_rejection())
+                }
+            }
+        },
+    )
+}
+
+/// Reads the ids of the users to make admins from `WEFT_ADMIN_USER_IDS`,
+/// comma separated, so there's someone to hand out roles to begin with
+fn admin_user_ids_from_env() -> Result<Vec<sqlx::types::Uuid>, Error> {
+    std::env::var("WEFT_ADMIN_USER_IDS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| id.parse().context("Invalid WEFT_ADMIN_USER_IDS"))
+        .collect()
+}
+
+/// Let `user` through only if their role grants `permission`
+pub async fn require_permission(
+    permission: Permission,
+    db_pool: sqlx::PgPool,
+    user: AuthenticatedUser,
+) -> Result<AuthenticatedUser, Rejection> {
+    let role = models::User::role_of(&db_pool, &user.user_id)
+        .await
+        .map_err(server_error)?
+        .unwrap_or_default();
+    if role.has_permission(permission) {
+        Ok(user)
+    } else {
+        Err(AuthError::Forbidden.into_rejection())
+    }
+}
+
+/// Like `auth_user`, but only lets through users whose role grants
+/// `permission`. Personal access tokens also need the `admin` scope.
+pub fn permitted_user(
+    token_manager: Arc<TokenManager>,
+    db_pool: sqlx::PgPool,
+    trusted_proxies: Arc<TrustedProxies>,
+    permission: Permission,
+) -> impl Filter<Extract = (AuthenticatedUser,), Error = Rejection> + Clone {
+    auth_user(token_manager, db_pool.clone(), trusted_proxies)
+        .and_then(|user| require_scope(TokenScope::Admin, user))
+        .and_then(move |user| require_permission(permission, db_pool.clone(), user))
+}
+
+pub async fn load_current_user(
+    user: AuthenticatedUser,
+    db_pool: sqlx::PgPool,
+) -> Result<impl Reply, Rejection> {
+    let user = models::User::get_by_id(&db_pool, &user.user_id)
+        .await
+        .map_err(server_error)?
+        .ok_or_else(warp::reject::not_found)?;
+    Ok(warp::reply::json(&user.get_profile()))
+}
+
+/// Apply a partial update to the user's own profile. Updates made to an
+/// outdated copy of the profile get a 409, and have to be redone on top of a
+/// fresh one.
+pub async fn save_current_user(
+    user: AuthenticatedUser,
+    db_pool: sqlx::PgPool,
+    storage: Arc<dyn Storage>,
+    payload: ProfileUpdate,
+) -> Result<impl Reply, Rejection> {
+    let validation_failed = |errors: Vec<ProfileValidationError>| {
+        ValidationFailed(errors.into_iter().map(FieldError::from).collect()).into_rejection()
+    };
+    let changes = payload
+        .validate(storage.base_url())
+        .map_err(validation_failed)?;
+    if let Some(Some(timezone)) = &changes.timezone {
+        if !profile::is_known_timezone(&db_pool, timezone)
+            .await
+            .map_err(server_error)?
+        {
+            return Err(validation_failed(vec![
+                ProfileValidationError::InvalidTimezone,
+            ]));
+        }
+    }
+
+    let handle_taken = || Conflict("Handle already taken").into_rejection();
+    if let Some(Some(handle)) = &changes.handle {
+        if !models::User::is_handle_available(&db_pool, &user.user_id, handle)
+            .await
+            .map_err(server_error)?
+        {
+            return Err(handle_taken());
+        }
+    }
+
+    let stale = || Conflict("Profile changed since it was loaded").into_rejection();
+    let mut user = models::User::get_by_id(&db_pool, &user.user_id)
+        .await
+        .map_err(server_error)?
+        .ok_or_else(warp::reject::not_found)?;
+    if user.updated_at() != &changes.updated_at {
+        return Err(stale());
+    }
+
+    user.apply(changes);
+    let (user, replaced_avatar_key) = user
+        .update(&db_pool)
+        .await
+        .map_err(|error| {
+            if models::is_unique_violation(&error) {
+                handle_taken()
+            } else {
+                server_error(error)
+            }
+        })?
+        .ok_or_else(stale)?;
+    if let Some(replaced_avatar_key) = replaced_avatar_key {
+        delete_replaced_avatar(&db_pool, storage, replaced_avatar_key).await;
+    }
+    Ok(warp::reply::json(&user.get_profile()))
+}
+
+/// Delete an uploaded avatar the user moved away from. Exports point at
+/// avatars instead of copying them, so it stays for as long as one that
+/// does may be downloaded. Not managing to is no reason to fail the request.
+async fn delete_replaced_avatar(
+    db_pool: &sqlx::PgPool,
+    storage: Arc<dyn Storage>,
+    avatar_key: String,
+) {
+    let in_use = DataExport::avatar_in_use(db_pool, &avatar_key)
+        .await
+        .unwrap_or_else(|error| {
+            log::warn!(
+                "Could not tell whether avatar {} is in use: {:?}",
+                avatar_key,
+                error
+            );
+            true
+        });
+    if in_use {
+        return;
+    }
+    let deleted_key = avatar_key.clone();
+    if let Err(error) =
+        storage::in_background(storage, move |storage| storage.delete(&deleted_key)).await
+    {
+        log::warn!("Could not delete replaced avatar {}: {}", avatar_key, error);
+    }
+}
+
+/// Replace the user's avatar with an uploaded PNG, JPEG or WebP picture,
+/// stored in every one of `avatars::AVATAR_SIZES`
+pub async fn upload_avatar(
+    user: AuthenticatedUser,
+    upload: warp::hyper::body::Bytes,
+    db_pool: sqlx::PgPool,
+    storage: Arc<dyn Storage>,
+) -> Result<impl Reply, Rejection> {
+    let avatar = tokio::task::spawn_blocking(move || Avatar::from_upload(&upload))
+        .await
+        .map_err(server_error)?
+        .map_err(|error| ValidationFailed(vec![FieldError::from(error)]).into_rejection())?;
+
+    // Named after their content, so they can be cached for good
+    let key = format!("avatars/{}/{}", user.user_id, avatar.hash);
+    let stored_key = key.clone();
+    let urls = storage::in_background(storage.clone(), move |storage| {
+        avatar
+            .versions
+            .iter()
+            .map(|(size, encoded)| {
+                let object = format!("{}/{}.{}", stored_key, size, avatar.extension);
+                storage.put(&object, encoded)?;
+                Ok((*size, storage.url(&object)?))
+            })
+            .collect::<Result<BTreeMap<_, _>, _>>()
     })
+    .await
+    .map_err(server_error)?;
+
+    let avatar_url = urls[&PROFILE_AVATAR_SIZE].clone();
+    let previous_key = models::User::set_avatar(&db_pool, &user.user_id, &key, &avatar_url)
+        .await
+        .map_err(server_error)?
+        .ok_or_else(warp::reject::not_found)?;
+    if let Some(previous_key) = previous_key.filter(|previous_key| *previous_key != key) {
+        delete_replaced_avatar(&db_pool, storage, previous_key).await;
+    }
+
+    Ok(warp::reply::json(&serde_json::json!({
+        "avatar_url": avatar_url,
+        "sizes": urls,
+    })))
+}
+
+/// Uploaded media. Keys name content that never changes, so responses can
+/// be cached for good.
+pub async fn serve_media(
+    path: warp::path::Tail,
+    storage: Arc<dyn Storage>,
+) -> Result<impl Reply, Rejection> {
+    let key = path.as_str().to_string();
+    let content_type = match key.rsplit('.').next() {
+        Some("png") => "image/png",
+        Some("jpg") => "image/jpeg",
+        _ => return Err(warp::reject::not_found()),
+    };
+    if !key.starts_with("avatars/") || !storage::is_valid_key(&key) {
+        return Err(warp::reject::not_found());
+    }
+
+    let content = storage::in_background(storage, move |storage| storage.get(&key))
+        .await
+        .map_err(server_error)?
+        .ok_or_else(warp::reject::not_found)?;
+    Ok(warp::reply::with_header(
+        warp::reply::with_header(
+            warp::reply::with_header(content, "content-type", content_type),
+            "cache-control",
+            "public, max-age=31536000, immutable",
+        ),
+        "x-content-type-options",
+        "nosniff",
+    ))
+}
+
+/// A user's public profile, by handle whatever its case. Handles users have
+/// moved away from redirect to the one they go by now.
+pub async fn load_user_profile(
+    user_handle: String,
+    db_pool: sqlx::PgPool,
+) -> Result<warp::reply::Response, Rejection> {
+    if !profile::is_valid_handle(&user_handle) {
+        return Err(warp::reject::not_found());
+    }
+
+    if let Some(profile) = models::User::get_by_handle(&db_pool, &user_handle)
+        .await
+        .map_err(server_error)?
+        .and_then(|user| user.get_public_profile())
+    {
+        return Ok(warp::reply::json(&profile).into_response());
+    }
+
+    match models::User::current_handle_for(&db_pool, &user_handle)
+        .await
+        .map_err(server_error)?
+    {
+        Some(handle) => Ok(warp::reply::with_status(
+            warp::reply::with_header(
+                warp::reply(),
+                "location",
+                format!("/api/v1/user/{}", handle),
+            ),
+            warp::http::StatusCode::MOVED_PERMANENTLY,
+        )
+        .into_response()),
+        None => Err(warp::reject::not_found()),
+    }
+}
+
+/// Count an attempt against each of `keys` before making it, or reject the
+/// request with a 429 if attempts are blocked for any of them
+async fn reserve_attempt(
+    throttle: &Throttle,
+    db_pool: &sqlx::PgPool,
+    keys: &[ThrottleKey],
+) -> Result<(), Rejection> {
+    match throttle
+        .reserve(db_pool, keys)
+        .await
+        .map_err(server_error)?
+    {
+        Some(throttled) => Err(TooManyRequests(throttled.retry_after).into_rejection()),
+        None => Ok(()),
+    }
+}
+
+/// Take back an attempt that succeeded. The account's failures are all
+/// forgotten, but the client's aren't: it may have been guessing at other
+/// accounts. Not being able to is no reason to fail the request, though.
+async fn attempt_succeeded(throttle: &Throttle, db_pool: &sqlx::PgPool, keys: &[ThrottleKey]) {
+    for key in keys {
+        let taken_back = match key {
+            ThrottleKey::LoginAccount(_) | ThrottleKey::MfaAccount(_) => {
+                throttle.reset(db_pool, key).await
+            }
+            ThrottleKey::LoginClient(_) | ThrottleKey::SignupClient(_) => {
+                throttle.release(db_pool, key).await
+            }
+        };
+        if let Err(error) = taken_back {
+            log::warn!("Could not take back attempt for {:?}: {}", key, error);
+        }
+    }
+}
+
+/// Record `event` in the audit log. Like the action it's about, which
+/// already happened by then, it's not undone if that fails.
+async fn audit(db_pool: &sqlx::PgPool, event: NewAuditEvent) {
+    if let Err(error) = event.record(db_pool).await {
+        log::error!("Could not record audit event {:?}: {}", event, error);
+    }
+}
+
+pub async fn signup_user(
+    signup: auth::TempSignup,
+    client: ClientInfo,
+    password_policy: Arc<PasswordPolicy>,
+    db_pool: sqlx::PgPool,
+    mailer: Arc<dyn Mailer>,
+    throttle: Arc<Throttle>,
+) -> Result<impl Reply, Rejection> {
+    // Every signup counts, successful or not
+    let throttle_keys = client
+        .ip_address
+        .iter()
+        .cloned()
+        .map(ThrottleKey::SignupClient)
+        .collect::<Vec<_>>();
+    reserve_attempt(&throttle, &db_pool, &throttle_keys).await?;
+
+    let signup = signup.validate(&password_policy).map_err(|errors| {
+        ValidationFailed(errors.into_iter().map(FieldError::from).collect()).into_rejection()
+    })?;
+    let context_msg = format!("Error creating user out of {:?}", &signup);
+    let new_user = signup
+        .try_into()
+        .context(context_msg)
+        .map_err(server_error)?;
+    let user = models::User::create(&db_pool, new_user)
+        .await
+        .map_err(|error| {
+            if models::is_unique_violation(&error) {
+                Conflict("Email address already registered").into_rejection()
+            } else {
+                server_error(error)
+            }
+        })?;
+    audit(
+        &db_pool,
+        NewAuditEvent::new(AuditEventKind::Signup, &client)
+            .with_user(user.id())
+            .with_details(serde_json::json!({ "method": "password" })),
+    )
+    .await;
+
+    // The account is usable without a verified address, and the user can
+    // always ask for another email, so don't fail the signup over this.
+    if let Err(error) = send_verification_email(&db_pool, mailer, &user).await {
+        log::warn!("Could not send verification email: {:?}", error);
+    }
+
+    Ok(warp::reply::json(&user.get_profile()))
+}
+
+async fn send_verification_email(
+    db_pool: &sqlx::PgPool,
+    mailer: Arc<dyn Mailer>,
+    user: &models::User,
+) -> Result<(), anyhow::Error> {
+    let message = EmailVerification::create(db_pool, user.id(), user.email_address()).await?;
+    send_in_background(mailer, message).await?;
+    Ok(())
+}
+
+pub async fn verify_email(
+    payload: VerifyEmail,
+    db_pool: sqlx::PgPool,
+) -> Result<impl Reply, Rejection> {
+    EmailVerification::redeem(&db_pool, &payload.token)
+        .await
+        .map_err(|error| match error {
+            VerificationError::InvalidToken => AuthError::InvalidVerificationToken.into_rejection(),
+            error => server_error(error),
+        })?;
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+pub async fn resend_verification_email(
+    user: AuthenticatedUser,
+    db_pool: sqlx::PgPool,
+    mailer: Arc<dyn Mailer>,
+) -> Result<impl Reply, Rejection> {
+    let user = get_user(&db_pool, &user.user_id).await?;
+    if user.is_email_verified() {
+        return Err(Conflict("Email address already verified").into_rejection());
+    }
+
+    send_verification_email(&db_pool, mailer, &user)
+        .await
+        .map_err(server_error)?;
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+/// Always answers the same way, and does the actual work in the background,
+/// so nobody can find out which email addresses have an account.
+pub async fn forgot_password(
+    payload: ForgotPassword,
+    client: ClientInfo,
+    db_pool: sqlx::PgPool,
+    mailer: Arc<dyn Mailer>,
+) -> Result<impl Reply, Rejection> {
+    tokio::spawn(async move {
+        let result = async {
+            if let Some(user) = models::User::get_by_email(&db_pool, &payload.email_address).await?
+            {
+                let message =
+                    PasswordReset::create(&db_pool, user.id(), user.email_address()).await?;
+                audit(
+                    &db_pool,
+                    NewAuditEvent::new(AuditEventKind::PasswordResetRequested, &client)
+                        .with_subject(user.id()),
+                )
+                .await;
+                send_in_background(mailer, message).await?;
+            }
+            Ok::<_, anyhow::Error>(())
+        };
+        if let Err(error) = result.await {
+            log::warn!("Could not send password reset email: {:?}", error);
+        }
+    });
+
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::ACCEPTED,
+    ))
+}
+
+pub async fn reset_password(
+    payload: ResetPassword,
+    client: ClientInfo,
+    password_policy: Arc<PasswordPolicy>,
+    db_pool: sqlx::PgPool,
+) -> Result<impl Reply, Rejection> {
+    let field_error = |field, code, message: String| {
+        ValidationFailed(vec![FieldError {
+            field,
+            code,
+            message,
+        }])
+        .into_rejection()
+    };
+    if payload.password != payload.password_confirm {
+        return Err(field_error(
+            "password_confirm",
+            "mismatch",
+            "Password mismatch".into(),
+        ));
+    }
+    // We don't know whose password this is until the token is used up, so
+    // there's no personal info to check against
+    password_policy
+        .check(&payload.password, &[])
+        .map_err(|violation| field_error("password", violation.code(), violation.to_string()))?;
+
+    let hashed_password = PasswordHasher::new_from_env_key()
+        .and_then(|hasher| hasher.hash_password(&payload.password))
+        .map_err(server_error)?;
+    let user_id = PasswordReset::redeem(&db_pool, &payload.token, &hashed_password)
+        .await
+        .map_err(|error| match error {
+            PasswordResetError::InvalidToken => AuthError::InvalidResetToken.into_rejection(),
+            error => server_error(error),
+        })?;
+    audit(
+        &db_pool,
+        NewAuditEvent::new(AuditEventKind::PasswordChanged, &client)
+            .with_user(&user_id)
+            .with_details(serde_json::json!({ "method": "reset" })),
+    )
+    .await;
+
+    // Every session was just revoked, including the one (if any) making this
+    // request
+    auth::with_cookies(
+        warp::reply::with_status(warp::reply(), warp::http::StatusCode::NO_CONTENT),
+        auth::clear_auth_cookies(),
+    )
+    .map_err(server_error)
+}
+
+/// Open a new session for `user_id`, who just logged in with `method`,
+/// minting its first access token and refresh token, returned as
+/// `Set-Cookie` header values.
+async fn start_session(
+    user_id: &sqlx::types::Uuid,
+    method: &str,
+    client: &ClientInfo,
+    db_pool: &sqlx::PgPool,
+    token_manager: &TokenManager,
+    refresh_manager: &RefreshTokenManager,
+) -> Result<Vec<String>, Rejection> {
+    // Every way of logging in ends up here
+    if models::User::is_suspended(db_pool, user_id)
+        .await
+        .map_err(server_error)?
+    {
+        return Err(AuthError::SuspendedAccount.into_rejection());
+    }
+
+    let session = Session::create(db_pool, user_id, client)
+        .await
+        .map_err(server_error)?;
+    let access_token = token_manager
+        .create_token_with_id(UserClaims { user_id: *user_id }, &session.id.to_string())
+        .map_err(server_error)?;
+    let refresh_token = refresh_manager
+        .issue(db_pool, user_id, &session.id)
+        .await
+        .map_err(server_error)?;
+    audit(
+        db_pool,
+        NewAuditEvent::new(AuditEventKind::LoginSucceeded, client)
+            .with_user(user_id)
+            .with_details(serde_json::json!({ "method": method, "session_id": session.id })),
+    )
+    .await;
+
+    Ok(vec![
+        auth::auth_cookie(&access_token),
+        auth::refresh_cookie(&refresh_token),
+    ])
+}
+
+pub async fn login_user(
+    credentials: auth::Credentials,
+    client: ClientInfo,
+    db_pool: sqlx::PgPool,
+    token_manager: Arc<TokenManager>,
+    refresh_manager: Arc<RefreshTokenManager>,
+    throttle: Arc<Throttle>,
+) -> Result<impl Reply, Rejection> {
+    let mut throttle_keys = vec![ThrottleKey::LoginAccount(
+        credentials.email_address().clone(),
+    )];
+    throttle_keys.extend(
+        client
+            .ip_address
+            .iter()
+            .cloned()
+            .map(ThrottleKey::LoginClient),
+    );
+    reserve_attempt(&throttle, &db_pool, &throttle_keys).await?;
+
+    let hasher = PasswordHasher::new_from_env_key().map_err(server_error)?;
+    let user = models::User::get_by_email(&db_pool, credentials.email_address())
+        .await
+        .map_err(server_error)?;
+
+    let verified = match user.as_ref().and_then(|user| user.hashed_password()) {
+        Some(hashed_password) => {
+            match hasher.verify_password(credentials.password(), hashed_password) {
+                Ok(verified) => verified,
+                // Nobody can log in with this password anymore, but that's
+                // on us, not on them
+                Err(error @ PasswordHasherError::UnknownKey(_)) => {
+                    log::error!("Cannot verify password of user: {}", error);
+                    false
+                }
+                Err(error) => return Err(server_error(error)),
+            }
+        }
+        None => {
+            // Burn roughly the same amount of time a verification would, so
+            // unknown emails (or password-less accounts) can't be told apart
+            // from wrong passwords.
+            hasher
+                .hash_password(credentials.password())
+                .map_err(server_error)?;
+            false
+        }
+    };
+
+    // The attempt stays counted, even against unknown accounts, so locking
+    // out doesn't tell them apart
+    let user = match user {
+        Some(user) if verified => user,
+        _ => {
+            let mut event = NewAuditEvent::new(AuditEventKind::LoginFailed, &client).with_details(
+                serde_json::json!({
+                    "method": "password",
+                    "email_address": credentials.email_address(),
+                }),
+            );
+            if let Some(user) = &user {
+                event = event.with_subject(user.id());
+            }
+            audit(&db_pool, event).await;
+            return Err(AuthError::InvalidCredentials.into_rejection());
+        }
+    };
+    attempt_succeeded(&throttle, &db_pool, &throttle_keys).await;
+
+    // No point asking for a second factor
+    if user.suspended_at().is_some() {
+        return Err(AuthError::SuspendedAccount.into_rejection());
+    }
+
+    // This is the only time we get to see the plain password, so take the
+    // chance to move its hash to the current key and parameters
+    if let Some(previous) = user
+        .hashed_password()
+        .filter(|hashed_password| hasher.needs_rehash(hashed_password))
+    {
+        let rehashed = hasher
+            .hash_password(credentials.password())
+            .map_err(server_error)?;
+        if let Err(error) =
+            models::User::replace_hashed_password(&db_pool, user.id(), previous, &rehashed).await
+        {
+            log::warn!("Could not rehash password for {}: {:?}", user.id(), error);
+        }
+    }
+
+    // The password alone isn't enough; the session only starts once the
+    // second factor checks out too
+    let mfa_methods = mfa_methods(&db_pool, user.id()).await?;
+    if !mfa_methods.is_empty() {
+        let cookie = mfa_pending_cookie(&db_pool, &token_manager, user.id()).await?;
+        return auth::with_cookies(
+            warp::reply::json(&serde_json::json!({
+                "mfa_required": true,
+                "mfa_methods": mfa_methods,
+            })),
+            vec![cookie],
+        )
+        .map_err(server_error);
+    }
+
+    let cookies = start_session(
+        user.id(),
+        "password",
+        &client,
+        &db_pool,
+        &token_manager,
+        &refresh_manager,
+    )
+    .await?;
+    auth::with_cookies(warp::reply::json(&user.get_profile()), cookies).map_err(server_error)
+}
+
+/// Second factors `user_id` has set up, if any
+async fn mfa_methods(
+    db_pool: &sqlx::PgPool,
+    user_id: &sqlx::types::Uuid,
+) -> Result<Vec<&'static str>, Rejection> {
+    let mut methods = vec![];
+    if TotpCredential::is_enabled(db_pool, user_id)
+        .await
+        .map_err(server_error)?
+    {
+        methods.push("totp");
+    }
+    if Passkey::exists_for(db_pool, user_id)
+        .await
+        .map_err(server_error)?
+    {
+        methods.push("passkey");
+    }
+    Ok(methods)
+}
+
+async fn mfa_pending_cookie(
+    db_pool: &sqlx::PgPool,
+    token_manager: &TokenManager,
+    user_id: &sqlx::types::Uuid,
+) -> Result<String, Rejection> {
+    let login_id = PendingMfaLogin::create(db_pool, user_id)
+        .await
+        .map_err(server_error)?;
+    let token = token_manager
+        .create_mfa_pending_token(UserClaims { user_id: *user_id }, &login_id.to_string())
+        .map_err(server_error)?;
+    Ok(auth::mfa_cookie(&token))
+}
+
+/// Once the second factor checks out, the "MFA pending" token is spent:
+/// whoever gets hold of it afterwards can't log in with it again
+async fn complete_mfa_login(
+    db_pool: &sqlx::PgPool,
+    claims: &UserClaims,
+    login_id: &str,
+) -> Result<(), Rejection> {
+    let login_id = login_id
+        .parse::<sqlx::types::Uuid>()
+        .map_err(|_| AuthError::InvalidToken.into_rejection())?;
+    if PendingMfaLogin::complete(db_pool, &login_id, &claims.user_id)
+        .await
+        .map_err(server_error)?
+    {
+        Ok(())
+    } else {
+        Err(AuthError::InvalidToken.into_rejection())
+    }
+}
+
+fn mfa_rejection(error: TotpError) -> Rejection {
+    match error {
+        TotpError::InvalidCode | TotpError::NotEnrolled => {
+            AuthError::InvalidMfaCode.into_rejection()
+        }
+        TotpError::AlreadyEnrolled => {
+            Conflict("Two-factor authentication already enabled").into_rejection()
+        }
+        error => server_error(error),
+    }
+}
+
+/// Second step of a login with two-factor authentication
+pub async fn login_mfa(
+    mfa_token: Option<String>,
+    payload: MfaCode,
+    client: ClientInfo,
+    db_pool: sqlx::PgPool,
+    token_manager: Arc<TokenManager>,
+    refresh_manager: Arc<RefreshTokenManager>,
+    throttle: Arc<Throttle>,
+    totp_cipher: Arc<TotpCipher>,
+) -> Result<impl Reply, Rejection> {
+    let (claims, login_id) = token_manager
+        .verify_mfa_pending_token(
+            mfa_token.ok_or_else(|| AuthError::MissingToken.into_rejection())?,
+        )
+        .map_err(|error| AuthError::from(error).into_rejection())?;
+
+    let mut throttle_keys = vec![ThrottleKey::MfaAccount(claims.user_id)];
+    throttle_keys.extend(
+        client
+            .ip_address
+            .iter()
+            .cloned()
+            .map(ThrottleKey::LoginClient),
+    );
+    reserve_attempt(&throttle, &db_pool, &throttle_keys).await?;
+
+    match TotpCredential::verify(&db_pool, &totp_cipher, &claims.user_id, &payload.code).await {
+        Ok(()) => attempt_succeeded(&throttle, &db_pool, &throttle_keys).await,
+        Err(error @ TotpError::InvalidCode) => {
+            audit(
+                &db_pool,
+                NewAuditEvent::new(AuditEventKind::LoginFailed, &client)
+                    .with_subject(&claims.user_id)
+                    .with_details(serde_json::json!({ "method": "totp" })),
+            )
+            .await;
+            return Err(mfa_rejection(error));
+        }
+        Err(error) => return Err(mfa_rejection(error)),
+    }
+    complete_mfa_login(&db_pool, &claims, &login_id).await?;
+
+    let user = get_user(&db_pool, &claims.user_id).await?;
+    let mut cookies = start_session(
+        user.id(),
+        "totp",
+        &client,
+        &db_pool,
+        &token_manager,
+        &refresh_manager,
+    )
+    .await?;
+    cookies.push(auth::clear_mfa_cookie());
+    auth::with_cookies(warp::reply::json(&user.get_profile()), cookies).map_err(server_error)
+}
+
+/// `failed` is what a passkey that doesn't check out turns into
+fn passkey_rejection(error: WebauthnError, failed: AuthError) -> Rejection {
+    match error {
+        WebauthnError::DuplicateCredential => {
+            Conflict("Passkey already registered").into_rejection()
+        }
+        WebauthnError::InvalidConfiguration(_)
+        | WebauthnError::RandomError
+        | WebauthnError::DatabaseError(_) => server_error(error),
+        error => {
+            log::info!("Passkey rejected: {}", error);
+            failed.into_rejection()
+        }
+    }
+}
+
+/// Second step of a login with a passkey as the second factor: the options
+/// to call `navigator.credentials.get()` with
+pub async fn begin_login_mfa_passkey(
+    mfa_token: Option<String>,
+    db_pool: sqlx::PgPool,
+    token_manager: Arc<TokenManager>,
+    relying_party: Arc<RelyingParty>,
+) -> Result<impl Reply, Rejection> {
+    let (claims, _) = token_manager
+        .verify_mfa_pending_token(
+            mfa_token.ok_or_else(|| AuthError::MissingToken.into_rejection())?,
+        )
+        .map_err(|error| AuthError::from(error).into_rejection())?;
+    let options = Passkey::start_authentication(&db_pool, &relying_party, Some(&claims.user_id))
+        .await
+        .map_err(|error| passkey_rejection(error, AuthError::InvalidMfaCode))?;
+    Ok(warp::reply::json(
+        &serde_json::json!({ "publicKey": options }),
+    ))
+}
+
+pub async fn login_mfa_passkey(
+    mfa_token: Option<String>,
+    credential: AssertionCredential,
+    client: ClientInfo,
+    db_pool: sqlx::PgPool,
+    token_manager: Arc<TokenManager>,
+    refresh_manager: Arc<RefreshTokenManager>,
+    relying_party: Arc<RelyingParty>,
+) -> Result<impl Reply, Rejection> {
+    let (claims, login_id) = token_manager
+        .verify_mfa_pending_token(
+            mfa_token.ok_or_else(|| AuthError::MissingToken.into_rejection())?,
+        )
+        .map_err(|error| AuthError::from(error).into_rejection())?;
+    let user_id =
+        match Passkey::finish_authentication(&db_pool, &relying_party, &credential, false).await {
+            Ok(user_id) => user_id,
+            Err(error) => {
+                audit(
+                    &db_pool,
+                    NewAuditEvent::new(AuditEventKind::LoginFailed, &client)
+                        .with_subject(&claims.user_id)
+                        .with_details(serde_json::json!({ "method": "passkey" })),
+                )
+                .await;
+                return Err(passkey_rejection(error, AuthError::InvalidMfaCode));
+            }
+        };
+    // The challenge was bound to the user, but better safe than sorry
+    if user_id != claims.user_id {
+        return Err(AuthError::InvalidMfaCode.into_rejection());
+    }
+    complete_mfa_login(&db_pool, &claims, &login_id).await?;
+
+    let user = get_user(&db_pool, &user_id).await?;
+    let mut cookies = start_session(
+        user.id(),
+        "passkey",
+        &client,
+        &db_pool,
+        &token_manager,
+        &refresh_manager,
+    )
+    .await?;
+    cookies.push(auth::clear_mfa_cookie());
+    auth::with_cookies(warp::reply::json(&user.get_profile()), cookies).map_err(server_error)
+}
+
+/// Passwordless login: the options to call `navigator.credentials.get()`
+/// with. Any of the user's passkeys will do, and we don't know who they are
+/// yet.
+pub async fn begin_passkey_login(
+    db_pool: sqlx::PgPool,
+    relying_party: Arc<RelyingParty>,
+) -> Result<impl Reply, Rejection> {
+    let options = Passkey::start_authentication(&db_pool, &relying_party, None)
+        .await
+        .map_err(|error| passkey_rejection(error, AuthError::InvalidCredentials))?;
+    Ok(warp::reply::json(
+        &serde_json::json!({ "publicKey": options }),
+    ))
+}
+
+/// A passkey that verified its user (PIN, biometrics...) is two factors on
+/// its own, so there's no second step
+pub async fn passkey_login(
+    credential: AssertionCredential,
+    client: ClientInfo,
+    db_pool: sqlx::PgPool,
+    token_manager: Arc<TokenManager>,
+    refresh_manager: Arc<RefreshTokenManager>,
+    relying_party: Arc<RelyingParty>,
+) -> Result<impl Reply, Rejection> {
+    let user_id =
+        match Passkey::finish_authentication(&db_pool, &relying_party, &credential, true).await {
+            Ok(user_id) => user_id,
+            Err(error) => {
+                audit(
+                    &db_pool,
+                    NewAuditEvent::new(AuditEventKind::LoginFailed, &client)
+                        .with_details(serde_json::json!({ "method": "passkey" })),
+                )
+                .await;
+                return Err(passkey_rejection(error, AuthError::InvalidCredentials));
+            }
+        };
+
+    let user = get_user(&db_pool, &user_id).await?;
+    let cookies = start_session(
+        user.id(),
+        "passkey",
+        &client,
+        &db_pool,
+        &token_manager,
+        &refresh_manager,
+    )
+    .await?;
+    auth::with_cookies(warp::reply::json(&user.get_profile()), cookies).map_err(server_error)
+}
+
+/// The options to call `navigator.credentials.create()` with
+pub async fn begin_passkey_registration(
+    user: AuthenticatedUser,
+    db_pool: sqlx::PgPool,
+    relying_party: Arc<RelyingParty>,
+) -> Result<impl Reply, Rejection> {
+    let user = get_user(&db_pool, &user.user_id).await?;
+    let options = Passkey::start_registration(
+        &db_pool,
+        &relying_party,
+        user.id(),
+        user.email_address().to_str(),
+    )
+    .await
+    .map_err(|error| passkey_rejection(error, AuthError::InvalidPasskey))?;
+    Ok(warp::reply::json(
+        &serde_json::json!({ "publicKey": options }),
+    ))
+}
+
+pub async fn finish_passkey_registration(
+    user: AuthenticatedUser,
+    credential: RegistrationCredential,
+    client: ClientInfo,
+    db_pool: sqlx::PgPool,
+    relying_party: Arc<RelyingParty>,
+) -> Result<impl Reply, Rejection> {
+    let passkey =
+        Passkey::finish_registration(&db_pool, &relying_party, &user.user_id, &credential)
+            .await
+            .map_err(|error| passkey_rejection(error, AuthError::InvalidPasskey))?;
+    audit(
+        &db_pool,
+        NewAuditEvent::new(AuditEventKind::PasskeyAdded, &client)
+            .with_user(&user.user_id)
+            .with_details(serde_json::json!({ "passkey_id": passkey.id, "name": passkey.name })),
+    )
+    .await;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&passkey),
+        warp::http::StatusCode::CREATED,
+    ))
+}
+
+pub async fn list_passkeys(
+    user: AuthenticatedUser,
+    db_pool: sqlx::PgPool,
+) -> Result<impl Reply, Rejection> {
+    let passkeys = Passkey::list(&db_pool, &user.user_id)
+        .await
+        .map_err(server_error)?;
+    Ok(warp::reply::json(&passkeys))
+}
+
+pub async fn rename_passkey(
+    passkey_id: sqlx::types::Uuid,
+    user: AuthenticatedUser,
+    payload: RenamePasskey,
+    db_pool: sqlx::PgPool,
+) -> Result<impl Reply, Rejection> {
+    if Passkey::rename(&db_pool, &user.user_id, &passkey_id, &payload.name)
+        .await
+        .map_err(server_error)?
+    {
+        Ok(warp::reply::with_status(
+            warp::reply(),
+            warp::http::StatusCode::NO_CONTENT,
+        ))
+    } else {
+        Err(warp::reject::not_found())
+    }
+}
+
+pub async fn delete_passkey(
+    passkey_id: sqlx::types::Uuid,
+    user: AuthenticatedUser,
+    client: ClientInfo,
+    db_pool: sqlx::PgPool,
+) -> Result<impl Reply, Rejection> {
+    let passkey = Passkey::list(&db_pool, &user.user_id)
+        .await
+        .map_err(server_error)?
+        .into_iter()
+        .find(|passkey| passkey.id == passkey_id)
+        .ok_or_else(warp::reject::not_found)?;
+    // Don't let users lock themselves out of their account
+    if passkey.user_verified && count_login_methods(&db_pool, &user.user_id).await? <= 1 {
+        return Err(Conflict("Cannot delete the only way to log in").into_rejection());
+    }
+
+    if Passkey::delete(&db_pool, &user.user_id, &passkey_id)
+        .await
+        .map_err(server_error)?
+    {
+        audit(
+            &db_pool,
+            NewAuditEvent::new(AuditEventKind::PasskeyRemoved, &client)
+                .with_user(&user.user_id)
+                .with_details(serde_json::json!({ "passkey_id": passkey_id })),
+        )
+        .await;
+        Ok(warp::reply::with_status(
+            warp::reply(),
+            warp::http::StatusCode::NO_CONTENT,
+        ))
+    } else {
+        Err(warp::reject::not_found())
+    }
+}
+
+/// How many ways `user_id` has to log in: a password, linked identities and
+/// passkeys. Passkeys that don't verify their user only make a second
+/// factor, so they don't count.
+async fn count_login_methods(
+    db_pool: &sqlx::PgPool,
+    user_id: &sqlx::types::Uuid,
+) -> Result<usize, Rejection> {
+    let identities = UserIdentity::list_for_user(db_pool, user_id)
+        .await
+        .map_err(server_error)?;
+    let passkeys = Passkey::list(db_pool, user_id)
+        .await
+        .map_err(server_error)?;
+    let has_password = get_user(db_pool, user_id)
+        .await?
+        .hashed_password()
+        .is_some();
+    let passkeys = passkeys
+        .iter()
+        .filter(|passkey| passkey.user_verified)
+        .count();
+    Ok(identities.len() + passkeys + has_password as usize)
+}
+
+/// Start (or restart) setting up an authenticator app
+pub async fn begin_totp_enrollment(
+    user: AuthenticatedUser,
+    db_pool: sqlx::PgPool,
+    totp_cipher: Arc<TotpCipher>,
+) -> Result<impl Reply, Rejection> {
+    let user = get_user(&db_pool, &user.user_id).await?;
+    let enrollment =
+        TotpCredential::begin_enrollment(&db_pool, &totp_cipher, user.id(), user.email_address())
+            .await
+            .map_err(mfa_rejection)?;
+    Ok(warp::reply::json(&enrollment))
+}
+
+pub async fn confirm_totp_enrollment(
+    user: AuthenticatedUser,
+    payload: MfaCode,
+    client: ClientInfo,
+    db_pool: sqlx::PgPool,
+    totp_cipher: Arc<TotpCipher>,
+) -> Result<impl Reply, Rejection> {
+    let recovery_codes =
+        TotpCredential::confirm(&db_pool, &totp_cipher, &user.user_id, &payload.code)
+            .await
+            .map_err(mfa_rejection)?;
+    audit(
+        &db_pool,
+        NewAuditEvent::new(AuditEventKind::TotpEnabled, &client).with_user(&user.user_id),
+    )
+    .await;
+    Ok(warp::reply::json(
+        &serde_json::json!({ "recovery_codes": recovery_codes }),
+    ))
+}
+
+/// Turning two-factor authentication off takes a valid code, so a stolen
+/// session alone can't do it
+pub async fn disable_totp(
+    user: AuthenticatedUser,
+    payload: MfaCode,
+    client: ClientInfo,
+    db_pool: sqlx::PgPool,
+    totp_cipher: Arc<TotpCipher>,
+) -> Result<impl Reply, Rejection> {
+    TotpCredential::verify(&db_pool, &totp_cipher, &user.user_id, &payload.code)
+        .await
+        .map_err(mfa_rejection)?;
+    TotpCredential::disable(&db_pool, &user.user_id)
+        .await
+        .map_err(server_error)?;
+    audit(
+        &db_pool,
+        NewAuditEvent::new(AuditEventKind::TotpDisabled, &client).with_user(&user.user_id),
+    )
+    .await;
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+/// Public keys other services can verify our access tokens with
+pub async fn jwks(token_manager: Arc<TokenManager>) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::with_header(
+        warp::reply::json(&token_manager.jwks()),
+        "cache-control",
+        "public, max-age=3600",
+    ))
+}
+
+/// How many stored password hashes still use an old key or old parameters
+pub async fn password_hash_report(
+    _admin: AuthenticatedUser,
+    db_pool: sqlx::PgPool,
+) -> Result<impl Reply, Rejection> {
+    let hasher = PasswordHasher::new_from_env_key().map_err(server_error)?;
+    let hashed_passwords = models::User::list_hashed_passwords(&db_pool)
+        .await
+        .map_err(server_error)?;
+    Ok(warp::reply::json(&hasher.report(&hashed_passwords)))
+}
+
+/// Security events about the current user's account, newest first. What
+/// others did to it shows without who they were or where they were.
+pub async fn list_own_audit_events(
+    query: AuditQuery,
+    user: AuthenticatedUser,
+    db_pool: sqlx::PgPool,
+) -> Result<impl Reply, Rejection> {
+    let page = AuditEvent::search(&db_pool, &query.for_user(&user.user_id))
+        .await
+        .map_err(server_error)?;
+    Ok(warp::reply::json(&page.redacted_for(&user.user_id)))
+}
+
+/// How recent a login has to be to delete an account that has neither a
+/// password nor TOTP to check again
+const RECENT_LOGIN_MINUTES: i64 = 10;
+
+/// Make sure it's really the user behind the session, before something as
+/// final as deleting their account: their password, if they have one, and
+/// their TOTP code, if they use it. Others must have logged in just now.
+async fn reauthenticate(
+    user: &AuthenticatedUser,
+    confirmation: &ConfirmDeletion,
+    client: &ClientInfo,
+    db_pool: &sqlx::PgPool,
+    throttle: &Throttle,
+    totp_cipher: &TotpCipher,
+) -> Result<models::User, Rejection> {
+    let account = models::User::get_by_id(db_pool, &user.user_id)
+        .await
+        .map_err(server_error)?
+        .ok_or_else(warp::reject::not_found)?;
+    let totp_enabled = TotpCredential::is_enabled(db_pool, &user.user_id)
+        .await
+        .map_err(server_error)?;
+
+    match account.hashed_password() {
+        Some(hashed_password) => {
+            let mut throttle_keys =
+                vec![ThrottleKey::LoginAccount(account.email_address().clone())];
+            throttle_keys.extend(
+                client
+                    .ip_address
+                    .iter()
+                    .cloned()
+                    .map(ThrottleKey::LoginClient),
+            );
+            reserve_attempt(throttle, db_pool, &throttle_keys).await?;
+            let hasher = PasswordHasher::new_from_env_key().map_err(server_error)?;
+            let password = confirmation.password.as_deref().unwrap_or_default();
+            let verified = match hasher.verify_password(password, hashed_password) {
+                Ok(verified) => verified,
+                Err(error @ PasswordHasherError::UnknownKey(_)) => {
+                    log::error!("Cannot verify password of user: {}", error);
+                    false
+                }
+                Err(error) => return Err(server_error(error)),
+            };
+            if !verified {
+                return Err(AuthError::InvalidCredentials.into_rejection());
+            }
+            attempt_succeeded(throttle, db_pool, &throttle_keys).await;
+        }
+        None if !totp_enabled => {
+            let session = match user.session_id() {
+                Some(session_id) => Session::get(db_pool, &user.user_id, session_id)
+                    .await
+                    .map_err(server_error)?,
+                None => None,
+            };
+            let recent = session
+                .map(|session| {
+                    session.created_at
+                        > chrono::Utc::now() - chrono::Duration::minutes(RECENT_LOGIN_MINUTES)
+                })
+                .unwrap_or(false);
+            if !recent {
+                return Err(AuthError::ReauthenticationRequired.into_rejection());
+            }
+        }
+        None => {}
+    }
+
+    if totp_enabled {
+        let code = confirmation.code.as_deref().unwrap_or_default();
+        TotpCredential::verify(db_pool, totp_cipher, &user.user_id, code)
+            .await
+            .map_err(mfa_rejection)?;
+    }
+    Ok(account)
+}
+
+/// Schedule the deletion of the current user's account, once the grace
+/// period is over. They're emailed a link to cancel it.
+pub async fn request_account_deletion(
+    user: AuthenticatedUser,
+    confirmation: ConfirmDeletion,
+    client: ClientInfo,
+    db_pool: sqlx::PgPool,
+    throttle: Arc<Throttle>,
+    totp_cipher: Arc<TotpCipher>,
+    mailer: Arc<dyn Mailer>,
+    deletion_policy: Arc<DeletionPolicy>,
+) -> Result<impl Reply, Rejection> {
+    let account = reauthenticate(
+        &user,
+        &confirmation,
+        &client,
+        &db_pool,
+        &throttle,
+        &totp_cipher,
+    )
+    .await?;
+    let (deletion, message) = ScheduledDeletion::schedule(
+        &db_pool,
+        &deletion_policy,
+        &user.user_id,
+        account.email_address(),
+    )
+    .await
+    .map_err(server_error)?;
+    tokio::spawn(async move {
+        if let Err(error) = send_in_background(mailer, message).await {
+            log::warn!("Could not send account deletion email: {:?}", error);
+        }
+    });
+    audit(
+        &db_pool,
+        NewAuditEvent::new(AuditEventKind::AccountDeletionRequested, &client)
+            .with_user(&user.user_id)
+            .with_details(serde_json::json!({ "purge_after": deletion.purge_after })),
+    )
+    .await;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&deletion),
+        warp::http::StatusCode::ACCEPTED,
+    ))
+}
+
+pub async fn load_account_deletion(
+    user: AuthenticatedUser,
+    db_pool: sqlx::PgPool,
+) -> Result<impl Reply, Rejection> {
+    let deletion = ScheduledDeletion::get(&db_pool, &user.user_id)
+        .await
+        .map_err(server_error)?
+        .ok_or_else(warp::reject::not_found)?;
+    Ok(warp::reply::json(&deletion))
+}
+
+/// Keep an account after all, through the link emailed when its deletion
+/// was scheduled
+pub async fn cancel_account_deletion_by_token(
+    payload: CancelDeletion,
+    client: ClientInfo,
+    db_pool: sqlx::PgPool,
+) -> Result<impl Reply, Rejection> {
+    let user_id = ScheduledDeletion::cancel_with_token(&db_pool, &payload.token)
+        .await
+        .map_err(server_error)?
+        .ok_or_else(|| AuthError::InvalidCancelToken.into_rejection())?;
+    audit(
+        &db_pool,
+        NewAuditEvent::new(AuditEventKind::AccountDeletionCanceled, &client).with_user(&user_id),
+    )
+    .await;
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+/// Keep the current user's account after all, if it isn't too late
+pub async fn cancel_account_deletion(
+    user: AuthenticatedUser,
+    client: ClientInfo,
+    db_pool: sqlx::PgPool,
+) -> Result<impl Reply, Rejection> {
+    if !ScheduledDeletion::cancel(&db_pool, &user.user_id)
+        .await
+        .map_err(server_error)?
+    {
+        return match ScheduledDeletion::get(&db_pool, &user.user_id)
+            .await
+            .map_err(server_error)?
+        {
+            Some(deletion) if deletion.is_due() => {
+                Err(Conflict("Account is already being deleted").into_rejection())
+            }
+            _ => Err(warp::reject::not_found()),
+        };
+    }
+    audit(
+        &db_pool,
+        NewAuditEvent::new(AuditEventKind::AccountDeletionCanceled, &client)
+            .with_user(&user.user_id),
+    )
+    .await;
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+/// Start building an archive of everything we hold about the current user.
+/// They get an email with a link to it once it's ready.
+pub async fn request_data_export(
+    user: AuthenticatedUser,
+    client: ClientInfo,
+    db_pool: sqlx::PgPool,
+    storage: Arc<dyn Storage>,
+    mailer: Arc<dyn Mailer>,
+    export_links: Arc<ExportLinks>,
+) -> Result<impl Reply, Rejection> {
+    let export = DataExport::request(&db_pool, &user.user_id)
+        .await
+        .map_err(server_error)?;
+    audit(
+        &db_pool,
+        NewAuditEvent::new(AuditEventKind::DataExportRequested, &client)
+            .with_user(&user.user_id)
+            .with_details(serde_json::json!({ "export_id": export.id })),
+    )
+    .await;
+
+    let export_id = export.id;
+    let builder_pool = db_pool.clone();
+    tokio::spawn(async move {
+        if let Err(error) =
+            exports::build_export(&builder_pool, storage, mailer, &export_links, &export_id).await
+        {
+            log::warn!("Could not build data export {}: {}", export_id, error);
+        }
+    });
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&export),
+        warp::http::StatusCode::ACCEPTED,
+    ))
+}
+
+/// The current user's latest data exports, with links to those that are
+/// ready
+pub async fn list_data_exports(
+    user: AuthenticatedUser,
+    db_pool: sqlx::PgPool,
+    export_links: Arc<ExportLinks>,
+) -> Result<impl Reply, Rejection> {
+    let exports = DataExport::list(&db_pool, &user.user_id)
+        .await
+        .map_err(server_error)?
+        .into_iter()
+        .map(|export| export.with_download_url(&export_links))
+        .collect::<Vec<_>>();
+    Ok(warp::reply::json(&exports))
+}
+
+/// The ZIP archive of a data export, through a signed link rather than a
+/// login, so it can be followed from an email
+pub async fn download_data_export(
+    export_id: sqlx::types::Uuid,
+    link: SignedLink,
+    db_pool: sqlx::PgPool,
+    storage: Arc<dyn Storage>,
+    export_links: Arc<ExportLinks>,
+) -> Result<warp::reply::Response, Rejection> {
+    if !export_links.verify(&export_id, &link) {
+        return Err(warp::reject::not_found());
+    }
+    let (completed_at, entries) = DataExport::downloadable(&db_pool, &export_id)
+        .await
+        .map_err(server_error)?
+        .ok_or_else(warp::reject::not_found)?;
+
+    let mut response =
+        warp::reply::Response::new(exports::stream_archive(storage, entries, completed_at));
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/zip"),
+    );
+    headers.insert(
+        header::CONTENT_DISPOSITION,
+        HeaderValue::from_str(&format!(
+            "attachment; filename=\"weft-export-{}.zip\"",
+            completed_at.format("%Y-%m-%d")
+        ))
+        .map_err(server_error)?,
+    );
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static("private, no-store"),
+    );
+    headers.insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    Ok(response)
+}
+
+pub async fn search_audit_events(
+    query: AuditQuery,
+    _admin: AuthenticatedUser,
+    db_pool: sqlx::PgPool,
+) -> Result<impl Reply, Rejection> {
+    let page = AuditEvent::search(&db_pool, &query)
+        .await
+        .map_err(server_error)?;
+    Ok(warp::reply::json(&page))
+}
+
+pub async fn list_users(
+    search: UserSearch,
+    _moderator: AuthenticatedUser,
+    db_pool: sqlx::PgPool,
+) -> Result<impl Reply, Rejection> {
+    let users = models::User::list(&db_pool, search.search(), search.limit(), search.offset())
+        .await
+        .map_err(server_error)?;
+    Ok(warp::reply::json(&users))
+}
+
+/// Make sure `actor` may act on `target_id`, returning both their roles.
+/// Unknown targets are a 404.
+async fn check_outranks(
+    db_pool: &sqlx::PgPool,
+    actor: &AuthenticatedUser,
+    target_id: &sqlx::types::Uuid,
+) -> Result<(Role, Role), Rejection> {
+    let target_role = models::User::role_of(db_pool, target_id)
+        .await
+        .map_err(server_error)?
+        .ok_or_else(warp::reject::not_found)?;
+    let actor_role = models::User::role_of(db_pool, &actor.user_id)
+        .await
+        .map_err(server_error)?
+        .unwrap_or_default();
+    if actor_role.outranks(target_role) {
+        Ok((actor_role, target_role))
+    } else {
+        Err(AuthError::Forbidden.into_rejection())
+    }
+}
+
+pub async fn suspend_user(
+    user_id: sqlx::types::Uuid,
+    moderator: AuthenticatedUser,
+    client: ClientInfo,
+    db_pool: sqlx::PgPool,
+    payload: SuspendUser,
+) -> Result<impl Reply, Rejection> {
+    check_outranks(&db_pool, &moderator, &user_id).await?;
+    let reason = payload
+        .reason
+        .as_deref()
+        .map(str::trim)
+        .filter(|reason| !reason.is_empty());
+    if !models::User::suspend(&db_pool, &user_id, reason)
+        .await
+        .map_err(server_error)?
+    {
+        return Err(Conflict("User already suspended").into_rejection());
+    }
+    audit(
+        &db_pool,
+        NewAuditEvent::new(AuditEventKind::UserSuspended, &client)
+            .with_actor(&moderator.user_id)
+            .with_subject(&user_id)
+            .with_details(serde_json::json!({ "reason": reason })),
+    )
+    .await;
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+pub async fn unsuspend_user(
+    user_id: sqlx::types::Uuid,
+    moderator: AuthenticatedUser,
+    client: ClientInfo,
+    db_pool: sqlx::PgPool,
+) -> Result<impl Reply, Rejection> {
+    check_outranks(&db_pool, &moderator, &user_id).await?;
+    if !models::User::unsuspend(&db_pool, &user_id)
+        .await
+        .map_err(server_error)?
+    {
+        return Err(Conflict("User is not suspended").into_rejection());
+    }
+    audit(
+        &db_pool,
+        NewAuditEvent::new(AuditEventKind::UserUnsuspended, &client)
+            .with_actor(&moderator.user_id)
+            .with_subject(&user_id),
+    )
+    .await;
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+/// Admins can hand out any role up to their own, to users below them
+pub async fn change_user_role(
+    user_id: sqlx::types::Uuid,
+    admin: AuthenticatedUser,
+    client: ClientInfo,
+    db_pool: sqlx::PgPool,
+    payload: ChangeRole,
+) -> Result<impl Reply, Rejection> {
+    let (admin_role, previous_role) = check_outranks(&db_pool, &admin, &user_id).await?;
+    if payload.role > admin_role {
+        return Err(AuthError::Forbidden.into_rejection());
+    }
+    if !models::User::set_role(&db_pool, &user_id, payload.role)
+        .await
+        .map_err(server_error)?
+    {
+        return Err(warp::reject::not_found());
+    }
+    audit(
+        &db_pool,
+        NewAuditEvent::new(AuditEventKind::UserRoleChanged, &client)
+            .with_actor(&admin.user_id)
+            .with_subject(&user_id)
+            .with_details(serde_json::json!({
+                "role": payload.role,
+                "previous_role": previous_role,
+            })),
+    )
+    .await;
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+/// Delete a user right away, purging them the same way a deletion they
+/// asked for is once its grace period is over
+pub async fn delete_user(
+    user_id: sqlx::types::Uuid,
+    admin: AuthenticatedUser,
+    client: ClientInfo,
+    db_pool: sqlx::PgPool,
+    storage: Arc<dyn Storage>,
+) -> Result<impl Reply, Rejection> {
+    check_outranks(&db_pool, &admin, &user_id).await?;
+    if !deletion::purge_account(&db_pool, storage, &user_id)
+        .await
+        .map_err(server_error)?
+    {
+        return Err(warp::reject::not_found());
+    }
+    audit(
+        &db_pool,
+        NewAuditEvent::new(AuditEventKind::UserDeleted, &client)
+            .with_actor(&admin.user_id)
+            .with_subject(&user_id),
+    )
+    .await;
+    Ok(warp::reply::with_status(
+        warp::reply(),
+        warp::http::StatusCode::NO_CONTENT,
+    ))
+}
+
+pub async fn refresh_session(
+    refresh_token: Option<String>,
+    db_pool: sqlx::PgPool,
+    token_manager: Arc<TokenManager>,
+    refresh_manager: Arc<RefreshTokenManager>,
+) -> Result<impl Reply, Rejection> {
+    let refresh_token =
+        refresh_token.ok_or_else(|| AuthError::InvalidRefreshToken.into_rejection())?;
+    let issued = refresh_manager
+        .rotate(&db_pool, &refresh_token)
+        .await
+        .map_err(|error| match error {
+            RefreshTokenError::InvalidToken => AuthError::InvalidRefreshToken.into_rejection(),
+            RefreshTokenError::ReusedToken => AuthError::ReusedRefreshToken.into_rejection(),
+            error => server_error(error),
+        })?;
+
+    let access_token = token_manager
+        .create_token_with_id(
+            UserClaims {
+                user_id: issued.user_id,
+            },
+            &issued.family_id.to_string(),
+        )
+        .map_err(server_error)?;
+
+    auth::with_cookies(
+        warp::reply(),
+        vec![
+            auth::auth_cookie(&access_token),
+            auth::refresh_cookie(&issued),
+        ],
+    )
+    .map_err(server_error)
+}
+
+pub async fn logout_user(
+    user: AuthenticatedUser,
+    client: ClientInfo,
+    db_pool: sqlx::PgPool,
+) -> Result<impl Reply, Rejection> {
+    if let Some(session_id) = user.session_id() {
+        Session::revoke(&db_pool, &user.user_id, session_id)
+            .await
+            .map_err(server_error)?;
+        audit(
+            &db_pool,
+            NewAuditEvent::new(AuditEventKind::Logout, &client)
+                .with_user(&user.user_id)
+                .with_details(serde_json::json!({ "session_id": session_id })),
+        )
+        .await;
+    }
+    auth::with_cookies(warp::reply(), auth::clear_auth_cookies()).map_err(server_error)
+}
+
+pub async fn list_sessions(
+    user: AuthenticatedUser,
+    db_pool: sqlx::PgPool,
+) -> Result<impl Reply, Rejection> {
+    let sessions = Session::list_active(&db_pool, &user.user_id)
+        .await
+        .map_err(server_error)?
+        .into_iter()
+        .map(|session| SessionInfo {
+            current: user.session_id() == Some(&session.id),
+            session,
+        })
+        .collect::<Vec<_>>();
+    Ok(warp::reply::json(&sessions))
+}
+
+pub async fn revoke_session(
+    session_id: sqlx::types::Uuid,
+    user: AuthenticatedUser,
+    client: ClientInfo,
+    db_pool: sqlx::PgPool,
+) -> Result<impl Reply, Rejection> {
+    if Session::revoke(&db_pool, &user.user_id, &session_id)
+        .await
+        .map_err(server_error)?
+    {
+        audit(
+            &db_pool,
+            NewAuditEvent::new(AuditEventKind::SessionRevoked, &client)
+                .with_user(&user.user_id)
+                .with_details(serde_json::json!({ "session_id": session_id })),
+        )
+        .await;
+        Ok(warp::reply::with_status(
+            warp::reply(),
+            warp::http::StatusCode::NO_CONTENT,
+        ))
+    } else {
+        Err(warp::reject::not_found())
+    }
+}
+
+pub async fn revoke_other_sessions(
+    user: AuthenticatedUser,
+    client: ClientInfo,
+    db_pool: sqlx::PgPool,
+) -> Result<impl Reply, Rejection> {
+    let revoked = Session::revoke_all(&db_pool, &user.user_id, user.session_id())
+        .await
+        .map_err(server_error)?;
+    audit(
+        &db_pool,
+        NewAuditEvent::new(AuditEventKind::SessionRevoked, &client)
+            .with_user(&user.user_id)
+            .with_details(
+                serde_json::json!({ "kept_session_id": user.session_id(), "count": revoked }),
+            ),
+    )
+    .await;
+    Ok(warp::reply::json(
+        &vec![("revoked", revoked)]
+            .into_iter()
+            .collect::<HashMap<_, _>>(),
+    ))
+}
+
+fn redirect_to(location: &str) -> impl Reply {
+    warp::reply::with_status(
+        warp::reply::with_header(warp::reply(), "location", location),
+        warp::http::StatusCode::FOUND,
+    )
+}
+
+fn oauth_rejection(error: OAuthError) -> Rejection {
+    match error {
+        OAuthError::InvalidConfiguration(_)
+        | OAuthError::DiscoveryError(_)
+        | OAuthError::DatabaseError(_) => server_error(error),
+        _ => AuthError::OAuthFailed.into_rejection(),
+    }
+}
+
+fn find_oauth_provider(
+    providers: &OAuthProviders,
+    provider_name: &str,
+) -> Result<Arc<OAuthProvider>, Rejection> {
+    providers
+        .get(provider_name)
+        .ok_or_else(warp::reject::not_found)
+}
+
+/// The user a token was issued to. One that's gone since makes the token
+/// as good as invalid.
+async fn get_user(
+    db_pool: &sqlx::PgPool,
+    user_id: &sqlx::types::Uuid,
+) -> Result<models::User, Rejection> {
+    models::User::get_by_id(db_pool, user_id)
+        .await
+        .map_err(server_error)?
+        .ok_or_else(|| AuthError::InvalidToken.into_rejection())
+}
+
+/// Find the user a completed OAuth flow belongs to. Known identities log
+/// straight into their user; linking flows attach the identity to the user
+/// that started them; new identities are matched to an existing user by
+/// provider-verified email address, or get a brand new user.
+async fn resolve_oauth_user(
+    db_pool: &sqlx::PgPool,
+    provider: &OAuthProvider,
+    flow: &CompletedFlow,
+    client: &ClientInfo,
+) -> Result<models::User, Rejection> {
+    // Deleted while the flow was going on
+    let user_gone = || AuthError::OAuthFailed.into_rejection();
+    let identity_linked = |user_id: &sqlx::types::Uuid| {
+        NewAuditEvent::new(AuditEventKind::IdentityLinked, client)
+            .with_user(user_id)
+            .with_details(serde_json::json!({ "provider": provider.name() }))
+    };
+
+    let claims = &flow.claims;
+    let identity = UserIdentity::get_by_subject(db_pool, provider.name(), &claims.sub)
+        .await
+        .map_err(server_error)?;
+
+    if let Some(link_user_id) = &flow.link_user_id {
+        match identity {
+            Some(identity) if &identity.user_id != link_user_id => {
+                return Err(Conflict("Identity is linked to another account").into_rejection())
+            }
+            Some(_) => {}
+            None => {
+                UserIdentity::create(
+                    db_pool,
+                    link_user_id,
+                    provider.name(),
+                    &claims.sub,
+                    claims.email.as_deref(),
+                )
+                .await
+                .map_err(server_error)?;
+                audit(db_pool, identity_linked(link_user_id)).await;
+            }
+        }
+        return models::User::get_by_id(db_pool, link_user_id)
+            .await
+            .map_err(server_error)?
+            .ok_or_else(user_gone);
+    }
+
+    if let Some(identity) = identity {
+        return models::User::get_by_id(db_pool, &identity.user_id)
+            .await
+            .map_err(server_error)?
+            .ok_or_else(user_gone);
+    }
+
+    let email_address = claims
+        .email
+        .as_deref()
+        .filter(|_| claims.email_verified)
+        .ok_or_else(|| oauth_rejection(OAuthError::UnverifiedEmail))?;
+    let email_address = EmailAddress::from_str(email_address)
+        .map_err(|_| AuthError::OAuthFailed.into_rejection())?;
+
+    // Anyone can claim an address at some providers, so an identity only
+    // gets into an existing account if it was linked from that account, or
+    // the provider is trusted to vouch for the address
+    let user = match models::User::get_by_email(db_pool, &email_address)
+        .await
+        .map_err(server_error)?
+    {
+        Some(user) if provider.trusts_email() => user,
+        Some(_) => {
+            return Err(Conflict(
+                "An account with this email address already exists: log in, then link this identity from your account",
+            )
+            .into_rejection())
+        }
+        None => {
+            let user = models::User::create_without_password(
+                db_pool,
+                &email_address,
+                claims.name.as_deref().unwrap_or_default(),
+            )
+            .await
+            .map_err(server_error)?;
+            audit(
+                db_pool,
+                NewAuditEvent::new(AuditEventKind::Signup, client)
+                    .with_user(user.id())
+                    .with_details(serde_json::json!({ "method": provider.name() })),
+            )
+            .await;
+            user
+        }
+    };
+
+    UserIdentity::create(
+        db_pool,
+        user.id(),
+        provider.name(),
+        &claims.sub,
+        claims.email.as_deref(),
+    )
+    .await
+    .map_err(server_error)?;
+    audit(db_pool, identity_linked(user.id())).await;
+
+    Ok(user)
+}
+
+async fn redirect_to_provider(
+    provider: &OAuthProvider,
+    db_pool: &sqlx::PgPool,
+    link_user_id: Option<&sqlx::types::Uuid>,
+) -> Result<warp::reply::Response, Rejection> {
+    let (url, state) = provider
+        .start(db_pool, link_user_id)
+        .await
+        .map_err(oauth_rejection)?;
+    auth::with_cookies(
+        redirect_to(url.as_str()),
+        vec![auth::oauth_state_cookie(&state)],
+    )
+    .map_err(server_error)
+}
+
+pub async fn oauth_start(
+    provider_name: String,
+    providers: Arc<OAuthProviders>,
+    db_pool: sqlx::PgPool,
+) -> Result<impl Reply, Rejection> {
+    let provider = find_oauth_provider(&providers, &provider_name)?;
+    redirect_to_provider(&provider, &db_pool, None).await
+}
+
+pub async fn oauth_link(
+    provider_name: String,
+    user: AuthenticatedUser,
+    providers: Arc<OAuthProviders>,
+    db_pool: sqlx::PgPool,
+) -> Result<impl Reply, Rejection> {
+    let provider = find_oauth_provider(&providers, &provider_name)?;
+    redirect_to_provider(&provider, &db_pool, Some(&user.user_id)).await
 }
 
-pub async fn load_current_user(
-    auth_token: AuthToken,
+#[allow(clippy::too_many_arguments)]
+pub async fn oauth_end(
+    provider_name: String,
+    response: AuthorizationResponse,
+    state_cookie: Option<String>,
+    client: ClientInfo,
+    providers: Arc<OAuthProviders>,
     db_pool: sqlx::PgPool,
+    token_manager: Arc<TokenManager>,
+    refresh_manager: Arc<RefreshTokenManager>,
 ) -> Result<impl Reply, Rejection> {
-    Ok(warp::reply::json(&format!(
-        "load_current_user : {:?} : {:?}",
-        auth_token, db_pool
-    )))
+    let provider = find_oauth_provider(&providers, &provider_name)?;
+    let (code, state) = match (response.code, response.state) {
+        (Some(code), Some(state)) if response.error.is_none() => (code, state),
+        _ => return Err(AuthError::OAuthFailed.into_rejection()),
+    };
+
+    // The state must come back to the same browser that started the flow
+    if state_cookie.as_deref() != Some(state.as_str()) {
+        return Err(AuthError::OAuthFailed.into_rejection());
+    }
+
+    let flow = provider
+        .finish(&db_pool, code, &state)
+        .await
+        .map_err(oauth_rejection)?;
+    let user = resolve_oauth_user(&db_pool, &provider, &flow, &client).await?;
+
+    // Linking happens from an already logged in session
+    if flow.link_user_id.is_some() {
+        return auth::with_cookies(
+            redirect_to(&auth::app_url()),
+            vec![auth::clear_oauth_state_cookie()],
+        )
+        .map_err(server_error);
+    }
+
+    // The provider vouches for the first factor only
+    if !mfa_methods(&db_pool, user.id()).await?.is_empty() {
+        return auth::with_cookies(
+            redirect_to(&format!(
+                "{}/login/mfa",
+                auth::app_url().trim_end_matches('/')
+            )),
+            vec![
+                mfa_pending_cookie(&db_pool, &token_manager, user.id()).await?,
+                auth::clear_oauth_state_cookie(),
+            ],
+        )
+        .map_err(server_error);
+    }
+
+    let mut cookies = start_session(
+        user.id(),
+        provider.name(),
+        &client,
+        &db_pool,
+        &token_manager,
+        &refresh_manager,
+    )
+    .await?;
+    cookies.push(auth::clear_oauth_state_cookie());
+
+    auth::with_cookies(redirect_to(&auth::app_url()), cookies).map_err(server_error)
 }
 
-pub async fn save_current_user(
-    auth_token: AuthToken,
+pub async fn list_access_tokens(
+    user: AuthenticatedUser,
     db_pool: sqlx::PgPool,
-    payload: UserProfile,
 ) -> Result<impl Reply, Rejection> {
-    Ok(warp::reply::json(&format!(
-        "save_current_user : {:?} : {:?} : {:?}",
-        auth_token, db_pool, payload,
-    )))
+    let tokens = PersonalAccessToken::list(&db_pool, &user.user_id)
+        .await
+        .map_err(server_error)?;
+    Ok(warp::reply::json(&tokens))
 }
 
-pub async fn load_user_profile(
-    user_handle: String,
+/// The response is the only time the token itself is ever shown
+pub async fn create_access_token(
+    user: AuthenticatedUser,
+    new_token: NewAccessToken,
+    client: ClientInfo,
     db_pool: sqlx::PgPool,
 ) -> Result<impl Reply, Rejection> {
-    Ok(warp::reply::json(&format!(
-        "load_user_profile : {:?} : {:?}",
-        user_handle, db_pool
-    )))
+    new_token.validate().map_err(|errors| {
+        ValidationFailed(errors.into_iter().map(FieldError::from).collect()).into_rejection()
+    })?;
+    let issued = PersonalAccessToken::create(&db_pool, &user.user_id, &new_token)
+        .await
+        .map_err(server_error)?;
+    audit(
+        &db_pool,
+        NewAuditEvent::new(AuditEventKind::AccessTokenCreated, &client)
+            .with_user(&user.user_id)
+            .with_details(serde_json::json!({
+                "token_id": issued.details.id,
+                "name": issued.details.name,
+                "scopes": issued.details.scopes,
+                "expires_at": issued.details.expires_at,
+            })),
+    )
+    .await;
+    Ok(warp::reply::with_status(
+        warp::reply::json(&issued),
+        warp::http::StatusCode::CREATED,
+    ))
 }
 
-pub async fn signup_user(
-    signup: auth::Signup,
+pub async fn revoke_access_token(
+    token_id: sqlx::types::Uuid,
+    user: AuthenticatedUser,
+    client: ClientInfo,
     db_pool: sqlx::PgPool,
-) -> Result<impl Reply, anyhow::Error> {
-    let context_msg = format!("Error creating user out of {:?}", &signup);
-    let new_user = signup.try_into().context(context_msg)?;
-    let user = models::User::create(&db_pool, new_user).await?;
-    Ok(warp::reply::json(&user.get_profile()))
+) -> Result<impl Reply, Rejection> {
+    if PersonalAccessToken::revoke(&db_pool, &user.user_id, &token_id)
+        .await
+        .map_err(server_error)?
+    {
+        audit(
+            &db_pool,
+            NewAuditEvent::new(AuditEventKind::AccessTokenRevoked, &client)
+                .with_user(&user.user_id)
+                .with_details(serde_json::json!({ "token_id": token_id })),
+        )
+        .await;
+        Ok(warp::reply::with_status(
+            warp::reply(),
+            warp::http::StatusCode::NO_CONTENT,
+        ))
+    } else {
+        Err(warp::reject::not_found())
+    }
 }
 
-pub async fn oauth_start() -> Result<impl Reply, Rejection> {
-Ok(warp::reply::with_header("", "some-header-name", "some-header-value"))
+pub async fn list_identities(
+    user: AuthenticatedUser,
+    db_pool: sqlx::PgPool,
+) -> Result<impl Reply, Rejection> {
+    let identities = UserIdentity::list_for_user(&db_pool, &user.user_id)
+        .await
+        .map_err(server_error)?;
+    Ok(warp::reply::json(&identities))
 }
 
-// pub async fn oauth2_flow() -> Result<impl Reply, Rejection> {
-//     yup_oauth2::parse_application_secret()
-// }
+pub async fn unlink_identity(
+    identity_id: sqlx::types::Uuid,
+    user: AuthenticatedUser,
+    client: ClientInfo,
+    db_pool: sqlx::PgPool,
+) -> Result<impl Reply, Rejection> {
+    // Don't let users lock themselves out of their account
+    if count_login_methods(&db_pool, &user.user_id).await? <= 1 {
+        return Err(Conflict("Cannot unlink the only way to log in").into_rejection());
+    }
+
+    if UserIdentity::delete(&db_pool, &user.user_id, &identity_id)
+        .await
+        .map_err(server_error)?
+    {
+        audit(
+            &db_pool,
+            NewAuditEvent::new(AuditEventKind::IdentityUnlinked, &client)
+                .with_user(&user.user_id)
+                .with_details(serde_json::json!({ "identity_id": identity_id })),
+        )
+        .await;
+        Ok(warp::reply::with_status(
+            warp::reply(),
+            warp::http::StatusCode::NO_CONTENT,
+        ))
+    } else {
+        Err(warp::reject::not_found())
+    }
+}
 
 /// You'll need to install `systemfd` and `cargo-watch`:
 /// ```
@@ -87,28 +2377,131 @@ fn main() -> Result<(), Error> {
         .enable_all()
         .build()?;
 
-    let google_client_secret = oauth2::ClientSecret::new(std::env::var("GOOGLE_CLIENT_SECRET")?);
+    let token_manager = Arc::new(TokenManager::new_from_env_key()?);
+    let refresh_manager = Arc::new(RefreshTokenManager::new_from_env()?);
 
     let pool = runtime.block_on(async {
         sqlx::postgres::PgPoolOptions::new().connect_lazy("postgres://localhost")
     })?;
+    let oauth_providers = Arc::new(runtime.block_on(OAuthProviders::from_env())?);
+
+    let mailer = mailer_from_env()?;
+    let password_policy = Arc::new(PasswordPolicy::new_from_env()?);
+    let relying_party = Arc::new(RelyingParty::new_from_env()?);
+    let throttle = Arc::new(Throttle::new_from_env()?);
+    let trusted_proxies =
+        Arc::new(TrustedProxies::new_from_env().context("Invalid WEFT_TRUSTED_PROXIES")?);
+    let csrf_policy = Arc::new(CsrfPolicy::new_from_env()?);
+    let storage = storage_from_env()?;
+    let deletion_policy = Arc::new(DeletionPolicy::new_from_env()?);
+    let export_links = Arc::new(ExportLinks::new_from_env()?);
+    let totp_cipher = Arc::new(TotpCipher::new_from_env()?);
+
+    let admin_user_ids = admin_user_ids_from_env()?;
+    if !admin_user_ids.is_empty() {
+        let promoted = runtime.block_on(models::User::grant_admin(&pool, &admin_user_ids))?;
+        if promoted > 0 {
+            log::info!("Made {} user(s) from WEFT_ADMIN_USER_IDS admins", promoted);
+        }
+    }
+    // Plain ones still work, so this is no reason not to start
+    match runtime.block_on(TotpCredential::encrypt_stored_secrets(&pool, &totp_cipher)) {
+        Ok(0) => {}
+        Ok(encrypted) => log::info!("Encrypted {} stored TOTP secret(s)", encrypted),
+        Err(error) => log::error!("Could not encrypt stored TOTP secrets: {}", error),
+    }
+
+    let with_auth_user = auth_user(token_manager.clone(), pool.clone(), trusted_proxies.clone());
+    let with_session_user =
+        session_user(token_manager.clone(), pool.clone(), trusted_proxies.clone());
+    let with_verified_user =
+        verified_user(token_manager.clone(), pool.clone(), trusted_proxies.clone());
+    let with_user_manager = permitted_user(
+        token_manager.clone(),
+        pool.clone(),
+        trusted_proxies.clone(),
+        Permission::ManageUsers,
+    );
+    let with_user_deleter = permitted_user(
+        token_manager.clone(),
+        pool.clone(),
+        trusted_proxies.clone(),
+        Permission::DeleteUsers,
+    );
+    let with_role_manager = permitted_user(
+        token_manager.clone(),
+        pool.clone(),
+        trusted_proxies.clone(),
+        Permission::ManageRoles,
+    );
+    let with_audit_viewer = permitted_user(
+        token_manager.clone(),
+        pool.clone(),
+        trusted_proxies.clone(),
+        Permission::ViewAuditLog,
+    );
+    let with_security_viewer = permitted_user(
+        token_manager.clone(),
+        pool.clone(),
+        trusted_proxies.clone(),
+        Permission::ViewSecurityReports,
+    );
+    let with_client = client_info(trusted_proxies.clone());
+    let with_csrf_protection = csrf_protection(csrf_policy, token_manager.clone(), pool.clone());
+
+    // Accounts get purged in the background once their grace period is over
+    runtime.spawn(deletion::purge_periodically(pool.clone(), storage.clone()));
+    // Builds of data exports that failed get retried, and expired ones removed
+    runtime.spawn(exports::run_periodically(
+        pool.clone(),
+        storage.clone(),
+        mailer.clone(),
+        export_links.clone(),
+    ));
 
     let with_database = warp::any().map(move || pool.clone());
-    let with_google_client_secret = warp::any().map(move || google_client_secret.clone());
+    let with_oauth_providers = warp::any().map(move || oauth_providers.clone());
+    let with_token_manager = warp::any().map(move || token_manager.clone());
+    let with_refresh_manager = warp::any().map(move || refresh_manager.clone());
+    let with_mailer = warp::any().map(move || mailer.clone());
+    let with_password_policy = warp::any().map(move || password_policy.clone());
+    let with_relying_party = warp::any().map(move || relying_party.clone());
+    let with_throttle = warp::any().map(move || throttle.clone());
+    let with_storage = warp::any().map(move || storage.clone());
+    let with_deletion_policy = warp::any().map(move || deletion_policy.clone());
+    let with_export_links = warp::any().map(move || export_links.clone());
+    let with_totp_cipher = warp::any().map(move || totp_cipher.clone());
 
-    let current_user_path = warp::path("user")
-        .and(warp::path::end())
-        .and(auth_user())
-        .and(with_database.clone());
+    let current_user_path = warp::path("user").and(warp::path::end());
     let get_current_user = current_user_path
-        .clone()
         .and(warp::get())
+        .and(with_auth_user.clone())
+        .and_then(|user| require_scope(TokenScope::ReadProfile, user))
+        .and(with_database.clone())
         .and_then(load_current_user);
     let update_current_user = current_user_path
         .and(warp::post())
+        .and(with_verified_user.clone())
+        .and(with_database.clone())
+        .and(with_storage.clone())
         .and(warp::body::content_length_limit(1024 * 32))
         .and(warp::body::json())
         .and_then(save_current_user);
+    let upload_avatar_route = warp::path("user")
+        .and(warp::path("avatar"))
+        .and(warp::path::end())
+        .and(warp::put())
+        .and(with_verified_user.clone())
+        .and(warp::body::content_length_limit(avatars::MAX_UPLOAD_BYTES))
+        .and(warp::body::bytes())
+        .and(with_database.clone())
+        .and(with_storage.clone())
+        .and_then(upload_avatar);
+    let media_route = warp::path("media")
+        .and(warp::get())
+        .and(warp::path::tail())
+        .and(with_storage.clone())
+        .and_then(serve_media);
 
     let current_user_routes = get_current_user.or(update_current_user);
 
@@ -120,33 +2513,524 @@ fn main() -> Result<(), Error> {
         .and(with_database.clone())
         .and_then(load_user_profile);
 
-    let user_session_routes = warp::path("signup")
+    let signup_route = warp::path("signup")
         .and(warp::path::end())
         .and(warp::post())
         .and(warp::body::content_length_limit(1024 * 32))
         .and(warp::body::json())
+        .and(with_client.clone())
+        .and(with_password_policy.clone())
         .and(with_database.clone())
-        .and_then(|signup: auth::Signup, db_pool: sqlx::PgPool| {
-            signup_user(signup, db_pool).map_err(|_| warp::reject::reject())
-            // Ok(signup_user(signup, db_pool).unwrap()).into()
-        });
+        .and(with_mailer.clone())
+        .and(with_throttle.clone())
+        .and_then(signup_user);
 
-    // let user_oauth_routes = warp::path("oauth2")
-    //     .and(warp::path("google"))
-    //     .and(warp::path::end())
-    //     .and
-    let oauth_route_prefix = warp::path("oauth2").and(warp::path("google"));
-    let oauth_start_route = oauth_route_prefix.and(warp::path("start")).and(warp::path::end()).and_then(oauth_start);
-    // let oauth_end = oauth2_route_prefix.and(warp::path("end")).and(warp::path::end())
-    //     .and(warp::get())
-    //     .and(warp::query::<HashMap<String, String>>())
-    //     .and_then(|| {})
-
-    let all_routes = warp::path("api").and(warp::path("v1")).and(
-        current_user_routes
-            .or(public_profile)
-            .or(user_session_routes),
-    );
+    let verify_email_path = warp::path("email").and(warp::path("verify"));
+    let verify_email_route = verify_email_path
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024 * 32))
+        .and(warp::body::json())
+        .and(with_database.clone())
+        .and_then(verify_email);
+    let resend_verification_route = verify_email_path
+        .and(warp::path("resend"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(with_session_user.clone())
+        .and(with_database.clone())
+        .and(with_mailer.clone())
+        .and_then(resend_verification_email);
+
+    let password_path = warp::path("password");
+    let forgot_password_route = password_path
+        .and(warp::path("forgot"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024 * 32))
+        .and(warp::body::json())
+        .and(with_client.clone())
+        .and(with_database.clone())
+        .and(with_mailer.clone())
+        .and_then(forgot_password);
+    let reset_password_route = password_path
+        .and(warp::path("reset"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024 * 32))
+        .and(warp::body::json())
+        .and(with_password_policy.clone())
+        .and(with_client.clone())
+        .and(with_database.clone())
+        .and_then(reset_password);
+
+    let login_route = warp::path("login")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024 * 32))
+        .and(warp::body::json())
+        .and(with_client.clone())
+        .and(with_database.clone())
+        .and(with_token_manager.clone())
+        .and(with_refresh_manager.clone())
+        .and(with_throttle.clone())
+        .and_then(login_user);
+    let login_mfa_route = warp::path("login")
+        .and(warp::path("mfa"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::cookie::optional(auth::MFA_TOKEN_COOKIE))
+        .and(warp::body::content_length_limit(1024 * 32))
+        .and(warp::body::json())
+        .and(with_client.clone())
+        .and(with_database.clone())
+        .and(with_token_manager.clone())
+        .and(with_refresh_manager.clone())
+        .and(with_throttle.clone())
+        .and(with_totp_cipher.clone())
+        .and_then(login_mfa);
+
+    let login_mfa_passkey_path = warp::path("login")
+        .and(warp::path("mfa"))
+        .and(warp::path("passkey"));
+    let begin_login_mfa_passkey_route = login_mfa_passkey_path
+        .and(warp::path("options"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::cookie::optional(auth::MFA_TOKEN_COOKIE))
+        .and(with_database.clone())
+        .and(with_token_manager.clone())
+        .and(with_relying_party.clone())
+        .and_then(begin_login_mfa_passkey);
+    let login_mfa_passkey_route = login_mfa_passkey_path
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::cookie::optional(auth::MFA_TOKEN_COOKIE))
+        .and(warp::body::content_length_limit(1024 * 32))
+        .and(warp::body::json())
+        .and(with_client.clone())
+        .and(with_database.clone())
+        .and(with_token_manager.clone())
+        .and(with_refresh_manager.clone())
+        .and(with_relying_party.clone())
+        .and_then(login_mfa_passkey);
+
+    let passkey_login_path = warp::path("login").and(warp::path("passkey"));
+    let begin_passkey_login_route = passkey_login_path
+        .and(warp::path("options"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(with_database.clone())
+        .and(with_relying_party.clone())
+        .and_then(begin_passkey_login);
+    let passkey_login_route = passkey_login_path
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024 * 32))
+        .and(warp::body::json())
+        .and(with_client.clone())
+        .and(with_database.clone())
+        .and(with_token_manager.clone())
+        .and(with_refresh_manager.clone())
+        .and(with_relying_party.clone())
+        .and_then(passkey_login);
+
+    let refresh_route = warp::path("token")
+        .and(warp::path("refresh"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::cookie::optional(auth::REFRESH_TOKEN_COOKIE))
+        .and(with_database.clone())
+        .and(with_token_manager.clone())
+        .and(with_refresh_manager.clone())
+        .and_then(refresh_session);
+
+    let logout_route = warp::path("logout")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(with_session_user.clone())
+        .and(with_client.clone())
+        .and(with_database.clone())
+        .and_then(logout_user);
+
+    let sessions_path = warp::path("sessions");
+    let list_sessions_route = sessions_path
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_session_user.clone())
+        .and(with_database.clone())
+        .and_then(list_sessions);
+    let revoke_session_route = sessions_path
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(with_session_user.clone())
+        .and(with_client.clone())
+        .and(with_database.clone())
+        .and_then(revoke_session);
+    let revoke_other_sessions_route = sessions_path
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(with_session_user.clone())
+        .and(with_client.clone())
+        .and(with_database.clone())
+        .and_then(revoke_other_sessions);
+
+    let user_session_routes = signup_route
+        .or(verify_email_route)
+        .or(resend_verification_route)
+        .or(forgot_password_route)
+        .or(reset_password_route)
+        .or(login_route)
+        .or(login_mfa_route)
+        .or(begin_login_mfa_passkey_route)
+        .or(login_mfa_passkey_route)
+        .or(begin_passkey_login_route)
+        .or(passkey_login_route)
+        .or(refresh_route)
+        .or(logout_route)
+        .or(list_sessions_route)
+        .or(revoke_session_route)
+        .or(revoke_other_sessions_route);
+
+    let totp_path = warp::path("mfa").and(warp::path("totp"));
+    let begin_totp_route = totp_path
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(with_session_user.clone())
+        .and(with_database.clone())
+        .and(with_totp_cipher.clone())
+        .and_then(begin_totp_enrollment);
+    let confirm_totp_route = totp_path
+        .and(warp::path("confirm"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(with_session_user.clone())
+        .and(warp::body::content_length_limit(1024 * 32))
+        .and(warp::body::json())
+        .and(with_client.clone())
+        .and(with_database.clone())
+        .and(with_totp_cipher.clone())
+        .and_then(confirm_totp_enrollment);
+    let disable_totp_route = totp_path
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(with_session_user.clone())
+        .and(warp::body::content_length_limit(1024 * 32))
+        .and(warp::body::json())
+        .and(with_client.clone())
+        .and(with_database.clone())
+        .and(with_totp_cipher.clone())
+        .and_then(disable_totp);
+
+    let passkeys_path = warp::path("passkeys");
+    let begin_passkey_registration_route = passkeys_path
+        .and(warp::path("options"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(with_session_user.clone())
+        .and(with_database.clone())
+        .and(with_relying_party.clone())
+        .and_then(begin_passkey_registration);
+    let finish_passkey_registration_route = passkeys_path
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(with_session_user.clone())
+        .and(warp::body::content_length_limit(1024 * 32))
+        .and(warp::body::json())
+        .and(with_client.clone())
+        .and(with_database.clone())
+        .and(with_relying_party.clone())
+        .and_then(finish_passkey_registration);
+    let list_passkeys_route = passkeys_path
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_session_user.clone())
+        .and(with_database.clone())
+        .and_then(list_passkeys);
+    let rename_passkey_route = passkeys_path
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::put())
+        .and(with_session_user.clone())
+        .and(warp::body::content_length_limit(1024 * 32))
+        .and(warp::body::json())
+        .and(with_database.clone())
+        .and_then(rename_passkey);
+    let delete_passkey_route = passkeys_path
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(with_session_user.clone())
+        .and(with_client.clone())
+        .and(with_database.clone())
+        .and_then(delete_passkey);
+
+    let user_mfa_routes = begin_totp_route
+        .or(confirm_totp_route)
+        .or(disable_totp_route)
+        .or(begin_passkey_registration_route)
+        .or(finish_passkey_registration_route)
+        .or(list_passkeys_route)
+        .or(rename_passkey_route)
+        .or(delete_passkey_route);
+
+    let oauth_route_prefix = warp::path("oauth2").and(warp::path::param::<String>());
+    let oauth_start_route = oauth_route_prefix
+        .and(warp::path("start"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_oauth_providers.clone())
+        .and(with_database.clone())
+        .and_then(oauth_start);
+    let oauth_link_route = oauth_route_prefix
+        .and(warp::path("link"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_session_user.clone())
+        .and(with_oauth_providers.clone())
+        .and(with_database.clone())
+        .and_then(oauth_link);
+    let oauth_end_route = oauth_route_prefix
+        .and(warp::path("end"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<AuthorizationResponse>())
+        .and(warp::cookie::optional(oauth::OAUTH_STATE_COOKIE))
+        .and(with_client.clone())
+        .and(with_oauth_providers.clone())
+        .and(with_database.clone())
+        .and(with_token_manager.clone())
+        .and(with_refresh_manager.clone())
+        .and_then(oauth_end);
+
+    let identities_path = warp::path("user").and(warp::path("identities"));
+    let list_identities_route = identities_path
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_session_user.clone())
+        .and(with_database.clone())
+        .and_then(list_identities);
+    let unlink_identity_route = identities_path
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(with_session_user.clone())
+        .and(with_client.clone())
+        .and(with_database.clone())
+        .and_then(unlink_identity);
+
+    let access_tokens_path = warp::path("tokens");
+    let list_access_tokens_route = access_tokens_path
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_session_user.clone())
+        .and(with_database.clone())
+        .and_then(list_access_tokens);
+    let create_access_token_route = access_tokens_path
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(with_session_user.clone())
+        .and(warp::body::content_length_limit(1024 * 32))
+        .and(warp::body::json())
+        .and(with_client.clone())
+        .and(with_database.clone())
+        .and_then(create_access_token);
+    let revoke_access_token_route = access_tokens_path
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(with_session_user.clone())
+        .and(with_client.clone())
+        .and(with_database.clone())
+        .and_then(revoke_access_token);
+
+    let access_token_routes = list_access_tokens_route
+        .or(create_access_token_route)
+        .or(revoke_access_token_route);
+
+    let user_oauth_routes = oauth_start_route
+        .or(oauth_link_route)
+        .or(oauth_end_route)
+        .or(list_identities_route)
+        .or(unlink_identity_route);
+
+    let password_hash_report_route = warp::path("admin")
+        .and(warp::path("password-hashes"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_security_viewer)
+        .and(with_database.clone())
+        .and_then(password_hash_report);
+
+    let admin_users_path = warp::path("admin").and(warp::path("users"));
+    let list_users_route = admin_users_path
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<UserSearch>())
+        .and(with_user_manager.clone())
+        .and(with_database.clone())
+        .and_then(list_users);
+    let suspend_user_route = admin_users_path
+        .and(warp::path::param::<sqlx::types::Uuid>())
+        .and(warp::path("suspend"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(with_user_manager.clone())
+        .and(with_client.clone())
+        .and(with_database.clone())
+        .and(warp::body::content_length_limit(1024 * 32))
+        .and(warp::body::json())
+        .and_then(suspend_user);
+    let unsuspend_user_route = admin_users_path
+        .and(warp::path::param::<sqlx::types::Uuid>())
+        .and(warp::path("unsuspend"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(with_user_manager)
+        .and(with_client.clone())
+        .and(with_database.clone())
+        .and_then(unsuspend_user);
+    let change_role_route = admin_users_path
+        .and(warp::path::param::<sqlx::types::Uuid>())
+        .and(warp::path("role"))
+        .and(warp::path::end())
+        .and(warp::put())
+        .and(with_role_manager)
+        .and(with_client.clone())
+        .and(with_database.clone())
+        .and(warp::body::content_length_limit(1024 * 32))
+        .and(warp::body::json())
+        .and_then(change_user_role);
+    let delete_user_route = admin_users_path
+        .and(warp::path::param::<sqlx::types::Uuid>())
+        .and(warp::path::end())
+        .and(warp::delete())
+        .and(with_user_deleter)
+        .and(with_client.clone())
+        .and(with_database.clone())
+        .and(with_storage.clone())
+        .and_then(delete_user);
+
+    let user_audit_route = warp::path("user")
+        .and(warp::path("audit"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<AuditQuery>())
+        .and(with_session_user.clone())
+        .and(with_database.clone())
+        .and_then(list_own_audit_events);
+    let account_deletion_path = warp::path("user")
+        .and(warp::path("deletion"))
+        .and(warp::path::end());
+    let request_deletion = account_deletion_path
+        .and(warp::post())
+        .and(with_session_user.clone())
+        .and(warp::body::content_length_limit(1024 * 32))
+        .and(warp::body::json())
+        .and(with_client.clone())
+        .and(with_database.clone())
+        .and(with_throttle.clone())
+        .and(with_totp_cipher.clone())
+        .and(with_mailer.clone())
+        .and(with_deletion_policy)
+        .and_then(request_account_deletion);
+    let load_deletion = account_deletion_path
+        .and(warp::get())
+        .and(with_session_user.clone())
+        .and(with_database.clone())
+        .and_then(load_account_deletion);
+    let cancel_deletion = account_deletion_path
+        .and(warp::delete())
+        .and(with_session_user.clone())
+        .and(with_client.clone())
+        .and(with_database.clone())
+        .and_then(cancel_account_deletion);
+    let cancel_deletion_by_token = warp::path("deletion")
+        .and(warp::path("cancel"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024 * 32))
+        .and(warp::body::json())
+        .and(with_client.clone())
+        .and(with_database.clone())
+        .and_then(cancel_account_deletion_by_token);
+    let account_deletion_routes = request_deletion
+        .or(load_deletion)
+        .or(cancel_deletion)
+        .or(cancel_deletion_by_token);
+    let data_export_path = warp::path("user").and(warp::path("export"));
+    let request_export = data_export_path
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(with_session_user.clone())
+        .and(with_client.clone())
+        .and(with_database.clone())
+        .and(with_storage.clone())
+        .and(with_mailer.clone())
+        .and(with_export_links.clone())
+        .and_then(request_data_export);
+    let list_exports = data_export_path
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_session_user.clone())
+        .and(with_database.clone())
+        .and(with_export_links.clone())
+        .and_then(list_data_exports);
+    let download_export = data_export_path
+        .and(warp::path::param::<sqlx::types::Uuid>())
+        .and(warp::path("archive.zip"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<SignedLink>())
+        .and(with_database.clone())
+        .and(with_storage.clone())
+        .and(with_export_links)
+        .and_then(download_data_export);
+    let data_export_routes = request_export.or(list_exports).or(download_export);
+    let admin_audit_route = warp::path("admin")
+        .and(warp::path("audit"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query::<AuditQuery>())
+        .and(with_audit_viewer)
+        .and(with_database.clone())
+        .and_then(search_audit_events);
+
+    let admin_routes = password_hash_report_route
+        .or(admin_audit_route)
+        .or(list_users_route)
+        .or(suspend_user_route)
+        .or(unsuspend_user_route)
+        .or(change_role_route)
+        .or(delete_user_route);
+
+    let api_routes = warp::path("api")
+        .and(warp::path("v1"))
+        .and(with_csrf_protection)
+        .and(
+            current_user_routes
+                .or(user_session_routes)
+                .or(user_mfa_routes)
+                .or(access_token_routes)
+                .or(user_audit_route)
+                .or(account_deletion_routes)
+                .or(data_export_routes)
+                .or(upload_avatar_route)
+                .or(media_route)
+                .or(user_oauth_routes)
+                .or(admin_routes)
+                .or(public_profile),
+        );
+
+    let jwks_route = warp::path(".well-known")
+        .and(warp::path("jwks.json"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_token_manager.clone())
+        .and_then(jwks);
+
+    let all_routes = api_routes.or(jwks_route);
 
     /**************************************************************************
      *  Server setup
@@ -157,10 +3041,10 @@ fn main() -> Result<(), Error> {
     // a `hyper::service::MakeService` for use with a `hyper::server::Server`.
     let service = warp::service(
         all_routes
-            // .recover(problem::unpack) // TODO
+            .recover(rejections::recover)
             .with(
                 warp::cors()
-                    .allow_methods(vec!["GET", "POST", "PUT"])
+                    .allow_methods(vec!["GET", "POST", "PUT", "DELETE"])
                     .allow_header("content-type")
                     .allow_header("authorization")
                     .allow_any_origin()
@@ -192,3 +3076,207 @@ fn main() -> Result<(), Error> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        auth::webauthn::tests::{
+            register_passkey, relying_party, SoftwareAuthenticator, ORIGIN, RP_ID,
+        },
+        test_db,
+    };
+
+    const TEST_SECRET: &str = "some-test-secret-long-enough-for-anything";
+
+    fn token_manager() -> Arc<TokenManager> {
+        Arc::new(TokenManager::new(TEST_SECRET))
+    }
+
+    fn refresh_manager() -> Arc<RefreshTokenManager> {
+        Arc::new(RefreshTokenManager::new(chrono::Duration::days(1)))
+    }
+
+    /// The challenge in the options a `begin_*` endpoint replied with
+    async fn challenge(reply: impl Reply) -> String {
+        let body = warp::hyper::body::to_bytes(reply.into_response().into_body())
+            .await
+            .unwrap();
+        let options: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        options["publicKey"]["challenge"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    /// What the "MFA pending" cookie holds once `user_id` got their
+    /// password right
+    async fn mfa_token(
+        db_pool: &sqlx::PgPool,
+        token_manager: &TokenManager,
+        user_id: &sqlx::types::Uuid,
+    ) -> String {
+        let login_id = PendingMfaLogin::create(db_pool, user_id).await.unwrap();
+        token_manager
+            .create_mfa_pending_token(UserClaims { user_id: *user_id }, &login_id.to_string())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_csrf_protection() {
+        let token_manager = token_manager();
+        // Never connected to, as long as no personal access token comes along
+        let db_pool = sqlx::postgres::PgPoolOptions::new()
+            .connect_lazy("postgres://localhost")
+            .unwrap();
+        let filter = csrf_protection(
+            Arc::new(CsrfPolicy::default()),
+            token_manager.clone(),
+            db_pool,
+        );
+        let access_token = token_manager
+            .create_token_with_id(
+                UserClaims {
+                    user_id: sqlx::types::Uuid::new_v4(),
+                },
+                &sqlx::types::Uuid::new_v4().to_string(),
+            )
+            .unwrap();
+        let request = |path: &str, authorization: &str| {
+            warp::test::request()
+                .method("POST")
+                .path(path)
+                .header("host", "weft.example")
+                .header("origin", "https://evil.example")
+                .header("authorization", authorization)
+        };
+
+        let bearer = format!("Bearer {}", access_token);
+        assert!(request("/api/v1/user", &bearer)
+            .filter(&filter)
+            .await
+            .is_ok());
+        for path in &["/api/v1/token/refresh", "/api/v1/login/mfa/passkey"] {
+            assert!(request(path, &bearer).filter(&filter).await.is_err());
+        }
+        for authorization in &["Bearer forged", "Basic d2VmdA=="] {
+            assert!(request("/api/v1/user", authorization)
+                .filter(&filter)
+                .await
+                .is_err());
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database"]
+    async fn test_passkey_login() {
+        let db_pool = test_db::pool().await;
+        let relying_party = Arc::new(relying_party());
+        let user = test_db::create_user(&db_pool).await;
+        let mut authenticator = SoftwareAuthenticator::new();
+        register_passkey(&db_pool, &mut authenticator, user.id()).await;
+
+        let options = begin_passkey_login(db_pool.clone(), relying_party.clone())
+            .await
+            .unwrap();
+        let credential = authenticator.assert(RP_ID, ORIGIN, &challenge(options).await);
+        let response = passkey_login(
+            credential,
+            ClientInfo::default(),
+            db_pool.clone(),
+            token_manager(),
+            refresh_manager(),
+            relying_party.clone(),
+        )
+        .await
+        .unwrap()
+        .into_response();
+        assert_eq!(response.status(), warp::http::StatusCode::OK);
+        assert_eq!(response.headers().get_all("set-cookie").iter().count(), 2);
+
+        // A passkey that doesn't verify its user is a second factor only
+        let other_user = test_db::create_user(&db_pool).await;
+        let mut authenticator = SoftwareAuthenticator::new();
+        authenticator.user_verification = false;
+        register_passkey(&db_pool, &mut authenticator, other_user.id()).await;
+
+        let options = begin_passkey_login(db_pool.clone(), relying_party.clone())
+            .await
+            .unwrap();
+        let credential = authenticator.assert(RP_ID, ORIGIN, &challenge(options).await);
+        let rejection = passkey_login(
+            credential,
+            ClientInfo::default(),
+            db_pool.clone(),
+            token_manager(),
+            refresh_manager(),
+            relying_party,
+        )
+        .await
+        .err()
+        .unwrap();
+        assert!(matches!(
+            rejection.find::<AuthError>(),
+            Some(AuthError::InvalidCredentials)
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database"]
+    async fn test_login_mfa_passkey() {
+        let db_pool = test_db::pool().await;
+        let (token_manager, relying_party) = (token_manager(), Arc::new(relying_party()));
+        let user = test_db::create_user(&db_pool).await;
+        let mut authenticator = SoftwareAuthenticator::new();
+        authenticator.user_verification = false;
+        register_passkey(&db_pool, &mut authenticator, user.id()).await;
+        let other_user = test_db::create_user(&db_pool).await;
+        let mut other_authenticator = SoftwareAuthenticator::new();
+        register_passkey(&db_pool, &mut other_authenticator, other_user.id()).await;
+
+        let mfa_token = mfa_token(&db_pool, &token_manager, user.id()).await;
+        let login = |credential| {
+            login_mfa_passkey(
+                Some(mfa_token.clone()),
+                credential,
+                ClientInfo::default(),
+                db_pool.clone(),
+                token_manager.clone(),
+                refresh_manager(),
+                relying_party.clone(),
+            )
+        };
+        let begin = || {
+            begin_login_mfa_passkey(
+                Some(mfa_token.clone()),
+                db_pool.clone(),
+                token_manager.clone(),
+                relying_party.clone(),
+            )
+        };
+
+        // Somebody else's passkey won't do
+        let options = begin().await.unwrap();
+        let credential = other_authenticator.assert(RP_ID, ORIGIN, &challenge(options).await);
+        let rejection = login(credential).await.err().unwrap();
+        assert!(matches!(
+            rejection.find::<AuthError>(),
+            Some(AuthError::InvalidMfaCode)
+        ));
+
+        let options = begin().await.unwrap();
+        let credential = authenticator.assert(RP_ID, ORIGIN, &challenge(options).await);
+        let response = login(credential).await.unwrap().into_response();
+        assert_eq!(response.status(), warp::http::StatusCode::OK);
+
+        // The "MFA pending" token is spent
+        let options = begin().await.unwrap();
+        let credential = authenticator.assert(RP_ID, ORIGIN, &challenge(options).await);
+        let rejection = login(credential).await.err().unwrap();
+        assert!(matches!(
+            rejection.find::<AuthError>(),
+            Some(AuthError::InvalidToken)
+        ));
+    }
+}
@@ -10,14 +10,131 @@ use sqlx::{
 };
 use url::Url;
 
-use crate::auth::{EmailAddress, HashedPassword, NewUser, UserProfile};
+use crate::auth::{
+    profile::{ProfileChanges, HANDLE_RESERVATION_DAYS, MAX_RETIRED_HANDLES},
+    roles::Role,
+    EmailAddress, HashedPassword, NewUser, PublicProfile, Session, UserProfile,
+};
+
+/// Whether `error` comes from a `UNIQUE` constraint, i.e. the row being
+/// written clashes with an existing one
+pub fn is_unique_violation(error: &sqlx::Error) -> bool {
+    match error {
+        sqlx::Error::Database(error) => error.code().as_deref() == Some("23505"),
+        _ => false,
+    }
+}
 
 #[derive(Debug, sqlx::FromRow)]
 pub struct User {
     id: sqlx::types::Uuid,
     email_address: EmailAddress,
     full_name: String,
-    hashed_password: HashedPassword,
+    /// Missing for users who only ever logged in through an OAuth provider
+    hashed_password: Option<HashedPassword>,
+    /// When the user proved they own `email_address`
+    verified_at: Option<DateTime<Utc>>,
+    role: Role,
+    /// Suspended users can't log in, nor use their personal access tokens
+    suspended_at: Option<DateTime<Utc>>,
+    handle: Option<String>,
+    avatar_url: Option<Url>,
+    /// BCP 47 language tag
+    locale: Option<String>,
+    /// IANA time zone name
+    timezone: Option<String>,
+    created_at: DateTime<Utc>,
+    /// When the profile last changed
+    updated_at: DateTime<Utc>,
+}
+
+/// A user as listed to moderators and admins
+#[derive(Debug, Serialize)]
+pub struct UserSummary {
+    pub id: Uuid,
+    pub email_address: String,
+    pub full_name: String,
+    pub role: Role,
+    pub verified_at: Option<DateTime<Utc>>,
+    pub suspended_at: Option<DateTime<Utc>>,
+    pub suspension_reason: Option<String>,
+}
+
+impl UserSummary {
+    fn from_row(row: &sqlx::postgres::PgRow) -> Result<Self, sqlx::Error> {
+        let role: String = row.try_get("role")?;
+        Ok(Self {
+            id: row.try_get("id")?,
+            email_address: row.try_get("email_address")?,
+            full_name: row.try_get("full_name")?,
+            role: Role::from_str(&role).map_err(|_| {
+                sqlx::Error::Decode(format!("Error decoding `{}` as Role", role).into())
+            })?,
+            verified_at: row.try_get("verified_at")?,
+            suspended_at: row.try_get("suspended_at")?,
+            suspension_reason: row.try_get("suspension_reason")?,
+        })
+    }
+}
+
+/// A `users` row as stored, to be parsed into a `User`
+struct UserRow {
+    id: Uuid,
+    email_address: String,
+    full_name: String,
+    hashed_password: Option<String>,
+    verified_at: Option<DateTime<Utc>>,
+    role: String,
+    suspended_at: Option<DateTime<Utc>>,
+    handle: Option<String>,
+    avatar_url: Option<String>,
+    locale: Option<String>,
+    timezone: Option<String>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+impl TryFrom<UserRow> for User {
+    type Error = sqlx::Error;
+
+    fn try_from(row: UserRow) -> Result<Self, Self::Error> {
+        let id = row.id;
+        Ok(Self {
+            id,
+            email_address: EmailAddress::from_stored(row.email_address),
+            full_name: row.full_name,
+            hashed_password: row
+                .hashed_password
+                .as_deref()
+                .map(HashedPassword::from_str)
+                .transpose()
+                .map_err(|_| {
+                    sqlx::Error::Decode(
+                        format!("Error decoding hashed password for user `{}`", id).into(),
+                    )
+                })?,
+            verified_at: row.verified_at,
+            role: Role::from_str(&row.role).map_err(|_| {
+                sqlx::Error::Decode(format!("Error decoding `{}` as Role", row.role).into())
+            })?,
+            suspended_at: row.suspended_at,
+            handle: row.handle,
+            avatar_url: row
+                .avatar_url
+                .as_deref()
+                .map(Url::parse)
+                .transpose()
+                .map_err(|_| {
+                    sqlx::Error::Decode(
+                        format!("Error decoding avatar URL for user `{}`", id).into(),
+                    )
+                })?,
+            locale: row.locale,
+            timezone: row.timezone,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+        })
+    }
 }
 
 impl User {
@@ -25,113 +142,537 @@ impl User {
         pool: &sqlx::PgPool,
         id: &sqlx::types::Uuid,
     ) -> Result<Option<Self>, sqlx::Error> {
-        sqlx::query!(
-            "SELECT id, email_address, full_name, hashed_password FROM users WHERE id = $1",
+        sqlx::query_as!(
+            UserRow,
+            r#"
+SELECT id, email_address, full_name, hashed_password, verified_at, role, suspended_at,
+    handle, avatar_url, locale, timezone, created_at, updated_at
+FROM users WHERE id = $1;"#,
             id
         )
         .fetch_optional(pool)
-        .await
-        .and_then(|maybe_row| match maybe_row {
-            Some(row) => Ok(Some(Self {
-                id: row.id,
-                // TODO: Take care of this unwrap
-                // email_address: match EmailAddress::from_str(&row.email_address) {
-                email_address: match EmailAddress::from_str(&row.email_address) {
-                    Ok(email_address) => email_address,
-                    Err(_) => {
-                        return Err(sqlx::Error::Decode(
-                            format!("Error decoding `{}` as EmailAddress", row.email_address)
-                                .into(),
-                        ))
-                    }
-                },
-                full_name: row.full_name,
-                hashed_password: match HashedPassword::from_str(&row.hashed_password) {
-                    Ok(hashed_password) => hashed_password,
-                    Err(error) => {
-                        return Err(sqlx::Error::Decode(
-                            format!("Error decoding hashed password for user `{}`", id).into(),
-                        ));
-                    }
-                },
-            })),
-            None => Ok(None),
-        })
+        .await?
+        .map(Self::try_from)
+        .transpose()
     }
 
-    pub async fn get_by_credentials(
+    pub async fn get_by_email(
         db_pool: &sqlx::PgPool,
         email: &EmailAddress,
-        hashed_password: &str,
     ) -> Result<Option<Self>, sqlx::Error> {
-        sqlx::query!("SELECT id, email_address, full_name, hashed_password FROM users WHERE email_address = $1 AND hashed_password = $2;",
-            email.to_str(),
-hashed_password,
+        sqlx::query_as!(
+            UserRow,
+            r#"
+SELECT id, email_address, full_name, hashed_password, verified_at, role, suspended_at,
+    handle, avatar_url, locale, timezone, created_at, updated_at
+FROM users WHERE lower(email_address) = lower($1);"#,
+            email.to_str()
+        )
+        .fetch_optional(db_pool)
+        .await?
+        .map(Self::try_from)
+        .transpose()
+    }
+
+    /// Apply validated profile changes, to be saved with `update`
+    pub fn apply(&mut self, changes: ProfileChanges) {
+        if let Some(full_name) = changes.full_name {
+            self.full_name = full_name;
+        }
+        if let Some(handle) = changes.handle {
+            self.handle = handle;
+        }
+        if let Some(avatar_url) = changes.avatar_url {
+            self.avatar_url = avatar_url;
+        }
+        if let Some(locale) = changes.locale {
+            self.locale = locale;
+        }
+        if let Some(timezone) = changes.timezone {
+            self.timezone = timezone;
+        }
+    }
+
+    /// Save the profile fields, unless the stored profile changed since this
+    /// copy was read, in which case `None` is returned. The saved user comes
+    /// back with its new `updated_at`, along with the key of the uploaded
+    /// avatar it no longer points at, if any. A handle being replaced goes
+    /// into the handle history.
+    pub async fn update(
+        &self,
+        db_pool: &sqlx::PgPool,
+    ) -> Result<Option<(Self, Option<String>)>, sqlx::Error> {
+        let mut transaction = db_pool.begin().await?;
+        let previous = match sqlx::query(
+            r#"
+SELECT handle, avatar_key, avatar_url FROM users
+WHERE id = $1 AND updated_at = $2
+FOR UPDATE;"#,
+        )
+        .bind(&self.id)
+        .bind(&self.updated_at)
+        .fetch_optional(&mut transaction)
+        .await?
+        {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let previous_handle: Option<String> = previous.try_get("handle")?;
+        let previous_avatar_key: Option<String> = previous.try_get("avatar_key")?;
+        let previous_avatar_url: Option<String> = previous.try_get("avatar_url")?;
+        let replaced_avatar_key = previous_avatar_key.filter(|_| {
+            previous_avatar_url.as_deref() != self.avatar_url.as_ref().map(Url::as_str)
+        });
+
+        let row = sqlx::query_as!(
+            UserRow,
+            r#"
+UPDATE users
+SET full_name = $3, handle = $4, avatar_url = $5, locale = $6, timezone = $7,
+    avatar_key = CASE WHEN avatar_url IS NOT DISTINCT FROM $5 THEN avatar_key END,
+    updated_at = greatest(now(), updated_at + interval '1 microsecond')
+WHERE id = $1 AND updated_at = $2
+RETURNING id, email_address, full_name, hashed_password, verified_at, role, suspended_at,
+    handle, avatar_url, locale, timezone, created_at, updated_at;"#,
+            &self.id,
+            &self.updated_at,
+            &self.full_name,
+            self.handle.as_deref(),
+            self.avatar_url.as_ref().map(Url::as_str),
+            self.locale.as_deref(),
+            self.timezone.as_deref()
+        )
+        .fetch_one(&mut transaction)
+        .await?;
+
+        // Changing only the case of a handle doesn't retire it
+        let retired_handle = previous_handle.filter(|previous| match &self.handle {
+            Some(handle) => !previous.eq_ignore_ascii_case(handle),
+            None => true,
+        });
+        // Whoever retired a handle long enough ago has let it go
+        sqlx::query(
+            "DELETE FROM handle_history WHERE retired_at <= now() - make_interval(days => $1);",
+        )
+        .bind(HANDLE_RESERVATION_DAYS)
+        .execute(&mut transaction)
+        .await?;
+        if let Some(previous) = retired_handle {
+            sqlx::query(
+                r#"
+INSERT INTO handle_history (handle, user_id) VALUES ($1, $2)
+ON CONFLICT ((lower(handle))) DO NOTHING;"#,
             )
-            .fetch_optional(db_pool)
-            .await
-        .and_then(|maybe_row| match maybe_row {
-            Some(row) => Ok(Some(Self {
-                id: row.id,
-                // TODO: Take care of this unwrap
-                email_address: match EmailAddress::from_str(&row.email_address) {
-                    Ok(email_address) => email_address,
-                    Err(_) => {
-                        return Err(sqlx::Error::Decode(
-                            format!("Error decoding `{}` as EmailAddress", row.email_address)
-                                .into(),
-                        ))
-                    }
-                },
-                full_name: row.full_name,
-                hashed_password: match HashedPassword::from_str(&row.hashed_password) {
-                    Ok(hashed_password) => hashed_password,
-                    Err(error) => {
-                        return Err(sqlx::Error::Decode(
-                            format!("Error decoding hashed password for user `{}`", email)
-                                .into(),
-                        ));
-                    }
-                },
-            })),
-            None => Ok(None),
-        })
+            .bind(previous)
+            .bind(&self.id)
+            .execute(&mut transaction)
+            .await?;
+            sqlx::query(
+                r#"
+DELETE FROM handle_history
+WHERE user_id = $1 AND lower(handle) NOT IN (
+    SELECT lower(handle) FROM handle_history
+    WHERE user_id = $1
+    ORDER BY retired_at DESC
+    LIMIT $2
+);"#,
+            )
+            .bind(&self.id)
+            .bind(MAX_RETIRED_HANDLES)
+            .execute(&mut transaction)
+            .await?;
+        }
+        if let Some(handle) = &self.handle {
+            // Taking back an old handle
+            sqlx::query(
+                "DELETE FROM handle_history WHERE lower(handle) = lower($1) AND user_id = $2;",
+            )
+            .bind(handle)
+            .bind(&self.id)
+            .execute(&mut transaction)
+            .await?;
+        }
+
+        let user = Self::try_from(row)?;
+        transaction.commit().await?;
+        Ok(Some((user, replaced_avatar_key)))
     }
 
-    pub async fn update(&self, db_pool: &sqlx::PgPool) -> Result<Self, sqlx::Error> {
-        todo!()
+    /// Point the user's avatar at an uploaded one, stored under `key`.
+    /// Returns the key of the uploaded avatar it replaces, if any, or `None`
+    /// if there's no such user.
+    pub async fn set_avatar(
+        db_pool: &sqlx::PgPool,
+        id: &Uuid,
+        key: &str,
+        avatar_url: &Url,
+    ) -> Result<Option<Option<String>>, sqlx::Error> {
+        let mut transaction = db_pool.begin().await?;
+        let previous_key: Option<String> =
+            match sqlx::query("SELECT avatar_key FROM users WHERE id = $1 FOR UPDATE;")
+                .bind(id)
+                .fetch_optional(&mut transaction)
+                .await?
+            {
+                Some(row) => row.try_get(0)?,
+                None => return Ok(None),
+            };
+        sqlx::query(
+            r#"
+UPDATE users
+SET avatar_key = $2, avatar_url = $3,
+    updated_at = greatest(now(), updated_at + interval '1 microsecond')
+WHERE id = $1;"#,
+        )
+        .bind(id)
+        .bind(key)
+        .bind(avatar_url.as_str())
+        .execute(&mut transaction)
+        .await?;
+        transaction.commit().await?;
+        Ok(Some(previous_key))
     }
 
-    pub async fn create(db_pool: &sqlx::PgPool, new_user: NewUser) -> Result<Self, sqlx::Error> {
-        // ON CONFLICT (email_address)
-        // DO
-        // UPDATE SET hashed_password = $2
+    /// The user going by `handle`, whatever its case. Suspended users'
+    /// profiles aren't public.
+    pub async fn get_by_handle(
+        db_pool: &sqlx::PgPool,
+        handle: &str,
+    ) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as!(
+            UserRow,
+            r#"
+SELECT id, email_address, full_name, hashed_password, verified_at, role, suspended_at,
+    handle, avatar_url, locale, timezone, created_at, updated_at
+FROM users WHERE lower(handle) = lower($1) AND suspended_at IS NULL;"#,
+            handle
+        )
+        .fetch_optional(db_pool)
+        .await?
+        .map(Self::try_from)
+        .transpose()
+    }
+
+    /// The handle the user who used to go by `old_handle` goes by now, if any
+    pub async fn current_handle_for(
+        db_pool: &sqlx::PgPool,
+        old_handle: &str,
+    ) -> Result<Option<String>, sqlx::Error> {
+        sqlx::query(
+            r#"
+SELECT users.handle
+FROM handle_history JOIN users ON users.id = handle_history.user_id
+WHERE lower(handle_history.handle) = lower($1)
+    AND handle_history.retired_at > now() - make_interval(days => $2)
+    AND users.handle IS NOT NULL
+    AND users.suspended_at IS NULL;"#,
+        )
+        .bind(old_handle)
+        .bind(HANDLE_RESERVATION_DAYS)
+        .fetch_optional(db_pool)
+        .await
+        .map(|maybe_row| maybe_row.map(|row| row.get(0)))
+    }
 
-        let result = sqlx::query(
+    /// Whether user `id` may go by `handle`: nobody else goes by it, or
+    /// went by it recently enough to still hold on to it
+    pub async fn is_handle_available(
+        db_pool: &sqlx::PgPool,
+        id: &Uuid,
+        handle: &str,
+    ) -> Result<bool, sqlx::Error> {
+        sqlx::query(
             r#"
-INSERT INTO users (email_address, hashed_password)
-VALUES ($1, $2)
-RETURNING id;"#,
+SELECT NOT EXISTS (SELECT 1 FROM users WHERE lower(handle) = lower($2) AND id <> $1)
+    AND NOT EXISTS (
+        SELECT 1 FROM handle_history
+        WHERE lower(handle) = lower($2) AND user_id <> $1
+            AND retired_at > now() - make_interval(days => $3)
+    );"#,
         )
-        .bind(new_user.email_address().to_str())
-        .bind(new_user.hashed_password().as_str())
+        .bind(id)
+        .bind(handle)
+        .bind(HANDLE_RESERVATION_DAYS)
         .fetch_one(db_pool)
+        .await
+        .map(|row| row.get(0))
+    }
+
+    /// Create a user, along with their default settings
+    pub async fn create(db_pool: &sqlx::PgPool, new_user: NewUser) -> Result<Self, sqlx::Error> {
+        let mut transaction = db_pool.begin().await?;
+        let row = sqlx::query_as!(
+            UserRow,
+            r#"
+INSERT INTO users (email_address, full_name, hashed_password)
+VALUES ($1, $2, $3)
+RETURNING id, email_address, full_name, hashed_password, verified_at, role, suspended_at,
+    handle, avatar_url, locale, timezone, created_at, updated_at;"#,
+            new_user.email_address().to_str(),
+            new_user.full_name(),
+            new_user.hashed_password().as_str()
+        )
+        .fetch_one(&mut transaction)
         .await?;
+        let user = Self::try_from(row)?;
+        Self::create_default_settings(&mut transaction, &user.id).await?;
+        transaction.commit().await?;
+        Ok(user)
+    }
 
-        Ok(Self {
-            id: result.get(0),
-            full_name: new_user.full_name().to_string(),
-            email_address: new_user.email_address().clone(),
-            hashed_password: new_user.hashed_password().clone(),
+    /// Create a user that can only log in through an OAuth provider. Only
+    /// provider-verified addresses are used for this, so the user starts out
+    /// verified.
+    pub async fn create_without_password(
+        db_pool: &sqlx::PgPool,
+        email_address: &EmailAddress,
+        full_name: &str,
+    ) -> Result<Self, sqlx::Error> {
+        let mut transaction = db_pool.begin().await?;
+        let row = sqlx::query_as!(
+            UserRow,
+            r#"
+INSERT INTO users (email_address, full_name, verified_at)
+VALUES ($1, $2, now())
+RETURNING id, email_address, full_name, hashed_password, verified_at, role, suspended_at,
+    handle, avatar_url, locale, timezone, created_at, updated_at;"#,
+            email_address.to_str(),
+            full_name
+        )
+        .fetch_one(&mut transaction)
+        .await?;
+        let user = Self::try_from(row)?;
+        Self::create_default_settings(&mut transaction, &user.id).await?;
+        transaction.commit().await?;
+        Ok(user)
+    }
+
+    async fn create_default_settings(
+        transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        id: &Uuid,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("INSERT INTO user_settings (user_id) VALUES ($1);")
+            .bind(id)
+            .execute(&mut *transaction)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn is_verified(db_pool: &sqlx::PgPool, id: &Uuid) -> Result<bool, sqlx::Error> {
+        sqlx::query("SELECT verified_at IS NOT NULL FROM users WHERE id = $1;")
+            .bind(id)
+            .fetch_optional(db_pool)
+            .await
+            .map(|maybe_row| maybe_row.map(|row| row.get(0)).unwrap_or(false))
+    }
+
+    /// The role of an existing user
+    pub async fn role_of(db_pool: &sqlx::PgPool, id: &Uuid) -> Result<Option<Role>, sqlx::Error> {
+        let row = sqlx::query("SELECT role FROM users WHERE id = $1;")
+            .bind(id)
+            .fetch_optional(db_pool)
+            .await?;
+        row.map(|row| {
+            let role: String = row.try_get(0)?;
+            Role::from_str(&role).map_err(|_| {
+                sqlx::Error::Decode(format!("Error decoding `{}` as Role", role).into())
+            })
         })
+        .transpose()
+    }
+
+    pub async fn is_suspended(db_pool: &sqlx::PgPool, id: &Uuid) -> Result<bool, sqlx::Error> {
+        sqlx::query("SELECT suspended_at IS NOT NULL FROM users WHERE id = $1;")
+            .bind(id)
+            .fetch_optional(db_pool)
+            .await
+            .map(|maybe_row| maybe_row.map(|row| row.get(0)).unwrap_or(false))
+    }
+
+    /// Users whose email address or name contains `search`, if given, by
+    /// email address
+    pub async fn list(
+        db_pool: &sqlx::PgPool,
+        search: Option<&str>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<UserSummary>, sqlx::Error> {
+        let rows = sqlx::query(
+            r#"
+SELECT id, email_address, full_name, role, verified_at, suspended_at, suspension_reason
+FROM users
+WHERE $1::text IS NULL
+    OR strpos(lower(email_address), lower($1)) > 0
+    OR strpos(lower(full_name), lower($1)) > 0
+ORDER BY lower(email_address)
+LIMIT $2 OFFSET $3;"#,
+        )
+        .bind(search)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(db_pool)
+        .await?;
+        rows.iter().map(UserSummary::from_row).collect()
+    }
+
+    /// Suspend a user, logging them out everywhere. Returns `false` if
+    /// there's no such user, or they're already suspended.
+    pub async fn suspend(
+        db_pool: &sqlx::PgPool,
+        id: &Uuid,
+        reason: Option<&str>,
+    ) -> Result<bool, sqlx::Error> {
+        let mut transaction = db_pool.begin().await?;
+        let suspended = sqlx::query(
+            r#"
+UPDATE users SET suspended_at = now(), suspension_reason = $2
+WHERE id = $1 AND suspended_at IS NULL;"#,
+        )
+        .bind(id)
+        .bind(reason)
+        .execute(&mut transaction)
+        .await?
+        .rows_affected()
+            > 0;
+        if suspended {
+            Session::revoke_all_within(&mut transaction, id, None).await?;
+        }
+        transaction.commit().await?;
+        Ok(suspended)
+    }
+
+    /// Returns `false` if there's no such suspended user
+    pub async fn unsuspend(db_pool: &sqlx::PgPool, id: &Uuid) -> Result<bool, sqlx::Error> {
+        sqlx::query(
+            r#"
+UPDATE users SET suspended_at = NULL, suspension_reason = NULL
+WHERE id = $1 AND suspended_at IS NOT NULL;"#,
+        )
+        .bind(id)
+        .execute(db_pool)
+        .await
+        .map(|done| done.rows_affected() > 0)
+    }
+
+    pub async fn set_role(
+        db_pool: &sqlx::PgPool,
+        id: &Uuid,
+        role: Role,
+    ) -> Result<bool, sqlx::Error> {
+        sqlx::query("UPDATE users SET role = $2 WHERE id = $1;")
+            .bind(id)
+            .bind(role.as_str())
+            .execute(db_pool)
+            .await
+            .map(|done| done.rows_affected() > 0)
+    }
+
+    /// Make sure every one of `ids` is an admin. Returns how many users
+    /// weren't yet.
+    pub async fn grant_admin(db_pool: &sqlx::PgPool, ids: &[Uuid]) -> Result<u64, sqlx::Error> {
+        sqlx::query("UPDATE users SET role = $2 WHERE id = ANY($1) AND role <> $2;")
+            .bind(ids)
+            .bind(Role::Admin.as_str())
+            .execute(db_pool)
+            .await
+            .map(|done| done.rows_affected())
+    }
+
+    /// Swap in a rehashed password, unless it changed since `previous` was
+    /// read (e.g. through a concurrent reset)
+    pub async fn replace_hashed_password(
+        db_pool: &sqlx::PgPool,
+        id: &Uuid,
+        previous: &HashedPassword,
+        hashed_password: &HashedPassword,
+    ) -> Result<bool, sqlx::Error> {
+        sqlx::query("UPDATE users SET hashed_password = $3 WHERE id = $1 AND hashed_password = $2;")
+            .bind(id)
+            .bind(previous.as_str())
+            .bind(hashed_password.as_str())
+            .execute(db_pool)
+            .await
+            .map(|done| done.rows_affected() > 0)
+    }
+
+    /// Every stored password hash, for reporting. Unparseable ones are left
+    /// out and logged.
+    pub async fn list_hashed_passwords(
+        db_pool: &sqlx::PgPool,
+    ) -> Result<Vec<HashedPassword>, sqlx::Error> {
+        let rows =
+            sqlx::query("SELECT id, hashed_password FROM users WHERE hashed_password IS NOT NULL;")
+                .fetch_all(db_pool)
+                .await?;
+
+        Ok(rows
+            .iter()
+            .filter_map(|row| {
+                let hashed_password: String = row.get(1);
+                HashedPassword::from_str(&hashed_password)
+                    .map_err(|error| {
+                        log::warn!(
+                            "User {} has a bad password hash: {}",
+                            row.get::<Uuid, _>(0),
+                            error
+                        )
+                    })
+                    .ok()
+            })
+            .collect())
+    }
+
+    pub fn id(&self) -> &Uuid {
+        &self.id
+    }
+
+    pub fn email_address(&self) -> &EmailAddress {
+        &self.email_address
+    }
+
+    pub fn hashed_password(&self) -> Option<&HashedPassword> {
+        self.hashed_password.as_ref()
+    }
+
+    pub fn is_email_verified(&self) -> bool {
+        self.verified_at.is_some()
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn suspended_at(&self) -> Option<&DateTime<Utc>> {
+        self.suspended_at.as_ref()
+    }
+
+    pub fn updated_at(&self) -> &DateTime<Utc> {
+        &self.updated_at
     }
 
     pub fn get_profile(&self) -> UserProfile {
         UserProfile {
             id: self.id.clone(),
             email_address: self.email_address.clone(),
+            full_name: self.full_name.clone(),
+            handle: self.handle.clone(),
+            avatar_url: self.avatar_url.clone(),
+            locale: self.locale.clone(),
+            timezone: self.timezone.clone(),
+            created_at: self.created_at,
+            updated_at: self.updated_at,
         }
     }
+
+    /// What anyone may see of the user, if they have a handle to be found by
+    pub fn get_public_profile(&self) -> Option<PublicProfile> {
+        self.handle.as_ref().map(|handle| PublicProfile {
+            handle: handle.clone(),
+            full_name: self.full_name.clone(),
+            avatar_url: self.avatar_url.clone(),
+            created_at: self.created_at,
+        })
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -144,3 +685,81 @@ pub struct Video {
     created: DateTime<Utc>,
     updated: DateTime<Utc>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_db;
+
+    async fn rename(db_pool: &sqlx::PgPool, mut user: User, handle: &str) -> User {
+        let updated_at = user.updated_at;
+        user.apply(ProfileChanges {
+            full_name: None,
+            handle: Some(Some(handle.into())),
+            avatar_url: None,
+            locale: None,
+            timezone: None,
+            updated_at,
+        });
+        user.update(db_pool).await.unwrap().unwrap().0
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a database"]
+    async fn test_retired_handles_are_let_go() {
+        let db_pool = test_db::pool().await;
+        let prefix = Uuid::new_v4().to_simple().to_string()[..8].to_string();
+        let handles = (0..MAX_RETIRED_HANDLES + 2)
+            .map(|n| format!("h{}_{}", prefix, n))
+            .collect::<Vec<_>>();
+        let mut user = test_db::create_user(&db_pool).await;
+        for handle in &handles {
+            user = rename(&db_pool, user, handle).await;
+        }
+        let other_user = test_db::create_user(&db_pool).await;
+
+        // Only the last few retired handles are held on to
+        let (current, retired) = handles.split_last().unwrap();
+        let (let_go, held) = retired.split_at(retired.len() - MAX_RETIRED_HANDLES as usize);
+        for handle in let_go {
+            assert!(User::is_handle_available(&db_pool, other_user.id(), handle)
+                .await
+                .unwrap());
+            assert_eq!(
+                User::current_handle_for(&db_pool, handle).await.unwrap(),
+                None
+            );
+        }
+        for handle in held {
+            assert!(
+                !User::is_handle_available(&db_pool, other_user.id(), handle)
+                    .await
+                    .unwrap()
+            );
+            assert_eq!(
+                User::current_handle_for(&db_pool, handle).await.unwrap(),
+                Some(current.clone())
+            );
+        }
+
+        // ...and only for a while
+        sqlx::query(
+            r#"
+UPDATE handle_history SET retired_at = now() - make_interval(days => $2 + 1)
+WHERE user_id = $1;"#,
+        )
+        .bind(user.id())
+        .bind(HANDLE_RESERVATION_DAYS)
+        .execute(&db_pool)
+        .await
+        .unwrap();
+        for handle in held {
+            assert!(User::is_handle_available(&db_pool, other_user.id(), handle)
+                .await
+                .unwrap());
+        }
+        let other_user = rename(&db_pool, other_user, &held[0]).await;
+        assert_eq!(other_user.handle.as_deref(), Some(held[0].as_str()));
+    }
+}
@@ -1,4 +1,11 @@
-use warp::reject::{custom, Reject, Rejection};
+use serde::Serialize;
+use warp::{
+    http::StatusCode,
+    reject::{custom, Reject, Rejection},
+    Reply,
+};
+
+use crate::auth::AuthError;
 
 pub trait IntoRejection {
     fn into_rejection(self: Self) -> Rejection;
@@ -18,3 +25,125 @@ where
 //         custom(self)
 //     }
 // }
+
+/// Wraps any unexpected error (database, hashing, signing...) so it can
+/// travel through warp as a rejection.
+#[derive(Debug)]
+pub struct ServerError(anyhow::Error);
+
+impl Reject for ServerError {}
+
+pub fn server_error<E: Into<anyhow::Error>>(error: E) -> Rejection {
+    custom(ServerError(error.into()))
+}
+
+/// The request clashes with the current state of things, e.g. it would
+/// duplicate something unique
+#[derive(Debug)]
+pub struct Conflict(pub &'static str);
+
+impl Reject for Conflict {}
+
+/// Too many attempts; the client may try again after the given time
+#[derive(Debug)]
+pub struct TooManyRequests(pub chrono::Duration);
+
+impl Reject for TooManyRequests {}
+
+/// What's wrong with one of the submitted fields, so forms can show it next
+/// to the offending input
+#[derive(Debug, Serialize)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// Some of the submitted fields are invalid
+#[derive(Debug)]
+pub struct ValidationFailed(pub Vec<FieldError>);
+
+impl Reject for ValidationFailed {}
+
+#[derive(Serialize)]
+struct ErrorMessage<'a> {
+    code: u16,
+    message: String,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    fields: &'a [FieldError],
+}
+
+fn error_reply_with_fields(
+    status: StatusCode,
+    message: String,
+    fields: &[FieldError],
+) -> warp::reply::Response {
+    warp::reply::with_status(
+        warp::reply::json(&ErrorMessage {
+            code: status.as_u16(),
+            message,
+            fields,
+        }),
+        status,
+    )
+    .into_response()
+}
+
+fn error_reply(status: StatusCode, message: String) -> warp::reply::Response {
+    error_reply_with_fields(status, message, &[])
+}
+
+/// Turn our own rejections into proper responses. Anything else is handed
+/// back to warp so it can keep producing its default responses.
+pub async fn recover(rejection: Rejection) -> Result<warp::reply::Response, Rejection> {
+    if let Some(error) = rejection.find::<AuthError>() {
+        let status = match error {
+            AuthError::UnverifiedEmail
+            | AuthError::Forbidden
+            | AuthError::CrossSiteRequest
+            | AuthError::SuspendedAccount => StatusCode::FORBIDDEN,
+            AuthError::InvalidVerificationToken
+            | AuthError::InvalidResetToken
+            | AuthError::InvalidPasskey
+            | AuthError::InvalidCancelToken => StatusCode::BAD_REQUEST,
+            _ => StatusCode::UNAUTHORIZED,
+        };
+        return Ok(error_reply(status, error.to_string()));
+    }
+
+    if let Some(ValidationFailed(fields)) = rejection.find::<ValidationFailed>() {
+        return Ok(error_reply_with_fields(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Validation failed".into(),
+            fields,
+        ));
+    }
+
+    if let Some(TooManyRequests(retry_after)) = rejection.find::<TooManyRequests>() {
+        // Whole seconds, rounded up, so clients don't come back too early
+        let seconds = (retry_after.num_milliseconds() + 999) / 1000;
+        let mut response = error_reply(
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many attempts, try again later".into(),
+        );
+        response.headers_mut().insert(
+            warp::http::header::RETRY_AFTER,
+            warp::http::HeaderValue::from(seconds.max(1)),
+        );
+        return Ok(response);
+    }
+
+    if let Some(Conflict(message)) = rejection.find::<Conflict>() {
+        return Ok(error_reply(StatusCode::CONFLICT, message.to_string()));
+    }
+
+    if let Some(ServerError(error)) = rejection.find::<ServerError>() {
+        log::error!("{:?}", error);
+        return Ok(error_reply(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Internal server error".into(),
+        ));
+    }
+
+    Err(rejection)
+}
@@ -0,0 +1,379 @@
+use std::{
+    collections::HashMap,
+    env, fmt, fs,
+    io::{self, Read, Seek, SeekFrom},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use url::Url;
+
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    InvalidConfiguration(String),
+    InvalidKey(String),
+    IoError(io::Error),
+    TaskError(String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::InvalidConfiguration(reason) => {
+                write!(f, "Invalid storage configuration: {}", reason)
+            }
+            StorageError::InvalidKey(key) => write!(f, "Invalid storage key `{}`", key),
+            StorageError::IoError(error) => write!(f, "Storage error: {:?}", error),
+            StorageError::TaskError(reason) => write!(f, "Storage task failed: {}", reason),
+        }
+    }
+}
+
+impl From<io::Error> for StorageError {
+    fn from(error: io::Error) -> Self {
+        StorageError::IoError(error)
+    }
+}
+
+/// Keys are `/` separated paths of plain names, e.g. `avatars/<user>/<hash>`
+pub fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.split('/').all(|part| {
+            !part.is_empty()
+                && !part.starts_with('.')
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+        })
+}
+
+fn check_key(key: &str) -> Result<(), StorageError> {
+    if is_valid_key(key) {
+        Ok(())
+    } else {
+        Err(StorageError::InvalidKey(key.into()))
+    }
+}
+
+/// Where uploaded media (avatars, for now) is kept. Stored objects never
+/// change: new content goes under a new key. Storage calls may block, so
+/// call them through `in_background` from async code.
+pub trait Storage: Send + Sync {
+    fn put(&self, key: &str, content: &[u8]) -> Result<(), StorageError>;
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError>;
+
+    /// Up to `length` bytes of the object at `key`, from `offset` on. Fewer
+    /// come back only at its end, so large objects can be read a chunk at a
+    /// time.
+    fn get_range(
+        &self,
+        key: &str,
+        offset: u64,
+        length: usize,
+    ) -> Result<Option<Vec<u8>>, StorageError>;
+
+    /// Delete the object at `key`, or every object under it. Deleting what
+    /// isn't there is no error.
+    fn delete(&self, key: &str) -> Result<(), StorageError>;
+
+    /// Where clients can download the object at `key` from
+    fn url(&self, key: &str) -> Result<Url, StorageError>;
+
+    /// What every object's URL starts with
+    fn base_url(&self) -> &Url;
+}
+
+/// Keeps objects as files in a directory, served by the media route
+pub struct DirectoryStorage {
+    directory: PathBuf,
+    base_url: Url,
+}
+
+impl DirectoryStorage {
+    pub fn new<P: Into<PathBuf>>(directory: P, base_url: Url) -> Self {
+        Self {
+            directory: directory.into(),
+            base_url,
+        }
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, StorageError> {
+        check_key(key)?;
+        Ok(key
+            .split('/')
+            .fold(self.directory.clone(), |path, part| path.join(part)))
+    }
+}
+
+impl Storage for DirectoryStorage {
+    fn put(&self, key: &str, content: &[u8]) -> Result<(), StorageError> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Write aside and rename, so nobody ever reads half an object
+        let partial = path.with_extension(format!("partial-{}", sqlx::types::Uuid::new_v4()));
+        fs::write(&partial, content)?;
+        fs::rename(&partial, &path)?;
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        match fs::read(self.path(key)?) {
+            Ok(content) => Ok(Some(content)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    fn get_range(
+        &self,
+        key: &str,
+        offset: u64,
+        length: usize,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        let mut file = match fs::File::open(self.path(key)?) {
+            Ok(file) => file,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error.into()),
+        };
+        file.seek(SeekFrom::Start(offset))?;
+        let mut content = Vec::with_capacity(length);
+        file.take(length as u64).read_to_end(&mut content)?;
+        Ok(Some(content))
+    }
+
+    fn delete(&self, key: &str) -> Result<(), StorageError> {
+        let path = self.path(key)?;
+        let deleted = if path.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+        match deleted {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn url(&self, key: &str) -> Result<Url, StorageError> {
+        check_key(key)?;
+        self.base_url
+            .join(key)
+            .map_err(|_| StorageError::InvalidKey(key.into()))
+    }
+
+    fn base_url(&self) -> &Url {
+        &self.base_url
+    }
+}
+
+/// Keeps objects in memory, for tests
+pub struct MemoryStorage {
+    objects: Mutex<HashMap<String, Vec<u8>>>,
+    base_url: Url,
+}
+
+impl Default for MemoryStorage {
+    fn default() -> Self {
+        Self {
+            objects: Default::default(),
+            base_url: Url::parse("http://media.invalid/").unwrap(),
+        }
+    }
+}
+
+impl MemoryStorage {
+    #[cfg(test)]
+    pub fn keys(&self) -> Vec<String> {
+        let mut keys = self
+            .objects
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn put(&self, key: &str, content: &[u8]) -> Result<(), StorageError> {
+        check_key(key)?;
+        self.objects
+            .lock()
+            .unwrap()
+            .insert(key.into(), content.to_vec());
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        check_key(key)?;
+        Ok(self.objects.lock().unwrap().get(key).cloned())
+    }
+
+    fn get_range(
+        &self,
+        key: &str,
+        offset: u64,
+        length: usize,
+    ) -> Result<Option<Vec<u8>>, StorageError> {
+        check_key(key)?;
+        Ok(self.objects.lock().unwrap().get(key).map(|content| {
+            let start = (offset as usize).min(content.len());
+            let end = start.saturating_add(length).min(content.len());
+            content[start..end].to_vec()
+        }))
+    }
+
+    fn delete(&self, key: &str) -> Result<(), StorageError> {
+        check_key(key)?;
+        let prefix = format!("{}/", key);
+        self.objects
+            .lock()
+            .unwrap()
+            .retain(|stored, _| stored != key && !stored.starts_with(&prefix));
+        Ok(())
+    }
+
+    fn url(&self, key: &str) -> Result<Url, StorageError> {
+        check_key(key)?;
+        self.base_url
+            .join(key)
+            .map_err(|_| StorageError::InvalidKey(key.into()))
+    }
+
+    fn base_url(&self) -> &Url {
+        &self.base_url
+    }
+}
+
+/// Pick a storage through `WEFT_STORAGE`:
+///
+/// - `directory` (default): files in `WEFT_STORAGE_DIR` (`./storage`), served
+///   from `WEFT_MEDIA_URL`, which defaults to `$WEFT_PUBLIC_URL/api/v1/media/`
+/// - `memory`: keeps objects in memory
+pub fn storage_from_env() -> Result<Arc<dyn Storage>, StorageError> {
+    match env::var("WEFT_STORAGE").as_deref().unwrap_or("directory") {
+        "directory" => {
+            let base_url = match env::var("WEFT_MEDIA_URL") {
+                Ok(url) => url,
+                Err(_) => format!(
+                    "{}/api/v1/media/",
+                    env::var("WEFT_PUBLIC_URL")
+                        .as_deref()
+                        .unwrap_or("http://127.0.0.1:3030")
+                        .trim_end_matches('/')
+                ),
+            };
+            // Without the trailing slash, keys would replace the last part
+            // of the path instead of going under it
+            let base_url = format!("{}/", base_url.trim_end_matches('/'));
+            Ok(Arc::new(DirectoryStorage::new(
+                env::var("WEFT_STORAGE_DIR").unwrap_or_else(|_| "storage".into()),
+                Url::parse(&base_url)
+                    .map_err(|_| StorageError::InvalidConfiguration(base_url.clone()))?,
+            )))
+        }
+        "memory" => Ok(Arc::new(MemoryStorage::default())),
+        other => Err(StorageError::InvalidConfiguration(format!(
+            "Unknown storage `{}`",
+            other
+        ))),
+    }
+}
+
+/// Run `task` against `storage` on the blocking thread pool
+pub async fn in_background<T, F>(storage: Arc<dyn Storage>, task: F) -> Result<T, StorageError>
+where
+    T: Send + 'static,
+    F: FnOnce(&dyn Storage) -> Result<T, StorageError> + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || task(storage.as_ref()))
+        .await
+        .map_err(|error| StorageError::TaskError(format!("{:?}", error)))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exercise(storage: &dyn Storage) {
+        storage.put("avatars/a/1/64.png", b"small").unwrap();
+        storage.put("avatars/a/1/512.png", b"large").unwrap();
+        storage.put("avatars/a/2/64.png", b"other").unwrap();
+        assert_eq!(
+            storage.get("avatars/a/1/64.png").unwrap(),
+            Some(b"small".to_vec())
+        );
+        assert_eq!(storage.get("avatars/a/3/64.png").unwrap(), None);
+        assert_eq!(
+            storage.get_range("avatars/a/1/512.png", 1, 3).unwrap(),
+            Some(b"arg".to_vec())
+        );
+        assert_eq!(
+            storage.get_range("avatars/a/1/512.png", 3, 10).unwrap(),
+            Some(b"ge".to_vec())
+        );
+        assert_eq!(
+            storage.get_range("avatars/a/1/512.png", 10, 10).unwrap(),
+            Some(vec![])
+        );
+        assert_eq!(
+            storage.get_range("avatars/a/3/64.png", 0, 10).unwrap(),
+            None
+        );
+
+        storage.delete("avatars/a/1").unwrap();
+        storage.delete("avatars/a/1").unwrap();
+        assert_eq!(storage.get("avatars/a/1/512.png").unwrap(), None);
+        assert_eq!(
+            storage.get("avatars/a/2/64.png").unwrap(),
+            Some(b"other".to_vec())
+        );
+
+        assert!(matches!(
+            storage.put("avatars/../secrets", b""),
+            Err(StorageError::InvalidKey(_))
+        ));
+        assert!(matches!(
+            storage.get("/etc/passwd"),
+            Err(StorageError::InvalidKey(_))
+        ));
+    }
+
+    #[test]
+    fn test_memory_storage() {
+        let storage = MemoryStorage::default();
+        exercise(&storage);
+        assert_eq!(storage.keys(), vec!["avatars/a/2/64.png".to_string()]);
+    }
+
+    #[test]
+    fn test_directory_storage() {
+        let directory =
+            env::temp_dir().join(format!("weft-storage-{}", sqlx::types::Uuid::new_v4()));
+        let storage = DirectoryStorage::new(
+            &directory,
+            Url::parse("https://weft.example/api/v1/media/").unwrap(),
+        );
+        exercise(&storage);
+        assert_eq!(
+            storage.url("avatars/a/2/64.png").unwrap().as_str(),
+            "https://weft.example/api/v1/media/avatars/a/2/64.png"
+        );
+
+        fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn test_keys() {
+        assert!(is_valid_key("avatars/0f8e/64.jpg"));
+        assert!(!is_valid_key(""));
+        assert!(!is_valid_key("avatars//64.jpg"));
+        assert!(!is_valid_key("avatars/.hidden"));
+        assert!(!is_valid_key("avatars/a b"));
+    }
+}
@@ -0,0 +1,31 @@
+//! For the tests that need a database. They're `#[ignore]`d, so plain
+//! `cargo test` still works anywhere, and shows them as ignored rather than
+//! passed. To run them (CI should), point `WEFT_TEST_DATABASE_URL` at a
+//! scratch database with the migrations run (`sqlx migrate run`), then run
+//! `cargo test -- --ignored`.
+
+use std::{env, str::FromStr};
+
+use sqlx::types::Uuid;
+
+use crate::{auth::EmailAddress, models::User};
+
+/// The test database. Tests that need one fail without it: they'd
+/// otherwise pass without checking anything.
+pub async fn pool() -> sqlx::PgPool {
+    let url = env::var("WEFT_TEST_DATABASE_URL")
+        .expect("Database tests need WEFT_TEST_DATABASE_URL to be set");
+    sqlx::PgPool::connect(&url)
+        .await
+        .expect("Could not connect to WEFT_TEST_DATABASE_URL")
+}
+
+/// A new, verified user without a password. Every test gets its own, so
+/// tests don't step on each other.
+pub async fn create_user(db_pool: &sqlx::PgPool) -> User {
+    let email_address = EmailAddress::from_str(&format!("{}@weft.example", Uuid::new_v4()))
+        .expect("Generated email address must be valid");
+    User::create_without_password(db_pool, &email_address, "Test User")
+        .await
+        .expect("Could not create test user")
+}
@@ -0,0 +1,412 @@
+//! Just enough of the ZIP format to stream an archive out as it's built:
+//! files are stored as they are, without compression, each one right after
+//! its header and followed by a data descriptor, and the central directory
+//! comes last. Nothing is ever seeked back to, and a file's size and CRC
+//! only have to be known once it's all gone out, so neither the archive nor
+//! any file in it has to exist as a whole anywhere.
+//!
+//! Media is compressed already, and JSON is small next to it, so deflating
+//! wouldn't buy much. ZIP64 records are added only once sizes or offsets
+//! outgrow the 32-bit fields, which every unzipper we care about reads.
+
+use chrono::{DateTime, Datelike, Timelike, Utc};
+
+const LOCAL_FILE_HEADER: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR: u32 = 0x0807_4b50;
+const CENTRAL_DIRECTORY_HEADER: u32 = 0x0201_4b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY: u32 = 0x0606_4b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR: u32 = 0x0706_4b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+const ZIP64_EXTRA_FIELD: u16 = 0x0001;
+
+const VERSION: u16 = 20;
+const ZIP64_VERSION: u16 = 45;
+/// CRC and sizes come in a data descriptor after the file, instead of in
+/// its local header
+const DATA_DESCRIPTOR_FOLLOWS: u16 = 1 << 3;
+/// File names are UTF-8
+const UTF8_NAMES: u16 = 1 << 11;
+const FLAGS: u16 = DATA_DESCRIPTOR_FOLLOWS | UTF8_NAMES;
+const STORED: u16 = 0;
+
+/// Stands in for values too large for their field, found in the ZIP64
+/// extra field instead
+const U32_OVERFLOW: u32 = u32::MAX;
+const U16_OVERFLOW: u16 = u16::MAX;
+
+fn put_u16(buffer: &mut Vec<u8>, value: u16) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buffer: &mut Vec<u8>, value: u32) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buffer: &mut Vec<u8>, value: u64) {
+    buffer.extend_from_slice(&value.to_le_bytes());
+}
+
+/// `value`, or the marker telling to look for it in the ZIP64 extra field
+fn u32_or_overflow(value: u64) -> u32 {
+    if value >= u64::from(U32_OVERFLOW) {
+        U32_OVERFLOW
+    } else {
+        value as u32
+    }
+}
+
+/// MS-DOS time and date, the only ones every unzipper understands. They
+/// can't go back further than 1980.
+fn dos_date_time(time: DateTime<Utc>) -> (u16, u16) {
+    if time.year() < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let dos_time = (time.hour() << 11) | (time.minute() << 5) | (time.second() / 2);
+    let dos_date = ((time.year() as u32 - 1980).min(127) << 9) | (time.month() << 5) | time.day();
+    (dos_time as u16, dos_date as u16)
+}
+
+/// A file whose header went out, waiting for its data descriptor
+#[derive(Debug)]
+struct PendingFile {
+    name: String,
+    time: u16,
+    date: u16,
+    /// Where its local header is
+    offset: u64,
+}
+
+/// Writes the headers of a ZIP archive, and keeps track of what's been
+/// written. Send each file's content right after its header, and its data
+/// descriptor right after that.
+#[derive(Debug, Default)]
+pub struct ZipStream {
+    /// Bytes of the archive written so far
+    offset: u64,
+    files: u64,
+    pending: Option<PendingFile>,
+    central_directory: Vec<u8>,
+}
+
+impl ZipStream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The header for file `name`, to be followed by its content, whatever
+    /// its size
+    pub fn file_header(&mut self, name: &str, modified: DateTime<Utc>) -> Vec<u8> {
+        let (time, date) = dos_date_time(modified);
+        let version = if self.offset >= u64::from(U32_OVERFLOW) {
+            ZIP64_VERSION
+        } else {
+            VERSION
+        };
+
+        let mut header = vec![];
+        put_u32(&mut header, LOCAL_FILE_HEADER);
+        put_u16(&mut header, version);
+        put_u16(&mut header, FLAGS);
+        put_u16(&mut header, STORED);
+        put_u16(&mut header, time);
+        put_u16(&mut header, date);
+        // CRC and sizes, found in the data descriptor instead
+        put_u32(&mut header, 0);
+        put_u32(&mut header, 0);
+        put_u32(&mut header, 0);
+        put_u16(&mut header, name.len() as u16);
+        put_u16(&mut header, 0);
+        header.extend_from_slice(name.as_bytes());
+
+        self.pending = Some(PendingFile {
+            name: name.into(),
+            time,
+            date,
+            offset: self.offset,
+        });
+        self.offset += header.len() as u64;
+        header
+    }
+
+    /// The data descriptor ending the file whose header went out last, once
+    /// all `size` bytes of it have, with `crc` their CRC-32
+    pub fn data_descriptor(&mut self, crc: u32, size: u64) -> Vec<u8> {
+        let file = self
+            .pending
+            .take()
+            .expect("A data descriptor follows a file header");
+        let zip64_size = size >= u64::from(U32_OVERFLOW);
+        let zip64_offset = file.offset >= u64::from(U32_OVERFLOW);
+        let version = if zip64_size || zip64_offset {
+            ZIP64_VERSION
+        } else {
+            VERSION
+        };
+
+        let mut descriptor = vec![];
+        put_u32(&mut descriptor, DATA_DESCRIPTOR);
+        put_u32(&mut descriptor, crc);
+        if zip64_size {
+            put_u64(&mut descriptor, size);
+            put_u64(&mut descriptor, size);
+        } else {
+            put_u32(&mut descriptor, size as u32);
+            put_u32(&mut descriptor, size as u32);
+        }
+
+        let mut extra = vec![];
+        if zip64_size || zip64_offset {
+            put_u16(&mut extra, ZIP64_EXTRA_FIELD);
+            put_u16(
+                &mut extra,
+                if zip64_size { 16 } else { 0 } + if zip64_offset { 8 } else { 0 },
+            );
+            if zip64_size {
+                put_u64(&mut extra, size);
+                put_u64(&mut extra, size);
+            }
+            if zip64_offset {
+                put_u64(&mut extra, file.offset);
+            }
+        }
+        let entry = &mut self.central_directory;
+        put_u32(entry, CENTRAL_DIRECTORY_HEADER);
+        put_u16(entry, ZIP64_VERSION);
+        put_u16(entry, version);
+        put_u16(entry, FLAGS);
+        put_u16(entry, STORED);
+        put_u16(entry, file.time);
+        put_u16(entry, file.date);
+        put_u32(entry, crc);
+        put_u32(entry, u32_or_overflow(size));
+        put_u32(entry, u32_or_overflow(size));
+        put_u16(entry, file.name.len() as u16);
+        put_u16(entry, extra.len() as u16);
+        // Comment length, disk number, internal and external attributes
+        put_u16(entry, 0);
+        put_u16(entry, 0);
+        put_u16(entry, 0);
+        put_u32(entry, 0);
+        put_u32(entry, u32_or_overflow(file.offset));
+        entry.extend_from_slice(file.name.as_bytes());
+        entry.extend_from_slice(&extra);
+
+        self.offset += size + descriptor.len() as u64;
+        self.files += 1;
+        descriptor
+    }
+
+    /// The central directory, which ends the archive
+    pub fn finish(self) -> Vec<u8> {
+        let directory_offset = self.offset;
+        let directory_size = self.central_directory.len() as u64;
+        let mut end = self.central_directory;
+
+        if self.files >= u64::from(U16_OVERFLOW)
+            || directory_offset >= u64::from(U32_OVERFLOW)
+            || directory_size >= u64::from(U32_OVERFLOW)
+        {
+            let zip64_end_offset = directory_offset + directory_size;
+            put_u32(&mut end, ZIP64_END_OF_CENTRAL_DIRECTORY);
+            // Size of the rest of the record
+            put_u64(&mut end, 44);
+            put_u16(&mut end, ZIP64_VERSION);
+            put_u16(&mut end, ZIP64_VERSION);
+            put_u32(&mut end, 0);
+            put_u32(&mut end, 0);
+            put_u64(&mut end, self.files);
+            put_u64(&mut end, self.files);
+            put_u64(&mut end, directory_size);
+            put_u64(&mut end, directory_offset);
+
+            put_u32(&mut end, ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR);
+            put_u32(&mut end, 0);
+            put_u64(&mut end, zip64_end_offset);
+            // Total number of disks
+            put_u32(&mut end, 1);
+        }
+
+        let files = if self.files >= u64::from(U16_OVERFLOW) {
+            U16_OVERFLOW
+        } else {
+            self.files as u16
+        };
+        put_u32(&mut end, END_OF_CENTRAL_DIRECTORY);
+        put_u16(&mut end, 0);
+        put_u16(&mut end, 0);
+        put_u16(&mut end, files);
+        put_u16(&mut end, files);
+        put_u32(&mut end, u32_or_overflow(directory_size));
+        put_u32(&mut end, u32_or_overflow(directory_offset));
+        // Comment length
+        put_u16(&mut end, 0);
+        end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::TimeZone;
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        let mut le = [0; 4];
+        le.copy_from_slice(&bytes[offset..offset + 4]);
+        u32::from_le_bytes(le)
+    }
+
+    fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+        let mut le = [0; 8];
+        le.copy_from_slice(&bytes[offset..offset + 8]);
+        u64::from_le_bytes(le)
+    }
+
+    /// Name and content of every file in `archive`, going from the central
+    /// directory to each file, the way unzippers do
+    fn unzip(archive: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let end = archive.len() - 22;
+        assert_eq!(u32_at(archive, end), END_OF_CENTRAL_DIRECTORY);
+        let files = u16_at(archive, end + 10);
+        let mut entry = u32_at(archive, end + 16) as usize;
+
+        (0..files)
+            .map(|_| {
+                assert_eq!(u32_at(archive, entry), CENTRAL_DIRECTORY_HEADER);
+                assert_eq!(u16_at(archive, entry + 8), FLAGS);
+                let crc = u32_at(archive, entry + 16);
+                let size = u32_at(archive, entry + 20) as usize;
+                let name_length = usize::from(u16_at(archive, entry + 28));
+                let extra_length = usize::from(u16_at(archive, entry + 30));
+                let name = &archive[entry + 46..entry + 46 + name_length];
+                let local = u32_at(archive, entry + 42) as usize;
+                entry += 46 + name_length + extra_length;
+
+                assert_eq!(u32_at(archive, local), LOCAL_FILE_HEADER);
+                assert_eq!(u16_at(archive, local + 6), FLAGS);
+                assert_eq!(u32_at(archive, local + 14), 0);
+                assert_eq!(&archive[local + 30..local + 30 + name_length], name);
+                let start = local
+                    + 30
+                    + usize::from(u16_at(archive, local + 26))
+                    + usize::from(u16_at(archive, local + 28));
+                let content = archive[start..start + size].to_vec();
+                assert_eq!(crc32fast::hash(&content), crc);
+
+                let descriptor = start + size;
+                assert_eq!(u32_at(archive, descriptor), DATA_DESCRIPTOR);
+                assert_eq!(u32_at(archive, descriptor + 4), crc);
+                assert_eq!(u32_at(archive, descriptor + 8) as usize, size);
+                assert_eq!(u32_at(archive, descriptor + 12) as usize, size);
+                (String::from_utf8(name.to_vec()).unwrap(), content)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_archive() {
+        let modified = Utc.ymd(2021, 3, 7).and_hms(12, 30, 10);
+        let files = vec![
+            (
+                "profile.json".to_string(),
+                b"{\"handle\":\"weft\"}".to_vec(),
+            ),
+            ("avatar/64.jpg".to_string(), vec![0xFF, 0xD8, 0, 1, 2]),
+            ("empty.txt".to_string(), vec![]),
+            ("ñandú.txt".to_string(), b"utf-8".to_vec()),
+        ];
+
+        let mut zip = ZipStream::new();
+        let mut archive = vec![];
+        for (name, content) in &files {
+            archive.extend(zip.file_header(name, modified));
+            // Content goes out in chunks, its CRC computed along the way
+            let mut crc = crc32fast::Hasher::new();
+            for chunk in content.chunks(2) {
+                crc.update(chunk);
+                archive.extend_from_slice(chunk);
+            }
+            archive.extend(zip.data_descriptor(crc.finalize(), content.len() as u64));
+        }
+        archive.extend(zip.finish());
+
+        assert_eq!(unzip(&archive), files);
+        assert_eq!(u16_at(&archive, 10), (12 << 11) | (30 << 5) | 5);
+        assert_eq!(u16_at(&archive, 12), (41 << 9) | (3 << 5) | 7);
+    }
+
+    #[test]
+    fn test_empty_archive() {
+        let archive = ZipStream::new().finish();
+        assert_eq!(archive.len(), 22);
+        assert!(unzip(&archive).is_empty());
+    }
+
+    #[test]
+    fn test_zip64_offsets() {
+        // As if over 4 GiB of files had gone out already
+        let mut zip = ZipStream {
+            offset: u64::from(u32::MAX) + 10,
+            ..ZipStream::default()
+        };
+        let header = zip.file_header("late.txt", Utc::now());
+        assert_eq!(u16_at(&header, 4), ZIP64_VERSION);
+        let descriptor = zip.data_descriptor(crc32fast::hash(b"late"), 4);
+        assert_eq!(descriptor.len(), 16);
+        let end = zip.finish();
+
+        // The central directory entry points at the ZIP64 extra field
+        assert_eq!(u32_at(&end, 42), U32_OVERFLOW);
+        let extra = 46 + "late.txt".len();
+        assert_eq!(u16_at(&end, extra), ZIP64_EXTRA_FIELD);
+        assert_eq!(u16_at(&end, extra + 2), 8);
+        assert_eq!(
+            &end[extra + 4..extra + 12],
+            &(u64::from(u32::MAX) + 10).to_le_bytes()
+        );
+
+        let zip64_end = extra + 12;
+        assert_eq!(u32_at(&end, zip64_end), ZIP64_END_OF_CENTRAL_DIRECTORY);
+        assert_eq!(
+            u32_at(&end, zip64_end + 56),
+            ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR
+        );
+        assert_eq!(u32_at(&end, end.len() - 22), END_OF_CENTRAL_DIRECTORY);
+        assert_eq!(u32_at(&end, end.len() - 6), U32_OVERFLOW);
+    }
+
+    #[test]
+    fn test_zip64_sizes() {
+        let size = u64::from(u32::MAX) + 1;
+        let mut zip = ZipStream::new();
+        zip.file_header("huge.bin", Utc::now());
+        let descriptor = zip.data_descriptor(0x1234_5678, size);
+
+        // 8-byte sizes, in the data descriptor and the ZIP64 extra field
+        assert_eq!(descriptor.len(), 24);
+        assert_eq!(u32_at(&descriptor, 0), DATA_DESCRIPTOR);
+        assert_eq!(u32_at(&descriptor, 4), 0x1234_5678);
+        assert_eq!(u64_at(&descriptor, 8), size);
+        assert_eq!(u64_at(&descriptor, 16), size);
+
+        let header_length = 30 + "huge.bin".len() as u64;
+        let end = zip.finish();
+        assert_eq!(u16_at(&end, 6), ZIP64_VERSION);
+        assert_eq!(u32_at(&end, 20), U32_OVERFLOW);
+        assert_eq!(u32_at(&end, 24), U32_OVERFLOW);
+        let extra = 46 + "huge.bin".len();
+        assert_eq!(u16_at(&end, extra), ZIP64_EXTRA_FIELD);
+        assert_eq!(u16_at(&end, extra + 2), 16);
+        assert_eq!(u64_at(&end, extra + 4), size);
+        assert_eq!(u64_at(&end, extra + 12), size);
+
+        // The central directory starts past the file and its descriptor
+        let zip64_end = extra + 20;
+        assert_eq!(u32_at(&end, zip64_end), ZIP64_END_OF_CENTRAL_DIRECTORY);
+        assert_eq!(u64_at(&end, zip64_end + 48), header_length + size + 24);
+    }
+}